@@ -0,0 +1,157 @@
+//! The peer map of the double server behind a `Mutex`, as it was, and
+//! behind a `RwLock`, as it is, see src/double_server/state.rs.
+//!
+//! `/who`, `/stats` and the gateway only read the map, peers joining and
+//! leaving write it. Every iteration has a number of readers walk the map
+//! at once, while a writer has peers join and leave:
+//!
+//! ```text
+//! cargo bench --bench peers
+//! ```
+//!
+//! The map is mirrored here rather than imported, the double server being
+//! a binary. The readers of a `RwLock` walk the map at once, those of a
+//! `Mutex` one after the other, which shows as readers are added.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// Peers of the map.
+const PEERS: u64 = 1000;
+/// Walks of every reader in one iteration.
+const WALKS: usize = 200;
+/// How long the writer waits between one peer leaving and joining again.
+const CHURN: Duration = Duration::from_micros(100);
+
+struct Member {
+    name: String,
+    sent: u64,
+}
+
+type Map = HashMap<u64, Member>;
+
+fn map() -> Map {
+    (0..PEERS)
+        .map(|id| {
+            let member = Member {
+                name: format!("peer-{}", id),
+                sent: id,
+            };
+            (id, member)
+        })
+        .collect()
+}
+
+fn walk(map: &Map) -> u64 {
+    map.values()
+        .map(|member| member.sent + member.name.len() as u64)
+        .sum()
+}
+
+fn churn(map: &mut Map, id: u64) {
+    if let Some(member) = map.remove(&id) {
+        map.insert(id, member);
+    }
+}
+
+/// A lock around the map.
+trait Shared: Send + Sync + 'static {
+    fn walk(&self) -> u64;
+    fn churn(&self, id: u64);
+}
+
+impl Shared for Mutex<Map> {
+    fn walk(&self) -> u64 {
+        walk(&self.lock().unwrap())
+    }
+
+    fn churn(&self, id: u64) {
+        churn(&mut self.lock().unwrap(), id)
+    }
+}
+
+impl Shared for RwLock<Map> {
+    fn walk(&self) -> u64 {
+        walk(&self.read().unwrap())
+    }
+
+    fn churn(&self, id: u64) {
+        churn(&mut self.write().unwrap(), id)
+    }
+}
+
+/// How long `readers` took to walk `shared` `WALKS` times each, the
+/// slowest of them, summed over `iters` iterations.
+fn contended<S: Shared>(shared: &Arc<S>, readers: usize, iters: u64) -> Duration {
+    let mut total = Duration::default();
+    for _ in 0..iters {
+        let start = Arc::new(Barrier::new(readers + 1));
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (shared, start, done) = (shared.clone(), start.clone(), done.clone());
+            thread::spawn(move || {
+                start.wait();
+                let mut id = 0;
+                while !done.load(Ordering::Relaxed) {
+                    shared.churn(id % PEERS);
+                    id += 1;
+                    thread::sleep(CHURN);
+                }
+            })
+        };
+        let walkers: Vec<_> = (1..readers)
+            .map(|_| {
+                let (shared, start) = (shared.clone(), start.clone());
+                thread::spawn(move || {
+                    start.wait();
+                    let began = Instant::now();
+                    for _ in 0..WALKS {
+                        black_box(shared.walk());
+                    }
+                    began.elapsed()
+                })
+            })
+            .collect();
+        // This thread is a reader too, the last.
+        start.wait();
+        let began = Instant::now();
+        for _ in 0..WALKS {
+            black_box(shared.walk());
+        }
+        let mut slowest = began.elapsed();
+        for walker in walkers {
+            slowest = slowest.max(walker.join().unwrap());
+        }
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        total += slowest;
+    }
+    total
+}
+
+fn peers(c: &mut Criterion) {
+    let mutex = Arc::new(Mutex::new(map()));
+    let rwlock = Arc::new(RwLock::new(map()));
+    let mut group = c.benchmark_group("peers");
+    for &readers in &[1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("mutex", readers),
+            &readers,
+            |b, &readers| b.iter_custom(|iters| contended(&mutex, readers, iters)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("rwlock", readers),
+            &readers,
+            |b, &readers| b.iter_custom(|iters| contended(&rwlock, readers, iters)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, peers);
+criterion_main!(benches);
@@ -0,0 +1,228 @@
+//! Not calling what keeps failing.
+//!
+//! A `Breaker` watches the outcome of calls to something downstream. After
+//! `failures` failures in a row it opens, and calls are turned down right
+//! away instead of waiting on something that is down. After `open_for` it
+//! half-opens: one call goes through as a probe, the others are still
+//! turned down. A probe that succeeds closes it again, one that fails opens
+//! it for another `open_for`.
+//!
+//! ```ignore
+//! let breaker = Breaker::new(5, Duration::from_secs(30));
+//! let call = breaker.call(|| post(&url, &body)).map_err(|e| match e {
+//!     Rejected::Open => warn!("{} is down, not calling", url),
+//!     Rejected::Failed(e) => warn!("{} failed: {}", url, e),
+//! });
+//! ```
+//!
+//! Code polling its own state machine uses `allow`, `success` and `failure`
+//! directly. A breaker of 0 `failures` never opens.
+
+use futures::{Async, Future, IntoFuture, Poll};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Whether a `Breaker` lets calls through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Circuit {
+    /// Every call goes through.
+    Closed,
+    /// Calls are turned down.
+    Open,
+    /// One call is let through to see if the other end is back.
+    HalfOpen,
+}
+
+impl Circuit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Counts the failures, see the module docs. Clones share them.
+#[derive(Clone)]
+pub struct Breaker {
+    inner: Arc<Mutex<Inner>>,
+    threshold: u32,
+    open_for: Duration,
+}
+
+struct Inner {
+    circuit: Circuit,
+    /// Failures in a row while closed.
+    failures: u32,
+    /// When an open breaker half-opens.
+    until: Instant,
+    /// Whether the probe of a half-open breaker is out.
+    probing: bool,
+    /// Times it opened.
+    trips: u64,
+}
+
+impl Breaker {
+    pub fn new(failures: u32, open_for: Duration) -> Breaker {
+        Breaker {
+            inner: Arc::new(Mutex::new(Inner {
+                circuit: Circuit::Closed,
+                failures: 0,
+                until: Instant::now(),
+                probing: false,
+                trips: 0,
+            })),
+            threshold: failures,
+            open_for,
+        }
+    }
+
+    /// Whether a call may go out now. An open breaker past `open_for`
+    /// half-opens and lets this call through as its probe.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.circuit {
+            Circuit::Closed => true,
+            Circuit::Open if Instant::now() >= inner.until => {
+                inner.circuit = Circuit::HalfOpen;
+                inner.probing = true;
+                true
+            }
+            Circuit::Open => false,
+            Circuit::HalfOpen if !inner.probing => {
+                inner.probing = true;
+                true
+            }
+            Circuit::HalfOpen => false,
+        }
+    }
+
+    /// A call that was allowed succeeded.
+    pub fn success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.circuit = Circuit::Closed;
+        inner.failures = 0;
+        inner.probing = false;
+    }
+
+    /// A call that was allowed failed.
+    pub fn failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.circuit {
+            Circuit::Closed => {
+                inner.failures += 1;
+                if self.threshold == 0 || inner.failures < self.threshold {
+                    return;
+                }
+            }
+            // Calls allowed before it opened may still be failing.
+            Circuit::Open => return,
+            Circuit::HalfOpen => {}
+        }
+        inner.circuit = Circuit::Open;
+        inner.until = Instant::now() + self.open_for;
+        inner.failures = 0;
+        inner.probing = false;
+        inner.trips += 1;
+    }
+
+    /// Whether a call now would be turned down, without making it the
+    /// probe. Work only worth doing if the call goes through can be shed
+    /// with it.
+    pub fn rejecting(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.circuit {
+            Circuit::Closed => false,
+            Circuit::Open => Instant::now() < inner.until,
+            Circuit::HalfOpen => inner.probing,
+        }
+    }
+
+    /// A call that was allowed was given up before it finished, a probe
+    /// can be sent again.
+    fn abandoned(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.circuit == Circuit::HalfOpen {
+            inner.probing = false;
+        }
+    }
+
+    /// What it does with the next call, before `allow` half-opens it.
+    pub fn circuit(&self) -> Circuit {
+        self.inner.lock().unwrap().circuit
+    }
+
+    /// How many times it opened.
+    pub fn trips(&self) -> u64 {
+        self.inner.lock().unwrap().trips
+    }
+
+    /// Make the call of `call` if the breaker allows it, and count how it
+    /// went.
+    pub fn call<C, F>(&self, call: C) -> Call<F::Future>
+    where
+        C: FnOnce() -> F,
+        F: IntoFuture,
+    {
+        let future = if self.allow() {
+            Some(call().into_future())
+        } else {
+            None
+        };
+        Call {
+            breaker: self.clone(),
+            future,
+        }
+    }
+}
+
+/// Why a `Call` failed.
+#[derive(Debug)]
+pub enum Rejected<E> {
+    /// The breaker did not let it through.
+    Open,
+    /// It was made, and failed.
+    Failed(E),
+}
+
+/// The future of `Breaker::call`.
+pub struct Call<F> {
+    breaker: Breaker,
+    /// `None` if the breaker turned it down.
+    future: Option<F>,
+}
+
+impl<F: Future> Future for Call<F> {
+    type Item = F::Item;
+    type Error = Rejected<F::Error>;
+
+    fn poll(&mut self) -> Poll<F::Item, Rejected<F::Error>> {
+        let future = match &mut self.future {
+            Some(future) => future,
+            None => return Err(Rejected::Open),
+        };
+        let result = match future.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(item)) => {
+                self.breaker.success();
+                Ok(Async::Ready(item))
+            }
+            Err(e) => {
+                self.breaker.failure();
+                Err(Rejected::Failed(e))
+            }
+        };
+        self.future = None;
+        result
+    }
+}
+
+impl<F> Drop for Call<F> {
+    fn drop(&mut self) {
+        if self.future.is_some() {
+            self.breaker.abandoned();
+        }
+    }
+}
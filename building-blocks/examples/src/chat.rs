@@ -23,14 +23,19 @@
 //! two, seeing the messages from the other client as they're received. For all
 //! connected clients they'll all join the same room and see everyone else's
 //! messages.
+//!
+//! Every client may send `LINES_PER_SECOND` lines per second, after a burst of
+//! `BURST_LINES`. Lines beyond that are not dropped, they are just read later.
 
 #![deny(warnings)]
 
 extern crate tokio;
 #[macro_use]
 extern crate futures;
+extern crate building_blocks;
 extern crate bytes;
 
+use building_blocks::shaping::{ShapedStream, TokenBucket};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::future::{self, Either};
 use futures::sync::mpsc;
@@ -42,6 +47,12 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
+/// Lines a client may send per second, on average.
+const LINES_PER_SECOND: f64 = 5.0;
+
+/// Lines a client may send in a burst.
+const BURST_LINES: u64 = 10;
+
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::UnboundedSender<Bytes>;
 
@@ -76,8 +87,9 @@ struct Peer {
     ///
     /// This handles sending and receiving data on the socket. When using
     /// `Lines`, we can work at the line level instead of having to manage the
-    /// raw byte operations.
-    lines: Lines,
+    /// raw byte operations. The lines read are shaped by a token bucket, so
+    /// that a single client cannot flood the chat.
+    lines: ShapedStream<Lines, TokenBucket>,
 
     /// Handle to the shared chat state.
     ///
@@ -143,7 +155,7 @@ impl Peer {
 
         Peer {
             name,
-            lines,
+            lines: ShapedStream::new(lines, TokenBucket::new(LINES_PER_SECOND, BURST_LINES)),
             state,
             rx,
             addr,
@@ -188,7 +200,7 @@ impl Future for Peer {
                 Async::Ready(Some(v)) => {
                     // Buffer the line. Once all lines are buffered, they will
                     // be flushed to the socket (right below).
-                    self.lines.buffer(&v);
+                    self.lines.get_mut().buffer(&v);
 
                     // If this is the last iteration, the loop will break even
                     // though there could still be lines to read. Because we did
@@ -203,7 +215,7 @@ impl Future for Peer {
         }
 
         // Flush the write buffer to the socket
-        let _ = self.lines.poll_flush()?;
+        let _ = self.lines.get_mut().poll_flush()?;
 
         // Read new lines from the socket
         while let Async::Ready(line) = self.lines.poll()? {
@@ -0,0 +1,340 @@
+//! Run the same load against chat servers, and compare how they did.
+//!
+//! Every server is given as a name and the command starting it, with
+//! `{addr}` for the address it is to listen on. A server relaying between
+//! two sides, like the `double_server`, has `{other}` for that of the other
+//! side too:
+//!
+//!     $ cargo run --release --bin chat-bench -- --peers 10 --messages 500 \
+//!           "rust=target/release/double_server {addr} {other}" \
+//!           "go=../go-chat/chat-server -listen {addr}"
+//!     server   delivered        per sec   p50 ms   p99 ms   max ms   cpu s
+//!     rust     50000/50000       118342     2.10     6.91    11.02    0.61
+//!     go       95000/95000       402113     3.32    12.80    20.47    1.35
+//!
+//! `--peers` peers connect to `{addr}` and send `--messages` messages of
+//! `--bytes` bytes each, as fast as the server takes them, and as many hear
+//! them at `{other}`, or at `{addr}` too when there is none. There, every
+//! peer hears every other, so many more messages are delivered. A message
+//! carries when it was sent, for the latency when it arrives. The run ends
+//! once every message arrived, or nothing arrived for `--timeout-secs`.
+//!
+//! The CPU time is that of the process the command starts, read from
+//! `/proc`, so a server is best started itself rather than by `cargo run`.
+//! Build the servers with `--release` first, for numbers worth comparing.
+//! The protocol is that of `chat-conformance`.
+
+#![deny(warnings)]
+
+extern crate libc;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest wait for a server to listen.
+const START_WAIT: Duration = Duration::from_secs(10);
+
+/// Wait for the peers to be taken in before they send.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// The load every server is put under.
+#[derive(Clone, Copy)]
+struct Load {
+    peers: usize,
+    messages: usize,
+    bytes: usize,
+    timeout: Duration,
+}
+
+/// How one server did.
+struct Report {
+    name: String,
+    delivered: usize,
+    expected: usize,
+    elapsed: Duration,
+    /// Latencies in microseconds, sorted.
+    latencies: Vec<u64>,
+    cpu: Option<Duration>,
+}
+
+/// A server killed once it was measured, however that ends.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn main() {
+    let (load, servers) = match parse_args() {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "usage: chat-bench [--peers <n>] [--messages <n>] [--bytes <n>] \
+                 [--timeout-secs <secs>] <name>=<command>..."
+            );
+            process::exit(2);
+        }
+    };
+
+    let mut reports = Vec::new();
+    for (name, command) in servers {
+        match run(&name, &command, load) {
+            Ok(report) => reports.push(report),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    println!(
+        "{:<8} {:>15} {:>12} {:>8} {:>8} {:>8} {:>7}",
+        "server", "delivered", "per sec", "p50 ms", "p99 ms", "max ms", "cpu s"
+    );
+    for report in &reports {
+        let rate = report.delivered as f64 / report.elapsed.as_secs_f64();
+        let cpu = report
+            .cpu
+            .map_or("-".to_string(), |cpu| format!("{:.2}", cpu.as_secs_f64()));
+        println!(
+            "{:<8} {:>15} {:>12.0} {:>8.2} {:>8.2} {:>8.2} {:>7}",
+            report.name,
+            format!("{}/{}", report.delivered, report.expected),
+            rate,
+            percentile(&report.latencies, 0.50),
+            percentile(&report.latencies, 0.99),
+            percentile(&report.latencies, 1.0),
+            cpu
+        );
+    }
+}
+
+fn parse_args() -> Result<(Load, Vec<(String, String)>), String> {
+    let mut load = Load {
+        peers: 10,
+        messages: 1000,
+        bytes: 64,
+        timeout: Duration::from_secs(10),
+    };
+    let mut servers = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut number = || -> Result<usize, String> {
+            let value = args.next().ok_or(format!("{} needs a value", arg))?;
+            value.parse().map_err(|e| format!("{}: {}", arg, e))
+        };
+        match arg.as_str() {
+            "--peers" => load.peers = number()?,
+            "--messages" => load.messages = number()?,
+            "--bytes" => load.bytes = number()?,
+            "--timeout-secs" => load.timeout = Duration::from_secs(number()? as u64),
+            server => match server.find('=') {
+                Some(at) => servers.push((server[..at].to_string(), server[at + 1..].to_string())),
+                None => return Err(format!("{:?} is not <name>=<command>", server)),
+            },
+        }
+    }
+    if servers.is_empty() {
+        return Err("no servers to compare".to_string());
+    }
+    if load.peers == 0 || load.messages == 0 {
+        return Err("--peers and --messages have to be at least 1".to_string());
+    }
+    Ok((load, servers))
+}
+
+/// Start the server of `command`, put it under `load`, and stop it. The
+/// CPU time is that of the load alone.
+fn run(name: &str, command: &str, load: Load) -> Result<Report, String> {
+    let (addr, other) = (free_addr(), free_addr());
+    let two_sided = command.contains("{other}");
+    let words: Vec<String> = command
+        .split_whitespace()
+        .map(|word| {
+            word.replace("{addr}", &addr.to_string())
+                .replace("{other}", &other.to_string())
+        })
+        .collect();
+    if words.is_empty() {
+        return Err("no command".to_string());
+    }
+    let server = Command::new(&words[0])
+        .args(&words[1..])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("{}: {}", words[0], e))?;
+    let server = Running(server);
+    let hearing_at = if two_sided { other } else { addr };
+    wait_listening(addr)?;
+    wait_listening(hearing_at)?;
+
+    // Every message reaches the peers hearing, and also the other peers
+    // sending when they share the side.
+    let sent = load.peers * load.messages;
+    let expected = if two_sided {
+        sent * load.peers
+    } else {
+        sent * (2 * load.peers - 1)
+    };
+
+    let start = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut senders = Vec::new();
+    for peer in 0..load.peers {
+        let hearing = connect(hearing_at, &format!("hear{}", peer))?;
+        spawn_hearing(hearing, start, tx.clone());
+        let sending = connect(addr, &format!("send{}", peer))?;
+        if !two_sided {
+            spawn_hearing(
+                sending.try_clone().map_err(|e| e.to_string())?,
+                start,
+                tx.clone(),
+            );
+        }
+        senders.push(sending);
+    }
+    drop(tx);
+    thread::sleep(SETTLE);
+
+    let cpu_before = cpu_time(server.0.id());
+    let sending_from = Instant::now();
+    let body = "x".repeat(load.bytes.saturating_sub(24));
+    let threads: Vec<_> = senders
+        .into_iter()
+        .map(|mut stream| {
+            let body = body.clone();
+            thread::spawn(move || -> io::Result<()> {
+                for _ in 0..load.messages {
+                    let sent_us = start.elapsed().as_micros() as u64;
+                    write!(stream, "{} {}\r\n", sent_us, body)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(expected);
+    let mut last = Instant::now();
+    while latencies.len() < expected {
+        match rx.recv_timeout(load.timeout) {
+            Ok(latency) => {
+                latencies.push(latency);
+                last = Instant::now();
+            }
+            Err(_) => break,
+        }
+    }
+    let cpu = match (cpu_before, cpu_time(server.0.id())) {
+        (Some(before), Some(after)) => Some(after - before),
+        _ => None,
+    };
+    for thread in threads {
+        if let Ok(Err(e)) = thread.join() {
+            eprintln!("{}: a peer could not send: {}", name, e);
+        }
+    }
+    drop(server);
+
+    latencies.sort_unstable();
+    Ok(Report {
+        name: name.to_string(),
+        delivered: latencies.len(),
+        expected,
+        elapsed: last.duration_since(sending_from),
+        latencies,
+        cpu,
+    })
+}
+
+/// Connect a peer called `name`.
+fn connect(addr: SocketAddr, name: &str) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(addr).map_err(|e| format!("connect {}: {}", addr, e))?;
+    write!(stream, "{}\r\n", name).map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Send the latency of every message `stream` hears to `tx`, until it
+/// closes. Lines without a time, like notices, are left out.
+fn spawn_hearing(stream: TcpStream, start: Instant, tx: mpsc::Sender<u64>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            // `name: <micros> xxx...`
+            let sent_us = line
+                .split_once(": ")
+                .map(|(_, text)| text)
+                .and_then(|text| text.split(' ').next())
+                .and_then(|micros| micros.trim().parse::<u64>().ok());
+            if let Some(sent_us) = sent_us {
+                let now_us = start.elapsed().as_micros() as u64;
+                if tx.send(now_us.saturating_sub(sent_us)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// The latency at `rank` of `sorted`, in milliseconds.
+fn percentile(sorted: &[u64], rank: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let at = ((sorted.len() - 1) as f64 * rank).round() as usize;
+    sorted[at] as f64 / 1000.0
+}
+
+/// The user and system time the process `pid` took so far, on Linux.
+fn cpu_time(pid: u32) -> Option<Duration> {
+    let mut stat = String::new();
+    fs::File::open(format!("/proc/{}/stat", pid))
+        .ok()?
+        .read_to_string(&mut stat)
+        .ok()?;
+    // The name in parentheses may have spaces, the fields after it not.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks <= 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        (utime + stime) as f64 / ticks as f64,
+    ))
+}
+
+/// An address nothing listens on, most likely still free when a server
+/// binds it.
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind a free port");
+    listener.local_addr().expect("the bound address")
+}
+
+fn wait_listening(addr: SocketAddr) -> Result<(), String> {
+    let deadline = Instant::now() + START_WAIT;
+    while TcpStream::connect(addr).is_err() {
+        if Instant::now() > deadline {
+            return Err(format!("nothing listens on {}", addr));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
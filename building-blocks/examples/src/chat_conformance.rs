@@ -0,0 +1,364 @@
+//! Check that a chat server speaks the protocol of `chat` and the
+//! `double_server`.
+//!
+//! Give the address peers connect to. A server relaying between two sides,
+//! like the `double_server`, is given the address of the other side too,
+//! whose peers hear those of the first:
+//!
+//!     $ cargo run --bin chat-conformance 127.0.0.1:8081 127.0.0.1:8080
+//!     ok    handshake    a name, then messages relayed as `name: line`
+//!     ok    ordering     200 messages relayed in the order sent
+//!     ok    lines        4096 bytes relayed, 1048577 without a break refused
+//!     FAIL  commands     no answer to /who naming sending3
+//!     ok    keepalive    5s idle kept open, 0 empty probes
+//!     5 checks, 1 failed
+//!
+//! Lines end with `\r\n`. The first line of a peer is its name, and every
+//! other one goes to the peers of the other side, or the other peers, as
+//! `name: line`, in the order sent, and not back to the peer. A line
+//! starting with `/` is a command, answered to the peer that sent it only.
+//! A peer sending more than a limit without a line break is disconnected.
+//! An idle peer stays connected, and is sent nothing but empty lines the
+//! server may probe it with, see `double_server::heartbeat`. The limit is
+//! that of the `double_server` unless given with `--max-line <bytes>`, and
+//! a peer stays idle `--idle-secs <secs>` before it sends again.
+//!
+//! The program exits with an error if a check failed, so it can run in CI.
+
+#![deny(warnings)]
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Longest wait for a line to arrive.
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Messages relayed by the ordering check.
+const ORDERED: usize = 200;
+
+/// Bytes of the long line the lines check has relayed.
+const LONG_LINE: usize = 4096;
+
+/// `max_unframed_bytes` of the `double_server` by default.
+const DEFAULT_MAX_LINE: usize = 1024 * 1024;
+
+/// The servers to check and how.
+struct Target {
+    /// Where the peers sending connect.
+    from: SocketAddr,
+    /// Where those hearing them connect.
+    to: SocketAddr,
+    max_line: usize,
+    idle: Duration,
+}
+
+type Check = fn(&Target, usize) -> Result<String, String>;
+
+fn main() {
+    let target = match parse_args() {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "usage: chat-conformance <addr> [<other side>] [--max-line <bytes>] [--idle-secs <secs>]"
+            );
+            process::exit(2);
+        }
+    };
+
+    let checks: [(&str, Check); 5] = [
+        ("handshake", handshake),
+        ("ordering", ordering),
+        ("lines", lines),
+        ("commands", commands),
+        ("keepalive", keepalive),
+    ];
+    let mut failed = 0;
+    for (index, (name, check)) in checks.iter().enumerate() {
+        match check(&target, index) {
+            Ok(done) => println!("ok    {:<12} {}", name, done),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {:<12} {}", name, e);
+            }
+        }
+    }
+    println!("{} checks, {} failed", checks.len(), failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn parse_args() -> Result<Target, String> {
+    let mut addrs = Vec::new();
+    let mut max_line = DEFAULT_MAX_LINE;
+    let mut idle = Duration::from_secs(5);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--max-line" => {
+                max_line = value()?.parse().map_err(|e| format!("--max-line: {}", e))?;
+            }
+            "--idle-secs" => {
+                let secs = value()?
+                    .parse()
+                    .map_err(|e| format!("--idle-secs: {}", e))?;
+                idle = Duration::from_secs(secs);
+            }
+            addr => {
+                let resolved = addr
+                    .to_socket_addrs()
+                    .map_err(|e| format!("{}: {}", addr, e))?
+                    .next()
+                    .ok_or(format!("{} does not resolve", addr))?;
+                addrs.push(resolved);
+            }
+        }
+    }
+    match addrs[..] {
+        [from] => Ok(Target {
+            from,
+            to: from,
+            max_line,
+            idle,
+        }),
+        [from, to] => Ok(Target {
+            from,
+            to,
+            max_line,
+            idle,
+        }),
+        _ => Err("one or two addresses".to_string()),
+    }
+}
+
+/// A connected peer.
+struct Peer {
+    name: String,
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Peer {
+    fn connect(addr: SocketAddr, name: String) -> Result<Peer, String> {
+        let stream = TcpStream::connect(addr).map_err(|e| format!("connect {}: {}", addr, e))?;
+        let reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let mut peer = Peer {
+            name,
+            stream,
+            reader,
+        };
+        let name = peer.name.clone();
+        peer.send(&name)?;
+        Ok(peer)
+    }
+
+    fn send(&mut self, line: &str) -> Result<(), String> {
+        self.write(format!("{}\r\n", line).as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.stream
+            .write_all(bytes)
+            .map_err(|e| format!("{} could not send: {}", self.name, e))
+    }
+
+    /// The next line, without its line break, `None` if none came in
+    /// `wait`. A closed connection is an error.
+    fn line(&mut self, wait: Duration) -> Result<Option<String>, String> {
+        let wait = wait.max(Duration::from_millis(1));
+        self.stream
+            .set_read_timeout(Some(wait))
+            .map_err(|e| e.to_string())?;
+        let mut bytes = Vec::new();
+        match self.reader.read_until(b'\n', &mut bytes) {
+            Ok(0) => Err(format!("the server closed the connection of {}", self.name)),
+            Ok(_) => {
+                if !bytes.ends_with(b"\r\n") {
+                    let line = String::from_utf8_lossy(&bytes);
+                    return Err(format!("{:?} does not end with \\r\\n", line));
+                }
+                bytes.truncate(bytes.len() - 2);
+                Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(format!("{} could not read: {}", self.name, e)),
+        }
+    }
+
+    /// The first line `matches`, skipping the others, `None` if none came
+    /// in `wait`.
+    fn find<F: Fn(&str) -> bool>(
+        &mut self,
+        wait: Duration,
+        matches: F,
+    ) -> Result<Option<String>, String> {
+        let deadline = Instant::now() + wait;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.line(left)? {
+                Some(line) if matches(&line) => return Ok(Some(line)),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Skip what arrives until nothing did for a moment.
+    fn drain(&mut self) -> Result<(), String> {
+        while self.line(Duration::from_millis(300))?.is_some() {}
+        Ok(())
+    }
+}
+
+/// A peer sending and one hearing it, once what the first sends arrives.
+fn pair(target: &Target, index: usize) -> Result<(Peer, Peer), String> {
+    let mut hearing = Peer::connect(target.to, format!("hearing{}", index))?;
+    let mut sending = Peer::connect(target.from, format!("sending{}", index))?;
+    // What is sent before the server took the names may be lost.
+    let deadline = Instant::now() + WAIT;
+    loop {
+        sending.send("ready?")?;
+        let heard = hearing.find(Duration::from_millis(200), |line| line.ends_with("ready?"))?;
+        if heard.is_some() {
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(format!("nothing {} sent arrived", sending.name));
+        }
+    }
+    hearing.drain()?;
+    sending.drain()?;
+    Ok((sending, hearing))
+}
+
+fn handshake(target: &Target, index: usize) -> Result<String, String> {
+    let (mut sending, mut hearing) = pair(target, index)?;
+    sending.send("hello")?;
+    let expected = format!("{}: hello", sending.name);
+    match hearing.find(WAIT, |line| line.contains("hello"))? {
+        Some(ref line) if *line == expected => {}
+        Some(line) => return Err(format!("{:?} arrived, not {:?}", line, expected)),
+        None => return Err(format!("{:?} did not arrive", expected)),
+    }
+    if let Some(line) = sending.find(Duration::from_millis(500), |line| line.contains("hello"))? {
+        return Err(format!("{:?} was sent back to the peer", line));
+    }
+    Ok("a name, then messages relayed as `name: line`".to_string())
+}
+
+fn ordering(target: &Target, index: usize) -> Result<String, String> {
+    let (mut sending, mut hearing) = pair(target, index)?;
+    let mut batch = Vec::new();
+    for n in 0..ORDERED {
+        batch.extend_from_slice(format!("message {}\r\n", n).as_bytes());
+    }
+    sending.write(&batch)?;
+    for n in 0..ORDERED {
+        let expected = format!("{}: message {}", sending.name, n);
+        match hearing.find(WAIT, |line| line.contains(": message "))? {
+            Some(ref line) if *line == expected => {}
+            Some(line) => return Err(format!("{:?} arrived where {:?} was due", line, expected)),
+            None => return Err(format!("{} of {} messages arrived", n, ORDERED)),
+        }
+    }
+    Ok(format!("{} messages relayed in the order sent", ORDERED))
+}
+
+fn lines(target: &Target, index: usize) -> Result<String, String> {
+    let (mut sending, mut hearing) = pair(target, index)?;
+    let long = "x".repeat(LONG_LINE);
+    sending.send(&long)?;
+    let expected = format!("{}: {}", sending.name, long);
+    match hearing.find(WAIT, |line| line.contains("xxxx"))? {
+        Some(ref line) if *line == expected => {}
+        Some(line) => {
+            return Err(format!(
+                "a line of {} bytes arrived as {}",
+                LONG_LINE,
+                line.len()
+            ))
+        }
+        None => return Err(format!("a line of {} bytes did not arrive", LONG_LINE)),
+    }
+
+    // Written in pieces, as long as the server reads them. The connection
+    // may be closed before all of it is written.
+    let piece = vec![b'y'; 64 * 1024];
+    let _ = sending.stream.set_write_timeout(Some(WAIT));
+    let mut written = 0;
+    while written <= target.max_line {
+        if sending.stream.write_all(&piece).is_err() {
+            break;
+        }
+        written += piece.len();
+    }
+    let deadline = Instant::now() + WAIT;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match sending.line(left) {
+            // A notice of why is fine.
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(_) => {
+                let refused = target.max_line + 1;
+                return Ok(format!(
+                    "{} bytes relayed, {} without a break refused",
+                    LONG_LINE, refused
+                ));
+            }
+        }
+    }
+    Err(format!(
+        "a peer sending more than {} bytes without a line break stayed connected",
+        target.max_line
+    ))
+}
+
+fn commands(target: &Target, index: usize) -> Result<String, String> {
+    let (mut sending, mut hearing) = pair(target, index)?;
+    sending.send("/who")?;
+    let name = sending.name.clone();
+    if sending.find(WAIT, |line| line.contains(&name))?.is_none() {
+        return Err(format!("no answer to /who naming {}", name));
+    }
+    if let Some(line) = hearing.find(Duration::from_millis(500), |line| line.contains("/who"))? {
+        return Err(format!("the command was relayed as {:?}", line));
+    }
+    Ok("/who answered to the peer only".to_string())
+}
+
+fn keepalive(target: &Target, index: usize) -> Result<String, String> {
+    let (mut sending, mut hearing) = pair(target, index)?;
+
+    // Only probes may come while idle, and the connection has to stay.
+    let mut probes = 0;
+    let deadline = Instant::now() + target.idle;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match sending.line(left)? {
+            Some(ref line) if line.is_empty() => probes += 1,
+            Some(line) => return Err(format!("{:?} came to an idle peer", line)),
+            None => break,
+        }
+    }
+    sending.send("still here")?;
+    if hearing
+        .find(WAIT, |line| line.ends_with("still here"))?
+        .is_none()
+    {
+        let secs = target.idle.as_secs();
+        return Err(format!("nothing arrived after {}s idle", secs));
+    }
+    Ok(format!(
+        "{}s idle kept open, {} empty probes",
+        target.idle.as_secs(),
+        probes
+    ))
+}
@@ -0,0 +1,176 @@
+//! Values replicas can change on their own and still agree on.
+//!
+//! Replicas that cannot reach each other, like servers cut off by a broken
+//! link, can keep taking changes. Once they exchange what they have, each
+//! `merge`s the other's into its own, in any order and as often as it
+//! likes, and all of them end up with the same value:
+//!
+//! ```ignore
+//! let mut clock = Clock::new("eu-1");
+//! let mut topic = LwwRegister::default();
+//! topic.set("releases".to_string(), clock.now());
+//! let mut moderators = OrSet::default();
+//! moderators.add("alice".to_string(), clock.now());
+//!
+//! // What came from another replica.
+//! topic.merge(&theirs.topic);
+//! moderators.merge(&theirs.moderators);
+//! clock.witness(topic.stamp());
+//! ```
+//!
+//! A `LwwRegister` holds the value set last, by its `Stamp`. An `OrSet` is a
+//! set where an add wins over a remove made without seeing it: a remove only
+//! takes out the adds its replica knew of. Both serialize, so that replicas
+//! can send them whole.
+//!
+//! Every change is stamped by the `Clock` of its replica: the wall clock in
+//! milliseconds, never going back, and never behind a stamp the replica
+//! saw. Stamps of different replicas are told apart by the replica, so no
+//! two are the same.
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When a change was made, and by which replica.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    pub time: u64,
+    pub replica: String,
+}
+
+/// Stamps the changes of one replica.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    replica: String,
+    last: u64,
+}
+
+impl Clock {
+    pub fn new(replica: &str) -> Clock {
+        Clock {
+            replica: replica.to_string(),
+            last: 0,
+        }
+    }
+
+    /// The stamp of a change made now, later than every stamp before.
+    pub fn now(&mut self) -> Stamp {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        self.last = wall.max(self.last + 1);
+        Stamp {
+            time: self.last,
+            replica: self.replica.clone(),
+        }
+    }
+
+    /// Keep the next stamps after `stamp`, one of another replica, so that
+    /// a change made after seeing it wins over it even if that replica's
+    /// clock is ahead.
+    pub fn witness(&mut self, stamp: Option<&Stamp>) {
+        if let Some(stamp) = stamp {
+            self.last = self.last.max(stamp.time);
+        }
+    }
+}
+
+/// The value set last.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: Option<(Stamp, T)>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        LwwRegister { value: None }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref().map(|(_, value)| value)
+    }
+
+    /// The stamp of the value, `None` if it was never set.
+    pub fn stamp(&self) -> Option<&Stamp> {
+        self.value.as_ref().map(|(stamp, _)| stamp)
+    }
+
+    /// Set `value`, unless the one there was set later than `stamp`.
+    pub fn set(&mut self, value: T, stamp: Stamp) {
+        if self.stamp().map_or(true, |current| stamp > *current) {
+            self.value = Some((stamp, value));
+        }
+    }
+
+    pub fn merge(&mut self, other: &LwwRegister<T>) {
+        if let Some((stamp, value)) = &other.value {
+            self.set(value.clone(), stamp.clone());
+        }
+    }
+}
+
+/// A set where an add wins over a remove that did not see it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet<T: Ord> {
+    /// Every add of every element, by its stamp.
+    adds: BTreeMap<T, BTreeSet<Stamp>>,
+    /// The adds taken out by removes.
+    removed: BTreeSet<Stamp>,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        OrSet {
+            adds: BTreeMap::new(),
+            removed: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+    pub fn contains(&self, value: &T) -> bool {
+        self.adds.get(value).map_or(false, |stamps| {
+            stamps.iter().any(|s| !self.removed.contains(s))
+        })
+    }
+
+    /// The elements, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.adds.keys().filter(move |value| self.contains(value))
+    }
+
+    pub fn add(&mut self, value: T, stamp: Stamp) {
+        self.adds.entry(value).or_default().insert(stamp);
+    }
+
+    /// Take out `value`, as far as this replica knows of its adds. `false`
+    /// if it was not in the set.
+    pub fn remove(&mut self, value: &T) -> bool {
+        let present = self.contains(value);
+        if let Some(stamps) = self.adds.get(value) {
+            self.removed.extend(stamps.iter().cloned());
+        }
+        present
+    }
+
+    /// The latest stamp of an add, for `Clock::witness`.
+    pub fn stamp(&self) -> Option<&Stamp> {
+        self.adds
+            .values()
+            .filter_map(|stamps| stamps.iter().last())
+            .max()
+    }
+
+    pub fn merge(&mut self, other: &OrSet<T>) {
+        for (value, stamps) in &other.adds {
+            let own = self.adds.entry(value.clone()).or_default();
+            own.extend(stamps.iter().cloned());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+    }
+}
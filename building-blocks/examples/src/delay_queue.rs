@@ -0,0 +1,187 @@
+//! Items handed out once their time comes.
+//!
+//! A `DelayQueue` holds items until a time of the wall clock, so that they
+//! can be saved and put back after a restart with the same times. One task
+//! takes the items as they are due from `expired`, a stream, while any
+//! number of others insert and remove them:
+//!
+//! ```ignore
+//! let queue = DelayQueue::new();
+//! let key = queue.insert(SystemTime::now() + Duration::from_secs(600), "tea");
+//! tokio::spawn(
+//!     queue
+//!         .expired()
+//!         .for_each(|item| Ok(println!("{} is ready", item)))
+//!         .map_err(|e| eprintln!("timer error = {:?}", e)),
+//! );
+//! ```
+//!
+//! Items due at the same time come out in the order they were inserted.
+//! Items that were due before they were inserted, or while the clock jumped,
+//! come out right away.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use tokio::timer::{self, Delay};
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The items, shared by every clone.
+pub struct DelayQueue<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Clone for DelayQueue<T> {
+    fn clone(&self) -> Self {
+        DelayQueue {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct Inner<T> {
+    /// The times and keys of the items, earliest first. Removed items are
+    /// left behind and skipped.
+    due: BinaryHeap<Reverse<(SystemTime, u64)>>,
+    items: HashMap<u64, (SystemTime, T)>,
+    next_key: u64,
+    /// The task taking the items, told of items due earlier than the one it
+    /// waits for.
+    task: Option<Task>,
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        DelayQueue {
+            inner: Arc::new(Mutex::new(Inner {
+                due: BinaryHeap::new(),
+                items: HashMap::new(),
+                next_key: 1,
+                task: None,
+            })),
+        }
+    }
+}
+
+impl<T> DelayQueue<T> {
+    pub fn new() -> DelayQueue<T> {
+        DelayQueue::default()
+    }
+
+    /// Hold `item` until `due`, returning its key to remove it with.
+    pub fn insert(&self, due: SystemTime, item: T) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let key = inner.next_key;
+        inner.next_key += 1;
+        let earliest = inner.due.peek().map(|Reverse((due, _))| *due);
+        inner.due.push(Reverse((due, key)));
+        inner.items.insert(key, (due, item));
+        if earliest.map_or(true, |earliest| due < earliest) {
+            if let Some(task) = inner.task.take() {
+                task.notify();
+            }
+        }
+        key
+    }
+
+    /// Take out the item `key`, if it was not handed out yet.
+    pub fn remove(&self, key: u64) -> Option<T> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.items.remove(&key).map(|(_, item)| item)
+    }
+
+    /// The items not handed out yet.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The keys, times and items not handed out yet, earliest first.
+    pub fn pending(&self) -> Vec<(u64, SystemTime, T)>
+    where
+        T: Clone,
+    {
+        let inner = self.inner.lock().unwrap();
+        let mut pending: Vec<(u64, SystemTime, T)> = inner
+            .items
+            .iter()
+            .map(|(&key, (due, item))| (key, *due, item.clone()))
+            .collect();
+        pending.sort_by_key(|&(key, due, _)| (due, key));
+        pending
+    }
+
+    /// The items as they come due. There should be one per queue, another
+    /// takes the items away from it.
+    pub fn expired(&self) -> Expired<T> {
+        Expired {
+            queue: self.clone(),
+            delay: None,
+        }
+    }
+}
+
+/// The stream of `DelayQueue::expired`. It only fails if the timer does.
+pub struct Expired<T> {
+    queue: DelayQueue<T>,
+    /// Until the earliest item is due.
+    delay: Option<Delay>,
+}
+
+impl<T> Stream for Expired<T> {
+    type Item = T;
+    type Error = timer::Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, timer::Error> {
+        loop {
+            let wait = {
+                let mut inner = self.queue.inner.lock().unwrap();
+                let now = SystemTime::now();
+                loop {
+                    let (due, key) = match inner.due.peek() {
+                        Some(&Reverse(next)) => next,
+                        None => {
+                            inner.task = Some(task::current());
+                            return Ok(Async::NotReady);
+                        }
+                    };
+                    if !inner.items.contains_key(&key) {
+                        inner.due.pop();
+                        continue;
+                    }
+                    match due.duration_since(now) {
+                        // Due, or before now.
+                        Err(_) => {}
+                        Ok(wait) if wait == Duration::from_secs(0) => {}
+                        Ok(wait) => {
+                            inner.task = Some(task::current());
+                            break wait;
+                        }
+                    }
+                    inner.due.pop();
+                    let (_, item) = inner.items.remove(&key).unwrap();
+                    self.delay = None;
+                    return Ok(Async::Ready(Some(item)));
+                }
+            };
+
+            // The wait is measured anew on every poll, so that the clock
+            // is followed when it jumps.
+            let deadline = Instant::now() + wait;
+            match &mut self.delay {
+                Some(delay) => delay.reset(deadline),
+                None => self.delay = Some(Delay::new(deadline)),
+            }
+            match self.delay.as_mut().unwrap().poll()? {
+                Async::Ready(()) => continue,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Accept loops that share the executor fairly.
+//!
+//! `TcpListener::incoming` accepts for as long as connections are waiting,
+//! so a connection storm keeps the accepting task busy while the peers
+//! already connected wait. `Acceptor` accepts at most `accepts_per_tick`
+//! connections before yielding, like the peers do with `LINES_PER_TICK`.
+//!
+//! Failing to accept, for example because the process ran out of file
+//! descriptors, ends `incoming` and with it the listener. `Acceptor` logs
+//! the error and pauses instead, so that the server recovers once the
+//! storm passed.
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::logging;
+
+/// How long accepting pauses after an error.
+const ERROR_PAUSE: Duration = Duration::from_millis(100);
+
+pub struct Acceptor {
+    listener: TcpListener,
+    per_tick: usize,
+    /// Connections accepted since the task last yielded.
+    accepted: usize,
+    /// Set after an error, until accepting resumes.
+    pause: Option<Delay>,
+}
+
+impl Acceptor {
+    pub fn new(listener: TcpListener, per_tick: usize) -> Acceptor {
+        Acceptor {
+            listener,
+            per_tick,
+            accepted: 0,
+            pause: None,
+        }
+    }
+}
+
+impl Stream for Acceptor {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<TcpStream>, io::Error> {
+        loop {
+            if let Some(pause) = &mut self.pause {
+                try_ready!(pause
+                    .poll()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+                self.pause = None;
+            }
+
+            if self.accepted == self.per_tick {
+                // Let other tasks run, and continue right after.
+                self.accepted = 0;
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+
+            match self.listener.poll_accept() {
+                Ok(Async::Ready((socket, _))) => {
+                    self.accepted += 1;
+                    return Ok(Async::Ready(Some(socket)));
+                }
+                Ok(Async::NotReady) => {
+                    self.accepted = 0;
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    logging::warn!("accept_failed"; "accept error = {:?}", e);
+                    self.pause = Some(Delay::new(Instant::now() + ERROR_PAUSE));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Which networks may connect.
+//!
+//! ```json
+//! "access": {
+//!     "allow": ["10.0.0.0/8", "192.168.1.0/24", "::1/128"],
+//!     "deny": ["10.66.0.0/16"]
+//! }
+//! ```
+//!
+//! A connection from an address in `deny` is refused, and so is one from an
+//! address outside `allow` unless `allow` is empty. The peer listeners and
+//! the transfer listener check right after accepting, before reading or
+//! writing anything: a refused connection is just closed.
+//!
+//! On SIGHUP the lists are read again from the file given with `--config`,
+//! without touching anything else it configures. If the file cannot be
+//! read, the lists stay as they were.
+
+use arc_swap::ArcSwap;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio_signal::unix::{Signal, SIGHUP};
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use crate::audit::{Action, Audit};
+use crate::config::AccessConfig;
+use crate::logging;
+
+/// A range of addresses, like `10.0.0.0/8`. An address alone is a range of
+/// one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || format!("{} is not a CIDR range", s);
+        let (network, prefix) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                masked(u32::from(network).into(), self.prefix, 32)
+                    == masked(u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                masked(network.into(), self.prefix, 128) == masked(ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The first `prefix` of the `bits` bits of `addr`.
+fn masked(addr: u128, prefix: u8, bits: u32) -> u128 {
+    let host_bits = bits - u32::from(prefix);
+    addr.checked_shr(host_bits)
+        .unwrap_or(0)
+        .checked_shl(host_bits)
+        .unwrap_or(0)
+}
+
+/// IPv4 addresses reach dual stack listeners mapped into IPv6.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// The lists in force, shared by all listeners.
+pub struct Access {
+    lists: ArcSwap<AccessConfig>,
+}
+
+impl Access {
+    pub fn new(config: &AccessConfig) -> Access {
+        Access {
+            lists: ArcSwap::from_pointee(config.clone()),
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let lists = self.lists.load();
+        if lists.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        lists.allow.is_empty() || lists.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Whether the peer of a connection just accepted may stay connected.
+    /// One that is gone already may not.
+    pub fn admits(&self, socket: &TcpStream) -> bool {
+        match socket.peer_addr() {
+            Ok(addr) if self.allows(addr.ip()) => true,
+            Ok(addr) => {
+                logging::info!("connection_denied", addr = addr; "connection from {} denied", addr);
+                false
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Only the lists of a config file.
+#[derive(Deserialize)]
+struct File {
+    #[serde(default)]
+    access: AccessConfig,
+}
+
+fn read(path: &Path) -> Result<AccessConfig, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let file: File = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    Ok(file.access)
+}
+
+/// Read the lists again from `path` on SIGHUP.
+pub fn reload_on_signal(
+    access: Arc<Access>,
+    audit: Arc<Audit>,
+    path: PathBuf,
+) -> impl Future<Item = (), Error = ()> {
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map_err(|e| logging::error!("signal_failed"; "signal error = {:?}", e))
+        .for_each(move |_| {
+            let (access, audit, path) = (access.clone(), audit.clone(), path.clone());
+            // Reading the file blocks.
+            thread::spawn(move || {
+                let target = path.display().to_string();
+                match read(&path) {
+                    Ok(lists) => {
+                        let detail = format!(
+                            "{} allowed and {} denied ranges",
+                            lists.allow.len(),
+                            lists.deny.len()
+                        );
+                        audit.record("signal SIGHUP", Action::ReloadAccess, &target, &detail);
+                        logging::info!("access_reloaded"; "access lists reloaded, {}", detail);
+                        access.lists.store(Arc::new(lists));
+                    }
+                    Err(e) => {
+                        let detail = format!("kept the lists, {}", e);
+                        audit.record("signal SIGHUP", Action::ReloadAccess, &target, &detail);
+                        logging::warn!(
+                            "access_reload_failed";
+                            "access lists kept, {}: {}", target, e
+                        );
+                    }
+                }
+            });
+            Ok(())
+        })
+}
@@ -0,0 +1,390 @@
+//! Admin endpoints of the HTTP gateway.
+//!
+//! They are only mounted when `admin_token` is configured, and every request
+//! has to present it:
+//!
+//! ```text
+//! curl -X POST -H 'Authorization: Bearer <admin_token>' \
+//!     'http://127.0.0.1:9000/admin/drain?deadline_secs=60'
+//! ```
+//!
+//! Every action is recorded, see `audit`.
+//!
+//! * `POST /admin/drain` starts draining, see `drain`. The deadline defaults
+//!   to the configured one.
+//! * `POST /admin/restart` restarts without downtime, see `restart`. It
+//!   answers once the new process serves.
+//! * `GET /admin/peers` lists the connected peers with where they connect
+//!   from, see `geoip`.
+//! * `GET /admin/connections` lists every connection not closed yet, with
+//!   the phase it is in and since when, see `conn`.
+//! * `POST /admin/trace?side=c&name=alice` logs every message of the peers
+//!   called alice on the c side, instead of a sample, see `logging`.
+//!   `DELETE` with the same query stops it, `GET /admin/trace` lists the
+//!   peers traced.
+//! * `GET /admin/announcements` lists the scheduled announcements, see
+//!   `schedule`. `POST` adds one, from a body like
+//!   `{"cron": "0 9 * * 1-5", "text": "good morning", "sides": ["c"]}`, and
+//!   answers its id. `DELETE /admin/announcements/<id>` removes one.
+//! * `POST /admin/snapshot` saves the history, the moderation and the
+//!   announcements to the configured file, see `snapshot`, and answers what
+//!   it holds.
+//! * `POST /admin/profile?seconds=30` samples the CPU for that long and
+//!   answers a flamegraph SVG, with the `profiling` feature only. See
+//!   `profiling`.
+
+use actix_web::{error, http::header, web, HttpRequest, HttpResponse};
+use futures::future::{self, Either, Future};
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audit::Action;
+use crate::config::{ScheduledConfig, SnapshotConfig};
+use crate::drain::NotStarted;
+use crate::logging;
+use crate::restart::Restarter;
+use crate::secret;
+use crate::snapshot;
+use crate::state::{Side, State};
+
+#[derive(Deserialize)]
+struct DrainQuery {
+    deadline_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct TraceQuery {
+    side: Side,
+    name: String,
+}
+
+/// How long a CPU profile samples unless asked otherwise.
+#[cfg(feature = "profiling")]
+const PROFILE_SECS: u64 = 30;
+
+/// Longest CPU profile, the request is open all along.
+#[cfg(feature = "profiling")]
+const MAX_PROFILE_SECS: u64 = 300;
+
+#[cfg(feature = "profiling")]
+#[derive(Deserialize)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+}
+
+/// The admin endpoints, shared by all gateway workers.
+#[derive(Clone)]
+pub struct Admin {
+    token: Arc<String>,
+    /// The configured drain deadline.
+    deadline: Duration,
+    /// Where the snapshot goes, if configured.
+    snapshot: Option<Arc<SnapshotConfig>>,
+    state: State,
+    restarter: Restarter,
+}
+
+impl Admin {
+    pub fn new(
+        token: String,
+        deadline: Duration,
+        snapshot: Option<SnapshotConfig>,
+        state: State,
+        restarter: Restarter,
+    ) -> Admin {
+        Admin {
+            token: Arc::new(token),
+            deadline,
+            snapshot: snapshot.map(Arc::new),
+            state,
+            restarter,
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.data(self.clone())
+            .route("/admin/drain", web::post().to(drain))
+            .route("/admin/restart", web::post().to_async(restart))
+            .route("/admin/peers", web::get().to(peers))
+            .route("/admin/connections", web::get().to(connections))
+            .route("/admin/trace", web::get().to(traced))
+            .route("/admin/trace", web::post().to(trace))
+            .route("/admin/trace", web::delete().to(untrace))
+            .route("/admin/announcements", web::get().to(announcements))
+            .route("/admin/announcements", web::post().to(schedule))
+            .route("/admin/announcements/{id}", web::delete().to(unschedule))
+            .route("/admin/snapshot", web::post().to_async(save_snapshot));
+        #[cfg(feature = "profiling")]
+        cfg.route("/admin/profile", web::post().to_async(profile));
+    }
+
+    fn authorized(&self, req: &HttpRequest) -> bool {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map_or(false, |token| secret::same_token(&self.token, token))
+    }
+}
+
+/// Who sent an authorized request, for the audit log. Everyone presents the
+/// same token, so only the address tells admins apart.
+fn actor(req: &HttpRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => format!("admin {}", addr),
+        None => "admin".to_string(),
+    }
+}
+
+fn drain(req: HttpRequest, query: web::Query<DrainQuery>, admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let deadline = query
+        .deadline_secs
+        .map_or(admin.deadline, Duration::from_secs);
+    match admin.state.drain.start(deadline) {
+        Ok(()) => {}
+        Err(NotStarted::Draining) => return HttpResponse::Conflict().body("already draining"),
+        Err(NotStarted::TooFar) => {
+            return HttpResponse::BadRequest().body("deadline_secs out of range")
+        }
+    }
+    let detail = format!("deadline {}s", deadline.as_secs());
+    admin
+        .state
+        .audit
+        .record(&actor(&req), Action::Drain, "server", &detail);
+    logging::info!("drain_started"; "draining, exiting within {}s", deadline.as_secs());
+
+    HttpResponse::Accepted().json(json!({
+        "peers": admin.state.peer_count(),
+        "deadline_secs": deadline.as_secs(),
+    }))
+}
+
+fn peers(req: HttpRequest, admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut peers = Vec::new();
+    for &side in &[Side::C, Side::Go] {
+        admin.state.side(side).for_each(|id, member| {
+            peers.push(json!({
+                "side": side.as_str(),
+                "name": member.name,
+                "conn": id.to_string(),
+                "addr": member.addr.to_string(),
+                "location": member.location,
+            }));
+        });
+    }
+    HttpResponse::Ok().json(peers)
+}
+
+fn connections(req: HttpRequest, admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let connections: Vec<_> = admin
+        .state
+        .connections
+        .list()
+        .into_iter()
+        .map(|conn| {
+            json!({
+                "conn": conn.id.to_string(),
+                "side": conn.side.as_str(),
+                "addr": conn.addr.to_string(),
+                "phase": conn.phase.as_str(),
+                "age_secs": conn.opened.elapsed().as_secs(),
+                "phase_secs": conn.since.elapsed().as_secs(),
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(connections)
+}
+
+fn traced(req: HttpRequest, admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let traced: Vec<_> = admin
+        .state
+        .sampler
+        .traced()
+        .into_iter()
+        .map(|(side, name)| json!({ "side": side.as_str(), "name": name }))
+        .collect();
+    HttpResponse::Ok().json(traced)
+}
+
+fn trace(req: HttpRequest, query: web::Query<TraceQuery>, admin: web::Data<Admin>) -> HttpResponse {
+    set_trace(&req, &query, &admin, true)
+}
+
+fn untrace(
+    req: HttpRequest,
+    query: web::Query<TraceQuery>,
+    admin: web::Data<Admin>,
+) -> HttpResponse {
+    set_trace(&req, &query, &admin, false)
+}
+
+fn set_trace(req: &HttpRequest, query: &TraceQuery, admin: &Admin, on: bool) -> HttpResponse {
+    if !admin.authorized(req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if admin.state.sampler.trace(query.side, &query.name, on) {
+        let target = format!("{} {}", query.side, query.name);
+        let detail = if on { "on" } else { "off" };
+        admin
+            .state
+            .audit
+            .record(&actor(req), Action::Trace, &target, detail);
+    }
+    HttpResponse::NoContent().finish()
+}
+
+fn announcements(req: HttpRequest, admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    HttpResponse::Ok().json(admin.state.schedule.list())
+}
+
+fn schedule(
+    req: HttpRequest,
+    body: web::Json<ScheduledConfig>,
+    admin: web::Data<Admin>,
+) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let announcement = body.into_inner();
+    let detail = format!("add {} {:?}", announcement.cron, announcement.text);
+    match admin.state.schedule.add(&admin.state, announcement) {
+        Ok(id) => {
+            let target = format!("announcement #{}", id);
+            admin
+                .state
+                .audit
+                .record(&actor(&req), Action::Schedule, &target, &detail);
+            HttpResponse::Created().json(json!({ "id": id }))
+        }
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+fn unschedule(req: HttpRequest, id: web::Path<u64>, admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if !admin.state.schedule.remove(*id) {
+        return HttpResponse::NotFound().finish();
+    }
+    let target = format!("announcement #{}", id);
+    admin
+        .state
+        .audit
+        .record(&actor(&req), Action::Schedule, &target, "remove");
+    HttpResponse::NoContent().finish()
+}
+
+fn save_snapshot(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    if !admin.authorized(&req) {
+        return Either::A(future::ok(HttpResponse::Unauthorized().finish()));
+    }
+    let config = match &admin.snapshot {
+        Some(config) => config.clone(),
+        None => {
+            let response = HttpResponse::NotFound().body("no snapshot configured");
+            return Either::A(future::ok(response));
+        }
+    };
+
+    // Writing the file blocks.
+    let state = admin.state.clone();
+    let actor = actor(&req);
+    Either::B(
+        web::block(move || {
+            let summary = snapshot::save(&state, &config)?;
+            let detail = format!("{} messages", summary.messages);
+            let target = config.path.display().to_string();
+            state
+                .audit
+                .record(&actor, Action::Snapshot, &target, &detail);
+            Ok::<_, std::io::Error>(summary)
+        })
+        .map(|summary| HttpResponse::Ok().json(summary))
+        .map_err(|e| match e {
+            error::BlockingError::Error(e) => error::ErrorInternalServerError(e.to_string()),
+            error::BlockingError::Canceled => error::ErrorInternalServerError("canceled"),
+        }),
+    )
+}
+
+fn restart(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    if !admin.authorized(&req) {
+        return Either::A(future::ok(HttpResponse::Unauthorized().finish()));
+    }
+
+    admin
+        .state
+        .audit
+        .record(&actor(&req), Action::Restart, "server", "");
+    let restarter = admin.restarter.clone();
+    // Restarting blocks until the new process serves.
+    Either::B(
+        web::block(move || restarter.restart())
+            .map(|pid| HttpResponse::Ok().json(json!({ "pid": pid })))
+            .map_err(|e| match e {
+                error::BlockingError::Error(e) => error::ErrorInternalServerError(e.to_string()),
+                error::BlockingError::Canceled => error::ErrorInternalServerError("canceled"),
+            }),
+    )
+}
+
+#[cfg(feature = "profiling")]
+fn profile(
+    req: HttpRequest,
+    query: web::Query<ProfileQuery>,
+    admin: web::Data<Admin>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    if !admin.authorized(&req) {
+        return Either::A(future::ok(HttpResponse::Unauthorized().finish()));
+    }
+
+    let seconds = query.seconds.unwrap_or(PROFILE_SECS).min(MAX_PROFILE_SECS);
+    let detail = format!("{}s", seconds);
+    admin
+        .state
+        .audit
+        .record(&actor(&req), Action::Profile, "cpu", &detail);
+    logging::info!("profile_started"; "profiling the cpu for {}s", seconds);
+    Either::B(
+        web::block(move || crate::profiling::cpu_profile(Duration::from_secs(seconds)))
+            .map(|svg| HttpResponse::Ok().content_type("image/svg+xml").body(svg))
+            .map_err(|e| match e {
+                error::BlockingError::Error(e) => error::ErrorInternalServerError(e.to_string()),
+                error::BlockingError::Canceled => error::ErrorInternalServerError("canceled"),
+            }),
+    )
+}
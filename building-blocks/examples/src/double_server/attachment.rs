@@ -0,0 +1,82 @@
+//! Small binary blobs attached to a message, like screenshots or logs.
+//!
+//! ```text
+//! /attach <name> <base64> [text]
+//! ```
+//!
+//! The line goes to the other side as it is, after the name of the peer
+//! like any message, for the programs there to decode. The server only
+//! checks that the data is base64 and within the limits, see
+//! `Config::attachments`. Integrations and the history get the text and
+//! the name and size of the file instead of the data.
+//!
+//! Attachments longer than a frame are split like any other message, see
+//! `frames`.
+
+use std::str;
+
+use crate::locale::Message;
+use crate::meter::human_bytes;
+
+const PREFIX: &[u8] = b"/attach ";
+
+/// An attachment line, borrowed from the message.
+pub struct Attachment<'a> {
+    pub name: &'a str,
+    /// Decoded length of the data.
+    pub size: usize,
+    pub text: &'a str,
+}
+
+impl<'a> Attachment<'a> {
+    /// Parse `message`, `None` if it does not attach anything.
+    pub fn parse(message: &'a [u8]) -> Option<Result<Attachment<'a>, Message>> {
+        if !message.starts_with(PREFIX) && message != b"/attach" {
+            return None;
+        }
+        let usage = || Message::new("attach_usage");
+        let line = match str::from_utf8(message.get(PREFIX.len()..).unwrap_or(b"")) {
+            Ok(line) => line,
+            Err(_) => return Some(Err(usage())),
+        };
+
+        let mut parts = line.splitn(3, ' ');
+        let (name, data) = match (parts.next(), parts.next()) {
+            (Some(name), Some(data)) if !name.is_empty() => (name, data),
+            _ => return Some(Err(usage())),
+        };
+        let text = parts.next().unwrap_or("").trim();
+        Some(match decoded_len(data.as_bytes()) {
+            Some(size) => Ok(Attachment { name, size, text }),
+            None => Err(Message::new("attach_not_base64").with("name", name)),
+        })
+    }
+
+    /// The message as integrations and the history see it.
+    pub fn summary(&self) -> String {
+        let file = format!("[{}, {}]", self.name, human_bytes(self.size as f64));
+        if self.text.is_empty() {
+            file
+        } else {
+            format!("{} {}", self.text, file)
+        }
+    }
+}
+
+/// The length of `data` decoded, if it is padded base64.
+fn decoded_len(data: &[u8]) -> Option<usize> {
+    if data.is_empty() || data.len() % 4 != 0 {
+        return None;
+    }
+    let padding = data.iter().rev().take_while(|&&b| b == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+    let valid = data[..data.len() - padding]
+        .iter()
+        .all(|&b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+    if !valid {
+        return None;
+    }
+    Some(data.len() / 4 * 3 - padding)
+}
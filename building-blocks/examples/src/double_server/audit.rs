@@ -0,0 +1,220 @@
+//! A record of every privileged action, kept apart from the chat.
+//!
+//! ```json
+//! "audit": { "path": "/var/log/double_server/audit.log" }
+//! ```
+//!
+//! Every drain, restart, CPU profile, trace, change of the schedule and
+//! reload of the access lists is appended to the file as a line of JSON,
+//! whether an admin asked for it over HTTP or a signal did, and so is every
+//! change of a moderator, see `moderation`:
+//!
+//! ```json
+//! {"seq":3,"time_ms":1565000000000,"actor":"admin 127.0.0.1:51234","action":"drain","target":"server","detail":"deadline 60s","prev":"9f2c…","hash":"41ad…"}
+//! ```
+//!
+//! Each line carries `prev`, the `hash` of the line before it, and its own
+//! `hash`, the SHA-256 of its other fields. Editing, dropping or reordering
+//! lines breaks the chain from there on. The file is checked when the server
+//! starts, which refuses to run on a broken chain, and every line is synced
+//! to disk as the action is taken.
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use crate::config::AuditConfig;
+use crate::durability::Durability;
+use crate::logging;
+use crate::state::now_ms;
+
+/// `prev` of the first line.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// What was done.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub enum Action {
+    Drain,
+    /// Exiting without waiting for the peers, on a second signal.
+    Exit,
+    Restart,
+    #[cfg(feature = "profiling")]
+    Profile,
+    ReloadAccess,
+    /// A change of a moderator, like setting the topic or a ban.
+    Moderate,
+    /// Adding or removing a scheduled announcement.
+    Schedule,
+    /// Logging every message of a peer, or no longer.
+    Trace,
+    /// Saving the state, see `snapshot`.
+    Snapshot,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Drain => "drain",
+            Action::Exit => "exit",
+            Action::Restart => "restart",
+            #[cfg(feature = "profiling")]
+            Action::Profile => "profile",
+            Action::ReloadAccess => "reload_access",
+            Action::Moderate => "moderate",
+            Action::Schedule => "schedule",
+            Action::Trace => "trace",
+            Action::Snapshot => "snapshot",
+        }
+    }
+}
+
+/// A line of the file.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    time_ms: u64,
+    actor: String,
+    action: String,
+    target: String,
+    detail: String,
+    prev: String,
+    #[serde(default)]
+    hash: String,
+}
+
+impl Entry {
+    /// The hash of the entry, over everything but `hash`.
+    fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for field in &[
+            &self.seq.to_string(),
+            &self.time_ms.to_string(),
+            &self.actor,
+            &self.action,
+            &self.target,
+            &self.detail,
+            &self.prev,
+        ] {
+            // Length first, so that no two entries hash the same fields.
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+struct Chain {
+    file: File,
+    durability: Durability,
+    /// `seq` of the next entry.
+    seq: u64,
+    /// `hash` of the last entry.
+    last: String,
+}
+
+/// The audit file, if configured. Without one actions are only logged to
+/// stdout.
+#[derive(Default)]
+pub struct Audit {
+    chain: Option<Mutex<Chain>>,
+}
+
+impl Audit {
+    /// Check the file of `config` and open it for appending, creating it if
+    /// there is none yet.
+    pub fn open(config: &AuditConfig) -> io::Result<Audit> {
+        let (seq, last) = match fs::read_to_string(&config.path) {
+            Ok(text) => verify(&text).map_err(|e| {
+                let message = format!("{}: {}", config.path.display(), e);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (0, GENESIS.to_string()),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        Ok(Audit {
+            chain: Some(Mutex::new(Chain {
+                file,
+                durability: config.durability,
+                seq,
+                last,
+            })),
+        })
+    }
+
+    /// Record that `actor` did `action` to `target`. Blocks until the line
+    /// is on disk, unless its `durability` is `async`.
+    pub fn record(&self, actor: &str, action: Action, target: &str, detail: &str) {
+        logging::info!(
+            "audit";
+            "audit: {} {} {} {}",
+            actor,
+            action.as_str(),
+            target,
+            detail
+        );
+        let chain = match &self.chain {
+            Some(chain) => chain,
+            None => return,
+        };
+        let mut chain = chain.lock().unwrap();
+        let mut entry = Entry {
+            seq: chain.seq,
+            time_ms: now_ms(),
+            actor: actor.to_string(),
+            action: action.as_str().to_string(),
+            target: target.to_string(),
+            detail: detail.to_string(),
+            prev: chain.last.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+
+        let mut line = serde_json::to_vec(&entry).expect("an audit entry serializes");
+        line.push(b'\n');
+        let written = chain
+            .file
+            .write_all(&line)
+            .and_then(|()| chain.durability.sync(&chain.file));
+        match written {
+            Ok(()) => {
+                chain.seq += 1;
+                chain.last = entry.hash;
+            }
+            Err(e) => logging::error!("audit_write_failed"; "audit write error = {:?}", e),
+        }
+    }
+}
+
+/// Check the chain of `text`, answering the next `seq` and the last hash.
+fn verify(text: &str) -> Result<(u64, String), String> {
+    let mut seq = 0;
+    let mut last = GENESIS.to_string();
+    for (number, line) in text.lines().enumerate() {
+        let broken = |why: &str| format!("audit chain broken at line {}, {}", number + 1, why);
+        let entry: Entry = serde_json::from_str(line).map_err(|e| broken(&e.to_string()))?;
+        if entry.seq != seq {
+            return Err(broken("out of sequence"));
+        }
+        if entry.prev != last {
+            return Err(broken("it does not follow the line before"));
+        }
+        if entry.hash != entry.digest() {
+            return Err(broken("it was changed"));
+        }
+        seq += 1;
+        last = entry.hash;
+    }
+    Ok((seq, last))
+}
@@ -0,0 +1,179 @@
+//! A server joining a bridge taking in what the others have before it
+//! serves.
+//!
+//! A server started empty would have no history to answer `/history` with,
+//! and let in names banned on the others until its links caught up. With a
+//! `backfill`, it first asks a server of the bridge, at its
+//! `replication_listen` address (see `replica`), for the last `messages` of
+//! its history and its moderation, and only accepts peers once it has them:
+//!
+//! ```json
+//! "backfill": { "from": "chat-1.example.org:8090", "messages": 1000 }
+//! ```
+//!
+//! The server asks for the messages after the last one it has, and how
+//! many at most. The other answers with the id of its own last one, its
+//! moderation as links send it (see `moderation`), the messages, one line
+//! of JSON each like the `replica` is sent them, and a last line once it
+//! sent everything:
+//!
+//! ```text
+//! BACKFILL 0 1000
+//! LAST 8111
+//! MODERATION {"topic":{...},"banned":{...},"moderators":{...}}
+//! {"id":7112,"side":"go","name":"alice","body":"hi","ts":1564999990001}
+//! ...
+//! END
+//! ```
+//!
+//! A transfer cut off before its `END` is asked for again after a delay,
+//! twice as long after every failure in a row up to
+//! `max_reconnect_delay_ms`, and takes up after the last message the
+//! server has, so what it was sent is not sent again. With a `journal`
+//! that holds across a restart of the server too. The messages keep the
+//! ids they had, and those of the server go on after them, so once it
+//! served the ids are its own: `backfill` is for its first start, and a
+//! server started again with it only asks after its own last message.
+//!
+//! Names are only claimed while connected, and the links tell each other
+//! who is on every server as they connect (see `roster`), so there are no
+//! registrations to take in.
+
+use building_blocks::retry::Backoff;
+use futures::future::{self, Either, Loop};
+use futures::{Future, Sink, Stream};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::timer::Delay;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::BackfillConfig;
+use crate::logging;
+use crate::moderation::Shared;
+use crate::replica;
+use crate::resolve;
+use crate::state::{State, StoredMessage};
+
+/// Messages of the history taken at once while answering.
+const PAGE: usize = 256;
+
+/// The lines answering `BACKFILL <since> <messages>`, the request after
+/// its name.
+pub fn answer(state: &State, request: &str) -> io::Result<Vec<String>> {
+    let mut words = request.split_whitespace().map(|word| word.parse::<u64>());
+    let (since, messages) = match (words.next(), words.next(), words.next()) {
+        (Some(Ok(since)), Some(Ok(messages)), None) => (since, messages),
+        _ => {
+            let e = format!("bad request {:?}", request);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    };
+
+    let last = state.last_message_id();
+    let moderation = serde_json::to_string(&state.moderation.shared())?;
+    let mut lines = vec![
+        format!("LAST {}", last),
+        format!("MODERATION {}", moderation),
+    ];
+    let mut next = since.max(last.saturating_sub(messages)) + 1;
+    loop {
+        let page = state.history_range(&(next..last + 1), PAGE);
+        match page.last() {
+            Some(message) => next = message.id + 1,
+            None => break,
+        }
+        lines.extend(page.iter().map(replica::json_line));
+    }
+    lines.push("END".to_string());
+    Ok(lines)
+}
+
+/// Take in the history and moderation of the server of `config`, asking
+/// again until it was all sent.
+pub fn run(state: State, config: BackfillConfig) -> impl Future<Item = (), Error = ()> {
+    let backoff = Backoff::new(Duration::from_millis(config.reconnect_delay_ms))
+        .max(Duration::from_millis(config.max_reconnect_delay_ms));
+
+    future::loop_fn(0, move |failures: u32| {
+        let (config, state) = (config.clone(), state.clone());
+        fetch(&config, state.clone()).then(move |result| {
+            let e = match result {
+                Ok(()) => {
+                    logging::info!(
+                        "backfill_done", addr = &config.from;
+                        "backfilled from {} up to #{}", config.from, state.last_message_id()
+                    );
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+                Err(e) => e,
+            };
+            logging::warn!(
+                "backfill_failed", addr = &config.from;
+                "backfill from {} error = {:?}", config.from, e
+            );
+            // A broken timer only means the next attempt is early.
+            let failures = failures + 1;
+            let delay = backoff.delay(failures);
+            let wait =
+                Delay::new(Instant::now() + delay).then(move |_| Ok(Loop::Continue(failures)));
+            Either::B(wait)
+        })
+    })
+}
+
+/// Ask for what there is after the last message of `state`, and take it in
+/// until the `END`.
+fn fetch(config: &BackfillConfig, state: State) -> impl Future<Item = (), Error = io::Error> {
+    let (from, messages) = (config.from.clone(), config.messages);
+    resolve::connect(&config.from)
+        .and_then({
+            let state = state.clone();
+            move |socket| {
+                let framed = Framed::new(socket, LinesCodec::new());
+                let since = state.last_message_id();
+                logging::info!(
+                    "backfill_started", addr = &from;
+                    "backfilling from {} after #{}", from, since
+                );
+                framed
+                    .send(format!("BACKFILL {} {}", since, messages))
+                    .map(move |framed| (framed, since))
+            }
+        })
+        .and_then(|(framed, since)| {
+            framed
+                .into_future()
+                .map_err(|(e, _)| e)
+                .map(move |(line, framed)| (line, framed, since))
+        })
+        .and_then(move |(line, framed, since)| {
+            replica::last(line, since)?;
+            let taken = framed.fold(false, move |ended, line| {
+                if ended {
+                    let e = format!("line {:?} after the end", line);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                if line == "END" {
+                    return Ok(true);
+                }
+                if let Some(json) = line.strip_prefix("MODERATION ") {
+                    let moderation: Shared = serde_json::from_str(json)?;
+                    state.moderation.restore(&moderation);
+                    return Ok(false);
+                }
+                let message: StoredMessage = serde_json::from_str(&line)?;
+                state.replicate(message);
+                Ok(false)
+            });
+            Ok(taken.and_then(|ended| {
+                if ended {
+                    Ok(())
+                } else {
+                    let e = "cut off before the end";
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, e))
+                }
+            }))
+        })
+        .flatten()
+}
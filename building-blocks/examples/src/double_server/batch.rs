@@ -0,0 +1,88 @@
+//! Messages relayed together, or not at all.
+//!
+//! A program that sends something in several parts, like a header and the
+//! records after it, does not want the messages of others in between, nor
+//! only some of the parts to arrive. It opens a batch with `/begin`, and
+//! the messages after it wait until `/commit`:
+//!
+//! ```text
+//! /begin
+//! order 7781
+//! item 12 x3
+//! item 40 x1
+//! /commit
+//! ```
+//!
+//! The batch is then relayed as one message, its parts joined with `\n`
+//! like a fenced message (see `frames`), so that no other message can come
+//! in between and it still ends with the only `\r\n`:
+//!
+//! ```text
+//! shop: order 7781\nitem 12 x3\nitem 40 x1
+//! ```
+//!
+//! `/abort` drops the batch. If a part cannot be relayed, like an
+//! attachment or one that makes the batch longer than `max_message_bytes`,
+//! the peer is told and nothing of the batch is relayed at `/commit`. The
+//! rate limits, the quota and the throttle take the batch as the one
+//! message it is. Commands in between are answered as ever. An exactly-once
+//! peer puts the id in front of the first part, see `once`.
+
+use bytes::{BufMut, BytesMut};
+
+use crate::attachment::Attachment;
+use crate::locale::Message;
+
+/// The messages of a peer waiting for `/commit`.
+pub struct Batch {
+    message: BytesMut,
+    parts: usize,
+    /// Most bytes of the joined message.
+    max: usize,
+    /// Set once a part could not be taken.
+    failed: bool,
+}
+
+impl Batch {
+    pub fn new(max: usize) -> Batch {
+        Batch {
+            message: BytesMut::new(),
+            parts: 0,
+            max,
+            failed: false,
+        }
+    }
+
+    /// Add the next part, returning why the batch fails if it does now.
+    pub fn push(&mut self, part: &[u8]) -> Option<Message> {
+        if self.failed {
+            return None;
+        }
+        let failed = if Attachment::parse(part).is_some() {
+            Message::new("batch_attachment")
+        } else if self.message.len() + 1 + part.len() > self.max {
+            Message::new("batch_too_large").with("max", self.max)
+        } else {
+            if self.parts > 0 {
+                self.message.reserve(1);
+                self.message.put("\n");
+            }
+            self.message.extend_from_slice(part);
+            self.parts += 1;
+            return None;
+        };
+        self.failed = true;
+        Some(failed)
+    }
+
+    /// The message to relay, or why there is none.
+    pub fn commit(self) -> Result<BytesMut, Message> {
+        if self.failed {
+            return Err(Message::new("batch_dropped"));
+        }
+        if self.parts == 0 {
+            return Err(Message::new("batch_empty"));
+        }
+        Ok(self.message)
+    }
+}
@@ -0,0 +1,585 @@
+//! Servers bridged to each other, without messages going around in circles.
+//!
+//! A peer may be another server, dialed with `"bridge": true` (see `dial`)
+//! or dialing this one. Such a link says so in the first line, its name,
+//! with the id of the server in front: `@bridge=eu-1 upstream`. Messages
+//! relayed to links then come after a line saying where they came from:
+//!
+//! ```text
+//! @origin=eu-1/42;via=eu-1,us-2;ttl=14
+//! bob: hi
+//! ```
+//!
+//! `origin` is the server the message was first sent to and its id there,
+//! `via` the servers it passed, in order. `ttl` starts at the `ttl` of the
+//! server of `origin` and is one less at every server it passes. A message
+//! coming in over a link is dropped if it passed this server already, its
+//! `ttl` runs out, it passed `max_hops` servers, or it came in over another
+//! link before, and it is not relayed to a link of a server in its `via`.
+//! The `ttl` catches what gets past the others, like a server that changed
+//! its id. Fields the server does not know are left out when relaying. On
+//! sides that keep the order of messages the tag has a `vc` too, see
+//! `causal`.
+//!
+//! A message of a link without a tag is taken for one sent to the link
+//! itself, and lines of peers that are not links are never taken for tags.
+//!
+//! ```json
+//! "bridge": { "id": "eu-1", "max_hops": 4, "ttl": 16 }
+//! ```
+//!
+//! Without an `id` the server makes one up at startup. Ids are made of
+//! letters, digits, `.`, `_` and `-`.
+//!
+//! A peer of another server is addressed as `name@id`, like `bob@us-2` in
+//! `/msg bob@us-2 hi`. Such a message is not flooded to every link, it goes
+//! over the link leading to `us-2` only, after a tag saying who it is for:
+//!
+//! ```text
+//! @msg=bob@us-2;from=alice@eu-1;ttl=16
+//! hi
+//! ```
+//!
+//! The servers learn which link leads where from the links themselves.
+//! Every server tells each of its links the servers it reaches, and in how
+//! many hops, with itself at 0: `@routes=eu-1:0,us-2:1`. It does so when
+//! the link connects and again whenever what it reaches changes, leaving
+//! out the servers it reaches over that very link. A server is reached over
+//! the link announcing it in the fewest hops, and not at all if that is
+//! `max_hops` or more.
+//!
+//! Where links make circles, `via` keeps a message from going around them:
+//! it is not sent over a link to a server it passed, and only crosses the
+//! other links of the circle once each. The id of a link this server dialed
+//! is only known once it announced itself, until then it is sent what the
+//! others are, and drops what passed it already. A tree worked out from the
+//! routes would cut more links, but a server only relays the messages of
+//! one side to the other: a link that reaches the first server of a message
+//! may still not be sent it by anyone else.
+
+use bytes::Bytes;
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Mutex;
+
+use crate::causal::VectorClock;
+use crate::config::BridgeConfig;
+use crate::conn::ConnId;
+use crate::locale::Message;
+use crate::logging;
+use crate::names;
+use crate::state::{Side, State};
+
+/// Origins remembered to drop messages that arrive again.
+const SEEN_LEN: usize = 4096;
+
+/// What the name line of a link starts with.
+const LOGIN_TAG: &[u8] = b"@bridge=";
+
+/// What the tag in front of a message relayed to a link starts with.
+const ORIGIN_TAG: &[u8] = b"@origin=";
+
+/// What the tag in front of a message for one peer starts with.
+const DIRECT_TAG: &[u8] = b"@msg=";
+
+/// What the servers a link reaches start with.
+const ROUTES_TAG: &[u8] = b"@routes=";
+
+/// A peer that is another server.
+#[derive(Debug)]
+pub struct Link {
+    /// Its id, unless this server dialed it.
+    pub remote: Option<String>,
+}
+
+/// Where a message came from, as tagged.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub origin: String,
+    pub via: Vec<String>,
+    /// Servers it may still pass.
+    pub ttl: u32,
+    /// The messages its server had relayed, see `causal`.
+    pub clock: Option<VectorClock>,
+}
+
+impl Hop {
+    /// The line in front of a message relayed to a link.
+    pub fn tag(&self) -> String {
+        let clock = match &self.clock {
+            Some(clock) => format!(";vc={}", clock),
+            None => String::new(),
+        };
+        format!(
+            "@origin={};via={};ttl={}{}\r\n",
+            self.origin,
+            self.via.join(","),
+            self.ttl,
+            clock
+        )
+    }
+
+    /// The tag `line` is, if it is one.
+    pub fn parse(line: &[u8]) -> Option<Hop> {
+        if !line.starts_with(ORIGIN_TAG) {
+            return None;
+        }
+        let tag = std::str::from_utf8(&line[1..]).ok()?;
+        let (mut origin, mut via, mut ttl, mut clock) = (None, None, None, None);
+        for field in tag.split(';') {
+            let mut split = field.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("origin"), Some(value)) => origin = Some(value.to_string()),
+                (Some("via"), Some(value)) => {
+                    via = Some(value.split(',').map(str::to_string).collect())
+                }
+                (Some("ttl"), Some(value)) => ttl = value.parse().ok(),
+                (Some("vc"), Some(value)) => clock = VectorClock::parse(value),
+                _ => {}
+            }
+        }
+        Some(Hop {
+            origin: origin?,
+            via: via?,
+            ttl: ttl?,
+            clock,
+        })
+    }
+}
+
+/// A peer, on this server or on another one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub name: String,
+    /// The id of its server, `None` for this one.
+    pub server: Option<String>,
+}
+
+impl Address {
+    /// `name@id`, or just `name`. Names may have an `@` too, only what
+    /// follows the last one is taken for an id, if it is one.
+    pub fn parse(text: &str) -> Address {
+        match text.rfind('@') {
+            Some(at) if at > 0 && valid_id(&text[at + 1..]) => Address {
+                name: text[..at].to_string(),
+                server: Some(text[at + 1..].to_string()),
+            },
+            _ => Address {
+                name: text.to_string(),
+                server: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.server {
+            Some(server) => write!(f, "{}@{}", self.name, server),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// A message for one peer, as tagged.
+#[derive(Debug, Clone)]
+pub struct Direct {
+    pub to: Address,
+    pub from: Address,
+    /// Servers it may still pass.
+    pub ttl: u32,
+}
+
+impl Direct {
+    /// The tag followed by `text`, what is sent to a link.
+    fn line(&self, text: &str) -> Bytes {
+        let line = format!(
+            "@msg={};from={};ttl={}\r\n{}\r\n",
+            self.to, self.from, self.ttl, text
+        );
+        Bytes::from(line)
+    }
+
+    /// The tag `line` is, if it is one.
+    pub fn parse(line: &[u8]) -> Option<Direct> {
+        if !line.starts_with(DIRECT_TAG) {
+            return None;
+        }
+        let tag = std::str::from_utf8(&line[1..]).ok()?;
+        let (mut to, mut from, mut ttl) = (None, None, None);
+        for field in tag.split(';') {
+            let mut split = field.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("msg"), Some(value)) => to = Some(Address::parse(value)),
+                (Some("from"), Some(value)) => from = Some(Address::parse(value)),
+                (Some("ttl"), Some(value)) => ttl = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(Direct {
+            to: to?,
+            from: from?,
+            ttl: ttl?,
+        })
+    }
+}
+
+/// Split the id of a link off its name line, if it is one.
+pub fn parse_login(name: &[u8]) -> Option<(String, &[u8])> {
+    if !name.starts_with(LOGIN_TAG) {
+        return None;
+    }
+    let rest = &name[LOGIN_TAG.len()..];
+    let space = rest.iter().position(|&b| b == b' ')?;
+    let id = std::str::from_utf8(&rest[..space]).ok()?;
+    if !valid_id(id) {
+        return None;
+    }
+    Some((id.to_string(), &rest[space + 1..]))
+}
+
+/// Whether `line` of a link announces the servers it reaches.
+pub fn is_routes(line: &[u8]) -> bool {
+    line.starts_with(ROUTES_TAG)
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+/// Why a message from a link is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// It passed this server before.
+    Loop,
+    /// Its `ttl` ran out.
+    Expired,
+    /// It passed `max_hops` servers.
+    Hops,
+    /// It came in over another link before.
+    Seen,
+}
+
+/// This server as one of the bridged.
+pub struct Bridges {
+    pub id: String,
+    max_hops: usize,
+    ttl: u32,
+    /// The origins of the messages that came in over links, newest last.
+    seen: Mutex<(VecDeque<String>, HashSet<String>)>,
+    /// What every link announced it reaches, by the connection of the link.
+    routes: Mutex<HashMap<ConnId, Announced>>,
+}
+
+/// The servers a link reaches.
+struct Announced {
+    side: Side,
+    /// The server of the link itself, the one at 0 hops.
+    server: Option<String>,
+    /// Hops from this server to each of them, over the link.
+    hops: HashMap<String, usize>,
+}
+
+/// How a server is reached: in how many hops, over which link of which
+/// side.
+type Route = (usize, Side, ConnId);
+
+impl Bridges {
+    /// Fails if the configured id is not a valid one.
+    pub fn new(config: &BridgeConfig) -> io::Result<Bridges> {
+        let id = match &config.id {
+            Some(id) if valid_id(id) => id.clone(),
+            Some(id) => {
+                let message = format!(
+                    "bridge id {:?} has characters other than letters, digits, '.', '_' and '-'",
+                    id
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            None => format!("{:016x}", RandomState::new().build_hasher().finish()),
+        };
+        Ok(Bridges {
+            id,
+            max_hops: config.max_hops,
+            ttl: config.ttl,
+            seen: Mutex::new((VecDeque::with_capacity(SEEN_LEN), HashSet::new())),
+            routes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The name line of a link from this server going by `name`.
+    pub fn login(&self, name: &str) -> String {
+        format!("@bridge={} {}", self.id, name)
+    }
+
+    /// Where the message `id`, sent to this server, came from.
+    pub fn local(&self, id: u64) -> Hop {
+        Hop {
+            origin: format!("{}/{}", self.id, id),
+            via: vec![self.id.clone()],
+            ttl: self.ttl,
+            clock: None,
+        }
+    }
+
+    /// Where a message that came in over a link with `hop` came from once
+    /// it passed this server, unless it is dropped.
+    pub fn forward(&self, mut hop: Hop) -> Result<Hop, Dropped> {
+        if hop.via.contains(&self.id) {
+            return Err(Dropped::Loop);
+        }
+        hop.ttl = hop.ttl.saturating_sub(1);
+        if hop.ttl == 0 {
+            return Err(Dropped::Expired);
+        }
+        if hop.via.len() >= self.max_hops {
+            return Err(Dropped::Hops);
+        }
+        let mut seen = self.seen.lock().unwrap();
+        let (order, origins) = &mut *seen;
+        if !origins.insert(hop.origin.clone()) {
+            return Err(Dropped::Seen);
+        }
+        order.push_back(hop.origin.clone());
+        if order.len() > SEEN_LEN {
+            let oldest = order.pop_front().unwrap();
+            origins.remove(&oldest);
+        }
+        drop(seen);
+        hop.via.push(self.id.clone());
+        Ok(hop)
+    }
+
+    /// The best route to every server reached, but this one.
+    fn best(&self) -> HashMap<String, Route> {
+        let routes = self.routes.lock().unwrap();
+        let mut best: HashMap<String, Route> = HashMap::new();
+        for (&link, announced) in routes.iter() {
+            for (id, &hops) in &announced.hops {
+                let route = (hops, announced.side, link);
+                match best.get(id) {
+                    // The older link wins a tie, whatever the order the
+                    // links are visited in.
+                    Some(&(shortest, _, other)) if (shortest, other) <= (hops, link) => {}
+                    _ => {
+                        best.insert(id.clone(), route);
+                    }
+                }
+            }
+        }
+        best.retain(|_, &mut (hops, _, _)| hops < self.max_hops);
+        best
+    }
+
+    /// The servers the routes lead to.
+    pub fn reached(&self) -> HashSet<String> {
+        self.best().into_keys().collect()
+    }
+
+    /// The link leading to the server `id`, if one does.
+    pub fn route(&self, id: &str) -> Option<(Side, ConnId)> {
+        self.best().get(id).map(|&(_, side, link)| (side, link))
+    }
+
+    /// The links to the servers `hop` passed, as far as they announced
+    /// themselves.
+    pub fn passed(&self, hop: &Hop) -> Vec<ConnId> {
+        let routes = self.routes.lock().unwrap();
+        routes
+            .iter()
+            .filter(|(_, announced)| {
+                let server = announced.server.as_ref();
+                server.map_or(false, |server| hop.via.contains(server))
+            })
+            .map(|(&link, _)| link)
+            .collect()
+    }
+
+    /// The servers announced to the link `link`.
+    fn announcement(&self, link: &ConnId) -> Bytes {
+        let mut reached: Vec<(String, usize)> = self
+            .best()
+            .into_iter()
+            .filter(|(_, (_, _, over))| over != link)
+            .map(|(id, (hops, _, _))| (id, hops))
+            .collect();
+        reached.sort();
+        let mut line = format!("@routes={}:0", self.id);
+        for (id, hops) in reached {
+            line.push_str(&format!(",{}:{}", id, hops));
+        }
+        line.push_str("\r\n");
+        Bytes::from(line)
+    }
+
+    /// Take in the servers the link `link` of `side` announced, if `line`
+    /// is an announcement, see `is_routes`. `Some(true)` if that changes
+    /// how any server is reached.
+    pub fn learn(&self, side: Side, link: ConnId, line: &[u8]) -> Option<bool> {
+        if !line.starts_with(ROUTES_TAG) {
+            return None;
+        }
+        let list = std::str::from_utf8(&line[ROUTES_TAG.len()..]).ok()?;
+        let (mut server, mut hops) = (None, HashMap::new());
+        for entry in list.split(',') {
+            let mut split = entry.splitn(2, ':');
+            if let (Some(id), Some(Ok(n))) = (split.next(), split.next().map(str::parse::<usize>)) {
+                if n == 0 && valid_id(id) {
+                    server = Some(id.to_string());
+                }
+                if valid_id(id) && id != self.id {
+                    hops.insert(id.to_string(), n.saturating_add(1));
+                }
+            }
+        }
+        let before = self.best();
+        let announced = Announced { side, server, hops };
+        self.routes.lock().unwrap().insert(link, announced);
+        Some(self.best() != before)
+    }
+
+    /// Forget what the link `link` announced, once it is gone. `true` if
+    /// that changes how any server is reached.
+    pub fn forget(&self, link: &ConnId) -> bool {
+        let before = self.best();
+        if self.routes.lock().unwrap().remove(link).is_none() {
+            return false;
+        }
+        self.best() != before
+    }
+}
+
+/// Tell the link `link` of `side` the servers this one reaches.
+pub fn greet(state: &State, side: Side, link: &ConnId) {
+    state.tell_line(side, link, state.bridges.announcement(link));
+}
+
+/// The side and connection of every link, but those closing, see `conn`.
+pub fn links(state: &State) -> Vec<(Side, ConnId)> {
+    let mut links = Vec::new();
+    for &side in &[Side::C, Side::Go] {
+        state.side(side).for_each(|id, member| {
+            if member.link.is_some() && member.lifecycle.is_active() {
+                links.push((side, *id));
+            }
+        });
+    }
+    links
+}
+
+/// Tell every link the servers this one reaches, once that changed.
+pub fn announce(state: &State) {
+    for (side, link) in links(state) {
+        greet(state, side, &link);
+    }
+}
+
+/// Send `text` of the peer `from` of this server, in `partition`, to the
+/// peer at `to`, and say how that went.
+pub fn send(
+    state: &State,
+    from: &str,
+    partition: Option<usize>,
+    to: &Address,
+    text: &str,
+) -> Message {
+    let server = match &to.server {
+        Some(server) if *server != state.bridges.id => server,
+        _ => {
+            let from = Address {
+                name: from.to_string(),
+                server: None,
+            };
+            return match deliver(state, partition, &to.name, &from, text) {
+                true => Message::new("msg_sent").with("to", to),
+                false => Message::new("msg_unknown").with("to", to),
+            };
+        }
+    };
+    let direct = Direct {
+        to: to.clone(),
+        from: Address {
+            name: from.to_string(),
+            server: Some(state.bridges.id.clone()),
+        },
+        ttl: state.bridges.ttl,
+    };
+    match state.bridges.route(server) {
+        Some((side, link)) => {
+            state.tell_line(side, &link, direct.line(text));
+            Message::new("msg_sent").with("to", to)
+        }
+        None => Message::new("msg_no_route").with("server", server),
+    }
+}
+
+/// Deliver the message `text` tagged with `direct` that came in over a
+/// link, or send it on towards its server.
+pub fn arrive(state: &State, mut direct: Direct, text: &[u8]) {
+    let text = String::from_utf8_lossy(text);
+    let server = match &direct.to.server {
+        Some(server) if *server != state.bridges.id => server.clone(),
+        _ => {
+            if !deliver(state, None, &direct.to.name, &direct.from, &text) {
+                logging::info!(
+                    "direct_missed";
+                    "message of {} for {} missed, not connected", direct.from, direct.to
+                );
+            }
+            return;
+        }
+    };
+    direct.ttl = direct.ttl.saturating_sub(1);
+    if direct.ttl == 0 {
+        state.metrics.bridge_messages_expired.add(1);
+        return;
+    }
+    match state.bridges.route(&server) {
+        Some((side, link)) => state.tell_line(side, &link, direct.line(&text)),
+        None => {
+            logging::info!(
+                "direct_unrouted";
+                "message of {} for {} dropped, no link leads to {}", direct.from, direct.to, server
+            );
+        }
+    }
+}
+
+/// Tell the peer of this server called `name`, in `partition`, the `text`
+/// of `from`. `false` if it is not connected.
+fn deliver(
+    state: &State,
+    partition: Option<usize>,
+    name: &str,
+    from: &Address,
+    text: &str,
+) -> bool {
+    let key = names::key(name);
+    let mut recipient = None;
+    for &side in &[Side::C, Side::Go] {
+        state.side(side).for_each(|id, member| {
+            let named = member.link.is_none() && names::key(&member.name) == key;
+            let reachable = partition.is_none() || member.partition == partition;
+            if named && reachable {
+                recipient = Some((side, *id));
+            }
+        });
+    }
+    match recipient {
+        Some((side, id)) => {
+            let message = Message::new("msg_received")
+                .with("from", from)
+                .with("text", text);
+            state.tell(side, &id, &message);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,264 @@
+//! Optional features a client turns on before its name, like IRC does.
+//!
+//! A client may start with `CAP LS` rather than its name, to be told what
+//! the server offers, ask for some with `CAP REQ`, and end with `CAP END`
+//! before sending its name as usual:
+//!
+//! ```text
+//! C: CAP LS
+//! S: CAP * LS :ack compress=zstd,gzip ids timestamps v2
+//! C: CAP REQ :ids timestamps
+//! S: CAP * ACK :ids timestamps
+//! C: CAP END
+//! C: alice
+//! ```
+//!
+//! - `ack`: the client puts an id of its own in front of every message and
+//!   is acknowledged, as after `/once`, see `once`. Only offered with
+//!   `exactly_once`.
+//! - `compress=<codec>`: everything after the line break of `CAP END` is
+//!   compressed both ways, as after `/compress <codec>`, see `compression`.
+//! - `ids`: the messages of other peers come with their id in front, like
+//!   `#4711 bob: hi`.
+//! - `timestamps`: they come with when the server took them in, like
+//!   `@time=2019-08-05T10:14:07.311Z bob: hi`, before the id if there is
+//!   one.
+//! - `v2`: the client speaks version 2 of the protocol, with the id and
+//!   the time of a message in its envelope instead, see `version`, and
+//!   long messages split into frames, see `frames`.
+//!
+//! A `CAP REQ` is granted whole or not at all, `NAK` then. `CAP LIST` tells
+//! what was granted. A first line that is not a `CAP` is the name, like it
+//! always was, so clients that know nothing of this work as before. So is
+//! a line after `CAP LS` or `CAP REQ` without a `CAP END`. The replies are
+//! read by programs, and not translated. Connections speaking an
+//! `encoding` have no handshake.
+//!
+//! A `Handshake` takes the lines one by one without any IO, see
+//! `protocol`, and `handshake` drives it on the lines of a connection.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future;
+use futures::{try_ready, Async, Future, Stream};
+
+use std::io;
+use std::sync::Arc;
+
+use crate::compression::{Codec, Codecs};
+use crate::logging;
+use crate::once;
+use crate::protocol::Event;
+use crate::state::{Delivery, State};
+use crate::version::{self, Version};
+use crate::wire::Message;
+use crate::{Lines, Transport};
+
+/// Most `CAP` lines a client sends before its name.
+const MAX_LINES: usize = 16;
+
+/// What a client asked for in its handshake.
+#[derive(Clone, Default)]
+pub struct Capabilities {
+    pub ack: bool,
+    pub compress: Option<Arc<dyn Codec>>,
+    pub ids: bool,
+    pub timestamps: bool,
+    pub version: Version,
+}
+
+impl Capabilities {
+    /// The names of what was granted, as `CAP LIST` tells them.
+    fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        if self.ack {
+            names.push("ack".to_string());
+        }
+        if let Some(codec) = &self.compress {
+            names.push(format!("compress={}", codec.name()));
+        }
+        if self.ids {
+            names.push("ids".to_string());
+        }
+        if self.timestamps {
+            names.push("timestamps".to_string());
+        }
+        if self.version == Version::V2 {
+            names.push("v2".to_string());
+        }
+        names
+    }
+
+    /// `line`, of the message of `delivery`, as the client asked to get
+    /// it. `numbered` if it gets the ids anyway, see `once`.
+    pub fn tag(&self, delivery: &Delivery, numbered: bool, line: &Bytes) -> Bytes {
+        if self.version == Version::V2 {
+            // The id of an exactly-once peer stays where it looks for it.
+            if numbered {
+                return version::envelope(delivery, &once::numbered(delivery.id, line));
+            }
+            return version::envelope(delivery, line);
+        }
+        let line = if numbered || self.ids {
+            once::numbered(delivery.id, line)
+        } else {
+            line.clone()
+        };
+        if !self.timestamps {
+            return line;
+        }
+        let time = format!("@time={} ", logging::rfc3339(delivery.ts));
+        let mut tagged = BytesMut::with_capacity(time.len() + line.len());
+        tagged.put(time);
+        tagged.put(&line[..]);
+        tagged.freeze()
+    }
+}
+
+/// What the server offers in a handshake.
+pub struct Offer {
+    ack: bool,
+    codecs: Arc<Codecs>,
+}
+
+impl Offer {
+    pub fn of(state: &State) -> Offer {
+        Offer {
+            ack: state.exactly_once.is_some(),
+            codecs: state.codecs.clone(),
+        }
+    }
+
+    /// What a server without exactly-once delivery or codecs offers.
+    #[cfg(test)]
+    pub fn plain() -> Offer {
+        Offer {
+            ack: false,
+            codecs: Arc::new(Codecs::new(&[]).unwrap()),
+        }
+    }
+
+    /// What the server offers, as `CAP LS` tells it.
+    fn names(&self) -> String {
+        let mut offered = Vec::new();
+        if self.ack {
+            offered.push("ack".to_string());
+        }
+        let codecs: Vec<_> = self
+            .codecs
+            .names()
+            .into_iter()
+            .filter(|&name| name != "none")
+            .collect();
+        if !codecs.is_empty() {
+            offered.push(format!("compress={}", codecs.join(",")));
+        }
+        offered.push("ids".to_string());
+        offered.push("timestamps".to_string());
+        offered.push("v2".to_string());
+        offered.join(" ")
+    }
+
+    /// Grant `requested`, the words of a `CAP REQ`, on top of `granted`,
+    /// or nothing if the server does not offer one of them.
+    fn request(&self, granted: &Capabilities, requested: &str) -> Option<Capabilities> {
+        let mut granted = granted.clone();
+        for word in requested.split_whitespace() {
+            match word {
+                "ack" if self.ack => granted.ack = true,
+                "ids" => granted.ids = true,
+                "timestamps" => granted.timestamps = true,
+                "v2" => granted.version = Version::V2,
+                _ if word.starts_with("compress=") => {
+                    granted.compress = Some(self.codecs.get(&word["compress=".len()..])?);
+                }
+                _ => return None,
+            }
+        }
+        Some(granted)
+    }
+}
+
+/// What a line of the handshake asks for.
+pub enum Step {
+    /// Send `CAP * ` and this to the client.
+    Reply(String),
+    /// `CAP END`, compressed from its line break on if that was granted.
+    End,
+    /// Not a `CAP` line but the name, which ends the handshake.
+    Name,
+}
+
+/// The handshake of one client, line by line.
+pub struct Handshake {
+    offer: Offer,
+    granted: Capabilities,
+    /// `CAP` lines taken so far.
+    taken: usize,
+}
+
+impl Handshake {
+    pub fn new(offer: Offer) -> Handshake {
+        Handshake {
+            offer,
+            granted: Capabilities::default(),
+            taken: 0,
+        }
+    }
+
+    /// What was granted so far.
+    pub fn granted(&self) -> &Capabilities {
+        &self.granted
+    }
+
+    /// Take `line`, failing once there were too many.
+    pub fn take(&mut self, line: &[u8]) -> Result<Step, String> {
+        if !line.starts_with(b"CAP ") && line != b"CAP" {
+            return Ok(Step::Name);
+        }
+        if self.taken == MAX_LINES {
+            return Err(format!("more than {} CAP lines", MAX_LINES));
+        }
+        self.taken += 1;
+
+        let line = String::from_utf8_lossy(&line[3..]).into_owned();
+        let mut words = line.trim().splitn(2, ' ');
+        let command = words.next().unwrap_or("").to_ascii_uppercase();
+        let rest = words.next().unwrap_or("").trim();
+        let rest = rest.strip_prefix(':').unwrap_or(rest);
+        let reply = match command.as_str() {
+            "LS" => format!("LS :{}", self.offer.names()),
+            "LIST" => format!("LIST :{}", self.granted.names().join(" ")),
+            "REQ" => match self.offer.request(&self.granted, rest) {
+                Some(more) => {
+                    self.granted = more;
+                    format!("ACK :{}", rest)
+                }
+                None => format!("NAK :{}", rest),
+            },
+            "END" => return Ok(Step::End),
+            _ => format!("NAK :{}", line.trim()),
+        };
+        Ok(Step::Reply(reply))
+    }
+}
+
+/// Take the handshake a client may start with. Resolves to its name, and
+/// `lines` to read the rest, with what was granted in their `connection`.
+pub fn handshake<S: Transport>(
+    lines: Lines<S>,
+) -> impl Future<Item = (Option<BytesMut>, Lines<S>), Error = io::Error> {
+    let mut lines = Some(lines);
+    future::poll_fn(move || {
+        let name = match lines.as_mut().unwrap().poll()? {
+            Async::Ready(Some(Event::Message(Message::Join(name)))) => Some(name),
+            Async::Ready(None) => None,
+            Async::Ready(Some(_)) => unreachable!("the first message is the name"),
+            Async::NotReady => {
+                // Waiting for the next line, the replies go out.
+                try_ready!(lines.as_mut().unwrap().poll_flush());
+                return Ok(Async::NotReady);
+            }
+        };
+        Ok(Async::Ready((name, lines.take().unwrap())))
+    })
+}
@@ -0,0 +1,251 @@
+//! Messages of other servers in the order they were written in.
+//!
+//! Over links messages take different ways, and a reply can come in before
+//! the message it answers, when that one went the long way around. On the
+//! sides in `causal`, a message is held back until this server relayed
+//! every message its server had when it was written:
+//!
+//! ```json
+//! "bridge": { "id": "eu-1", "causal": ["c", "go"], "causal_wait_ms": 2000 }
+//! ```
+//!
+//! For that every server counts the messages of each server it relayed,
+//! its own among them, and the tag of a message it sends to its links
+//! carries the counts it had then, those of 0 left out:
+//!
+//! ```text
+//! @origin=us-2/7;via=us-2;ttl=16;vc=eu-1:12,us-2:4
+//! ```
+//!
+//! That is, `us-2` wrote the message after its fourth, and after relaying
+//! the twelfth of `eu-1`. It is held back until this server relayed as many
+//! of each, and relayed as it is if the servers do not count.
+//!
+//! A message that is lost, like one that ran out of `ttl`, would hold the
+//! ones after it back forever, so none is held back longer than
+//! `causal_wait_ms`. It is then relayed, and counted, as if what it came
+//! after were there. A server that restarted counts from 0 again, and its
+//! messages come in as they are until it has as many as the others counted
+//! of it. Messages are counted on every side alike, servers with different
+//! `causal` can be bridged.
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use tokio::timer::Interval;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bridge::{self, Hop};
+use crate::config::BridgeConfig;
+use crate::conn::ConnId;
+use crate::logging;
+use crate::state::{ChatEvent, Side, State};
+
+/// How many messages of every server there were, by id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    /// The clock of the `vc` field of a tag, `None` if it is not one.
+    pub fn parse(field: &str) -> Option<VectorClock> {
+        let mut clock = BTreeMap::new();
+        for entry in field.split(',').filter(|entry| !entry.is_empty()) {
+            let mut split = entry.rsplitn(2, ':');
+            let count = split.next()?.parse().ok()?;
+            clock.insert(split.next()?.to_string(), count);
+        }
+        Some(VectorClock(clock))
+    }
+
+    fn get(&self, server: &str) -> u64 {
+        self.0.get(server).cloned().unwrap_or(0)
+    }
+
+    /// Whether a message of `origin` written at `self` comes next at
+    /// `relayed`: what it came after was relayed, or it is one of the
+    /// messages of a server that restarted.
+    fn follows(&self, origin: &str, relayed: &VectorClock) -> bool {
+        self.0.iter().all(|(server, &count)| {
+            let seen = relayed.get(server);
+            if server == origin {
+                count <= seen + 1
+            } else {
+                count <= seen
+            }
+        })
+    }
+
+    fn merge(&mut self, other: &VectorClock) {
+        for (server, &count) in &other.0 {
+            let own = self.0.entry(server.clone()).or_insert(0);
+            *own = (*own).max(count);
+        }
+    }
+}
+
+impl fmt::Display for VectorClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(_, &count)| count > 0)
+            .map(|(server, count)| format!("{}:{}", server, count))
+            .collect();
+        f.write_str(&entries.join(","))
+    }
+}
+
+/// A message relayed to `side` from the peer `from`, as `State::relay`
+/// and `State::publish` take it.
+pub struct Relayed {
+    pub side: Side,
+    pub from: ConnId,
+    pub partition: Option<usize>,
+    pub line: Bytes,
+    /// Of `State::next_message_id`.
+    pub id: u64,
+    pub decoded: Instant,
+    pub hop: Hop,
+    pub event: ChatEvent,
+}
+
+impl Relayed {
+    fn relay(self, state: &State) {
+        // In the history before it goes out, so that an exactly-once peer
+        // resuming gets it one way or the other, see `once::replay`.
+        state.publish(self.event.clone());
+        state.relay(&self);
+    }
+}
+
+struct Held {
+    message: Relayed,
+    since: Instant,
+}
+
+impl Held {
+    fn follows(&self, relayed: &VectorClock) -> bool {
+        let hop = &self.message.hop;
+        hop.clock
+            .as_ref()
+            .map_or(true, |clock| clock.follows(server(hop), relayed))
+    }
+}
+
+struct Clocks {
+    /// The messages of every server relayed.
+    relayed: VectorClock,
+    /// Held back, in the order they came in.
+    held: Vec<Held>,
+}
+
+pub struct Causal {
+    sides: Vec<Side>,
+    wait: Duration,
+    clocks: Mutex<Clocks>,
+}
+
+impl Causal {
+    pub fn new(config: &BridgeConfig) -> Causal {
+        Causal {
+            sides: config.causal.clone(),
+            wait: Duration::from_millis(config.causal_wait_ms),
+            clocks: Mutex::new(Clocks {
+                relayed: VectorClock::default(),
+                held: Vec::new(),
+            }),
+        }
+    }
+
+    /// Whether messages are held back on any side, and so counted.
+    pub fn enabled(&self) -> bool {
+        !self.sides.is_empty()
+    }
+}
+
+/// Count the message of a peer of this server that `hop` is the tag of,
+/// relayed to `side`, if it goes to a link, and put the counts in the tag.
+pub fn stamp(state: &State, side: Side, hop: &mut Hop) {
+    let causal = &state.causal;
+    if !causal.enabled() || !bridge::links(state).iter().any(|&(s, _)| s == side) {
+        return;
+    }
+    let mut clocks = causal.clocks.lock().unwrap();
+    let own = clocks
+        .relayed
+        .0
+        .entry(state.bridges.id.clone())
+        .or_insert(0);
+    *own += 1;
+    hop.clock = Some(clocks.relayed.clone());
+}
+
+/// Relay `message`, or hold it back until what it came after was relayed.
+pub fn relay(state: &State, message: Relayed) {
+    let causal = &state.causal;
+    let clock = match &message.hop.clock {
+        Some(clock) if causal.enabled() => clock.clone(),
+        _ => return message.relay(state),
+    };
+    // Relayed with the lock held, so that the messages of two links go out
+    // in the order they were counted in.
+    let mut clocks = causal.clocks.lock().unwrap();
+    if causal.sides.contains(&message.side) && !clock.follows(server(&message.hop), &clocks.relayed)
+    {
+        state.metrics.causal_messages_held.add(1);
+        clocks.held.push(Held {
+            message,
+            since: Instant::now(),
+        });
+        return;
+    }
+    clocks.relayed.merge(&clock);
+    message.relay(state);
+    release(state, &mut clocks, None);
+}
+
+/// Every `causal_wait_ms / 4`, relay the messages held back too long.
+pub fn expire(state: State) -> impl Future<Item = (), Error = ()> {
+    let every = (state.causal.wait / 4).max(Duration::from_millis(10));
+    Interval::new(Instant::now() + every, every)
+        .map_err(|e| logging::error!("timer_failed"; "causal timer error = {:?}", e))
+        .for_each(move |_| {
+            let causal = &state.causal;
+            let mut clocks = causal.clocks.lock().unwrap();
+            if !clocks.held.is_empty() {
+                let deadline = Instant::now() - causal.wait;
+                release(&state, &mut clocks, Some(deadline));
+            }
+            Ok(())
+        })
+}
+
+/// Relay the held messages that may be relayed now, in order. With a
+/// `deadline`, those held since before it too.
+fn release(state: &State, clocks: &mut Clocks, deadline: Option<Instant>) {
+    loop {
+        let relayed = &clocks.relayed;
+        let next = clocks.held.iter().position(|held| {
+            held.follows(relayed) || deadline.map_or(false, |deadline| held.since <= deadline)
+        });
+        let held = match next {
+            Some(next) => clocks.held.remove(next),
+            None => return,
+        };
+        if !held.follows(&clocks.relayed) {
+            state.metrics.causal_messages_expired.add(1);
+        }
+        if let Some(clock) = &held.message.hop.clock {
+            clocks.relayed.merge(clock);
+        }
+        held.message.relay(state);
+    }
+}
+
+/// The server the message of `hop` was first sent to.
+fn server(hop: &Hop) -> &str {
+    hop.origin.split('/').next().unwrap_or("")
+}
@@ -0,0 +1,456 @@
+//! Commands peers can send instead of a message.
+//!
+//! A line starting with `/` is a command. It is not relayed, the reply only
+//! goes back to the peer that sent it:
+//!
+//! ```text
+//! /who                 the connected peers of both sides, and of the other
+//!                      servers of the bridge, see `roster`
+//! /history [count]     the last messages, 10 unless given, up to the
+//!                      whole history
+//! /search <text>       the last messages containing text
+//! /quota               the bytes the peer relayed today, and its quota
+//! /stats               the peers using the most bandwidth, and the latency
+//!                      of the messages of both sides
+//! /send <peer> <file> <size> [port]
+//!                      offer a file to a peer of the other side, see
+//!                      `transfer` for this and the next two
+//! /accept <id>         accept an offer
+//! /reject <id>         turn an offer down, or take one back
+//! /compress [codec]    compress the connection, see `compression`
+//! /locale [locale]     what the server tells the peer things in, see
+//!                      `locale`
+//! /remind <delay> <peer> <text>
+//!                      have text delivered to a peer, or #c or #go for a
+//!                      side, after a delay like 10m, see `remind`
+//! /msg <peer> <text>   send text to one peer only, of either side, or of
+//!                      another server as peer@server, see `bridge`
+//! /topic [text]        the topic, or set it, see `moderation` for this
+//!                      and the next four
+//! /ban <peer>          drop what a peer sends, and turn the name away
+//! /unban <peer>
+//! /mod [peer]          make a peer a moderator, or list the moderators
+//!                      and the banned
+//! /unmod <peer>
+//! /once [id]           exactly-once delivery from here on, after the
+//!                      message with that id, see `once`
+//! /begin               hold the messages back until /commit relays them
+//!                      as one, or /abort drops them, see `batch`
+//! ```
+//!
+//! `/attach` is the exception, it sends a message with a file attached, see
+//! `attachment`.
+//!
+//! The messages of `/history` are not all buffered at once. They are read
+//! from the history a page at a time whenever the socket took the previous
+//! one, see `Transcript`.
+
+use crate::bridge::{self, Address};
+use crate::compression::Codec;
+use crate::conn::ConnId;
+use crate::locale::Message;
+use crate::meter::human_bytes;
+use crate::metrics::{human_duration, QUANTILES};
+use crate::moderation::{self, Change};
+use crate::remind;
+use crate::state::{Side, State, StoredMessage};
+use crate::transfer::{self, Party};
+
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Messages listed by `/history` without a count, and most found by
+/// `/search`.
+const DEFAULT_MESSAGES: usize = 10;
+
+/// Peers listed by `/stats`.
+const STATS_PEERS: usize = 10;
+
+/// Messages of a transcript taken from the history at once.
+const TRANSCRIPT_PAGE: usize = 64;
+
+#[derive(Debug)]
+pub enum Command {
+    Who,
+    History(usize),
+    Search(String),
+    Quota,
+    Stats,
+    Send {
+        peer: String,
+        file: String,
+        size: u64,
+        port: Option<u16>,
+    },
+    Accept(u64),
+    Reject(u64),
+    /// The codecs offered without a name.
+    Compress(Option<String>),
+    /// The locales there are without one.
+    Locale(Option<String>),
+    Remind {
+        delay: Duration,
+        to: String,
+        text: String,
+    },
+    Msg {
+        to: Address,
+        text: String,
+    },
+    /// The topic without a text.
+    Topic(Option<String>),
+    Moderate {
+        change: Change,
+        name: String,
+    },
+    /// `/mod` without a peer.
+    Moderators,
+    /// The id of the last message the peer took in, if it resumes.
+    Once(Option<u64>),
+    Begin,
+}
+
+impl Command {
+    /// Parse a line starting with `/`.
+    pub fn parse(line: &str) -> Result<Command, Message> {
+        let mut parts = line.trim().splitn(2, ' ');
+        let name = parts.next().unwrap_or("");
+        let arg = parts.next().map(str::trim).unwrap_or("");
+
+        match name {
+            "/who" => Ok(Command::Who),
+            "/history" if arg.is_empty() => Ok(Command::History(DEFAULT_MESSAGES)),
+            "/history" => match arg.parse() {
+                Ok(count) => Ok(Command::History(count)),
+                Err(_) => Err(Message::new("history_usage")),
+            },
+            "/search" if arg.is_empty() => Err(Message::new("search_usage")),
+            "/search" => Ok(Command::Search(arg.to_lowercase())),
+            "/quota" => Ok(Command::Quota),
+            "/stats" => Ok(Command::Stats),
+            "/send" => {
+                let usage = || Message::new("send_usage");
+                let args: Vec<&str> = arg.split_whitespace().collect();
+                let (peer, file, size, port) = match args[..] {
+                    [peer, file, size] => (peer, file, size, None),
+                    [peer, file, size, port] => (peer, file, size, Some(port)),
+                    _ => return Err(usage()),
+                };
+                Ok(Command::Send {
+                    peer: peer.to_string(),
+                    file: file.to_string(),
+                    size: size.parse().map_err(|_| usage())?,
+                    port: match port {
+                        Some(port) => Some(port.parse().map_err(|_| usage())?),
+                        None => None,
+                    },
+                })
+            }
+            "/accept" => match arg.parse() {
+                Ok(id) => Ok(Command::Accept(id)),
+                Err(_) => Err(Message::new("accept_usage")),
+            },
+            "/reject" => match arg.parse() {
+                Ok(id) => Ok(Command::Reject(id)),
+                Err(_) => Err(Message::new("reject_usage")),
+            },
+            "/compress" if arg.is_empty() => Ok(Command::Compress(None)),
+            "/compress" => Ok(Command::Compress(Some(arg.to_lowercase()))),
+            "/locale" if arg.is_empty() => Ok(Command::Locale(None)),
+            "/locale" => Ok(Command::Locale(Some(arg.to_string()))),
+            "/remind" => {
+                let usage = || Message::new("remind_usage");
+                let mut args = arg.splitn(3, char::is_whitespace);
+                let delay = args.next().and_then(remind::parse_delay);
+                let to = args.next().filter(|to| !to.is_empty());
+                let text = args.next().map(str::trim).filter(|text| !text.is_empty());
+                match (delay, to, text) {
+                    (Some(delay), Some(to), Some(text)) => Ok(Command::Remind {
+                        delay,
+                        to: to.to_string(),
+                        text: text.to_string(),
+                    }),
+                    _ => Err(usage()),
+                }
+            }
+            "/msg" => {
+                let mut args = arg.splitn(2, char::is_whitespace);
+                let to = args.next().filter(|to| !to.is_empty());
+                let text = args.next().map(str::trim).filter(|text| !text.is_empty());
+                match (to, text) {
+                    (Some(to), Some(text)) => Ok(Command::Msg {
+                        to: Address::parse(to),
+                        text: text.to_string(),
+                    }),
+                    _ => Err(Message::new("msg_usage")),
+                }
+            }
+            "/topic" if arg.is_empty() => Ok(Command::Topic(None)),
+            "/topic" => Ok(Command::Topic(Some(arg.to_string()))),
+            "/mod" if arg.is_empty() => Ok(Command::Moderators),
+            "/ban" | "/unban" | "/mod" | "/unmod" => {
+                let change = match name {
+                    "/ban" => Change::Ban,
+                    "/unban" => Change::Unban,
+                    "/mod" => Change::Mod,
+                    _ => Change::Unmod,
+                };
+                if arg.is_empty() {
+                    return Err(Message::new("moderation_usage").with("command", name));
+                }
+                Ok(Command::Moderate {
+                    change,
+                    name: arg.to_string(),
+                })
+            }
+            "/once" if arg.is_empty() => Ok(Command::Once(None)),
+            "/once" => match arg.parse() {
+                Ok(last) => Ok(Command::Once(Some(last))),
+                Err(_) => Err(Message::new("once_usage")),
+            },
+            "/begin" => Ok(Command::Begin),
+            // Taken by the open batch, see `batch`.
+            "/commit" | "/abort" => Err(Message::new("batch_none").with("command", name)),
+            _ => Err(Message::new("unknown_command").with("command", name)),
+        }
+    }
+
+    /// Name of the command, as used for its rate limit.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Who => "/who",
+            Command::History(_) => "/history",
+            Command::Search(_) => "/search",
+            Command::Quota => "/quota",
+            Command::Stats => "/stats",
+            Command::Send { .. } => "/send",
+            Command::Accept(_) => "/accept",
+            Command::Reject(_) => "/reject",
+            Command::Compress(_) => "/compress",
+            Command::Locale(_) => "/locale",
+            Command::Remind { .. } => "/remind",
+            Command::Msg { .. } => "/msg",
+            Command::Topic(_) => "/topic",
+            Command::Moderate { change, .. } => change.command(),
+            Command::Moderators => "/mod",
+            Command::Once(_) => "/once",
+            Command::Begin => "/begin",
+        }
+    }
+
+    /// Run the command for the peer `name` of `side`, on `conn` from
+    /// `addr`, in `partition`.
+    pub fn run(
+        &self,
+        state: &State,
+        side: Side,
+        name: &str,
+        conn: ConnId,
+        addr: SocketAddr,
+        partition: Option<usize>,
+    ) -> Reply {
+        match self {
+            Command::Who => {
+                let mut reply = Vec::new();
+                for &side in &[Side::C, Side::Go] {
+                    // Only the peers that can be talked to.
+                    let mut names = Vec::new();
+                    state.side(side).for_each(|_, member| {
+                        if partition.is_none() || member.partition == partition {
+                            names.push(member.name.clone());
+                        }
+                    });
+                    names.sort();
+                    let line = Message::new("who_side")
+                        .with("side", side)
+                        .with("names", names.join(", "));
+                    reply.push(line);
+                }
+                for (server, peers) in state.roster.remote(state) {
+                    for &side in &[Side::C, Side::Go] {
+                        let names: Vec<&str> = peers
+                            .iter()
+                            .filter(|(s, _)| *s == side)
+                            .map(|(_, name)| name.as_str())
+                            .collect();
+                        let line = Message::new("who_server")
+                            .with("side", side)
+                            .with("server", &server)
+                            .with("names", names.join(", "));
+                        reply.push(line);
+                    }
+                }
+                Reply::Lines(reply)
+            }
+            Command::History(count) => match state.newest(*count) {
+                Some(ids) => Reply::Transcript(Transcript { ids }),
+                None => Reply::Lines(vec![Message::new("history_empty")]),
+            },
+            Command::Search(text) => {
+                let mut found: Vec<Message> = state
+                    .history(None, None)
+                    .iter()
+                    .rev()
+                    .filter(|m| m.body.to_lowercase().contains(text.as_str()))
+                    .take(DEFAULT_MESSAGES)
+                    .map(format_message)
+                    .collect();
+                if found.is_empty() {
+                    let none = Message::new("search_empty").with("text", format!("{:?}", text));
+                    return Reply::Lines(vec![none]);
+                }
+                found.reverse();
+                Reply::Lines(found)
+            }
+            Command::Quota => Reply::Lines(match &state.quotas {
+                Some(quotas) => {
+                    let (used, limit) = quotas.usage(side, name);
+                    vec![Message::new("quota_usage")
+                        .with("used", used)
+                        .with("limit", limit)]
+                }
+                None => vec![Message::new("quota_none")],
+            }),
+            Command::Stats => {
+                let peers = state.traffic();
+                let mut reply = vec![Message::new("stats_peers").with("peers", peers.len())];
+                for peer in peers.iter().take(STATS_PEERS) {
+                    let t = &peer.traffic;
+                    let line = Message::new("stats_peer")
+                        .with("name", &peer.name)
+                        .with("side", peer.side)
+                        .with("in_rate", human_bytes(t.ingress_rate))
+                        .with("out_rate", human_bytes(t.egress_rate))
+                        .with("in_total", human_bytes(t.ingress_total as f64))
+                        .with("out_total", human_bytes(t.egress_total as f64));
+                    reply.push(line);
+                }
+                for &side in &[Side::C, Side::Go] {
+                    let latency = state.metrics.message_latency(side);
+                    let quantiles: Vec<String> = QUANTILES
+                        .iter()
+                        .map(|&q| {
+                            let p = (q * 100.0).round();
+                            format!("p{} {}", p, human_duration(latency.quantile(q)))
+                        })
+                        .collect();
+                    let line = Message::new("stats_latency")
+                        .with("side", side)
+                        .with("quantiles", quantiles.join(" "))
+                        .with("delivered", latency.count());
+                    reply.push(line);
+                }
+                Reply::Lines(reply)
+            }
+            Command::Send {
+                peer,
+                file,
+                size,
+                port,
+            } => {
+                let from = Party {
+                    side,
+                    conn,
+                    addr,
+                    name: name.to_string(),
+                };
+                let reply = transfer::offer(state, from, partition, peer, file, *size, *port);
+                Reply::Lines(vec![reply])
+            }
+            Command::Accept(id) => Reply::Lines(vec![transfer::accept(state, *id, &conn)]),
+            Command::Reject(id) => Reply::Lines(vec![transfer::reject(state, *id, &conn)]),
+            Command::Compress(None) => Reply::Lines(vec![
+                Message::new("compress_codecs").with("codecs", state.codecs.names().join(", "))
+            ]),
+            Command::Compress(Some(codec)) if codec == "none" => Reply::Compress(None),
+            Command::Compress(Some(codec)) => match state.codecs.get(codec) {
+                Some(codec) => Reply::Compress(Some(codec)),
+                None => Reply::Lines(vec![Message::new("compress_unknown")
+                    .with("codec", codec)
+                    .with("codecs", state.codecs.names().join(", "))]),
+            },
+            Command::Locale(None) => Reply::Lines(vec![
+                Message::new("locale_list").with("locales", state.catalog.tags())
+            ]),
+            Command::Locale(Some(tag)) => match state.catalog.find(tag) {
+                Some(locale) => Reply::Locale(locale),
+                None => Reply::Lines(vec![Message::new("locale_unknown")
+                    .with("locale", tag)
+                    .with("locales", state.catalog.tags())]),
+            },
+            Command::Remind { delay, to, text } => Reply::Lines(vec![state
+                .reminders
+                .add(side, name, partition, *delay, to, text)]),
+            Command::Msg { to, text } => {
+                Reply::Lines(vec![bridge::send(state, name, partition, to, text)])
+            }
+            Command::Topic(None) => Reply::Lines(vec![match state.moderation.topic() {
+                Some(topic) => Message::new("topic_is")
+                    .with("topic", topic.text)
+                    .with("by", topic.by),
+                None => Message::new("topic_none"),
+            }]),
+            Command::Topic(Some(text)) => Reply::Lines(
+                moderation::set_topic(state, name, text)
+                    .into_iter()
+                    .collect(),
+            ),
+            Command::Moderate { change, name: peer } => {
+                Reply::Lines(vec![moderation::change(state, name, *change, peer)])
+            }
+            Command::Moderators => {
+                let (moderators, banned) = state.moderation.list();
+                Reply::Lines(vec![Message::new("moderation_list")
+                    .with("moderators", moderators.join(", "))
+                    .with("banned", banned.join(", "))])
+            }
+            Command::Once(_) if state.exactly_once.is_none() => {
+                Reply::Lines(vec![Message::new("once_off")])
+            }
+            Command::Once(last) => Reply::Once(*last),
+            Command::Begin => Reply::Begin,
+        }
+    }
+}
+
+/// What a command replies.
+pub enum Reply {
+    Lines(Vec<Message>),
+    /// Too many lines to buffer at once, taken a page at a time.
+    Transcript(Transcript),
+    /// Compress the connection from here on, `None` for `none`.
+    Compress(Option<Arc<dyn Codec>>),
+    /// Tell the peer things in this locale of `State::catalog` from here on.
+    Locale(usize),
+    /// Deliver exactly once from here on, after the message with this id.
+    Once(Option<u64>),
+    /// Hold the messages of the peer back until `/commit`.
+    Begin,
+}
+
+/// Messages of the history being sent to a peer. Messages that drop out of
+/// the history before their page is taken are left out.
+#[derive(Debug)]
+pub struct Transcript {
+    /// Ids of the messages not sent yet.
+    ids: Range<u64>,
+}
+
+impl Transcript {
+    /// The lines of the next page, `None` once all were taken.
+    pub fn next_page(&mut self, state: &State) -> Option<Vec<Message>> {
+        let page = state.history_range(&self.ids, TRANSCRIPT_PAGE);
+        self.ids.start = page.last()?.id + 1;
+        Some(page.iter().map(format_message).collect())
+    }
+}
+
+fn format_message(m: &StoredMessage) -> Message {
+    Message::new("history_message")
+        .with("id", m.id)
+        .with("name", &m.name)
+        .with("side", m.side)
+        .with("body", &m.body)
+}
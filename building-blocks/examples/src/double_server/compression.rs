@@ -0,0 +1,266 @@
+//! Compression of the connections of peers, negotiated by each of them.
+//!
+//! ```text
+//! /compress            the codecs the server offers, preferred first
+//! /compress <codec>    compress the rest of the connection with codec
+//! ```
+//!
+//! The server offers the codecs of `Config::compression`, and `none`.
+//! After `/compress gzip` and its line break, everything the peer sends is
+//! gzip compressed. The server answers `* compressing with gzip` and
+//! compresses everything it sends after that line. A connection is
+//! compressed once, with the same codec both ways, until it closes.
+//!
+//! Every write is flushed by the codec, so that a line can be decompressed
+//! as soon as it arrives instead of waiting for more to fill a block.
+//!
+//! A few bytes may decompress to gigabytes. What a peer sends decompresses
+//! to at most `Config::max_unframed_bytes` at a time, waiting to be taken
+//! as lines, or the connection fails like one with a line too long.
+//!
+//! A codec is a `Codec`, which makes the encoder and the decoder of each
+//! connection. Another algorithm is one more in `builtin`.
+
+use bytes::BytesMut;
+#[cfg(feature = "compression")]
+use flate2::Compression;
+
+use std::io;
+#[cfg(feature = "compression")]
+use std::io::Write;
+use std::sync::Arc;
+
+/// An algorithm peers may pick.
+pub trait Codec: Send + Sync {
+    /// What peers call it in `/compress`.
+    fn name(&self) -> &'static str;
+    /// Compresses what the server sends on one connection.
+    fn encoder(&self) -> Box<dyn Encoder>;
+    /// Decompresses what one peer sends, failing once one `decode` makes
+    /// more than `max` bytes.
+    fn decoder(&self, max: usize) -> Box<dyn Decoder>;
+}
+
+/// The compressing end of one connection.
+pub trait Encoder: Send {
+    /// Compress `input` onto `out`, flushed so that the other end can
+    /// decompress all of it.
+    fn encode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()>;
+}
+
+/// The decompressing end of one connection.
+pub trait Decoder: Send {
+    /// Decompress `input`, the next bytes of the stream, onto `out`.
+    fn decode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()>;
+}
+
+/// The codecs offered, in the order of `Config::compression`.
+pub struct Codecs {
+    offered: Vec<Arc<dyn Codec>>,
+}
+
+impl Codecs {
+    /// The codecs called `names`, failing on one that is not known.
+    pub fn new(names: &[String]) -> Result<Codecs, String> {
+        let known = builtin();
+        let offered = names
+            .iter()
+            .map(|name| {
+                known
+                    .iter()
+                    .find(|codec| codec.name() == name)
+                    .cloned()
+                    .ok_or_else(|| match name.as_str() {
+                        "zstd" | "gzip" => format!("{} needs the compression feature", name),
+                        _ => format!("unknown compression codec {}", name),
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Codecs { offered })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Codec>> {
+        self.offered
+            .iter()
+            .find(|codec| codec.name() == name)
+            .cloned()
+    }
+
+    /// The names to offer peers, `none` last.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.offered.iter().map(|codec| codec.name()).collect();
+        names.push("none");
+        names
+    }
+}
+
+/// Every codec the server implements, none without the `compression`
+/// feature.
+pub fn builtin() -> Vec<Arc<dyn Codec>> {
+    #[cfg(feature = "compression")]
+    return vec![Arc::new(Zstd), Arc::new(Gzip)];
+    #[cfg(not(feature = "compression"))]
+    Vec::new()
+}
+
+/// Move what a streaming encoder or decoder wrote to its `Vec` onto `out`.
+#[cfg(feature = "compression")]
+fn drain(written: &mut Vec<u8>, out: &mut BytesMut) {
+    out.extend_from_slice(written);
+    written.clear();
+}
+
+/// What a streaming decoder writes to, which takes at most `max` bytes
+/// until drained. The decoders decompress into buffers of their own, of a
+/// few KB, and write them out as they fill up, so nothing bigger is made.
+#[cfg(feature = "compression")]
+struct Capped {
+    written: Vec<u8>,
+    max: usize,
+}
+
+#[cfg(feature = "compression")]
+impl Capped {
+    fn new(max: usize) -> Capped {
+        Capped {
+            written: Vec::new(),
+            max,
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.len() + buf.len() > self.max {
+            let e = format!("decompresses to more than {} bytes", self.max);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+struct Gzip;
+
+#[cfg(feature = "compression")]
+impl Codec for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn encoder(&self) -> Box<dyn Encoder> {
+        // Chat lines are short, speed matters more than the last few bytes.
+        let level = Compression::fast();
+        Box::new(flate2::write::GzEncoder::new(Vec::new(), level))
+    }
+
+    fn decoder(&self, max: usize) -> Box<dyn Decoder> {
+        Box::new(flate2::write::GzDecoder::new(Capped::new(max)))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Encoder for flate2::write::GzEncoder<Vec<u8>> {
+    fn encode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.write_all(input)?;
+        // A sync flush, the stream goes on.
+        self.flush()?;
+        drain(self.get_mut(), out);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Decoder for flate2::write::GzDecoder<Capped> {
+    fn decode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.write_all(input)?;
+        self.flush()?;
+        drain(&mut self.get_mut().written, out);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+struct Zstd;
+
+#[cfg(feature = "compression")]
+impl Codec for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn encoder(&self) -> Box<dyn Encoder> {
+        let encoder = zstd::stream::write::Encoder::new(Vec::new(), 1)
+            .expect("a zstd context for a valid level");
+        Box::new(encoder)
+    }
+
+    fn decoder(&self, max: usize) -> Box<dyn Decoder> {
+        let decoder = zstd::stream::write::Decoder::new(Capped::new(max))
+            .expect("a zstd decompression context");
+        Box::new(decoder)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Encoder for zstd::stream::write::Encoder<'static, Vec<u8>> {
+    fn encode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.write_all(input)?;
+        // Ends the block, not the frame.
+        self.flush()?;
+        drain(self.get_mut(), out);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Decoder for zstd::stream::write::Decoder<'static, Capped> {
+    fn decode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.write_all(input)?;
+        self.flush()?;
+        drain(&mut self.get_mut().written, out);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    fn compressed(codec: &dyn Codec, plain: &[u8]) -> BytesMut {
+        let mut out = BytesMut::new();
+        codec.encoder().encode(plain, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn lines_are_decompressed() {
+        for codec in builtin() {
+            let lines = compressed(&*codec, b"alice\r\nhi\r\n");
+            let mut plain = BytesMut::new();
+            codec.decoder(64).decode(&lines, &mut plain).unwrap();
+            assert_eq!(&plain[..], &b"alice\r\nhi\r\n"[..], "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn bombs_fail_before_decompressing() {
+        for codec in builtin() {
+            let bomb = compressed(&*codec, &vec![0; 16 * 1024 * 1024]);
+            assert!(bomb.len() < 64 * 1024, "{}", codec.name());
+            let mut plain = BytesMut::new();
+            let e = codec
+                .decoder(256 * 1024)
+                .decode(&bomb, &mut plain)
+                .unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", codec.name());
+            assert!(plain.is_empty());
+        }
+    }
+}
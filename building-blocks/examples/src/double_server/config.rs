@@ -0,0 +1,1709 @@
+//! Server configuration.
+//!
+//! The two listen addresses can still be given as the first two positional
+//! arguments, like before. Everything else lives in an optional JSON file
+//! passed with `--config <path>`:
+//!
+//! ```json
+//! {
+//!     "c_listen": "127.0.0.1:8081",
+//!     "go_listen": "127.0.0.1:8080",
+//!     "dial": [{ "side": "go", "addr": "chat.example.org:8080", "name": "upstream", "bridge": true }],
+//!     "bridge": { "id": "eu-1", "max_hops": 4, "ttl": 16, "sync_secs": 30, "causal": ["go"] },
+//!     "http_listen": "127.0.0.1:9000",
+//!     "grpc_listen": "127.0.0.1:50051",
+//!     "transfer_listen": "127.0.0.1:8082",
+//!     "replication_listen": "127.0.0.1:8090",
+//!     "single_port": { "listen": "0.0.0.0:8000", "side": "go" },
+//!     "tls": {
+//!         "listen": "0.0.0.0:8443",
+//!         "http_listen": "0.0.0.0:9443",
+//!         "cert": "chain.pem",
+//!         "key": "key.pem"
+//!     },
+//!     "backfill": { "from": "chat-1.example.org:8090", "messages": 1000 },
+//!     "transfers": { "max_bytes": 10485760, "offer_secs": 60 },
+//!     "attachments": { "max_bytes": 65536, "per_minute_bytes": 524288 },
+//!     "compression": ["gzip"],
+//!     "filters": ["emoji"],
+//!     "matrix": {
+//!         "homeserver": "http://127.0.0.1:8008",
+//!         "server_name": "example.org",
+//!         "as_token": "...",
+//!         "hs_token": "...",
+//!         "room_id": "!abcdef:example.org"
+//!     },
+//!     "incoming_webhooks": [
+//!         { "token": "...", "username": "ci" }
+//!     ],
+//!     "outgoing_webhooks": [
+//!         { "url": "http://127.0.0.1:5000/chat", "keywords": ["deploy"], "retry_jitter": 0.2 }
+//!     ],
+//!     "mqtt": { "broker": "127.0.0.1:1883" },
+//!     "kafka": { "broker": "127.0.0.1:9092", "topic": "chat", "max_reconnect_delay_ms": 30000 },
+//!     "nats": { "server": "127.0.0.1:4222", "name": "eu-1" },
+//!     "membership": { "listen": "0.0.0.0:7946", "seeds": ["chat-2.example.org:7946"] },
+//!     "statsd": { "server": "127.0.0.1:8125", "prefix": "chat.eu-1" },
+//!     "circuit_breaker": { "failures": 5, "open_secs": 30 },
+//!     "rate_limits": {
+//!         "messages": { "per_second": 2, "burst": 10 },
+//!         "global": { "per_second": 200, "burst": 400 },
+//!         "admission": "reject",
+//!         "commands": { "/history": { "per_second": 0.1, "burst": 2 } }
+//!     },
+//!     "quotas": { "daily_bytes": 1000000, "path": "/var/lib/double_server/quotas.json" },
+//!     "exactly_once": { "path": "/var/lib/double_server/once.log", "ids_per_peer": 1024 },
+//!     "drain": {
+//!         "deadline_secs": 60,
+//!         "linger_secs": 2,
+//!         "retry_after_secs": 300,
+//!         "new_connections": "close"
+//!     },
+//!     "admin_token": "...",
+//!     "reuseport": true,
+//!     "instances": 4,
+//!     "instance_state": "partitioned",
+//!     "listen_backlog": 1024,
+//!     "accepts_per_tick": 32,
+//!     "lines_per_tick": 50,
+//!     "reads_per_tick": 4,
+//!     "read_buffer": { "initial_bytes": 512, "max_bytes": 65536, "growth_factor": 4 },
+//!     "max_unframed_bytes": 262144,
+//!     "max_message_bytes": 4194304,
+//!     "peer_shards": 64,
+//!     "write_policy": { "c": "latency", "go": "throughput" },
+//!     "encoding": { "go": "msgpack" },
+//!     "flush_delay_ms": 2,
+//!     "heartbeat": { "idle_secs": 60, "timeout_secs": 20 },
+//!     "watchdog": { "interval_ms": 1000, "max_timer_skew_ms": 200, "max_lock_wait_ms": 50 },
+//!     "access": { "allow": ["10.0.0.0/8"], "deny": ["10.66.0.0/16"] },
+//!     "names": { "max_chars": 24, "allow": ["letters", "digits"], "reject_confusable": true },
+//!     "moderation": { "moderators": ["alice"] },
+//!     "locales": { "default": "de", "catalogs": { "de": "locales/de.json" } },
+//!     "announcements": { "join": "-> {name} joined the {side} side", "leave": "<- {name} left" },
+//!     "reminders": { "path": "/var/lib/double_server/reminders.json", "max_per_peer": 20, "durability": "async" },
+//!     "scheduled": [{ "cron": "45 8 * * 1-5", "text": "stand-up in 15 minutes", "sides": ["go"] }],
+//!     "geoip": { "country_db": "GeoLite2-Country.mmdb", "asn_db": "GeoLite2-ASN.mmdb" },
+//!     "audit": { "path": "/var/log/double_server/audit.log", "durability": "sync" },
+//!     "snapshot": { "path": "/var/lib/double_server/snapshot.json", "every_secs": 300 },
+//!     "wal": { "path": "/var/lib/double_server/wal", "fsync": "interval", "fsync_interval_ms": 100 },
+//!     "sentry": { "dsn": "https://key@sentry.example.org/42", "environment": "production" },
+//!     "log_format": "json",
+//!     "log_sample_every": 1000
+//! }
+//! ```
+//!
+//! `--reuseport`, `--instances <n>` and `--log-format <format>` override
+//! what the file says.
+//!
+//! Addresses may name hosts, see `resolve`.
+
+use building_blocks::breaker::Breaker;
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::access::Cidr;
+use crate::compression::{self, Codecs};
+use crate::durability::Durability;
+use crate::encoding::Encoding;
+use crate::filter::FilterKind;
+use crate::logging::LogFormat;
+use crate::names::CharClass;
+use crate::resolve::{self, HostPort};
+use crate::state::Side;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address the c side listens on.
+    #[serde(deserialize_with = "resolve::listen")]
+    pub c_listen: SocketAddr,
+
+    /// Address the go side listens on.
+    #[serde(deserialize_with = "resolve::listen")]
+    pub go_listen: SocketAddr,
+
+    /// Services the server connects to as peers, see `dial`.
+    pub dial: Vec<DialConfig>,
+
+    /// This server among the servers bridged to it, see `bridge`.
+    pub bridge: BridgeConfig,
+
+    /// Address of the HTTP gateway used by the integrations. Clients
+    /// knowing it speaks HTTP/2 may start with it, see `gateway`.
+    ///
+    /// The gateway is only started when this or `tls.http_listen` is set.
+    #[serde(deserialize_with = "resolve::listen_opt")]
+    pub http_listen: Option<SocketAddr>,
+
+    /// Address of the gRPC API, see `proto/double_server.proto`.
+    #[serde(deserialize_with = "resolve::listen_opt")]
+    pub grpc_listen: Option<SocketAddr>,
+
+    /// Address peers relay files through, see `transfer`. Without it, files
+    /// can only be sent over connections the server brokers.
+    #[serde(deserialize_with = "resolve::listen_opt")]
+    pub transfer_listen: Option<SocketAddr>,
+
+    /// Address read replicas follow the history from, see `replica`.
+    #[serde(deserialize_with = "resolve::listen_opt")]
+    pub replication_listen: Option<SocketAddr>,
+
+    /// One more port, taking every kind of connection the server serves,
+    /// told apart by what they send first, see `sniff`. Not listened on
+    /// when absent.
+    pub single_port: Option<SinglePortConfig>,
+
+    /// Where to serve TLS, with the protocol and the side picked by ALPN,
+    /// see `tls`. Needs the `tls` feature.
+    pub tls: Option<TlsConfig>,
+
+    /// Only serve reads, of the history of a primary, see `replica`.
+    pub replica: Option<ReplicaConfig>,
+
+    /// Take in the history and moderation of another server of the bridge
+    /// before serving, see `backfill`.
+    pub backfill: Option<BackfillConfig>,
+
+    /// Limits of the files peers send each other.
+    pub transfers: TransferConfig,
+
+    /// Limits of the attachments of messages, see `attachment`.
+    pub attachments: AttachmentConfig,
+
+    /// Codecs peers may compress their connection with, preferred first,
+    /// see `compression`. `none` is always offered.
+    pub compression: Vec<String>,
+
+    /// Matrix application service bridge, disabled when absent.
+    pub matrix: Option<MatrixConfig>,
+
+    /// Slack compatible incoming webhooks served by the HTTP gateway.
+    pub incoming_webhooks: Vec<IncomingWebhook>,
+
+    /// HTTP endpoints notified about chat events.
+    pub outgoing_webhooks: Vec<OutgoingWebhook>,
+
+    /// MQTT bridge, disabled when absent.
+    pub mqtt: Option<MqttConfig>,
+
+    /// Kafka sink for every message, disabled when absent.
+    pub kafka: Option<KafkaConfig>,
+
+    /// Fan-out to other servers through NATS, disabled when absent.
+    pub nats: Option<NatsConfig>,
+
+    /// Which servers of the cluster are up, see `membership`. Not tracked
+    /// when absent.
+    pub membership: Option<MembershipConfig>,
+
+    /// Where to push the metrics over StatsD, see `statsd`. Not pushed when
+    /// absent.
+    pub statsd: Option<StatsdConfig>,
+
+    /// When the outgoing webhooks and Kafka stop being called after
+    /// failing, see `building_blocks::breaker`.
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// How fast each peer may send messages and commands.
+    pub rate_limits: RateLimits,
+
+    /// Daily quotas of the bytes each peer may relay, unlimited when absent.
+    pub quotas: Option<QuotaConfig>,
+
+    /// Where the ids of the messages of exactly-once peers are kept, see
+    /// `once`. Peers cannot ask for it when absent.
+    pub exactly_once: Option<ExactlyOnceConfig>,
+
+    /// How the server shuts down, see `drain`.
+    pub drain: DrainConfig,
+
+    /// Token the admin endpoints of the HTTP gateway require, as
+    /// `Authorization: Bearer <token>`. They are not mounted when absent.
+    pub admin_token: Option<String>,
+
+    /// Bind the chat listeners with `SO_REUSEPORT`, so that other sockets
+    /// and processes can listen on the same addresses. The kernel spreads
+    /// the connections over all of them.
+    pub reuseport: bool,
+
+    /// Listeners per side, all on the same address. More than one needs
+    /// `reuseport`.
+    pub instances: usize,
+
+    /// Whether the peers of different instances talk to each other.
+    pub instance_state: InstanceState,
+
+    /// Connections the kernel queues for every listener until they are
+    /// accepted. It caps the value at `net.core.somaxconn`.
+    pub listen_backlog: u32,
+
+    /// Most connections a chat or gRPC listener accepts before letting other
+    /// tasks run.
+    pub accepts_per_tick: usize,
+
+    /// Most lines a peer handles in each direction before letting other
+    /// tasks run: taken off its channel to write them out, and read from its
+    /// socket to relay them. Small servers do fine with few, busy ones waste
+    /// less time switching tasks with more.
+    pub lines_per_tick: usize,
+
+    /// Most reads from its socket a peer does before letting other tasks
+    /// run. A read takes what fits into the free space of the read buffer,
+    /// see `read_buffer`.
+    pub reads_per_tick: usize,
+
+    /// How much room the read buffer of a peer makes for every read.
+    pub read_buffer: ReadBufferConfig,
+
+    /// Most bytes a peer may send without a line break. A peer going over
+    /// is disconnected, so that it cannot make the server buffer without
+    /// bound. Longer messages are split into frames, see `frames`.
+    pub max_unframed_bytes: usize,
+
+    /// Most bytes of a message split into frames. Longer ones are dropped.
+    pub max_message_bytes: usize,
+
+    /// Shards the peers of each side are split into, see `state::Peers`.
+    /// Peers joining and leaving only wait for those of the same shard.
+    pub peer_shards: usize,
+
+    /// Whether the peers of each side favor latency or throughput.
+    pub write_policy: WritePolicies,
+
+    /// What the peers of each side speak instead of lines, see `encoding`.
+    pub encoding: Encodings,
+
+    /// How long messages to a peer wait for more before they are written,
+    /// so that a burst goes out with one write instead of one per message.
+    /// Every message is delayed by up to this much. Lines of the server
+    /// itself are never delayed. Only for the sides with the `throughput`
+    /// write policy, 0 writes right away.
+    pub flush_delay_ms: u64,
+
+    /// When idle connections are probed, see `heartbeat`.
+    pub heartbeat: HeartbeatConfig,
+
+    /// Which networks may connect, see `access`.
+    pub access: AccessConfig,
+
+    /// What peers may call themselves, see `names`.
+    pub names: NameRules,
+
+    /// Who may set the topic and ban peers, see `moderation`.
+    pub moderation: ModerationConfig,
+
+    /// Translations of what the server tells peers, see `locale`.
+    pub locales: LocaleConfig,
+
+    /// What peers are told when others come and go, see `locale`.
+    pub announcements: Announcements,
+
+    /// Announcements made on a schedule, see `schedule`.
+    pub scheduled: Vec<ScheduledConfig>,
+
+    /// What peers may leave for later with `/remind`, see `remind`.
+    pub reminders: RemindersConfig,
+
+    /// What rewrites messages before they are relayed, in order, see
+    /// `filter`. None by default.
+    pub filters: Vec<FilterKind>,
+
+    /// When the server counts as degraded, see `watchdog`.
+    pub watchdog: WatchdogConfig,
+
+    /// Where to look up the location of peers, see `geoip`. Needs the
+    /// `geoip` feature.
+    pub geoip: Option<GeoIpConfig>,
+
+    /// Where privileged actions are recorded, see `audit`. Only logged to
+    /// stdout when absent.
+    pub audit: Option<AuditConfig>,
+
+    /// Where `POST /admin/snapshot` saves the state, and where it is
+    /// restored from at startup, see `snapshot`. The endpoint answers 404
+    /// when absent.
+    pub snapshot: Option<SnapshotConfig>,
+
+    /// Where the history is written ahead to, see `journal`. It only lives
+    /// in memory when absent, or until the next `snapshot`.
+    pub wal: Option<WalConfig>,
+
+    /// Where unexpected failures are reported, see `reporting`. Needs the
+    /// `sentry-reports` feature.
+    pub sentry: Option<SentryConfig>,
+
+    /// `text` or `json`, see `logging`.
+    pub log_format: LogFormat,
+
+    /// Log one in this many messages, 1 logs all of them, see `logging`.
+    pub log_sample_every: u64,
+
+    /// The file given with `--config`, which SIGHUP reads the access lists
+    /// from again.
+    #[serde(skip)]
+    pub config_path: Option<PathBuf>,
+
+    /// Set by `--matrix-registration <path>`: write the registration file
+    /// for the homeserver to `path` and exit instead of serving.
+    #[serde(skip)]
+    pub matrix_registration: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct MatrixConfig {
+    /// Base URL of the homeserver's client-server API.
+    pub homeserver: String,
+
+    /// Server name used in user IDs, the part after the colon.
+    pub server_name: String,
+
+    /// Token the bridge authenticates with towards the homeserver.
+    pub as_token: String,
+
+    /// Token the homeserver authenticates with towards the bridge.
+    pub hs_token: String,
+
+    /// Room the conversation is mirrored into.
+    pub room_id: String,
+
+    /// Localpart prefix of the puppets. With the default, alice on the c
+    /// side shows up as `@chat_c_alice:<server_name>`.
+    #[serde(default = "default_user_prefix")]
+    pub user_prefix: String,
+
+    /// Localpart of the bridge's own user.
+    #[serde(default = "default_sender_localpart")]
+    pub sender_localpart: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct IncomingWebhook {
+    /// Secret part of the hook URL, `/hooks/<token>`.
+    pub token: String,
+
+    /// Name the posted lines are attributed to.
+    #[serde(default = "default_webhook_username")]
+    pub username: String,
+
+    /// Whether the `username` of a payload overrides the configured one.
+    #[serde(default = "default_true")]
+    pub allow_username: bool,
+
+    /// Only deliver to the peers of this side instead of both.
+    #[serde(default)]
+    pub side: Option<Side>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct OutgoingWebhook {
+    /// Endpoint the JSON payloads are POSTed to.
+    pub url: String,
+
+    /// Kinds of events delivered, only messages by default.
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<EventKind>,
+
+    /// Only deliver events of peers on this side.
+    #[serde(default)]
+    pub side: Option<Side>,
+
+    /// Only deliver events of the peer with this name.
+    #[serde(default)]
+    pub sender: Option<String>,
+
+    /// Only deliver messages containing one of these words, compared case
+    /// insensitively. Empty means every message.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+
+    /// Attempts made before a delivery is given up.
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, doubled after every further failure.
+    #[serde(default = "default_webhook_retry_delay")]
+    pub retry_delay_ms: u64,
+
+    /// Longest delay between two attempts.
+    #[serde(default = "default_max_retry_delay")]
+    pub max_retry_delay_ms: u64,
+
+    /// Fraction of every delay, from 0 to 1, cut off at random so that the
+    /// retries of many deliveries spread out. None by default.
+    #[serde(default)]
+    pub retry_jitter: f64,
+}
+
+/// The kinds of `ChatEvent` an outgoing webhook can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Join,
+    Message,
+    Leave,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    /// Address of the broker.
+    pub broker: HostPort,
+
+    /// Client identifier presented to the broker.
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+
+    /// Prefix of every topic the bridge publishes or subscribes to.
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+
+    /// Quality of service of publishes and the subscription, 0 or 1.
+    #[serde(default)]
+    pub qos: u8,
+
+    /// Keep alive interval in seconds, 0 disables it.
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u16,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicaConfig {
+    /// The `replication_listen` address of the primary.
+    pub primary: HostPort,
+
+    /// Wait before following again once the connection dropped, doubled
+    /// after every further failure in a row.
+    #[serde(default = "default_dial_reconnect_delay")]
+    pub reconnect_delay_ms: u64,
+
+    /// Longest wait before following again.
+    #[serde(default = "default_max_retry_delay")]
+    pub max_reconnect_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackfillConfig {
+    /// The `replication_listen` address of the server to take it from.
+    pub from: HostPort,
+
+    /// Most messages of its history to take.
+    #[serde(default = "default_backfill_messages")]
+    pub messages: usize,
+
+    /// Wait before asking again once the transfer was cut off, doubled
+    /// after every further failure in a row.
+    #[serde(default = "default_dial_reconnect_delay")]
+    pub reconnect_delay_ms: u64,
+
+    /// Longest wait before asking again.
+    #[serde(default = "default_max_retry_delay")]
+    pub max_reconnect_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DialConfig {
+    /// Side the service is a peer of.
+    pub side: Side,
+
+    /// Address of the service.
+    pub addr: HostPort,
+
+    /// Name the service goes by among the peers.
+    pub name: String,
+
+    /// Line written to the service right after connecting, none by default.
+    #[serde(default)]
+    pub login: Option<String>,
+
+    /// Whether the service is another server of the bridge, see `bridge`.
+    /// Its login then has the id of this server in front, and is the id
+    /// without one.
+    #[serde(default)]
+    pub bridge: bool,
+
+    /// Wait before dialing again, doubled after every further failure in a
+    /// row.
+    #[serde(default = "default_dial_reconnect_delay")]
+    pub reconnect_delay_ms: u64,
+
+    /// Longest wait before dialing again.
+    #[serde(default = "default_max_retry_delay")]
+    pub max_reconnect_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BridgeConfig {
+    /// Id of this server among the bridged, made up at startup when absent.
+    pub id: Option<String>,
+
+    /// Servers a message may pass before it is dropped.
+    pub max_hops: usize,
+
+    /// Servers a message sent to this server may pass, whatever the others
+    /// have for `max_hops`.
+    pub ttl: u32,
+
+    /// How often the links compare who is connected where, see `roster`.
+    pub sync_secs: u64,
+
+    /// Sides the messages of other servers are relayed to in the order
+    /// they were written in, see `causal`.
+    pub causal: Vec<Side>,
+
+    /// Longest a message of another server is held back for the messages
+    /// it came after.
+    pub causal_wait_ms: u64,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        BridgeConfig {
+            id: None,
+            max_hops: 8,
+            ttl: 16,
+            sync_secs: 30,
+            causal: Vec::new(),
+            causal_wait_ms: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KafkaConfig {
+    /// Address of the broker leading `partition` of `topic`.
+    pub broker: HostPort,
+
+    /// Topic the messages are produced to.
+    pub topic: String,
+
+    #[serde(default)]
+    pub partition: i32,
+
+    /// Client identifier presented to the broker.
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+
+    /// Acknowledgements the broker waits for: 1 for the leader only, -1 for
+    /// all in-sync replicas.
+    #[serde(default = "default_kafka_acks")]
+    pub acks: i16,
+
+    /// Most messages sent in one produce request.
+    #[serde(default = "default_kafka_batch_size")]
+    pub batch_size: usize,
+
+    /// How long a message may wait for others to fill its batch.
+    #[serde(default = "default_kafka_linger")]
+    pub linger_ms: u64,
+
+    /// Attempts made before a batch is given up.
+    #[serde(default = "default_kafka_attempts")]
+    pub max_attempts: u32,
+
+    /// Wait before reconnecting to the broker, doubled after every further
+    /// failure in a row.
+    #[serde(default = "default_kafka_reconnect_delay")]
+    pub reconnect_delay_ms: u64,
+
+    /// Longest wait before reconnecting.
+    #[serde(default = "default_max_retry_delay")]
+    pub max_reconnect_delay_ms: u64,
+
+    /// Fraction of every wait, from 0 to 1, cut off at random. None by
+    /// default.
+    #[serde(default)]
+    pub reconnect_jitter: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Failures in a row after which an integration is not called, 0 to
+    /// always call it.
+    pub failures: u32,
+
+    /// How long it is not called before a call is tried again.
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failures: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    /// A breaker for one integration.
+    pub fn breaker(&self) -> Breaker {
+        Breaker::new(self.failures, Duration::from_secs(self.open_secs))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatsConfig {
+    /// Address of a server of the cluster.
+    pub server: HostPort,
+
+    /// Name of this server, unique within the cluster.
+    pub name: String,
+
+    /// Prefix of the subjects the servers publish to.
+    #[serde(default = "default_nats_subject")]
+    pub subject: String,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinglePortConfig {
+    /// Address to listen on, besides those of the sides.
+    #[serde(deserialize_with = "resolve::listen")]
+    pub listen: SocketAddr,
+
+    /// The side of peers that do not pick one with a `SIDE` line.
+    #[serde(default = "default_single_port_side")]
+    pub side: Side,
+
+    /// How long to wait for the first bytes of a connection. One that
+    /// sends nothing in time is taken for a peer of `side`.
+    #[serde(default = "default_sniff_ms")]
+    pub sniff_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// Address of the TLS listener. TLS is only served on the
+    /// `single_port` when absent.
+    #[serde(default, deserialize_with = "resolve::listen_opt")]
+    pub listen: Option<SocketAddr>,
+
+    /// Address of the HTTP gateway over TLS, speaking HTTP/2 with the
+    /// clients asking for it with ALPN, see `gateway`.
+    #[serde(default, deserialize_with = "resolve::listen_opt")]
+    pub http_listen: Option<SocketAddr>,
+
+    /// The certificate chain, PEM encoded, the server's own first.
+    pub cert: PathBuf,
+
+    /// The private key, PEM encoded.
+    pub key: PathBuf,
+
+    /// The side of peers whose client asks for no side with ALPN.
+    #[serde(default = "default_tls_side")]
+    pub side: Side,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MembershipConfig {
+    /// UDP address the servers of the cluster talk to this one on.
+    #[serde(deserialize_with = "resolve::listen")]
+    pub listen: SocketAddr,
+
+    /// Any of the other servers, to join the cluster through.
+    #[serde(default)]
+    pub seeds: Vec<HostPort>,
+
+    /// Name of this server, unique within the cluster. The bridge id when
+    /// absent, see `bridge`.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// How often another server is pinged.
+    #[serde(default = "default_membership_period_ms")]
+    pub period_ms: u64,
+
+    /// How long to wait for an ack before asking others to ping.
+    #[serde(default = "default_ping_timeout_ms")]
+    pub ping_timeout_ms: u64,
+
+    /// How many others to ask.
+    #[serde(default = "default_indirect_probes")]
+    pub indirect_probes: usize,
+
+    /// How long a server that stopped answering has to come back before it
+    /// is taken for dead.
+    #[serde(default = "default_suspect_ms")]
+    pub suspect_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdConfig {
+    /// Address of the agent, which listens on UDP.
+    pub server: HostPort,
+
+    /// Put in front of every name, with a dot.
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+
+    /// How often the metrics are pushed.
+    #[serde(default = "default_statsd_interval_secs")]
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Limit of the messages of a peer, unlimited when absent.
+    pub messages: Option<Rate>,
+
+    /// Limits of commands by name, like `"/history"`, independent of the
+    /// message limit and of each other.
+    pub commands: HashMap<String, Rate>,
+
+    /// Limit of the commands without an entry in `commands`.
+    pub default_command: Rate,
+
+    /// Limit of the messages of all peers together, unlimited when absent.
+    ///
+    /// Keeps a spike on one side from flooding the peers and integrations
+    /// consuming the other side.
+    pub global: Option<Rate>,
+
+    /// What happens to a message over the `global` limit.
+    pub admission: Admission,
+}
+
+/// How a message over the global limit is handled. Either way the peer gets
+/// a notice starting with `SLOWDOWN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Admission {
+    /// Hold the message back, and stop reading from its peer, until it may
+    /// be relayed.
+    Delay,
+    /// Drop the message.
+    Reject,
+}
+
+/// A token bucket: up to `burst` at once, `per_second` on average.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimits {
+    /// Reject buckets that never refill or never hold a token, whatever is
+    /// limited by them would wait forever.
+    fn validate(&self) -> Result<(), String> {
+        let named = self
+            .messages
+            .iter()
+            .map(|rate| ("rate_limits.messages".to_string(), rate))
+            .chain(self.global.iter().map(|rate| ("rate_limits.global".to_string(), rate)))
+            .chain(Some(("rate_limits.default_command".to_string(), &self.default_command)))
+            .chain(
+                self.commands
+                    .iter()
+                    .map(|(command, rate)| (format!("rate_limits.commands.{}", command), rate)),
+            );
+        for (name, rate) in named {
+            if !(rate.per_second > 0.0) || !rate.per_second.is_finite() {
+                return Err(format!("{}.per_second must be more than 0", name));
+            }
+            if rate.burst == 0 {
+                return Err(format!("{}.burst must be at least 1", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            messages: None,
+            commands: HashMap::new(),
+            // Commands walk the peers or the history, so allow a few in a
+            // row but not more than one every five seconds on average.
+            default_command: Rate {
+                per_second: 0.2,
+                burst: 3,
+            },
+            global: None,
+            admission: Admission::Delay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaConfig {
+    /// Bytes of messages each peer may send per day (UTC).
+    pub daily_bytes: u64,
+
+    /// File the usage is saved to, so that it survives restarts.
+    #[serde(default = "default_quota_path")]
+    pub path: PathBuf,
+
+    /// Whether a save waits for the disk, `async` unless set, see
+    /// `durability`.
+    #[serde(default = "default_async")]
+    pub durability: Durability,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExactlyOnceConfig {
+    /// File the ids of the messages relayed are written to, see `once`.
+    pub path: PathBuf,
+
+    /// Ids of the last messages kept for each peer, to be acknowledged
+    /// again rather than relayed when sent again.
+    #[serde(default = "default_once_ids_per_peer")]
+    pub ids_per_peer: usize,
+
+    /// Peers ids are kept for. Those who did not send a message the
+    /// longest are forgotten first.
+    #[serde(default = "default_once_max_peers")]
+    pub max_peers: usize,
+
+    /// Whether a write waits for the disk, `sync` unless set, see
+    /// `durability`.
+    #[serde(default = "default_sync")]
+    pub durability: Durability,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "geoip"), allow(dead_code))]
+pub struct GeoIpConfig {
+    /// A MaxMind country (or city) database.
+    pub country_db: Option<PathBuf>,
+    /// A MaxMind ASN database.
+    pub asn_db: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NameRules {
+    /// Fewest characters of a name.
+    pub min_chars: usize,
+
+    /// Most characters of a name.
+    pub max_chars: usize,
+
+    /// What names may be made of.
+    pub allow: Vec<CharClass>,
+
+    /// Turn away names that look like the name of a connected peer.
+    pub reject_confusable: bool,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules {
+            min_chars: 1,
+            max_chars: 32,
+            allow: vec![
+                CharClass::Letters,
+                CharClass::Digits,
+                CharClass::Punctuation,
+            ],
+            reject_confusable: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    /// Names of the peers that are moderators whatever the others make
+    /// moderators or no longer, on this server.
+    pub moderators: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LocaleConfig {
+    /// The locale of peers that did not pick one, `en` or one of
+    /// `catalogs`.
+    pub default: String,
+
+    /// The file translating the messages into each locale. English is
+    /// built in.
+    pub catalogs: HashMap<String, PathBuf>,
+}
+
+impl Default for LocaleConfig {
+    fn default() -> Self {
+        LocaleConfig {
+            default: "en".to_string(),
+            catalogs: HashMap::new(),
+        }
+    }
+}
+
+/// Templates of the notices to every peer of the partition (both sides)
+/// when a peer joins or leaves, with the placeholders `{name}` and `{side}`.
+/// None are sent without a template.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Announcements {
+    pub join: Option<String>,
+    pub leave: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledConfig {
+    /// When, like `0 9 * * 1-5`, in UTC.
+    pub cron: String,
+
+    /// What is announced, one line.
+    pub text: String,
+
+    /// Who it is announced to, both sides by default.
+    #[serde(default = "default_scheduled_sides")]
+    pub sides: Vec<Side>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RemindersConfig {
+    /// File the reminders are saved to, so that they survive restarts. They
+    /// are only kept in memory when absent.
+    pub path: Option<PathBuf>,
+
+    /// Reminders a peer may have waiting at once, 0 to turn `/remind` off.
+    pub max_per_peer: usize,
+
+    /// How far ahead a reminder may be.
+    pub max_delay_secs: u64,
+
+    /// Whether a save waits for the disk, see `durability`.
+    pub durability: Durability,
+}
+
+impl Default for RemindersConfig {
+    fn default() -> Self {
+        RemindersConfig {
+            path: None,
+            max_per_peer: 10,
+            max_delay_secs: 7 * 24 * 60 * 60,
+            durability: Durability::Async,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// How often the server checks itself.
+    pub interval_ms: u64,
+
+    /// How late a check may run before the runtime counts as unresponsive.
+    pub max_timer_skew_ms: u64,
+
+    /// Bytes that may wait for peers before they count as backed up, see
+    /// `Stats::queued_outbound_bytes`.
+    pub max_queued_bytes: u64,
+
+    /// How long taking a lock of the shared state may take before it counts
+    /// as contended.
+    pub max_lock_wait_ms: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            interval_ms: 1000,
+            max_timer_skew_ms: 200,
+            max_queued_bytes: 256 * 1024 * 1024,
+            max_lock_wait_ms: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    /// The file the actions are appended to.
+    pub path: PathBuf,
+
+    /// Whether a write waits for the disk, `sync` unless set, see
+    /// `durability`.
+    #[serde(default = "default_sync")]
+    pub durability: Durability,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnapshotConfig {
+    /// The file the snapshot is written to, replaced by every one.
+    pub path: PathBuf,
+
+    /// Also write one every this many seconds, with no change since the
+    /// last one skipped. Only when asked if 0, the default.
+    #[serde(default)]
+    pub every_secs: u64,
+
+    /// Whether a write waits for the disk, `sync` unless set, see
+    /// `durability`.
+    #[serde(default = "default_sync")]
+    pub durability: Durability,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WalConfig {
+    /// The directory of the log.
+    pub path: PathBuf,
+
+    /// When what is written is synced to the disk.
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+
+    /// How often at most with `interval`.
+    #[serde(default = "default_fsync_interval_ms")]
+    pub fsync_interval_ms: u64,
+}
+
+/// When the log is synced to the disk, see `journal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// After every write.
+    Always,
+    /// Every `fsync_interval_ms` at most.
+    Interval,
+    /// Whenever the OS does.
+    Never,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::Always
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "sentry-reports"), allow(dead_code))]
+pub struct SentryConfig {
+    /// Where the project of the server takes events.
+    pub dsn: String,
+
+    /// Like `production`, to tell deployments apart.
+    #[serde(default)]
+    pub environment: Option<String>,
+}
+
+/// Most listeners per side.
+pub const MAX_INSTANCES: usize = 64;
+
+/// How the peers of several instances are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceState {
+    /// Like a single instance, every peer talks to every other.
+    Shared,
+    /// Peers only talk to the peers that connected through the same
+    /// instance, and only see them with `/who`. History, quotas, limits and
+    /// the integrations remain server wide.
+    Partitioned,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DrainConfig {
+    /// How long peers get to disconnect before the server closes their
+    /// connections.
+    pub deadline_secs: u64,
+
+    /// How long peers get to close their half of the connection after the
+    /// deadline before the server exits anyway, see `drain`.
+    pub linger_secs: u64,
+
+    /// When peers are told to reconnect after the server shut down, see
+    /// `drain::goodbye`. After a restart they may right away.
+    pub retry_after_secs: u64,
+
+    /// What happens to connections made while draining.
+    pub new_connections: NewConnections,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct WritePolicies {
+    pub c: WritePolicy,
+    pub go: WritePolicy,
+}
+
+impl WritePolicies {
+    pub fn of(&self, side: Side) -> WritePolicy {
+        match side {
+            Side::C => self.c,
+            Side::Go => self.go,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct Encodings {
+    pub c: Option<Encoding>,
+    pub go: Option<Encoding>,
+}
+
+impl Encodings {
+    pub fn of(&self, side: Side) -> Option<Encoding> {
+        match side {
+            Side::C => self.c,
+            Side::Go => self.go,
+        }
+    }
+}
+
+/// How the writes to a peer are traded off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WritePolicy {
+    /// Set `TCP_NODELAY` and write every message right away, for
+    /// interactive programs.
+    Latency,
+    /// Leave Nagle's algorithm on and batch messages over
+    /// `flush_delay_ms`, for programs sending in bulk.
+    Throughput,
+}
+
+impl Default for WritePolicy {
+    fn default() -> Self {
+        WritePolicy::Throughput
+    }
+}
+
+/// The room made in the read buffer of a peer for the next read adapts to
+/// the connection. It starts at `initial_bytes`, and grows by
+/// `growth_factor` whenever a read filled it, up to `max_bytes`. Once the
+/// peer has nothing more to read, the buffer shrinks back to what the lines
+/// of the peer were recently.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReadBufferConfig {
+    pub initial_bytes: usize,
+    pub max_bytes: usize,
+    pub growth_factor: f64,
+    /// Free buffers grown bigger than the recent lines need while a peer
+    /// is idle.
+    pub shrink_when_idle: bool,
+}
+
+impl Default for ReadBufferConfig {
+    fn default() -> Self {
+        ReadBufferConfig {
+            initial_bytes: 1024,
+            max_bytes: 64 * 1024,
+            growth_factor: 2.0,
+            shrink_when_idle: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// How long a connection may go without traffic before it is probed,
+    /// 0 never probes.
+    pub idle_secs: u64,
+    /// How long a probe, or anything else written, may take to get through
+    /// before the peer is taken for gone.
+    pub timeout_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            idle_secs: 30,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// The ranges connections may come from, any if empty.
+    pub allow: Vec<Cidr>,
+    /// The ranges connections may not come from, even if allowed.
+    pub deny: Vec<Cidr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// Largest file that may be offered.
+    pub max_bytes: u64,
+    /// How long an offer may wait to be accepted, and then for both peers
+    /// to connect to the transfer listener.
+    pub offer_secs: u64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            max_bytes: 100 * 1024 * 1024,
+            offer_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AttachmentConfig {
+    /// Largest attachment, decoded.
+    pub max_bytes: u64,
+    /// Most bytes of attachments a peer may send per minute, decoded.
+    pub per_minute_bytes: u64,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            max_bytes: 256 * 1024,
+            per_minute_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// How the listeners behave while the server drains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NewConnections {
+    /// Accept, send a notice that the server is shutting down, and close.
+    Refuse,
+    /// Stop listening, so that clients get connection refused.
+    Close,
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        DrainConfig {
+            deadline_secs: 30,
+            linger_secs: 5,
+            retry_after_secs: 60,
+            new_connections: NewConnections::Refuse,
+        }
+    }
+}
+
+fn default_sync() -> Durability {
+    Durability::Sync
+}
+
+fn default_async() -> Durability {
+    Durability::Async
+}
+
+fn default_quota_path() -> PathBuf {
+    PathBuf::from("quotas.json")
+}
+
+fn default_fsync_interval_ms() -> u64 {
+    100
+}
+
+fn default_once_ids_per_peer() -> usize {
+    1024
+}
+
+fn default_once_max_peers() -> usize {
+    10_000
+}
+
+fn default_nats_subject() -> String {
+    "double_server".to_string()
+}
+
+fn default_single_port_side() -> Side {
+    Side::Go
+}
+
+fn default_sniff_ms() -> u64 {
+    500
+}
+
+fn default_tls_side() -> Side {
+    Side::Go
+}
+
+fn default_membership_period_ms() -> u64 {
+    1000
+}
+
+fn default_ping_timeout_ms() -> u64 {
+    300
+}
+
+fn default_indirect_probes() -> usize {
+    3
+}
+
+fn default_suspect_ms() -> u64 {
+    5000
+}
+
+fn default_statsd_prefix() -> String {
+    "double_server".to_string()
+}
+
+fn default_statsd_interval_secs() -> u64 {
+    10
+}
+
+fn default_scheduled_sides() -> Vec<Side> {
+    vec![Side::C, Side::Go]
+}
+
+fn default_kafka_acks() -> i16 {
+    1
+}
+
+fn default_kafka_batch_size() -> usize {
+    100
+}
+
+fn default_kafka_linger() -> u64 {
+    100
+}
+
+fn default_kafka_attempts() -> u32 {
+    5
+}
+
+fn default_kafka_reconnect_delay() -> u64 {
+    5000
+}
+
+fn default_client_id() -> String {
+    "double_server".to_string()
+}
+
+fn default_mqtt_topic() -> String {
+    "chat".to_string()
+}
+
+fn default_mqtt_keep_alive() -> u16 {
+    30
+}
+
+fn default_webhook_events() -> Vec<EventKind> {
+    vec![EventKind::Message]
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_retry_delay() -> u64 {
+    500
+}
+
+fn default_dial_reconnect_delay() -> u64 {
+    1000
+}
+
+fn default_max_retry_delay() -> u64 {
+    60_000
+}
+
+fn default_backfill_messages() -> usize {
+    1000
+}
+
+fn default_user_prefix() -> String {
+    "chat_".to_string()
+}
+
+fn default_sender_localpart() -> String {
+    "chatbridge".to_string()
+}
+
+fn default_webhook_username() -> String {
+    "webhook".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            c_listen: "127.0.0.1:8081".parse().unwrap(),
+            go_listen: "127.0.0.1:8080".parse().unwrap(),
+            dial: Vec::new(),
+            bridge: BridgeConfig::default(),
+            http_listen: None,
+            grpc_listen: None,
+            transfer_listen: None,
+            replication_listen: None,
+            single_port: None,
+            tls: None,
+            replica: None,
+            backfill: None,
+            transfers: TransferConfig::default(),
+            attachments: AttachmentConfig::default(),
+            compression: compression::builtin()
+                .iter()
+                .map(|codec| codec.name().to_string())
+                .collect(),
+            matrix: None,
+            incoming_webhooks: Vec::new(),
+            outgoing_webhooks: Vec::new(),
+            mqtt: None,
+            kafka: None,
+            nats: None,
+            membership: None,
+            statsd: None,
+            circuit_breaker: CircuitBreakerConfig::default(),
+            rate_limits: RateLimits::default(),
+            quotas: None,
+            exactly_once: None,
+            drain: DrainConfig::default(),
+            admin_token: None,
+            reuseport: false,
+            instances: 1,
+            instance_state: InstanceState::Shared,
+            // What std listens with.
+            listen_backlog: 128,
+            accepts_per_tick: 64,
+            lines_per_tick: 10,
+            reads_per_tick: 16,
+            read_buffer: ReadBufferConfig::default(),
+            max_unframed_bytes: 1024 * 1024,
+            max_message_bytes: 16 * 1024 * 1024,
+            peer_shards: 16,
+            write_policy: WritePolicies::default(),
+            encoding: Encodings::default(),
+            flush_delay_ms: 0,
+            heartbeat: HeartbeatConfig::default(),
+            access: AccessConfig::default(),
+            names: NameRules::default(),
+            moderation: ModerationConfig::default(),
+            locales: LocaleConfig::default(),
+            announcements: Announcements::default(),
+            scheduled: Vec::new(),
+            reminders: RemindersConfig::default(),
+            filters: Vec::new(),
+            watchdog: WatchdogConfig::default(),
+            geoip: None,
+            audit: None,
+            snapshot: None,
+            wal: None,
+            sentry: None,
+            log_format: LogFormat::Text,
+            log_sample_every: 100,
+            config_path: None,
+            matrix_registration: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read a configuration file.
+    pub fn from_file(path: &str) -> Result<Config, Box<dyn Error>> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let config = serde_json::from_reader(file).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(config)
+    }
+
+    /// What this asks of a part the build was made without, see the
+    /// features in `Cargo.toml`.
+    fn missing_feature(&self) -> Option<String> {
+        let encoded = |encoding: Option<Encoding>| {
+            encoding.map_or(false, |encoding| encoding != Encoding::Json)
+        };
+        let needs = [
+            (
+                "http_listen",
+                "gateway",
+                self.http_listen.is_some(),
+                cfg!(feature = "gateway"),
+            ),
+            (
+                "matrix",
+                "gateway",
+                self.matrix.is_some() || self.matrix_registration.is_some(),
+                cfg!(feature = "gateway"),
+            ),
+            (
+                "outgoing_webhooks",
+                "gateway",
+                !self.outgoing_webhooks.is_empty(),
+                cfg!(feature = "gateway"),
+            ),
+            (
+                "tls",
+                "tls",
+                self.tls.is_some(),
+                cfg!(feature = "tls"),
+            ),
+            (
+                "grpc_listen",
+                "grpc",
+                self.grpc_listen.is_some(),
+                cfg!(feature = "grpc"),
+            ),
+            (
+                "encoding",
+                "encodings",
+                encoded(self.encoding.c) || encoded(self.encoding.go),
+                cfg!(feature = "encodings"),
+            ),
+        ];
+        needs
+            .iter()
+            .find(|&&(_, _, asked, built)| asked && !built)
+            .map(|(what, feature, _, _)| format!("{} needs the {} feature", what, feature))
+    }
+
+    /// Build the configuration from the command line.
+    ///
+    /// Positional arguments override whatever the config file says, no
+    /// matter in which order they are given.
+    pub fn from_args() -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        let mut positional = Vec::new();
+        let mut config_path = None;
+        let mut matrix_registration = None;
+        let mut reuseport = false;
+        let mut instances = None;
+        let mut log_format = None;
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let path = args.next().ok_or("--config needs a path")?;
+                    config = Config::from_file(&path)?;
+                    config_path = Some(PathBuf::from(path));
+                }
+                "--matrix-registration" => {
+                    let path = args.next().ok_or("--matrix-registration needs a path")?;
+                    matrix_registration = Some(PathBuf::from(path));
+                }
+                "--reuseport" => reuseport = true,
+                "--instances" => {
+                    let n = args.next().ok_or("--instances needs a number")?;
+                    instances = Some(n.parse()?);
+                }
+                "--log-format" => {
+                    let format = args.next().ok_or("--log-format needs text or json")?;
+                    log_format = Some(format.parse()?);
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        if let Some(addr) = positional.get(0) {
+            config.c_listen = resolve::listen_addr(addr)?;
+        }
+        if let Some(addr) = positional.get(1) {
+            config.go_listen = resolve::listen_addr(addr)?;
+        }
+        config.config_path = config_path;
+        config.matrix_registration = matrix_registration;
+        config.reuseport |= reuseport;
+        if let Some(instances) = instances {
+            config.instances = instances;
+        }
+        if let Some(log_format) = log_format {
+            config.log_format = log_format;
+        }
+
+        if config.instances == 0 || config.instances > MAX_INSTANCES {
+            return Err(format!("instances must be between 1 and {}", MAX_INSTANCES).into());
+        }
+        if config.instances > 1 && !config.reuseport {
+            return Err("more than one instance needs --reuseport".into());
+        }
+        if config.accepts_per_tick == 0 {
+            return Err("accepts_per_tick must be at least 1".into());
+        }
+        if config.lines_per_tick == 0 {
+            return Err("lines_per_tick must be at least 1".into());
+        }
+        if config.reads_per_tick == 0 {
+            return Err("reads_per_tick must be at least 1".into());
+        }
+        let read_buffer = &config.read_buffer;
+        if read_buffer.initial_bytes == 0 {
+            return Err("read_buffer.initial_bytes must be at least 1".into());
+        }
+        if read_buffer.max_bytes < read_buffer.initial_bytes {
+            return Err("read_buffer.max_bytes must be at least initial_bytes".into());
+        }
+        if read_buffer.growth_factor < 1.0 {
+            return Err("read_buffer.growth_factor must be at least 1".into());
+        }
+        if config.max_unframed_bytes < 2 {
+            return Err("max_unframed_bytes must be at least 2".into());
+        }
+        if config.max_message_bytes == 0 {
+            return Err("max_message_bytes must be at least 1".into());
+        }
+        if config.attachments.max_bytes > config.attachments.per_minute_bytes {
+            return Err("attachments.max_bytes must be at most per_minute_bytes".into());
+        }
+        Codecs::new(&config.compression)?;
+        if let Some(e) = config.missing_feature() {
+            return Err(e.into());
+        }
+        if config.transfers.offer_secs == 0 {
+            return Err("transfers.offer_secs must be at least 1".into());
+        }
+        if config.heartbeat.timeout_secs == 0 {
+            return Err("heartbeat.timeout_secs must be at least 1".into());
+        }
+        if config.names.min_chars == 0 {
+            return Err("names.min_chars must be at least 1".into());
+        }
+        if config.names.max_chars < config.names.min_chars {
+            return Err("names.max_chars must be at least min_chars".into());
+        }
+        if config.watchdog.interval_ms == 0 {
+            return Err("watchdog.interval_ms must be at least 1".into());
+        }
+        if let Some(statsd) = &config.statsd {
+            if statsd.interval_secs == 0 {
+                return Err("statsd.interval_secs must be at least 1".into());
+            }
+        }
+        if config.log_sample_every == 0 {
+            return Err("log_sample_every must be at least 1".into());
+        }
+        let drain = Duration::from_secs(config.drain.deadline_secs)
+            .checked_add(Duration::from_secs(config.drain.linger_secs));
+        if drain.and_then(|drain| Instant::now().checked_add(drain)).is_none() {
+            return Err("drain.deadline_secs and linger_secs are too long".into());
+        }
+        config.rate_limits.validate()?;
+        if config.peer_shards == 0 {
+            return Err("peer_shards must be at least 1".into());
+        }
+        if let Some(tls) = &config.tls {
+            let serves = tls.listen.is_some() || tls.http_listen.is_some();
+            if !serves && config.single_port.is_none() {
+                return Err("tls needs a listen address or a single_port".into());
+            }
+        }
+        Ok(config)
+    }
+}
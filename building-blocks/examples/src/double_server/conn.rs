@@ -0,0 +1,261 @@
+//! The connections of the peers, from the first byte to the last.
+//!
+//! Every connection gets a `ConnId` once it is accepted or dialed: the
+//! generation of the process, counted up by every restart (see `restart`),
+//! and a number counted up within it. Ids are never reused, not even by the
+//! process taking over from this one while both still serve.
+//!
+//! A connection goes through these phases, never back:
+//!
+//! ```text
+//! handshaking   accepted, until it has a name       see `cap`
+//! active        a peer, sent to and read from
+//! draining      closing, writing what is left       see `drain`
+//! closed        gone, no longer listed
+//! ```
+//!
+//! `/metrics` counts the connections in every phase, and
+//! `GET /admin/connections` lists them, see `admin`. Broadcasts and the
+//! bridge only go to active connections, those draining get nothing new.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::metrics::Counter;
+use crate::state::Side;
+
+/// Environment variable with the generation of a restarted process.
+pub const GENERATION_ENV: &str = "DOUBLE_SERVER_GENERATION";
+
+/// A connection of a peer, unique for as long as the server runs.
+///
+/// Peers are told apart by it rather than by their address: two clients
+/// behind one NAT may come from the same, and so may a client reconnecting
+/// before its old connection is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnId {
+    generation: u64,
+    index: u64,
+}
+
+impl fmt::Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.generation, self.index)
+    }
+}
+
+/// Where a connection is in its life, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Handshaking,
+    Active,
+    Draining,
+    Closed,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [
+        Phase::Handshaking,
+        Phase::Active,
+        Phase::Draining,
+        Phase::Closed,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Handshaking => "handshaking",
+            Phase::Active => "active",
+            Phase::Draining => "draining",
+            Phase::Closed => "closed",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The phase of one connection, read without locking, like by the
+/// broadcasts. Cloning is cheap, every clone sees the same phase.
+#[derive(Clone)]
+pub struct Lifecycle(Arc<AtomicU8>);
+
+impl Lifecycle {
+    pub fn phase(&self) -> Phase {
+        Phase::ALL[self.0.load(Ordering::Acquire) as usize]
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.phase() == Phase::Active
+    }
+
+    /// Move on to `phase`, unless the connection is there or past it
+    /// already. Whether it moved.
+    fn enter(&self, phase: Phase) -> bool {
+        self.0.fetch_max(phase as u8, Ordering::AcqRel) < phase as u8
+    }
+}
+
+/// A connection as `GET /admin/connections` lists it.
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct Listed {
+    pub id: ConnId,
+    pub side: Side,
+    pub addr: SocketAddr,
+    pub phase: Phase,
+    /// When the connection was accepted.
+    pub opened: Instant,
+    /// When it entered `phase`.
+    pub since: Instant,
+}
+
+struct Entry {
+    side: Side,
+    addr: SocketAddr,
+    lifecycle: Lifecycle,
+    opened: Instant,
+    since: Instant,
+}
+
+/// Every connection not closed yet, and the ids of the next.
+pub struct Connections {
+    generation: u64,
+    next_index: AtomicU64,
+    live: Mutex<HashMap<ConnId, Entry>>,
+    /// Connections that closed since the server started.
+    pub closed: Counter,
+}
+
+impl Connections {
+    /// The connections of a process of `generation`, see `generation`.
+    pub fn new(generation: u64) -> Connections {
+        Connections {
+            generation,
+            next_index: AtomicU64::new(1),
+            live: Mutex::new(HashMap::new()),
+            closed: Counter::default(),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Allocate the id of a new connection of a peer of `side` at `addr`,
+    /// and list it as handshaking until the returned handle is dropped.
+    pub fn open(self: &Arc<Self>, side: Side, addr: SocketAddr) -> Handle {
+        let id = ConnId {
+            generation: self.generation,
+            index: self.next_index.fetch_add(1, Ordering::Relaxed),
+        };
+        let lifecycle = Lifecycle(Arc::new(AtomicU8::new(Phase::Handshaking as u8)));
+        let now = Instant::now();
+        let entry = Entry {
+            side,
+            addr,
+            lifecycle: lifecycle.clone(),
+            opened: now,
+            since: now,
+        };
+        self.live.lock().unwrap().insert(id, entry);
+        Handle {
+            id,
+            lifecycle,
+            connections: self.clone(),
+        }
+    }
+
+    fn enter(&self, id: &ConnId, lifecycle: &Lifecycle, phase: Phase) {
+        let mut live = self.live.lock().unwrap();
+        if !lifecycle.enter(phase) {
+            return;
+        }
+        if phase == Phase::Closed {
+            live.remove(id);
+            self.closed.add(1);
+        } else if let Some(entry) = live.get_mut(id) {
+            entry.since = Instant::now();
+        }
+    }
+
+    /// How many connections are in each phase but `Closed`.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn counts(&self) -> Vec<(Phase, usize)> {
+        let live = self.live.lock().unwrap();
+        Phase::ALL
+            .iter()
+            .filter(|&&phase| phase != Phase::Closed)
+            .map(|&phase| {
+                let n = live
+                    .values()
+                    .filter(|entry| entry.lifecycle.phase() == phase)
+                    .count();
+                (phase, n)
+            })
+            .collect()
+    }
+
+    /// Every connection not closed yet, oldest first.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn list(&self) -> Vec<Listed> {
+        let live = self.live.lock().unwrap();
+        let mut listed: Vec<Listed> = live
+            .iter()
+            .map(|(&id, entry)| Listed {
+                id,
+                side: entry.side,
+                addr: entry.addr,
+                phase: entry.lifecycle.phase(),
+                opened: entry.opened,
+                since: entry.since,
+            })
+            .collect();
+        listed.sort_by_key(|listed| listed.id);
+        listed
+    }
+}
+
+/// One connection, listed until this is dropped.
+pub struct Handle {
+    id: ConnId,
+    lifecycle: Lifecycle,
+    connections: Arc<Connections>,
+}
+
+impl Handle {
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    /// Move the connection on to `phase`, see `Phase`. Entering a phase it
+    /// is past already does nothing.
+    pub fn enter(&self, phase: Phase) {
+        self.connections.enter(&self.id, &self.lifecycle, phase);
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.enter(Phase::Closed);
+    }
+}
+
+/// The generation of this process: one more than that of the process it
+/// took over from, or 0.
+pub fn generation() -> u64 {
+    env::var(GENERATION_ENV)
+        .ok()
+        .and_then(|generation| generation.parse().ok())
+        .unwrap_or(0)
+}
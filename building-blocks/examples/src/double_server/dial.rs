@@ -0,0 +1,155 @@
+//! Peers the server connects to, rather than waiting for them.
+//!
+//! A service the server can reach but that cannot reach it, across a NAT or
+//! a firewall, can still be one of the peers: the server dials it and keeps
+//! dialing it whenever the connection drops.
+//!
+//! ```json
+//! "dial": [{ "side": "go", "addr": "chat.example.org:8080", "name": "upstream", "login": "eu-bridge" }]
+//! ```
+//!
+//! Once connected, the server writes `login`, if there is one, for a service
+//! that expects the first line of a client to be its name, like this server
+//! does. The service then is a peer of `side` called `name`: what it sends
+//! is relayed like the lines of any other peer, and it is sent what they
+//! send. A service that is another server of a bridge is dialed with
+//! `"bridge": true`, see `bridge`.
+//!
+//! A connection that failed or ended is dialed again after a delay, twice as
+//! long after every failure in a row up to `max_reconnect_delay_ms`. Nothing
+//! is dialed once the server drains. With partitioned instances dialed
+//! peers are in the first one.
+
+use building_blocks::retry::Backoff;
+use bytes::BytesMut;
+use futures::future::{self, Either, Loop};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bridge::Link;
+use crate::cap::Capabilities;
+use crate::config::{Config, DialConfig, InstanceState};
+use crate::logging;
+use crate::names;
+use crate::resolve;
+use crate::state::State;
+use crate::{tune, Arrival, Lines, Peer};
+
+/// Keep the peer of `dial` connected, a future that runs until the server
+/// drains.
+pub fn run(
+    dial: DialConfig,
+    state: State,
+    config: Arc<Config>,
+) -> impl Future<Item = (), Error = ()> {
+    let backoff = Backoff::new(Duration::from_millis(dial.reconnect_delay_ms))
+        .max(Duration::from_millis(dial.max_reconnect_delay_ms));
+    let dial = Arc::new(dial);
+
+    future::loop_fn(0, move |failures: u32| {
+        let (dial, state) = (dial.clone(), state.clone());
+        connect(dial.clone(), state.clone(), config.clone()).then(move |result| {
+            let failures = match result {
+                Ok(()) => {
+                    logging::info!(
+                        "dial_closed", side = dial.side, addr = &dial.addr;
+                        "connection to {} closed", dial.addr
+                    );
+                    1
+                }
+                Err(e) => {
+                    logging::warn!(
+                        "dial_failed", side = dial.side, addr = &dial.addr;
+                        "connection to {} error = {:?}", dial.addr, e
+                    );
+                    failures + 1
+                }
+            };
+            if state.drain.is_draining() {
+                return Either::A(future::ok(Loop::Break(())));
+            }
+            let delay = backoff.delay(failures);
+            logging::info!(
+                "dial_reconnecting", side = dial.side, addr = &dial.addr;
+                "dialing {} again in {:?}", dial.addr, delay
+            );
+            // A broken timer only means the next attempt is early.
+            let wait =
+                Delay::new(Instant::now() + delay).then(move |_| Ok(Loop::Continue(failures)));
+            Either::B(wait)
+        })
+    })
+}
+
+/// Connect to the service of `dial` and serve it as a peer until the
+/// connection ends.
+fn connect(
+    dial: Arc<DialConfig>,
+    state: State,
+    config: Arc<Config>,
+) -> impl Future<Item = (), Error = io::Error> {
+    resolve::connect(&dial.addr)
+        .and_then({
+            let state = state.clone();
+            move |socket| {
+                let login = match (&dial.login, dial.bridge) {
+                    (Some(login), false) => format!("{}\r\n", login),
+                    (None, false) => String::new(),
+                    (login, true) => {
+                        let bridges = &state.bridges;
+                        let login = login.as_ref().unwrap_or(&bridges.id);
+                        format!("{}\r\n", bridges.login(login))
+                    }
+                };
+                tokio::io::write_all(socket, login).map(move |(socket, _)| (socket, dial))
+            }
+        })
+        .and_then(move |(socket, dial): (TcpStream, Arc<DialConfig>)| {
+            let addr = socket.peer_addr()?;
+            tune(&socket, dial.side, &config, addr);
+
+            let conn = state.connections.open(dial.side, addr);
+            let id = conn.id();
+            let name = names::check(&config.names, &state, dial.name.as_bytes())
+                .and_then(|normalized| names::claim(&state, normalized, id))
+                .map_err(|e| {
+                    let text = state.catalog.render(state.catalog.default_locale(), &e);
+                    io::Error::new(io::ErrorKind::InvalidInput, text)
+                })?;
+            logging::info!(
+                "dial_connected", side = dial.side, conn = id, addr = addr, name = &name;
+                "`{}` is joining the {} side from {}", name, dial.side, dial.addr
+            );
+
+            let partition = match config.instance_state {
+                InstanceState::Shared => None,
+                InstanceState::Partitioned => Some(0),
+            };
+            let arrival = Arrival {
+                conn,
+                addr,
+                partition,
+                location: state.geoip.locate(addr.ip()),
+                capabilities: Capabilities::default(),
+                link: if dial.bridge {
+                    Some(Link { remote: None })
+                } else {
+                    None
+                },
+            };
+            let mut lines = Lines::new(socket, &config, &state.catalog);
+            // The service does not send a name, the dial has it.
+            lines.connection.named();
+            if dial.bridge {
+                lines.connection.link();
+            }
+            let name = BytesMut::from(name.as_bytes());
+            Ok(Peer::new(name, dial.side, state, lines, &config, arrival))
+        })
+        .flatten()
+}
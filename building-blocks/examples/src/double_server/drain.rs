@@ -0,0 +1,251 @@
+//! Shutting down without cutting the peers off.
+//!
+//! Draining starts on SIGTERM or SIGINT, with `POST /admin/drain` (see
+//! `admin`), or once a new process took the listeners over, see `restart`.
+//! From then on the listeners turn new connections away, see
+//! `NewConnections`, and the peers are counted down to the deadline. At the
+//! deadline, every peer is told why the server closes, and when to come
+//! back, in a line it can act upon:
+//!
+//! ```text
+//! * SERVER_CLOSING restart 0
+//! * SERVER_CLOSING shutdown 60
+//! ```
+//!
+//! The server writes it with what is left for the peer and closes its half
+//! of the connection, then reads on until the peer closed its own, for
+//! at most `linger_secs`. Exiting with data of a peer unread would reset the
+//! connection, and the peer would lose what it did not read yet either.
+//!
+//! The server exits as soon as the last peer left or the linger passed. A
+//! second signal exits right away.
+//!
+//! After a hand over every listener stops accepting instead, so that new
+//! connections go to the new process.
+
+use futures::future::{self, Either, Shared};
+use futures::sync::oneshot;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audit::Action;
+use crate::locale::Message;
+use crate::logging;
+use crate::state::State;
+
+/// How often the peers still connected are counted.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Whether the server is draining, shared by everything that cares.
+#[derive(Clone)]
+pub struct Drain {
+    /// Taken by the call that starts draining.
+    start: Arc<Mutex<Option<oneshot::Sender<Instant>>>>,
+    /// Resolves to the deadline once draining started.
+    started: Shared<oneshot::Receiver<Instant>>,
+    /// Whether draining started because another process took over.
+    handed_over: Arc<AtomicBool>,
+    /// Taken once the deadline passed.
+    close: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    /// Resolves once the peers should close their connections.
+    closing: Shared<oneshot::Receiver<()>>,
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        let (tx, rx) = oneshot::channel();
+        let (close, closing) = oneshot::channel();
+        Drain {
+            start: Arc::new(Mutex::new(Some(tx))),
+            started: rx.shared(),
+            handed_over: Arc::new(AtomicBool::new(false)),
+            close: Arc::new(Mutex::new(Some(close))),
+            closing: closing.shared(),
+        }
+    }
+
+    /// Start draining, giving the peers `deadline` to leave.
+    pub fn start(&self, deadline: Duration) -> Result<(), NotStarted> {
+        self.begin(deadline, false)
+    }
+
+    /// Start draining because another process took the listeners over.
+    pub fn hand_over(&self, deadline: Duration) -> Result<(), NotStarted> {
+        self.begin(deadline, true)
+    }
+
+    fn begin(&self, deadline: Duration, handed_over: bool) -> Result<(), NotStarted> {
+        // Not under the lock, a panic there would poison it.
+        let deadline = Instant::now()
+            .checked_add(deadline)
+            .ok_or(NotStarted::TooFar)?;
+        match self.start.lock().unwrap().take() {
+            Some(tx) => {
+                // Set before anyone learns that draining started.
+                self.handed_over.store(handed_over, Ordering::SeqCst);
+                let _ = tx.send(deadline);
+                Ok(())
+            }
+            None => Err(NotStarted::Draining),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.start.lock().unwrap().is_none()
+    }
+
+    /// Resolves to the deadline once draining started.
+    pub fn started(&self) -> impl Future<Item = Instant, Error = ()> {
+        self.started
+            .clone()
+            .map(|deadline| *deadline)
+            .map_err(|_| ())
+    }
+
+    /// Resolves once another process took the listeners over, never
+    /// otherwise. Listeners stop accepting when it does.
+    pub fn handed_over(&self) -> impl Future<Item = (), Error = ()> {
+        let handed_over = self.handed_over.clone();
+        self.started().and_then(move |_| {
+            if handed_over.load(Ordering::SeqCst) {
+                Either::A(future::ok(()))
+            } else {
+                Either::B(future::empty())
+            }
+        })
+    }
+
+    /// Resolves once the deadline passed, and peers should close their
+    /// connections.
+    pub fn closing(&self) -> impl Future<Item = (), Error = ()> {
+        self.closing.clone().map(|_| ()).map_err(|_| ())
+    }
+
+    fn is_handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::SeqCst)
+    }
+}
+
+/// Why draining did not start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotStarted {
+    /// The server was draining already.
+    Draining,
+    /// The deadline is further away than the clock goes.
+    TooFar,
+}
+
+/// The last line of the server to the peers, see the module documentation.
+/// After a restart, the new process serves already.
+pub fn goodbye(drain: &Drain, retry_after: Duration) -> String {
+    if drain.is_handed_over() {
+        "SERVER_CLOSING restart 0".to_string()
+    } else {
+        format!("SERVER_CLOSING shutdown {}", retry_after.as_secs())
+    }
+}
+
+/// Count the peers down once draining started, and have them close their
+/// connections at the deadline. Resolves when the server should exit, at
+/// most `linger` after that.
+pub fn run(state: State, linger: Duration) -> impl Future<Item = (), Error = ()> {
+    state.drain.started().and_then(move |deadline| {
+        let mut announced = None;
+        Interval::new(Instant::now(), TICK)
+            .map_err(|e| logging::error!("timer_failed"; "drain timer error = {:?}", e))
+            .take_while(move |_| Ok(countdown(&state, deadline, linger, &mut announced)))
+            .for_each(|_| Ok(()))
+    })
+}
+
+/// Tell the peers how long they have left, every ten seconds and every
+/// second of the last five. Returns whether to keep waiting.
+fn countdown(
+    state: &State,
+    deadline: Instant,
+    linger: Duration,
+    announced: &mut Option<u64>,
+) -> bool {
+    let peers = state.peer_count();
+    if peers == 0 {
+        logging::info!("drain_finished"; "all peers left, exiting");
+        return false;
+    }
+    let now = Instant::now();
+    // A deadline this far away is never reached, let alone with the linger.
+    if deadline.checked_add(linger).map_or(false, |end| now >= end) {
+        logging::info!("drain_finished"; "linger passed with {} peers left, exiting", peers);
+        return false;
+    }
+    if now >= deadline {
+        if let Some(close) = state.drain.close.lock().unwrap().take() {
+            logging::info!(
+                "drain_deadline_passed";
+                "drain deadline passed with {} peers left, closing", peers
+            );
+            let _ = close.send(());
+        }
+        return true;
+    }
+
+    let left = deadline - now;
+    let secs = left.as_secs() + if left.subsec_nanos() > 0 { 1 } else { 0 };
+    let due = announced.is_none() || secs % 10 == 0 || secs <= 5;
+    if due && *announced != Some(secs) {
+        *announced = Some(secs);
+        let notice = if state.drain.is_handed_over() {
+            Message::new("drain_restarting")
+        } else {
+            Message::new("drain_shutting_down")
+        };
+        state.announce(None, None, &notice.with("secs", secs));
+    }
+    true
+}
+
+/// Start draining on SIGTERM or SIGINT, and exit on the second one.
+pub fn on_signals(state: State, deadline: Duration) -> impl Future<Item = (), Error = ()> {
+    signals()
+        .map_err(|e| logging::error!("signal_failed"; "signal error = {:?}", e))
+        .for_each(move |()| {
+            match state.drain.start(deadline) {
+                Ok(()) => {
+                    let detail = format!("deadline {}s", deadline.as_secs());
+                    state
+                        .audit
+                        .record("signal", Action::Drain, "server", &detail);
+                    logging::info!("drain_started"; "draining, exiting within {}s", deadline.as_secs());
+                    return Ok(());
+                }
+                // `Config::load` turns such deadlines away.
+                Err(NotStarted::TooFar) => {
+                    logging::error!("drain_failed"; "drain deadline of {}s is too far", deadline.as_secs());
+                }
+                Err(NotStarted::Draining) => {}
+            }
+            state.audit.record("signal", Action::Exit, "server", "");
+            logging::info!("exiting"; "exiting without waiting for the peers");
+            if let Some(quotas) = &state.quotas {
+                if let Err(e) = quotas.save() {
+                    logging::error!("quota_save_failed"; "quota save error = {:?}", e);
+                }
+            }
+            if let Err(e) = state.reminders.save() {
+                logging::error!("reminder_save_failed"; "reminder save error = {:?}", e);
+            }
+            process::exit(1);
+        })
+}
+
+fn signals() -> impl Stream<Item = (), Error = io::Error> {
+    let term = Signal::new(SIGTERM).flatten_stream();
+    let int = Signal::new(SIGINT).flatten_stream();
+    term.select(int).map(|_| ())
+}
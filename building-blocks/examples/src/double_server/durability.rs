@@ -0,0 +1,59 @@
+//! How safe what the server saves is from a crash, against how long saving
+//! it takes, set for each kind of data on its own.
+//!
+//! ```json
+//! "audit": { "path": "/var/log/double_server/audit.log", "durability": "sync" },
+//! "quotas": { "daily_bytes": 1000000, "durability": "async" }
+//! ```
+//!
+//! `sync` has the file synced to the disk before a write counts as done.
+//! `async` leaves it to the OS: writes are quicker, and a crash of the
+//! machine, not one of the server, may lose the last of them. A file that
+//! is replaced in one go is whole either way, the old one or the new one.
+//!
+//! ```text
+//! audit          sync    an action is on disk before it is done
+//! exactly_once   sync    an id is on disk before its message is relayed
+//! snapshot       sync    on disk before the log of the history is cut
+//! quotas         async   saved every few seconds anyway
+//! reminders      async   likewise
+//! ```
+//!
+//! The history has its own `wal.fsync`, see `journal`, as its writes are
+//! grouped.
+
+use serde_derive::Deserialize;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// When a write counts as done, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Once it is on the disk.
+    Sync,
+    /// Once the OS has it.
+    Async,
+}
+
+impl Durability {
+    /// Sync what was written to `file`, if it has to be.
+    pub fn sync(self, file: &File) -> io::Result<()> {
+        match self {
+            Durability::Sync => file.sync_data(),
+            Durability::Async => Ok(()),
+        }
+    }
+}
+
+/// Replace the file at `path` with `bytes` in one go, so that a crash
+/// midway cannot leave a truncated file behind.
+pub fn replace(path: &Path, bytes: &[u8], durability: Durability) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    durability.sync(&file)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,287 @@
+//! Listeners speaking JSON, MessagePack or CBOR rather than lines.
+//!
+//! The peers of a side with an encoding send and are sent a `Frame` for
+//! every message, serialized with serde in that encoding, instead of the
+//! lines of the others. Each side is picked on its own, and lines are the
+//! default:
+//!
+//! ```json
+//! "encoding": { "go": "msgpack" }
+//! ```
+//!
+//! Every frame is its length as 4 bytes, big endian, then the frame. It is
+//! a map with the kind of frame in `type`, here as JSON:
+//!
+//! ```text
+//! {"type":"join","name":"alice"}                 the name, the first frame
+//! {"type":"chat","text":"hi"}                    a message
+//! {"type":"command","text":"/who"}               a command
+//! {"type":"chat","from":"bob","text":"hello"}    a message of another peer
+//! {"type":"chat","id":4711,"from":"bob","text":"hello"}   see `once`
+//! {"type":"notice","text":"c: alice"}            a line of the server
+//! {"type":"line","text":""}                      anything else, see below
+//! ```
+//!
+//! A message is one frame however long it is, or however many lines it
+//! has: `frames` has nothing to split. The server only takes frames of up
+//! to twice `max_message_bytes`, for what the encoding adds, and the
+//! messages in them of up to `max_message_bytes` like any other.
+//!
+//! The encodings sit where `compression` does, under the lines: what a
+//! peer sends is made into the lines it would have sent, and the lines the
+//! server sends are made into frames, so that everything else works the
+//! same for every encoding. The heartbeat probes, and the tags of a link,
+//! see `bridge`, are sent as `line`s. A connection with an encoding is not
+//! compressed as well. Another encoding is one more serializer in
+//! `Encoding::serialize` and `Encoding::deserialize`.
+//!
+//! MessagePack and CBOR need the `encodings` feature, JSON is always there.
+
+use bytes::{BufMut, BytesMut};
+use serde_derive::{Deserialize, Serialize};
+
+use std::io;
+
+use crate::compression::{Decoder, Encoder};
+use crate::frames;
+
+/// Bytes in front of every frame, for its length.
+const LENGTH: usize = 4;
+
+/// A serializer peers of a side may speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+/// Everything peers and the server send each other, in an encoding.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Frame {
+    /// The name of the peer, the first frame it sends.
+    Join { name: String },
+    /// A message, of another peer if it comes `from` one, with the `id` of
+    /// an exactly-once peer in front, see `once`.
+    Chat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+        text: String,
+    },
+    /// A line starting with `/`, see `commands`.
+    Command { text: String },
+    /// A line from the server itself.
+    Notice { text: String },
+    /// A line as it is, like a heartbeat probe.
+    Line { text: String },
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    /// Why the encoding cannot be spoken, without the `encodings` feature.
+    /// `Config` turns it away before a peer would get to speak it.
+    #[cfg(not(feature = "encodings"))]
+    fn unavailable(self) -> String {
+        format!("{} needs the encodings feature", self.name())
+    }
+
+    fn serialize(self, frame: &Frame) -> io::Result<Vec<u8>> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        match self {
+            Encoding::Json => serde_json::to_vec(frame).map_err(|e| invalid(e.to_string())),
+            #[cfg(feature = "encodings")]
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(frame).map_err(|e| invalid(e.to_string()))
+            }
+            #[cfg(feature = "encodings")]
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(frame, &mut bytes)
+                    .map_err(|e| invalid(e.to_string()))?;
+                Ok(bytes)
+            }
+            #[cfg(not(feature = "encodings"))]
+            _ => Err(invalid(self.unavailable())),
+        }
+    }
+
+    fn deserialize(self, bytes: &[u8]) -> io::Result<Frame> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| invalid(e.to_string())),
+            #[cfg(feature = "encodings")]
+            Encoding::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| invalid(e.to_string()))
+            }
+            #[cfg(feature = "encodings")]
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| invalid(e.to_string())),
+            #[cfg(not(feature = "encodings"))]
+            _ => Err(invalid(self.unavailable())),
+        }
+    }
+
+    /// The ends of one connection speaking the encoding, with frames of
+    /// lines of at most `max_frame` bytes, and messages of at most
+    /// `max_message`.
+    pub fn ends(
+        self,
+        max_frame: usize,
+        max_message: usize,
+    ) -> (Box<dyn Encoder>, Box<dyn Decoder>) {
+        let outgoing = Outgoing {
+            encoding: self,
+            line: BytesMut::new(),
+            message: BytesMut::new(),
+        };
+        let incoming = Incoming {
+            encoding: self,
+            raw: BytesMut::new(),
+            joined: false,
+            max_frame,
+            max_bytes: max_message.saturating_mul(2).max(1024),
+        };
+        (Box::new(outgoing), Box::new(incoming))
+    }
+}
+
+/// Makes frames of the lines the server sends.
+struct Outgoing {
+    encoding: Encoding,
+    /// The start of a line whose line break is still to come.
+    line: BytesMut,
+    /// The frames of a message continued in the next one, see `frames`.
+    message: BytesMut,
+}
+
+impl Encoder for Outgoing {
+    fn encode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.line.extend_from_slice(input);
+        while let Some(end) = self.line.windows(2).position(|bytes| bytes == b"\r\n") {
+            let mut frame = self.line.split_to(end + 2);
+            frame.truncate(end);
+            if frame.ends_with(b"\\") {
+                self.message.extend_from_slice(&frame[..end - 1]);
+                continue;
+            }
+            self.message.extend_from_slice(&frame);
+            let line = self.message.take();
+            let bytes = self.encoding.serialize(&outgoing(&line))?;
+            out.reserve(LENGTH + bytes.len());
+            out.put_u32_be(bytes.len() as u32);
+            out.put(bytes);
+        }
+        Ok(())
+    }
+}
+
+/// What the server sends as `line`.
+fn outgoing(line: &[u8]) -> Frame {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    if line.starts_with(b"* ") {
+        return Frame::Notice {
+            text: text(&line[2..]),
+        };
+    }
+    // `#4711 bob: hello` to an exactly-once peer.
+    let (id, rest) = match numbered(line) {
+        Some((id, rest)) => (Some(id), rest),
+        None => (None, line),
+    };
+    match rest.windows(2).position(|bytes| bytes == b": ") {
+        Some(colon) => Frame::Chat {
+            id,
+            from: Some(text(&rest[..colon])),
+            text: text(&rest[colon + 2..]),
+        },
+        None => Frame::Line { text: text(line) },
+    }
+}
+
+/// The id in front of a message to an exactly-once peer, and the rest.
+fn numbered(line: &[u8]) -> Option<(u64, &[u8])> {
+    if !line.starts_with(b"#") {
+        return None;
+    }
+    let space = line.iter().position(|&b| b == b' ')?;
+    let id = std::str::from_utf8(&line[1..space]).ok()?.parse().ok()?;
+    Some((id, &line[space + 1..]))
+}
+
+/// Makes the lines of the frames a peer sends.
+struct Incoming {
+    encoding: Encoding,
+    /// What was read of the frames still incomplete.
+    raw: BytesMut,
+    /// Whether the peer sent its name.
+    joined: bool,
+    /// Most bytes of a line, see `Config::max_unframed_bytes`.
+    max_frame: usize,
+    /// Most bytes of a frame.
+    max_bytes: usize,
+}
+
+impl Decoder for Incoming {
+    fn decode(&mut self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.raw.extend_from_slice(input);
+        while self.raw.len() >= LENGTH {
+            let mut length = [0; LENGTH];
+            length.copy_from_slice(&self.raw[..LENGTH]);
+            let length = u32::from_be_bytes(length) as usize;
+            if length > self.max_bytes {
+                let e = format!("a frame of {} bytes, more than {}", length, self.max_bytes);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            if self.raw.len() < LENGTH + length {
+                break;
+            }
+            let frame = self.raw.split_to(LENGTH + length);
+            let frame = self.encoding.deserialize(&frame[LENGTH..])?;
+            self.incoming(frame, out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Incoming {
+    /// Put the lines a peer sending lines would have sent for `frame` on
+    /// `out`.
+    fn incoming(&mut self, frame: Frame, out: &mut BytesMut) -> io::Result<()> {
+        let text = match (self.joined, frame) {
+            (false, Frame::Join { name }) => {
+                self.joined = true;
+                // A name is a line.
+                let name = name.replace(['\r', '\n'], " ");
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(b"\r\n");
+                return Ok(());
+            }
+            (false, frame) => {
+                let e = format!("{:?} before the name", frame);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            (true, Frame::Chat { text, .. })
+            | (true, Frame::Command { text })
+            | (true, Frame::Line { text }) => text,
+            (true, frame) => {
+                let e = format!("{:?} is not sent to the server", frame);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+        // The lines of the message are joined with `\n` like in a fence.
+        let text = text.replace("\r\n", "\n");
+        out.extend_from_slice(&frames::split(text.as_bytes(), self.max_frame));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Filters rewriting messages before they are relayed.
+//!
+//! ```json
+//! "filters": ["emoji"]
+//! ```
+//!
+//! A `MessageFilter` gets the text of every message of a peer, after the
+//! server took it (its rate limits, the throttle and the quota went by the
+//! message as it was sent) and before it goes to the other side, the
+//! integrations and the history. The filters of `Config::filters` run in
+//! their order, each on what the one before left. Attachments and messages
+//! that are not UTF-8 are relayed as they are.
+//!
+//! Built in are:
+//!
+//! - `emoji`, which turns shortcodes like `:smile:` or `:+1:` into the
+//!   emoji they stand for, as GitHub and Slack name them. Anything else
+//!   between colons, like `10:30:00`, is left alone.
+
+use serde_derive::Deserialize;
+
+use crate::state::Side;
+
+/// Rewrites the messages of peers, see the module docs.
+pub trait MessageFilter: Send + Sync {
+    /// `text` of the peer `name` of `side` as it should be relayed, `None`
+    /// to leave it as it is.
+    fn filter(&self, side: Side, name: &str, text: &str) -> Option<String>;
+}
+
+/// The filters that can be picked in `Config::filters`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    Emoji,
+}
+
+/// The filters of the server, in the order they run.
+pub struct Filters {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl Filters {
+    pub fn new(kinds: &[FilterKind]) -> Filters {
+        let filters = kinds
+            .iter()
+            .map(|kind| match kind {
+                FilterKind::Emoji => Box::new(Emoji) as Box<dyn MessageFilter>,
+            })
+            .collect();
+        Filters { filters }
+    }
+
+    /// `message` of the peer `name` of `side` after every filter, `None`
+    /// if none of them changed it.
+    pub fn apply(&self, side: Side, name: &str, message: &[u8]) -> Option<String> {
+        if self.filters.is_empty() {
+            return None;
+        }
+        let mut text = std::str::from_utf8(message).ok()?.to_string();
+        let mut changed = false;
+        for filter in &self.filters {
+            if let Some(filtered) = filter.filter(side, name, &text) {
+                text = filtered;
+                changed = true;
+            }
+        }
+        if changed {
+            Some(text)
+        } else {
+            None
+        }
+    }
+}
+
+/// Expands emoji shortcodes.
+pub struct Emoji;
+
+impl MessageFilter for Emoji {
+    fn filter(&self, _side: Side, _name: &str, text: &str) -> Option<String> {
+        let is_code = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '+' || c == '-';
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        let mut changed = false;
+        while let Some(open) = rest.find(':') {
+            let after = &rest[open + 1..];
+            let len = after.find(|c: char| !is_code(c)).unwrap_or(after.len());
+            let emoji = Some(&after[..len])
+                .filter(|code| !code.is_empty() && after[len..].starts_with(':'))
+                .and_then(emojis::get_by_shortcode);
+            match emoji {
+                Some(emoji) => {
+                    expanded.push_str(&rest[..open]);
+                    expanded.push_str(emoji.as_str());
+                    rest = &after[len + 1..];
+                    changed = true;
+                }
+                // The colon that did not end a shortcode may start one.
+                None => {
+                    expanded.push_str(&rest[..=open]);
+                    rest = after;
+                }
+            }
+        }
+        if !changed {
+            return None;
+        }
+        expanded.push_str(rest);
+        Some(expanded)
+    }
+}
@@ -0,0 +1,206 @@
+//! Messages longer than a frame, or of several lines.
+//!
+//! A peer may not send more than `max_unframed_bytes` without a line break,
+//! so longer messages are split into frames. Every frame but the last ends
+//! with a backslash, and the message goes on in the next one:
+//!
+//! ```text
+//! Traceback (most recent call last):\
+//!   File "bridge.py", line 12, in <module>\
+//! ...
+//! ```
+//!
+//! The server joins the frames, without the backslashes, and relays the
+//! message once the last one arrived, as long as it stays within
+//! `max_message_bytes`. The other side gets it split the same way if it is
+//! longer than a frame. A message that ends with a backslash itself gets
+//! one more, and an empty frame after it.
+//!
+//! Only peers that know of frames send and get them: clients speaking
+//! version 2 (see `version`), the links of a bridge, and connections with
+//! an `encoding`, which splits and joins them under the lines. A client of
+//! version 1 sends lines that are messages as they are, a backslash at the
+//! end included, and gets every message as one line however long it is.
+//!
+//! Lines between two fences are one message, so that a snippet can be
+//! pasted without relaying every line of it on its own:
+//!
+//! ````text
+//! ```rust
+//! fn main() {}
+//! ```
+//! ````
+//!
+//! The opening fence may name a language, the closing one is the fence
+//! alone. The message keeps the fences and joins the lines with `\n`, so
+//! that it still ends with the only `\r\n` when it is relayed.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// What a frame added to the message being received.
+pub enum Assembled {
+    /// More frames follow.
+    Incomplete,
+    /// The frame was the last one of the message.
+    Complete(BytesMut),
+    /// The message got longer than allowed. It is dropped, and so are the
+    /// frames of it still to come.
+    TooLarge,
+}
+
+/// The fence around a message of several lines.
+const FENCE: &[u8] = b"```";
+
+/// Joins the frames of one peer into messages.
+pub struct Reassembler {
+    /// What was received of the current message.
+    message: BytesMut,
+    /// Where the current line starts in `message`.
+    line_start: usize,
+    /// Whether the last frame was continued.
+    mid_line: bool,
+    /// Whether the current message is fenced.
+    block: bool,
+    /// Whether the current message is being dropped. Only its current line
+    /// is kept then, to find where it ends.
+    dropping: bool,
+    /// Whether a backslash at the end of a frame continues the message.
+    continuation: bool,
+    /// Most bytes of a message.
+    max: usize,
+}
+
+impl Reassembler {
+    pub fn new(max: usize) -> Reassembler {
+        Reassembler {
+            message: BytesMut::new(),
+            line_start: 0,
+            mid_line: false,
+            block: false,
+            dropping: false,
+            continuation: false,
+            max,
+        }
+    }
+
+    /// Join the frames ending with a backslash from here on, for a peer that
+    /// knows of them, see the module docs.
+    pub fn continuation(&mut self) {
+        self.continuation = true;
+    }
+
+    /// Whether frames ending with a backslash continue the message.
+    pub fn continues(&self) -> bool {
+        self.continuation
+    }
+
+    /// Add the next frame, a line without its line break.
+    pub fn push(&mut self, mut frame: BytesMut) -> Assembled {
+        let continued = self.continuation && frame.ends_with(b"\\");
+        if continued {
+            let len = frame.len() - 1;
+            frame.truncate(len);
+        }
+
+        if self.block && !self.mid_line {
+            self.message.reserve(1);
+            self.message.put("\n");
+            self.line_start = self.message.len();
+        }
+        self.message.extend_from_slice(&frame);
+        self.mid_line = continued;
+
+        let mut assembled = Assembled::Incomplete;
+        if self.message.len() > self.max && !self.dropping {
+            self.dropping = true;
+            assembled = Assembled::TooLarge;
+        }
+        if self.dropping {
+            // Enough of the line to tell whether it is a fence.
+            self.message.advance(self.line_start);
+            self.message.truncate(FENCE.len() + 1);
+            self.line_start = 0;
+        }
+        if continued {
+            return assembled;
+        }
+
+        let line = &self.message[self.line_start..];
+        let ended = if self.block {
+            line == FENCE
+        } else {
+            self.block = !self.dropping && opens_block(line);
+            !self.block
+        };
+        if !ended {
+            return assembled;
+        }
+
+        self.block = false;
+        self.line_start = 0;
+        let message = self.message.take();
+        if self.dropping {
+            self.dropping = false;
+            return assembled;
+        }
+        Assembled::Complete(message)
+    }
+}
+
+/// Whether `line` opens a fenced message, rather than being one line with
+/// fences in it.
+fn opens_block(line: &[u8]) -> bool {
+    line.starts_with(FENCE) && !line[FENCE.len()..].windows(FENCE.len()).any(|w| w == FENCE)
+}
+
+/// `line` as frames of at most `max` bytes each, every one followed by a
+/// line break. `max` is at least 2, so that a frame fits a byte of the line
+/// and the backslash.
+pub fn split(line: &[u8], max: usize) -> BytesMut {
+    let mut frames = BytesMut::with_capacity(line.len() + 3 * (line.len() / max + 2));
+    let mut rest = line;
+    while rest.len() >= max {
+        let (frame, tail) = rest.split_at(max - 1);
+        frames.put(frame);
+        frames.put("\\\r\n");
+        rest = tail;
+    }
+    frames.put(rest);
+    if rest.ends_with(b"\\") {
+        // Or the backslash would continue the message.
+        frames.put("\\\r\n");
+    }
+    frames.put("\r\n");
+    frames
+}
+
+/// `lines`, each followed by a line break, with those longer than `max` or
+/// ending with a backslash split with `split`, for a peer getting frames.
+pub fn fit(lines: &Bytes, max: usize) -> Bytes {
+    let splits = |line: &[u8]| line.len() >= max || line.ends_with(b"\\");
+    if !each_line(lines).any(splits) {
+        return lines.clone();
+    }
+    let mut fitted = BytesMut::with_capacity(lines.len() + lines.len() / max * 3 + 8);
+    for line in each_line(lines) {
+        fitted.extend_from_slice(&split(line, max));
+    }
+    fitted.freeze()
+}
+
+/// The lines in `bytes`, without their line breaks.
+fn each_line(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = bytes;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest
+            .windows(2)
+            .position(|bytes| bytes == b"\r\n")
+            .unwrap_or(rest.len());
+        let line = &rest[..end];
+        rest = &rest[(end + 2).min(rest.len())..];
+        Some(line)
+    })
+}
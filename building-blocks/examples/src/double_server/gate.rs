@@ -0,0 +1,144 @@
+//! What a sender goes through before its messages are relayed, whether it
+//! is a peer of the line protocol or a gRPC client, see `grpc`.
+//!
+//! ```text
+//! name         checked and claimed on joining, see `names`
+//! banned       see `moderation`
+//! writable     nothing is sent to a replica, see `replica`
+//! rate         the limits of the sender, see `ratelimit`
+//! attachment   its size, and the bytes per minute of the sender
+//! throttle     the limit of all senders together
+//! quota        the bytes of the day, see `quota`
+//! filter       what the message is relayed as, see `filter`
+//! ```
+//!
+//! Each step is a function returning the notice saying why a message is
+//! refused, in the order they are taken. A peer is told with a notice and
+//! goes on, a gRPC call ends with it, see `grpc`.
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::attachment::Attachment;
+use crate::config::NameRules;
+use crate::conn::ConnId;
+use crate::locale::Message;
+use crate::logging;
+use crate::meter::human_bytes;
+use crate::names;
+use crate::ratelimit::Limiter;
+use crate::state::{Side, State};
+
+/// The name `name` of a sender of `side` at `addr` goes by, claimed for the
+/// connection `id`, or why it cannot join.
+pub fn name(
+    rules: &NameRules,
+    state: &State,
+    side: Side,
+    addr: SocketAddr,
+    name: &[u8],
+    id: ConnId,
+) -> Result<String, Message> {
+    names::check(rules, state, name)
+        .and_then(|normalized| names::claim(state, normalized, id))
+        .map_err(|e| {
+            state.metrics.names_rejected.add(1);
+            logging::info!(
+                "name_rejected", side = side, addr = addr;
+                "rejected the name {:?} of {}: {}", String::from_utf8_lossy(name), addr, e
+            );
+            e
+        })
+}
+
+/// Refuse every message on a replica.
+pub fn writable(state: &State) -> Result<(), Message> {
+    if state.read_only {
+        return Err(Message::new("replica_read_only"));
+    }
+    Ok(())
+}
+
+/// Refuse the messages of `name` while it is banned.
+pub fn banned(state: &State, name: &str) -> Result<(), Message> {
+    if state.moderation.is_banned(name) {
+        return Err(Message::new("moderation_banned"));
+    }
+    Ok(())
+}
+
+/// Account for a message in the limits of its sender.
+pub fn rate(limiter: &mut Limiter) -> Result<(), Message> {
+    limiter
+        .message()
+        .map_err(|wait| Message::new("sending_too_fast").with_message("retry", retry_after(wait)))
+}
+
+/// The size of the attachment `message` is, if it is one, or why it is
+/// malformed.
+pub fn attached(message: &[u8]) -> Result<Option<u64>, Message> {
+    match Attachment::parse(message) {
+        Some(Ok(attachment)) => Ok(Some(attachment.size as u64)),
+        Some(Err(e)) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// Account for an attachment of `size` bytes, at most `max`, in the limits
+/// of its sender.
+pub fn attachment(limiter: &mut Limiter, max: u64, size: u64) -> Result<(), Message> {
+    if size > max {
+        let max = human_bytes(max as f64);
+        return Err(Message::new("attach_too_large").with("max", max));
+    }
+    limiter
+        .attachment(size)
+        .map_err(|wait| Message::new("attach_too_fast").with_message("retry", retry_after(wait)))
+}
+
+/// Take a message from the global throttle, dropping it if it is over,
+/// for a sender that cannot be held back.
+pub fn throttle(state: &State) -> Result<(), Message> {
+    state.throttle.take().map_err(|wait| {
+        state.metrics.throttle_messages_rejected.add(1);
+        Message::new("throttle_dropped").with_message("retry", retry_after(wait))
+    })
+}
+
+/// Account for `message` of `name` of `side` in its daily quota.
+pub fn quota(state: &State, side: Side, name: &str, message: &[u8]) -> Result<(), Message> {
+    let quotas = match &state.quotas {
+        Some(quotas) => quotas,
+        None => return Ok(()),
+    };
+    quotas
+        .charge(side, name, message.len() as u64)
+        .map_err(|exceeded| {
+            Message::new("quota_exceeded")
+                .with("used", exceeded.used)
+                .with("limit", exceeded.limit)
+        })
+}
+
+/// `message` of `name` of `side` as it is relayed. Only plain messages are
+/// filtered, see `filter`.
+pub fn filter<'a>(state: &State, side: Side, name: &str, message: &'a [u8]) -> Cow<'a, [u8]> {
+    if Attachment::parse(message).is_some() {
+        return Cow::Borrowed(message);
+    }
+    match state.filters.apply(side, name, message) {
+        Some(text) => Cow::Owned(text.into_bytes()),
+        None => Cow::Borrowed(message),
+    }
+}
+
+/// When a rate limited sender may try again, for notices.
+pub fn retry_after(wait: Duration) -> Message {
+    // Limits that are never refilled wait "forever".
+    if wait.as_secs() > 86400 {
+        return Message::new("retry_later");
+    }
+    let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+    Message::new("retry_in").with("secs", secs)
+}
@@ -0,0 +1,188 @@
+//! Runtime of the integrations, and the HTTP listener they share.
+//!
+//! actix-web drives its own single threaded runtimes, so instead of trying to
+//! share the tokio runtime the chat listeners run on, the gateway gets a
+//! dedicated thread with its own actix `System`. The two halves only talk
+//! through `State`, whose channels work from any executor.
+//!
+//! HTTP/2 is served next to HTTP/1.1, so that a client sends its requests
+//! over one connection at once rather than one connection each: over TLS,
+//! see `tls.http_listen`, to clients picking `h2` with ALPN, and in plain
+//! text to clients starting with the HTTP/2 preface. On the `single_port`
+//! and the TLS listener of the chat, HTTP/2 is gRPC, see `sniff`.
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use futures::Future;
+use serde_json::json;
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::graphql;
+use crate::logging;
+use crate::matrix;
+use crate::metrics;
+use crate::restart::Restarter;
+use crate::state::State;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::webhook;
+
+/// Whether any configured integration needs the gateway.
+pub fn needed(config: &Config) -> bool {
+    let https = config.tls.as_ref().map_or(false, |tls| tls.http_listen.is_some());
+    config.http_listen.is_some()
+        || https
+        || config.matrix.is_some()
+        || !config.outgoing_webhooks.is_empty()
+}
+
+/// Start the gateway thread, serving HTTP on `http` and HTTP over TLS on
+/// `https`, those given.
+///
+/// Returns once the HTTP server (if any) is started, so that a failure is
+/// reported at startup like it is for the chat listeners.
+pub fn spawn(
+    config: Arc<Config>,
+    state: State,
+    http: Option<TcpListener>,
+    https: Option<TcpListener>,
+    restarter: Restarter,
+) -> io::Result<()> {
+    let (bound_tx, bound_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let sys = actix_rt::System::new("gateway");
+
+        // Background work of the integrations. These are queued now and
+        // start running together with the system.
+        if let Some(matrix) = &config.matrix {
+            actix_rt::spawn(matrix::relay(matrix.clone(), state.subscribe()));
+        }
+        if !config.outgoing_webhooks.is_empty() {
+            actix_rt::spawn(webhook::relay(
+                config.outgoing_webhooks.clone(),
+                &config.circuit_breaker,
+                state.metrics.clone(),
+                state.subscribe(),
+            ));
+        }
+
+        if http.is_some() || https.is_some() {
+            if let Err(e) = serve(http, https, &config, &state, &restarter) {
+                let _ = bound_tx.send(Err(e));
+                return;
+            }
+        }
+        let _ = bound_tx.send(Ok(()));
+
+        if let Err(e) = sys.run() {
+            logging::error!("gateway_failed"; "gateway error = {:?}", e);
+        }
+    });
+
+    bound_rx
+        .recv()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "gateway thread died")))
+}
+
+/// Serve HTTP on `http` and HTTP over TLS on `https`, with the endpoints
+/// of the integrations.
+fn serve(
+    http: Option<TcpListener>,
+    https: Option<TcpListener>,
+    config: &Config,
+    state: &State,
+    restarter: &Restarter,
+) -> io::Result<()> {
+    // Endpoint state is created once here and cloned into every worker,
+    // so that all workers see the same data.
+    let appservice = config
+        .matrix
+        .as_ref()
+        .map(|matrix| matrix::Appservice::new(matrix.clone(), state.clone()));
+    let incoming = webhook::Incoming::new(config.incoming_webhooks.clone(), state.clone());
+    let graphql = graphql::Graphql::new(state.clone());
+    let admin = config.admin_token.as_ref().map(|token| {
+        let deadline = Duration::from_secs(config.drain.deadline_secs);
+        Admin::new(
+            token.clone(),
+            deadline,
+            config.snapshot.clone(),
+            state.clone(),
+            restarter.clone(),
+        )
+    });
+    let handed_over = state.drain.handed_over();
+    let state = state.clone();
+
+    let mut server = HttpServer::new(move || {
+        let appservice = appservice.clone();
+        let incoming = incoming.clone();
+        let graphql = graphql.clone();
+        let admin = admin.clone();
+        App::new()
+            .data(state.clone())
+            .route("/metrics", web::get().to(metrics))
+            .route("/health", web::get().to(health))
+            .configure(move |cfg: &mut web::ServiceConfig| {
+                if let Some(appservice) = &appservice {
+                    appservice.configure(cfg);
+                }
+                incoming.configure(cfg);
+                graphql.configure(cfg);
+                if let Some(admin) = &admin {
+                    admin.configure(cfg);
+                }
+            })
+    })
+    // Signals start draining, see `drain`, rather than stopping the
+    // gateway right away.
+    .disable_signals();
+    if let Some(listener) = http {
+        server = server.listen(listener)?;
+    }
+    // actix-web offers `h2` and `http/1.1` with ALPN itself.
+    #[cfg(feature = "tls")]
+    if let (Some(listener), Some(config)) = (https, &config.tls) {
+        server = server.listen_ssl(listener, tls::builder(config)?)?;
+    }
+    // Only opened with a `tls` section, which needs the feature.
+    #[cfg(not(feature = "tls"))]
+    drop(https);
+    let server = server.start();
+
+    // Leave new requests to the process that took over.
+    actix_rt::spawn(handed_over.and_then(move |()| server.stop(true)));
+
+    Ok(())
+}
+
+fn metrics(state: web::Data<State>) -> HttpResponse {
+    let mut body = state.metrics.render();
+    metrics::render_traffic(&mut body, &state.traffic());
+    metrics::render_memory(&mut body, &state.memory());
+    metrics::render_stats(&mut body, &state.stats());
+    metrics::render_connections(&mut body, &state.connections);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+/// Whether the watchdog found something wrong, see `watchdog`.
+fn health(state: web::Data<State>) -> HttpResponse {
+    let problems = state.health.problems();
+    if problems.is_empty() {
+        HttpResponse::Ok().json(json!({ "status": "ok" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({
+            "status": "degraded",
+            "problems": problems,
+        }))
+    }
+}
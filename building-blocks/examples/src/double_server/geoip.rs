@@ -0,0 +1,126 @@
+//! Where peers connect from, with the `geoip` feature.
+//!
+//! ```text
+//! cargo run --features geoip --bin double_server -- --config server.json
+//! ```
+//!
+//! ```json
+//! "geoip": {
+//!     "country_db": "/usr/share/GeoIP/GeoLite2-Country.mmdb",
+//!     "asn_db": "/usr/share/GeoIP/GeoLite2-ASN.mmdb"
+//! }
+//! ```
+//!
+//! The country and the autonomous system of every peer are looked up in the
+//! MaxMind databases of `Config::geoip` when it connects, either database
+//! may be left out. The join is logged with them, `GET /admin/peers` lists
+//! them (see `admin`) and the series of each peer at `/metrics` are labelled
+//! with `country` and `asn`.
+//!
+//! The databases are read into memory at startup, so a lookup does not
+//! block. Addresses they do not know, like private ones, have no location.
+
+use serde_derive::Serialize;
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+use crate::config::GeoIpConfig;
+
+/// What the databases know of an address.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Location {
+    /// ISO 3166 code, like `DE`.
+    pub country: Option<String>,
+    /// Number of the autonomous system.
+    pub asn: Option<u32>,
+    /// Who runs the autonomous system.
+    pub organization: Option<String>,
+}
+
+impl Location {
+    pub fn is_known(&self) -> bool {
+        *self != Location::default()
+    }
+}
+
+impl fmt::Display for Location {
+    /// Like `DE, AS3320 Deutsche Telekom AG`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        match (self.asn, &self.organization) {
+            (Some(asn), Some(organization)) => parts.push(format!("AS{} {}", asn, organization)),
+            (Some(asn), None) => parts.push(format!("AS{}", asn)),
+            _ => {}
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// The databases, shared by all peers. Without any, every address has no
+/// location.
+#[derive(Default)]
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    country: Option<maxminddb::Reader<Vec<u8>>>,
+    #[cfg(feature = "geoip")]
+    asn: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Read the databases of `config`.
+    #[cfg(feature = "geoip")]
+    pub fn open(config: &GeoIpConfig) -> io::Result<GeoIp> {
+        let open = |path: &Option<std::path::PathBuf>| match path {
+            Some(path) => maxminddb::Reader::open_readfile(path)
+                .map(Some)
+                .map_err(|e| {
+                    let message = format!("{}: {}", path.display(), e);
+                    io::Error::new(io::ErrorKind::InvalidInput, message)
+                }),
+            None => Ok(None),
+        };
+        Ok(GeoIp {
+            country: open(&config.country_db)?,
+            asn: open(&config.asn_db)?,
+        })
+    }
+
+    /// Fails, there is nothing to read the databases with.
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(_config: &GeoIpConfig) -> io::Result<GeoIp> {
+        let message = "geoip needs the geoip feature";
+        Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        use maxminddb::geoip2;
+
+        let mut location = Location::default();
+        if let Some(reader) = &self.country {
+            if let Ok(found) = reader.lookup::<geoip2::Country>(ip) {
+                location.country = found
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_string);
+            }
+        }
+        if let Some(reader) = &self.asn {
+            if let Ok(found) = reader.lookup::<geoip2::Asn>(ip) {
+                location.asn = found.autonomous_system_number;
+                location.organization = found.autonomous_system_organization.map(str::to_string);
+            }
+        }
+        location
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn locate(&self, _ip: IpAddr) -> Location {
+        Location::default()
+    }
+}
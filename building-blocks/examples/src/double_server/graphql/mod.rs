@@ -0,0 +1,467 @@
+//! GraphQL endpoint for dashboards and web frontends.
+//!
+//! Served by the HTTP gateway at `/graphql`: queries are POSTed as JSON, and
+//! subscriptions run over a WebSocket opened on the same path, see `ws`.
+//! The schema is fixed:
+//!
+//! ```graphql
+//! type Query {
+//!   peers(side: Side): [Peer!]!
+//!   # Newest messages, oldest first.
+//!   history(limit: Int, before: ID): [Message!]!
+//! }
+//!
+//! type Subscription {
+//!   messageAdded(side: Side): Message!
+//! }
+//!
+//! enum Side { C GO }
+//!
+//! type Peer { side: Side! name: String! addr: String! }
+//!
+//! type Message {
+//!   id: ID!
+//!   side: Side!
+//!   sender: String!
+//!   body: String!
+//!   # Milliseconds since the epoch.
+//!   ts: Float!
+//! }
+//! ```
+//!
+//! Introspection is not supported.
+
+mod parser;
+mod ws;
+
+use actix_web::{web, HttpResponse};
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+
+use crate::state::{Side, State, StoredMessage};
+
+use self::parser::{Document, Field, Fragment, Operation, OperationKind, Selection};
+
+/// Deepest nesting of fragments, which also catches fragment cycles.
+const MAX_DEPTH: usize = 32;
+
+/// The usual shape of a GraphQL request, over HTTP and WebSocket alike.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+/// A parsed request, ready to be executed.
+struct Prepared {
+    document: Document,
+    /// Index of the operation in `document` to execute.
+    operation: usize,
+    variables: Map<String, Value>,
+}
+
+impl Prepared {
+    fn new(request: Request) -> Result<Prepared, String> {
+        let document = parser::parse(&request.query)?;
+
+        let operation = match &request.operation_name {
+            Some(name) => document
+                .operations
+                .iter()
+                .position(|op| op.name.as_ref() == Some(name))
+                .ok_or_else(|| format!("unknown operation {:?}", name))?,
+            None if document.operations.len() == 1 => 0,
+            None => return Err("operationName is required".to_string()),
+        };
+
+        // Declared variables that were not given take their default.
+        let mut variables = request.variables.unwrap_or_default();
+        for (name, default) in &document.operations[operation].variables {
+            if !variables.contains_key(name) {
+                variables.insert(name.clone(), default.clone().unwrap_or(Value::Null));
+            }
+        }
+
+        Ok(Prepared {
+            document,
+            operation,
+            variables,
+        })
+    }
+
+    fn operation(&self) -> &Operation {
+        &self.document.operations[self.operation]
+    }
+
+    fn execution(&self) -> Execution<'_> {
+        Execution {
+            fragments: &self.document.fragments,
+            variables: &self.variables,
+        }
+    }
+}
+
+fn error(message: &str) -> Value {
+    json!({ "errors": [{ "message": message }] })
+}
+
+/// What executing a selection needs besides the selection itself.
+struct Execution<'a> {
+    fragments: &'a HashMap<String, Fragment>,
+    variables: &'a Map<String, Value>,
+}
+
+impl<'a> Execution<'a> {
+    /// Whether `@skip` and `@include` let the selection through.
+    fn included(&self, directives: &[parser::Directive]) -> Result<bool, String> {
+        for directive in directives {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, value)| value.resolve(self.variables));
+            let condition = match condition {
+                Some(Value::Bool(condition)) => condition,
+                _ => return Err(format!("@{} needs a boolean \"if\"", directive.name)),
+            };
+            match directive.name.as_str() {
+                "skip" if condition => return Ok(false),
+                "include" if !condition => return Ok(false),
+                "skip" | "include" => {}
+                other => return Err(format!("unknown directive @{}", other)),
+            }
+        }
+        Ok(true)
+    }
+
+    /// The fields selected on an object of type `typename`, with fragments
+    /// expanded.
+    fn collect(
+        &self,
+        selection: &'a [Selection],
+        typename: &str,
+        depth: usize,
+        fields: &mut Vec<&'a Field>,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("fragments are nested too deeply".to_string());
+        }
+
+        for selection in selection {
+            match selection {
+                Selection::Field(field) => {
+                    if self.included(&field.directives)? {
+                        fields.push(field);
+                    }
+                }
+                Selection::FragmentSpread { name, directives } => {
+                    let fragment = self
+                        .fragments
+                        .get(name)
+                        .ok_or_else(|| format!("unknown fragment {}", name))?;
+                    if self.included(directives)? && fragment.type_condition == typename {
+                        self.collect(&fragment.selection, typename, depth + 1, fields)?;
+                    }
+                }
+                Selection::InlineFragment {
+                    type_condition,
+                    directives,
+                    selection,
+                } => {
+                    let applies = type_condition.as_ref().map_or(true, |t| t == typename);
+                    if self.included(directives)? && applies {
+                        self.collect(selection, typename, depth + 1, fields)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick the selected fields out of a resolved value.
+    ///
+    /// The resolvers produce objects holding every field of their type, plus
+    /// their `__typename`, so apart from the root fields there is nothing
+    /// left to resolve.
+    fn project(&self, value: &Value, selection: &'a [Selection]) -> Result<Value, String> {
+        let object = match value {
+            Value::Array(items) => {
+                return items
+                    .iter()
+                    .map(|item| self.project(item, selection))
+                    .collect();
+            }
+            Value::Object(object) => object,
+            _ => return Ok(value.clone()),
+        };
+        let typename = object["__typename"].as_str().unwrap_or("");
+
+        let mut fields = Vec::new();
+        self.collect(selection, typename, 0, &mut fields)?;
+
+        let mut out = Map::new();
+        for field in fields {
+            let value = match field.name.as_str() {
+                "__typename" => Value::from(typename),
+                name => {
+                    let value = object.get(name).ok_or_else(|| {
+                        format!("cannot query field {:?} on type {:?}", name, typename)
+                    })?;
+                    if let Some((arg, _)) = field.arguments.first() {
+                        return Err(format!("unknown argument {:?} on field {:?}", arg, name));
+                    }
+                    // Peer and Message only have scalar fields.
+                    self.complete(field, value, false)?
+                }
+            };
+            out.insert(field.response_key().to_string(), value);
+        }
+        Ok(Value::Object(out))
+    }
+
+    /// Check that fields of object type, and only those, have a selection,
+    /// and project their value.
+    fn complete(&self, field: &'a Field, value: &Value, object: bool) -> Result<Value, String> {
+        match (object, field.selection.is_empty()) {
+            (true, true) => Err(format!(
+                "field {:?} must have a selection of subfields",
+                field.name
+            )),
+            (false, false) => Err(format!(
+                "field {:?} must not have a selection, it has no subfields",
+                field.name
+            )),
+            (true, false) => self.project(value, &field.selection),
+            (false, true) => Ok(value.clone()),
+        }
+    }
+
+    /// The arguments of `field` with variables resolved, rejecting any not in
+    /// `known`.
+    fn arguments(&self, field: &Field, known: &[&str]) -> Result<Map<String, Value>, String> {
+        let mut arguments = Map::new();
+        for (name, value) in &field.arguments {
+            if !known.contains(&name.as_str()) {
+                return Err(format!(
+                    "unknown argument {:?} on field {:?}",
+                    name, field.name
+                ));
+            }
+            arguments.insert(name.clone(), value.resolve(self.variables));
+        }
+        Ok(arguments)
+    }
+}
+
+fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::C => "C",
+        Side::Go => "GO",
+    }
+}
+
+/// The `side` argument, if given.
+fn side_argument(arguments: &Map<String, Value>) -> Result<Option<Side>, String> {
+    match arguments.get("side") {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(side)) if side == "C" => Ok(Some(Side::C)),
+        Some(Value::String(side)) if side == "GO" => Ok(Some(Side::Go)),
+        Some(other) => Err(format!("invalid side {}", other)),
+    }
+}
+
+fn message_object(id: u64, side: Side, sender: &str, body: &str, ts: u64) -> Value {
+    json!({
+        "__typename": "Message",
+        // IDs are serialized as strings.
+        "id": id.to_string(),
+        "side": side_name(side),
+        "sender": sender,
+        "body": body,
+        "ts": ts as f64,
+    })
+}
+
+fn stored_message(m: &StoredMessage) -> Value {
+    message_object(m.id, m.side, &m.name, &m.body, m.ts)
+}
+
+/// A running `messageAdded` subscription.
+struct Subscription {
+    prepared: Prepared,
+    side: Option<Side>,
+}
+
+impl Subscription {
+    fn new(prepared: Prepared) -> Result<Subscription, String> {
+        let side = {
+            let execution = prepared.execution();
+            let field = Subscription::root(&prepared, &execution)?;
+            side_argument(&execution.arguments(field, &["side"])?)?
+        };
+        let subscription = Subscription { prepared, side };
+
+        // Try the selection on a made up message, so that a bad one is
+        // reported now rather than with every message.
+        subscription.data(&message_object(0, Side::C, "", "", 0))?;
+        Ok(subscription)
+    }
+
+    fn root<'a>(prepared: &'a Prepared, execution: &Execution<'a>) -> Result<&'a Field, String> {
+        let mut fields = Vec::new();
+        execution.collect(
+            &prepared.operation().selection,
+            "Subscription",
+            0,
+            &mut fields,
+        )?;
+        match fields[..] {
+            [field] if field.name == "messageAdded" => Ok(field),
+            [field] => Err(format!(
+                "cannot query field {:?} on type \"Subscription\"",
+                field.name
+            )),
+            _ => Err("a subscription must select exactly one field".to_string()),
+        }
+    }
+
+    fn matches(&self, side: Side) -> bool {
+        self.side.map_or(true, |s| s == side)
+    }
+
+    /// The `data` of the response for a new message.
+    fn data(&self, message: &Value) -> Result<Value, String> {
+        let execution = self.prepared.execution();
+        let field = Subscription::root(&self.prepared, &execution)?;
+        let mut data = Map::new();
+        data.insert(
+            field.response_key().to_string(),
+            execution.complete(field, message, true)?,
+        );
+        Ok(Value::Object(data))
+    }
+}
+
+/// Shared by the HTTP handlers and the WebSocket sessions.
+#[derive(Clone)]
+pub struct Graphql {
+    state: State,
+}
+
+impl Graphql {
+    pub fn new(state: State) -> Graphql {
+        Graphql { state }
+    }
+
+    /// Mount `/graphql`.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.data(self.clone()).service(
+            web::resource("/graphql")
+                .route(web::post().to(post))
+                .route(web::get().to(ws::connect)),
+        );
+    }
+
+    /// Execute a query or mutation, returning the whole response document.
+    fn execute(&self, prepared: &Prepared) -> Value {
+        let operation = prepared.operation();
+        let execution = prepared.execution();
+
+        match operation.kind {
+            OperationKind::Query => {}
+            OperationKind::Mutation => return error("mutations are not supported"),
+            OperationKind::Subscription => {
+                return error("subscriptions are only served over WebSocket")
+            }
+        }
+
+        match self.query(&execution, &operation.selection) {
+            Ok(data) => json!({ "data": data }),
+            Err(e) => json!({ "data": null, "errors": [{ "message": e }] }),
+        }
+    }
+
+    fn query<'a>(
+        &self,
+        execution: &Execution<'a>,
+        selection: &'a [Selection],
+    ) -> Result<Value, String> {
+        let mut fields = Vec::new();
+        execution.collect(selection, "Query", 0, &mut fields)?;
+
+        let mut data = Map::new();
+        for field in fields {
+            let value = match field.name.as_str() {
+                "__typename" => Value::from("Query"),
+                "peers" => {
+                    let arguments = execution.arguments(field, &["side"])?;
+                    let value = self.peers(side_argument(&arguments)?);
+                    execution.complete(field, &value, true)?
+                }
+                "history" => {
+                    let arguments = execution.arguments(field, &["limit", "before"])?;
+                    let value = self.history(&arguments)?;
+                    execution.complete(field, &value, true)?
+                }
+                "__schema" | "__type" => return Err("introspection is not supported".to_string()),
+                name => return Err(format!("cannot query field {:?} on type \"Query\"", name)),
+            };
+            data.insert(field.response_key().to_string(), value);
+        }
+        Ok(Value::Object(data))
+    }
+
+    fn peers(&self, side: Option<Side>) -> Value {
+        let mut peers = Vec::new();
+        for &s in &[Side::C, Side::Go] {
+            if side.map_or(false, |side| side != s) {
+                continue;
+            }
+            self.state.side(s).for_each(|_, member| {
+                peers.push(json!({
+                    "__typename": "Peer",
+                    "side": side_name(s),
+                    "name": member.name,
+                    "addr": member.addr.to_string(),
+                }));
+            });
+        }
+        Value::Array(peers)
+    }
+
+    fn history(&self, arguments: &Map<String, Value>) -> Result<Value, String> {
+        let limit = match arguments.get("limit") {
+            None | Some(Value::Null) => None,
+            Some(limit) => Some(
+                limit
+                    .as_u64()
+                    .ok_or_else(|| format!("invalid limit {}", limit))? as usize,
+            ),
+        };
+        // IDs may be given as strings or as integers.
+        let before = match arguments.get("before") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(id)) => {
+                Some(id.parse().map_err(|_| format!("invalid id {:?}", id))?)
+            }
+            Some(id) => Some(id.as_u64().ok_or_else(|| format!("invalid id {}", id))?),
+        };
+
+        let messages = self.state.history(before, limit);
+        Ok(messages.iter().map(stored_message).collect())
+    }
+}
+
+fn post(graphql: web::Data<Graphql>, request: web::Json<Request>) -> HttpResponse {
+    let response = match Prepared::new(request.into_inner()) {
+        Ok(prepared) => graphql.execute(&prepared),
+        Err(e) => error(&e),
+    };
+    HttpResponse::Ok().json(response)
+}
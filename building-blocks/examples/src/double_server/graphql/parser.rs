@@ -0,0 +1,558 @@
+//! Parser for GraphQL documents.
+//!
+//! Everything that may appear in an executable document is understood:
+//! operations, variables, aliases, arguments, directives and fragments. Type
+//! system definitions are not, the schema is fixed.
+
+use serde_json::{Map, Number, Value};
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+#[derive(Debug)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub name: Option<String>,
+    /// Declared variables with their default value.
+    pub variables: Vec<(String, Option<Value>)>,
+    pub selection: Vec<Selection>,
+}
+
+#[derive(Debug)]
+pub enum Selection {
+    Field(Field),
+    FragmentSpread {
+        name: String,
+        directives: Vec<Directive>,
+    },
+    InlineFragment {
+        type_condition: Option<String>,
+        directives: Vec<Directive>,
+        selection: Vec<Selection>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Input)>,
+    pub directives: Vec<Directive>,
+    pub selection: Vec<Selection>,
+}
+
+impl Field {
+    /// The key of the field in the response.
+    pub fn response_key(&self) -> &str {
+        self.alias.as_ref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Directive {
+    pub name: String,
+    pub arguments: Vec<(String, Input)>,
+}
+
+#[derive(Debug)]
+pub struct Fragment {
+    pub type_condition: String,
+    pub selection: Vec<Selection>,
+}
+
+/// An argument value, which may refer to variables.
+///
+/// Enum values are represented by their name as a string.
+#[derive(Debug)]
+pub enum Input {
+    Variable(String),
+    Const(Value),
+    List(Vec<Input>),
+    Object(Vec<(String, Input)>),
+}
+
+impl Input {
+    /// The value with all variables replaced. Unknown variables are null.
+    pub fn resolve(&self, variables: &Map<String, Value>) -> Value {
+        match self {
+            Input::Variable(name) => variables.get(name).cloned().unwrap_or(Value::Null),
+            Input::Const(value) => value.clone(),
+            Input::List(items) => items.iter().map(|item| item.resolve(variables)).collect(),
+            Input::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.resolve(variables)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Document {
+    pub operations: Vec<Operation>,
+    pub fragments: HashMap<String, Fragment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    End,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            // Commas are insignificant, like whitespace.
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => {
+                chars.next();
+            }
+            '#' => {
+                while chars.peek().map_or(false, |&c| c != '\n' && c != '\r') {
+                    chars.next();
+                }
+            }
+            '!' | '$' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '|' | '}' | '&' => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            }
+            '.' => {
+                for _ in 0..3 {
+                    if chars.next() != Some('.') {
+                        return Err("expected \"...\"".to_string());
+                    }
+                }
+                tokens.push(Token::Spread);
+            }
+            '"' => tokens.push(Token::Str(string(&mut chars)?)),
+            '-' | '0'..='9' => tokens.push(number(&mut chars)?),
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c != '_' && !c.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected character {:?}", c)),
+        }
+    }
+
+    tokens.push(Token::End);
+    Ok(tokens)
+}
+
+fn number(chars: &mut Peekable<Chars>) -> Result<Token, String> {
+    let mut text = String::new();
+    let mut float = false;
+    while let Some(&c) = chars.peek() {
+        match c {
+            '0'..='9' | '-' | '+' => {}
+            '.' | 'e' | 'E' => float = true,
+            _ => break,
+        }
+        text.push(c);
+        chars.next();
+    }
+
+    let token = if float {
+        text.parse().ok().map(Token::Float)
+    } else {
+        text.parse().ok().map(Token::Int)
+    };
+    token.ok_or_else(|| format!("invalid number {:?}", text))
+}
+
+fn string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    chars.next();
+    let mut out = String::new();
+
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        if chars.peek() != Some(&'"') {
+            // The empty string.
+            return Ok(out);
+        }
+        chars.next();
+
+        // A block string, taken as is up to the closing quotes.
+        let mut quotes = 0;
+        for c in chars {
+            if c == '"' {
+                quotes += 1;
+                if quotes == 3 {
+                    return Ok(out);
+                }
+                continue;
+            }
+            for _ in 0..quotes {
+                out.push('"');
+            }
+            quotes = 0;
+            out.push(c);
+        }
+        return Err("unterminated string".to_string());
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok(out),
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| format!("invalid escape \\u{}", hex))?
+                    }
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => c,
+                    _ => return Err("invalid escape".to_string()),
+                };
+                out.push(escaped);
+            }
+            '\n' | '\r' => break,
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+/// Deepest nesting of selection sets, list and object values and list
+/// types. Parsing recurses into each, so it is bounded before the stack is.
+const MAX_NESTING: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// How deeply nested the parser is, see `MAX_NESTING`.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if *self.peek() == Token::Punct(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?}, found {:?}", c, self.peek()))
+        }
+    }
+
+    /// Parse with `parse` one level deeper, failing past `MAX_NESTING`.
+    fn nested<T>(&mut self, parse: fn(&mut Parser) -> Result<T, String>) -> Result<T, String> {
+        if self.depth == MAX_NESTING {
+            return Err("nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn peek_name(&self, name: &str) -> bool {
+        match self.peek() {
+            Token::Name(n) => n == name,
+            _ => false,
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Token::Name(name) => Ok(name),
+            token => Err(format!("expected a name, found {:?}", token)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Document, String> {
+        let mut document = Document::default();
+
+        while *self.peek() != Token::End {
+            if self.peek_name("fragment") {
+                self.next();
+                let name = self.name()?;
+                if !self.peek_name("on") {
+                    return Err(format!("fragment {} needs a type condition", name));
+                }
+                self.next();
+                let type_condition = self.name()?;
+                self.directives()?;
+                let selection = self.selection_set()?;
+                document.fragments.insert(
+                    name,
+                    Fragment {
+                        type_condition,
+                        selection,
+                    },
+                );
+            } else {
+                let operation = self.operation()?;
+                document.operations.push(operation);
+            }
+        }
+
+        if document.operations.is_empty() {
+            return Err("the document contains no operation".to_string());
+        }
+        Ok(document)
+    }
+
+    fn operation(&mut self) -> Result<Operation, String> {
+        // The `{ ... }` shorthand for an anonymous query.
+        if *self.peek() == Token::Punct('{') {
+            return Ok(Operation {
+                kind: OperationKind::Query,
+                name: None,
+                variables: Vec::new(),
+                selection: self.selection_set()?,
+            });
+        }
+
+        let kind = match self.name()?.as_str() {
+            "query" => OperationKind::Query,
+            "mutation" => OperationKind::Mutation,
+            "subscription" => OperationKind::Subscription,
+            other => return Err(format!("unexpected {:?}", other)),
+        };
+        let name = match self.peek() {
+            Token::Name(_) => Some(self.name()?),
+            _ => None,
+        };
+
+        let mut variables = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                self.expect('$')?;
+                let name = self.name()?;
+                self.expect(':')?;
+                self.skip_type()?;
+                let default = if self.eat('=') {
+                    Some(self.value()?.resolve(&Map::new()))
+                } else {
+                    None
+                };
+                self.directives()?;
+                variables.push((name, default));
+            }
+        }
+        self.directives()?;
+
+        Ok(Operation {
+            kind,
+            name,
+            variables,
+            selection: self.selection_set()?,
+        })
+    }
+
+    /// Variable types are not checked, the resolvers check their arguments.
+    fn skip_type(&mut self) -> Result<(), String> {
+        if self.eat('[') {
+            self.nested(Parser::skip_type)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.expect('{')?;
+        let mut selection = Vec::new();
+        while !self.eat('}') {
+            selection.push(self.nested(Parser::selection)?);
+        }
+        if selection.is_empty() {
+            return Err("empty selection set".to_string());
+        }
+        Ok(selection)
+    }
+
+    fn selection(&mut self) -> Result<Selection, String> {
+        if *self.peek() == Token::Spread {
+            self.next();
+            let is_spread = match self.peek() {
+                Token::Name(n) => n != "on",
+                _ => false,
+            };
+            if is_spread {
+                return Ok(Selection::FragmentSpread {
+                    name: self.name()?,
+                    directives: self.directives()?,
+                });
+            }
+
+            let type_condition = if self.peek_name("on") {
+                self.next();
+                Some(self.name()?)
+            } else {
+                None
+            };
+            return Ok(Selection::InlineFragment {
+                type_condition,
+                directives: self.directives()?,
+                selection: self.selection_set()?,
+            });
+        }
+
+        let mut alias = None;
+        let mut name = self.name()?;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let arguments = self.arguments()?;
+        let directives = self.directives()?;
+        let selection = if *self.peek() == Token::Punct('{') {
+            self.selection_set()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Selection::Field(Field {
+            alias,
+            name,
+            arguments,
+            directives,
+            selection,
+        }))
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, Input)>, String> {
+        let mut arguments = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let name = self.name()?;
+                self.expect(':')?;
+                arguments.push((name, self.value()?));
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn directives(&mut self) -> Result<Vec<Directive>, String> {
+        let mut directives = Vec::new();
+        while self.eat('@') {
+            directives.push(Directive {
+                name: self.name()?,
+                arguments: self.arguments()?,
+            });
+        }
+        Ok(directives)
+    }
+
+    fn value(&mut self) -> Result<Input, String> {
+        let value = match self.next() {
+            Token::Punct('$') => return Ok(Input::Variable(self.name()?)),
+            Token::Punct('[') => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.nested(Parser::value)?);
+                }
+                return Ok(Input::List(items));
+            }
+            Token::Punct('{') => {
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.nested(Parser::value)?));
+                }
+                return Ok(Input::Object(fields));
+            }
+            Token::Int(v) => Value::from(v),
+            Token::Float(v) => Number::from_f64(v).map_or(Value::Null, Value::Number),
+            Token::Str(v) => Value::String(v),
+            Token::Name(ref v) if v == "true" => Value::Bool(true),
+            Token::Name(ref v) if v == "false" => Value::Bool(false),
+            Token::Name(ref v) if v == "null" => Value::Null,
+            Token::Name(v) => Value::String(v),
+            token => return Err(format!("expected a value, found {:?}", token)),
+        };
+        Ok(Input::Const(value))
+    }
+}
+
+/// Parse an executable document.
+pub fn parse(src: &str) -> Result<Document, String> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+        depth: 0,
+    };
+    parser.document()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_selections_parse() {
+        let document = parse("{ history(limit: [[1]]) { name text } }").unwrap();
+        assert_eq!(document.operations.len(), 1);
+    }
+
+    #[test]
+    fn deeply_nested_list_fails() {
+        let query = format!("{{ history(limit: {}) {{ text }} }}", "[".repeat(100_000));
+        assert_eq!(parse(&query).unwrap_err(), "nested too deeply");
+    }
+
+    #[test]
+    fn deeply_nested_selections_fail() {
+        let query = format!("{}{}", "{a".repeat(100_000), "}".repeat(100_000));
+        assert_eq!(parse(&query).unwrap_err(), "nested too deeply");
+    }
+
+    #[test]
+    fn deeply_nested_list_type_fails() {
+        let query = format!("query ($a: {}Int) {{ a }}", "[".repeat(100_000));
+        assert_eq!(parse(&query).unwrap_err(), "nested too deeply");
+    }
+}
@@ -0,0 +1,275 @@
+//! GraphQL over WebSocket.
+//!
+//! Speaks the `graphql-ws` subprotocol of subscriptions-transport-ws, which
+//! is what Apollo and GraphiQL clients use: after `connection_init` the
+//! client `start`s operations by id, and receives `data` for each until it
+//! `stop`s them. Queries are answered once and completed right away, so a
+//! frontend can do all its work over the one socket.
+
+use actix_http::ws::{self, Codec, Frame, Message};
+use actix_web::{error, http::header, web, Error, HttpRequest, HttpResponse};
+use bytes::BytesMut;
+use futures::sync::mpsc;
+use futures::{task, Async, Future, Poll, Stream};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tokio::codec::{Decoder, Encoder};
+use tokio::timer::Interval;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::logging;
+use crate::state::{now_ms, ChatEvent};
+
+use super::{message_object, Graphql, OperationKind, Prepared, Request, Subscription};
+
+const PROTOCOL: &str = "graphql-ws";
+
+/// How often to send `ka` messages, which keep proxies from closing the
+/// socket while no messages are sent.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Frames or events handled per poll before yielding to other tasks.
+const EVENTS_PER_TICK: usize = 10;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit,
+    Start { id: String, payload: Request },
+    Stop { id: String },
+    ConnectionTerminate,
+}
+
+/// Handle the WebSocket upgrade on `GET /graphql`.
+pub fn connect(
+    req: HttpRequest,
+    payload: web::Payload,
+    graphql: web::Data<Graphql>,
+) -> Result<HttpResponse, Error> {
+    let mut res = ws::handshake(req.head())?;
+    let offered = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok())
+        .map_or(false, |protocols| {
+            protocols.split(',').any(|p| p.trim() == PROTOCOL)
+        });
+    if offered {
+        res.header(header::SEC_WEBSOCKET_PROTOCOL, PROTOCOL);
+    }
+
+    // The session runs as its own task and writes its messages into the
+    // response body.
+    let (tx, rx) = mpsc::unbounded();
+    actix_rt::spawn(Session {
+        graphql: graphql.get_ref().clone(),
+        events: graphql.state.subscribe(),
+        payload,
+        buf: BytesMut::new(),
+        codec: Codec::new(),
+        tx,
+        keepalive: None,
+        subscriptions: HashMap::new(),
+    });
+
+    let mut codec = Codec::new();
+    let body = rx
+        .map_err(|()| error::ErrorInternalServerError("session closed"))
+        .and_then(move |msg| {
+            let mut buf = BytesMut::new();
+            codec.encode(msg, &mut buf)?;
+            Ok(buf.freeze())
+        });
+    Ok(res.streaming(body))
+}
+
+struct Session {
+    graphql: Graphql,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+    payload: web::Payload,
+    /// Received data not yet decoded into frames.
+    buf: BytesMut,
+    codec: Codec,
+    tx: mpsc::UnboundedSender<Message>,
+    /// Started by `connection_init`.
+    keepalive: Option<Interval>,
+    subscriptions: HashMap<String, Subscription>,
+}
+
+impl Session {
+    fn send(&self, msg: Value) {
+        // Fails once the connection is gone, at which point the payload
+        // ends too and the session with it.
+        let _ = self.tx.unbounded_send(Message::Text(msg.to_string()));
+    }
+
+    fn send_error(&self, id: &str, message: &str) {
+        self.send(json!({ "type": "error", "id": id, "payload": { "message": message } }));
+    }
+
+    /// Handle a frame from the client, returning whether the session goes on.
+    fn frame(&mut self, frame: Frame) -> bool {
+        let text = match frame {
+            Frame::Text(Some(text)) | Frame::Binary(Some(text)) => text,
+            Frame::Text(None) | Frame::Binary(None) | Frame::Pong(_) => return true,
+            Frame::Ping(ping) => {
+                let _ = self.tx.unbounded_send(Message::Pong(ping));
+                return true;
+            }
+            Frame::Close(reason) => {
+                let _ = self.tx.unbounded_send(Message::Close(reason));
+                return false;
+            }
+        };
+
+        match serde_json::from_slice(&text) {
+            Ok(msg) => self.message(msg),
+            Err(e) => {
+                self.send(json!({
+                    "type": "connection_error",
+                    "payload": { "message": e.to_string() },
+                }));
+                true
+            }
+        }
+    }
+
+    fn message(&mut self, msg: ClientMessage) -> bool {
+        match msg {
+            ClientMessage::ConnectionInit => {
+                self.send(json!({ "type": "connection_ack" }));
+                self.send(json!({ "type": "ka" }));
+                self.keepalive = Some(Interval::new(Instant::now() + KEEPALIVE, KEEPALIVE));
+            }
+            ClientMessage::Start { id, payload } => self.start(id, payload),
+            ClientMessage::Stop { id } => {
+                if self.subscriptions.remove(&id).is_some() {
+                    self.send(json!({ "type": "complete", "id": id }));
+                }
+            }
+            ClientMessage::ConnectionTerminate => {
+                let _ = self.tx.unbounded_send(Message::Close(None));
+                return false;
+            }
+        }
+        true
+    }
+
+    fn start(&mut self, id: String, request: Request) {
+        if self.subscriptions.contains_key(&id) {
+            self.send_error(&id, "an operation with this id is already running");
+            return;
+        }
+
+        let prepared = match Prepared::new(request) {
+            Ok(prepared) => prepared,
+            Err(e) => return self.send_error(&id, &e),
+        };
+        if prepared.operation().kind != OperationKind::Subscription {
+            let payload = self.graphql.execute(&prepared);
+            self.send(json!({ "type": "data", "id": id, "payload": payload }));
+            self.send(json!({ "type": "complete", "id": id }));
+            return;
+        }
+
+        match Subscription::new(prepared) {
+            Ok(subscription) => {
+                self.subscriptions.insert(id, subscription);
+            }
+            Err(e) => self.send_error(&id, &e),
+        }
+    }
+
+    fn event(&self, event: ChatEvent) {
+        if let ChatEvent::Message {
+            id,
+            side,
+            name,
+            body,
+        } = event
+        {
+            let message = message_object(id, side, &name, &body, now_ms());
+            for (id, subscription) in &self.subscriptions {
+                if !subscription.matches(side) {
+                    continue;
+                }
+                let payload = match subscription.data(&message) {
+                    Ok(data) => json!({ "data": data }),
+                    Err(e) => json!({ "data": null, "errors": [{ "message": e }] }),
+                };
+                self.send(json!({ "type": "data", "id": id, "payload": payload }));
+            }
+        }
+    }
+}
+
+impl Future for Session {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        // Frames from the client.
+        let mut budget = EVENTS_PER_TICK;
+        loop {
+            loop {
+                match self.codec.decode(&mut self.buf) {
+                    Ok(Some(frame)) => {
+                        if !self.frame(frame) {
+                            return Ok(Async::Ready(()));
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        logging::error!("graphql_ws_failed"; "graphql websocket error = {:?}", e);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+
+            if budget == 0 {
+                task::current().notify();
+                break;
+            }
+            budget -= 1;
+
+            match self.payload.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.buf.extend_from_slice(&chunk),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    logging::error!("graphql_ws_failed"; "graphql websocket error = {:?}", e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        if let Some(keepalive) = &mut self.keepalive {
+            loop {
+                match keepalive.poll() {
+                    Ok(Async::Ready(_)) => {
+                        let _ = self
+                            .tx
+                            .unbounded_send(Message::Text(json!({ "type": "ka" }).to_string()));
+                    }
+                    Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        logging::error!("graphql_ws_failed"; "graphql websocket error = {:?}", e);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+        }
+
+        for _ in 0..EVENTS_PER_TICK {
+            match self.events.poll()? {
+                Async::Ready(Some(event)) => self.event(event),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
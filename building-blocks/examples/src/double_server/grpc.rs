@@ -0,0 +1,726 @@
+//! gRPC API for programmatic clients.
+//!
+//! The service is defined in `proto/double_server.proto`, clients for any
+//! language can be generated from it. The usual Rust gRPC stack needs a newer
+//! tokio than this crate is built on, so the server speaks HTTP/2 with `h2`
+//! directly and encodes its handful of messages by hand, see `Writer` and
+//! `Reader`.
+//!
+//! The listener expects HTTP/2 without TLS ("prior knowledge"), which is what
+//! gRPC clients use for plain `http://` targets.
+//!
+//! A `Chat` client joins and sends like a peer does, see `gate`: its name
+//! is checked and claimed, and its messages count against the same limits.
+//! It cannot be held back like a peer though, so a message over the global
+//! throttle is dropped whatever `admission` says. A message that is refused
+//! ends the call, with a status saying why.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::sync::mpsc;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::header::{HeaderMap, HeaderValue};
+use http::{Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+
+use std::io;
+use std::net;
+use std::sync::Arc;
+
+use crate::accept::Acceptor;
+use crate::config::Config;
+use crate::conn::{self, Phase};
+use crate::gate;
+use crate::locale::Message;
+use crate::logging;
+use crate::profiling;
+use crate::ratelimit::Limiter;
+use crate::state::{now_ms, ChatEvent, Side, State, StoredMessage};
+use crate::wire::chat_line;
+
+/// Largest request message accepted, the gRPC default.
+const MAX_MESSAGE: usize = 4 << 20;
+
+/// Bytes a `ChatRequest` takes on top of its body, its tag and length.
+const REQUEST_OVERHEAD: usize = 16;
+
+/// How many chat events are streamed per poll, the same fairness rule as
+/// `LINES_PER_TICK` for the peers.
+const EVENTS_PER_TICK: usize = 10;
+
+// The gRPC status codes used here.
+const OK: u32 = 0;
+const INVALID_ARGUMENT: u32 = 3;
+const PERMISSION_DENIED: u32 = 7;
+const RESOURCE_EXHAUSTED: u32 = 8;
+const FAILED_PRECONDITION: u32 = 9;
+const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+
+/// Outcome of a call, sent to the client in the `grpc-status` trailer.
+#[derive(Debug)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: &str) -> Status {
+        Status {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn ok() -> Status {
+        Status::new(OK, "")
+    }
+}
+
+impl From<h2::Error> for Status {
+    fn from(e: h2::Error) -> Status {
+        Status::new(INTERNAL, &e.to_string())
+    }
+}
+
+/// Appends the fields of a protobuf message.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+
+    // Proto3 leaves out scalar fields holding the default value.
+
+    fn uint(&mut self, field: u32, v: u64) {
+        if v != 0 {
+            self.key(field, 0);
+            self.varint(v);
+        }
+    }
+
+    fn string(&mut self, field: u32, v: &str) {
+        if !v.is_empty() {
+            self.key(field, 2);
+            self.varint(v.len() as u64);
+            self.buf.extend_from_slice(v.as_bytes());
+        }
+    }
+
+    /// An embedded message, written even when empty.
+    fn message(&mut self, field: u32, v: &[u8]) {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+}
+
+/// A field value, as far as the wire format tells.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn malformed() -> Status {
+    Status::new(INVALID_ARGUMENT, "malformed message")
+}
+
+/// Iterates over the fields of a protobuf message.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, Status> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.buf.split_first().ok_or_else(malformed)?;
+            self.buf = rest;
+            v |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(malformed())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Status> {
+        if self.buf.len() < n {
+            return Err(malformed());
+        }
+        let (v, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(v)
+    }
+
+    /// The next field number and value, `None` at the end of the message.
+    fn field(&mut self) -> Result<Option<(u32, Value<'a>)>, Status> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            _ => return Err(malformed()),
+        };
+        Ok(Some(((key >> 3) as u32, value)))
+    }
+}
+
+fn string(v: &[u8]) -> Result<String, Status> {
+    String::from_utf8(v.to_vec()).map_err(|_| Status::new(INVALID_ARGUMENT, "string is not utf-8"))
+}
+
+fn side_number(side: Side) -> u64 {
+    match side {
+        Side::C => 0,
+        Side::Go => 1,
+    }
+}
+
+fn side_from_number(v: u64) -> Result<Side, Status> {
+    match v {
+        0 => Ok(Side::C),
+        1 => Ok(Side::Go),
+        _ => Err(Status::new(INVALID_ARGUMENT, "unknown side")),
+    }
+}
+
+// `Event.Kind`
+const JOINED: u64 = 0;
+const MESSAGE: u64 = 1;
+const LEFT: u64 = 2;
+
+fn encode_event(kind: u64, side: Side, sender: &str, id: u64, body: &str, ts: u64) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.uint(1, kind);
+    w.uint(2, side_number(side));
+    w.string(3, sender);
+    w.uint(4, id);
+    w.string(5, body);
+    w.uint(6, ts);
+    w.buf
+}
+
+fn chat_event(event: &ChatEvent) -> Vec<u8> {
+    let ts = now_ms();
+    match event {
+        ChatEvent::Joined { side, name } => encode_event(JOINED, *side, name, 0, "", ts),
+        ChatEvent::Message {
+            id,
+            side,
+            name,
+            body,
+        } => encode_event(MESSAGE, *side, name, *id, body, ts),
+        ChatEvent::Left { side, name } => encode_event(LEFT, *side, name, 0, "", ts),
+    }
+}
+
+fn stored_event(m: &StoredMessage) -> Vec<u8> {
+    encode_event(MESSAGE, m.side, &m.name, m.id, &m.body, m.ts)
+}
+
+/// Prefix a message with the gRPC framing: a compression flag and the length.
+fn frame(msg: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(msg.len() + 5);
+    buf.put_u8(0);
+    buf.put_u32_be(msg.len() as u32);
+    buf.put_slice(msg);
+    buf.freeze()
+}
+
+/// Split the next complete message, of at most `max` bytes, off `buf`.
+fn take_frame(buf: &mut BytesMut, max: usize) -> Result<Option<Bytes>, Status> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(Status::new(
+            UNIMPLEMENTED,
+            "compressed messages are not supported",
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > max {
+        return Err(Status::new(RESOURCE_EXHAUSTED, "message too large"));
+    }
+    if buf.len() < len + 5 {
+        return Ok(None);
+    }
+    buf.split_to(5);
+    Ok(Some(buf.split_to(len).freeze()))
+}
+
+fn response() -> Response<()> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/grpc")
+        .body(())
+        .unwrap()
+}
+
+fn trailers(status: &Status) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(status.code));
+    if let Ok(message) = HeaderValue::from_str(&status.message) {
+        if !status.message.is_empty() {
+            trailers.insert("grpc-message", message);
+        }
+    }
+    trailers
+}
+
+/// End a call that sent no messages with `status`.
+fn fail(respond: &mut SendResponse<Bytes>, status: &Status) {
+    // A "trailers only" response: the status goes into the headers.
+    let mut response = response();
+    response.headers_mut().extend(trailers(status));
+    let _ = respond.send_response(response, true);
+}
+
+/// Accept gRPC connections on `listener`, up to `per_tick` at a time, until
+/// another process takes over.
+pub fn serve(
+    listener: net::TcpListener,
+    state: State,
+    config: Arc<Config>,
+    per_tick: usize,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::from_std(listener, &Handle::default())?;
+    let handed_over = state.drain.handed_over();
+
+    Ok(Acceptor::new(listener, per_tick)
+        .map_err(|e| logging::warn!("accept_failed"; "grpc accept error = {:?}", e))
+        .for_each(move |socket| {
+            // Only fails once the client is gone.
+            if let Ok(addr) = socket.peer_addr() {
+                connection(socket, addr, state.clone(), config.clone());
+            }
+            Ok(())
+        })
+        .select(handed_over)
+        .map(|_| ())
+        .map_err(|_| ()))
+}
+
+/// Spawn a task serving the gRPC connection of the client at `addr` on
+/// `socket`, whatever it was accepted by, see `sniff`.
+pub fn connection<S>(socket: S, addr: net::SocketAddr, state: State, config: Arc<Config>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let span = profiling::span!("grpc_connection", addr = %addr);
+    let connection = h2::server::handshake(socket)
+        .and_then(move |connection| {
+            // Every call runs in its own task, the connection task only
+            // moves frames.
+            connection.for_each(move |(request, respond)| {
+                let span = profiling::span!("grpc_call", path = %request.uri().path());
+                let call = call(request, respond, addr, state.clone(), config.clone());
+                tokio::spawn(profiling::instrument(call, span));
+                Ok(())
+            })
+        })
+        .map_err(move |e| {
+            logging::warn!(
+                "connection_failed", addr = addr;
+                "grpc connection error = {:?}", e
+            )
+        });
+    tokio::spawn(profiling::instrument(connection, span));
+}
+
+/// Dispatch a call to its method.
+fn call(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    addr: net::SocketAddr,
+    state: State,
+    config: Arc<Config>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let grpc = request
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/grpc"));
+    if !grpc {
+        let response = Response::builder()
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body(())
+            .unwrap();
+        let _ = respond.send_response(response, true);
+        return Box::new(future::ok(()));
+    }
+
+    let path = request.uri().path().to_string();
+    let body = request.into_body();
+    match path.as_str() {
+        "/double_server.Chat/Chat" => {
+            Box::new(ChatCall::new(body, respond, addr, state, config))
+        }
+        "/double_server.Chat/ListPeers" => Box::new(unary(body, respond, state, list_peers)),
+        "/double_server.Chat/History" => Box::new(unary(body, respond, state, history)),
+        _ => {
+            fail(&mut respond, &Status::new(UNIMPLEMENTED, "unknown method"));
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+/// Reads a request body to its end.
+struct ReadBody {
+    body: RecvStream,
+    buf: BytesMut,
+}
+
+impl Future for ReadBody {
+    type Item = BytesMut;
+    type Error = Status;
+
+    fn poll(&mut self) -> Poll<BytesMut, Status> {
+        while let Some(chunk) = try_ready!(self.body.poll()) {
+            let _ = self.body.release_capacity().release_capacity(chunk.len());
+            if self.buf.len() + chunk.len() > MAX_MESSAGE + 5 {
+                return Err(Status::new(RESOURCE_EXHAUSTED, "message too large"));
+            }
+            self.buf.extend_from_slice(&chunk);
+        }
+        Ok(Async::Ready(self.buf.take()))
+    }
+}
+
+/// A call with one request and one response message.
+fn unary(
+    body: RecvStream,
+    mut respond: SendResponse<Bytes>,
+    state: State,
+    method: fn(&State, &[u8]) -> Result<Vec<u8>, Status>,
+) -> impl Future<Item = (), Error = ()> {
+    let read = ReadBody {
+        body,
+        buf: BytesMut::new(),
+    };
+
+    read.then(move |res| {
+        let result = res
+            .and_then(|mut buf| {
+                take_frame(&mut buf, MAX_MESSAGE)?
+                    .ok_or_else(|| Status::new(INVALID_ARGUMENT, "missing request message"))
+            })
+            .and_then(|msg| method(&state, &msg));
+
+        match result {
+            Ok(msg) => {
+                if let Ok(mut send) = respond.send_response(response(), false) {
+                    let _ = send.send_data(frame(&msg), false);
+                    let _ = send.send_trailers(trailers(&Status::ok()));
+                }
+            }
+            Err(status) => fail(&mut respond, &status),
+        }
+        Ok(())
+    })
+}
+
+fn list_peers(state: &State, _request: &[u8]) -> Result<Vec<u8>, Status> {
+    let mut w = Writer::default();
+    for &side in &[Side::C, Side::Go] {
+        state.side(side).for_each(|_, member| {
+            let mut peer = Writer::default();
+            peer.uint(1, side_number(side));
+            peer.string(2, &member.name);
+            peer.string(3, &member.addr.to_string());
+            w.message(1, &peer.buf);
+        });
+    }
+    Ok(w.buf)
+}
+
+fn history(state: &State, request: &[u8]) -> Result<Vec<u8>, Status> {
+    let mut limit = 0;
+    let mut before_id = 0;
+    let mut reader = Reader { buf: request };
+    while let Some((field, value)) = reader.field()? {
+        match (field, value) {
+            (1, Value::Varint(v)) => limit = v,
+            (2, Value::Varint(v)) => before_id = v,
+            _ => {}
+        }
+    }
+
+    let before = Some(before_id).filter(|&id| id != 0);
+    let limit = Some(limit as usize).filter(|&limit| limit != 0);
+    let mut w = Writer::default();
+    for message in state.history(before, limit) {
+        w.message(1, &stored_event(&message));
+    }
+    Ok(w.buf)
+}
+
+/// The bidirectional `Chat` call.
+struct ChatCall {
+    state: State,
+    config: Arc<Config>,
+    addr: net::SocketAddr,
+    body: RecvStream,
+    /// `None` once the call is over.
+    send: Option<SendStream<Bytes>>,
+    /// Request data not yet split into messages.
+    buf: BytesMut,
+    /// Side and name given in the join.
+    joined: Option<(Side, String)>,
+    /// The connection the name is claimed for, from the join on.
+    conn: Option<conn::Handle>,
+    /// The rate limits of the client, like those of a peer.
+    limiter: Limiter,
+    /// The chat events, subscribed to on join.
+    events: Option<mpsc::UnboundedReceiver<ChatEvent>>,
+}
+
+impl ChatCall {
+    fn new(
+        body: RecvStream,
+        mut respond: SendResponse<Bytes>,
+        addr: net::SocketAddr,
+        state: State,
+        config: Arc<Config>,
+    ) -> ChatCall {
+        ChatCall {
+            state,
+            limiter: Limiter::new(&config.rate_limits, &config.attachments),
+            config,
+            addr,
+            body,
+            send: respond.send_response(response(), false).ok(),
+            buf: BytesMut::new(),
+            joined: None,
+            conn: None,
+            events: None,
+        }
+    }
+
+    /// End the call with `code`, and `refused` saying why.
+    fn refused(&self, code: u32, refused: &Message) -> Status {
+        let catalog = &self.state.catalog;
+        Status::new(code, &catalog.render(catalog.default_locale(), refused))
+    }
+
+    /// Whether the joined client may send `text`, the steps of `gate`.
+    fn admit(&mut self, side: Side, name: &str, text: &str) -> Result<(), Status> {
+        let text = text.as_bytes();
+        gate::banned(&self.state, name).map_err(|e| self.refused(PERMISSION_DENIED, &e))?;
+        let attached = gate::attached(text).map_err(|e| self.refused(INVALID_ARGUMENT, &e))?;
+        gate::writable(&self.state).map_err(|e| self.refused(FAILED_PRECONDITION, &e))?;
+        let max_attachment = self.config.attachments.max_bytes;
+        gate::rate(&mut self.limiter)
+            .and_then(|()| match attached {
+                Some(size) => gate::attachment(&mut self.limiter, max_attachment, size),
+                None => Ok(()),
+            })
+            .and_then(|()| gate::throttle(&self.state))
+            .and_then(|()| gate::quota(&self.state, side, name, text))
+            .map_err(|e| self.refused(RESOURCE_EXHAUSTED, &e))
+    }
+
+    /// Handle a `ChatRequest`.
+    fn request(&mut self, msg: &[u8]) -> Result<(), Status> {
+        let mut join = None;
+        let mut body = None;
+        let mut reader = Reader { buf: msg };
+        while let Some((field, value)) = reader.field()? {
+            match (field, value) {
+                (1, Value::Bytes(v)) => join = Some(v),
+                (2, Value::Bytes(v)) => body = Some(string(v)?),
+                _ => {}
+            }
+        }
+
+        if let Some(join) = join {
+            return self.join(join);
+        }
+        let (side, name) = match &self.joined {
+            Some(joined) => joined.clone(),
+            None => {
+                return Err(Status::new(
+                    FAILED_PRECONDITION,
+                    "the first request must be a join",
+                ))
+            }
+        };
+
+        // Like for webhooks, the line protocol cannot carry line breaks.
+        for text in body.unwrap_or_default().lines() {
+            // Empty lines are not relayed for peers either, see `wire`.
+            if text.is_empty() {
+                continue;
+            }
+            self.admit(side, &name, text)?;
+
+            let filtered = gate::filter(&self.state, side, &name, text.as_bytes());
+            let body = String::from_utf8_lossy(&filtered).into_owned();
+            let line = chat_line(name.as_bytes(), &filtered);
+            self.state
+                .broadcast(side.other(), None, None, &line.freeze());
+
+            self.state.publish(ChatEvent::Message {
+                id: self.state.next_message_id(),
+                side,
+                name: name.clone(),
+                body,
+            });
+        }
+        Ok(())
+    }
+
+    /// Handle a `Join`.
+    fn join(&mut self, msg: &[u8]) -> Result<(), Status> {
+        if self.joined.is_some() {
+            return Err(Status::new(FAILED_PRECONDITION, "already joined"));
+        }
+
+        let mut side = Side::C;
+        let mut name = String::new();
+        let mut reader = Reader { buf: msg };
+        while let Some((field, value)) = reader.field()? {
+            match (field, value) {
+                (1, Value::Varint(v)) => side = side_from_number(v)?,
+                (2, Value::Bytes(v)) => name = string(v)?,
+                _ => {}
+            }
+        }
+
+        let conn = self.state.connections.open(side, self.addr);
+        let name = gate::name(
+            &self.config.names,
+            &self.state,
+            side,
+            self.addr,
+            name.as_bytes(),
+            conn.id(),
+        )
+        .map_err(|e| self.refused(INVALID_ARGUMENT, &e))?;
+        conn.enter(Phase::Active);
+        self.conn = Some(conn);
+
+        self.events = Some(self.state.subscribe());
+        self.state.publish(ChatEvent::Joined {
+            side,
+            name: name.clone(),
+        });
+        self.joined = Some((side, name));
+        Ok(())
+    }
+
+    fn poll_call(&mut self) -> Poll<(), Status> {
+        // Requests are at most a message long, and taken a few at a time,
+        // like the lines of a peer.
+        // A longer one is refused as soon as its length came in, so no more
+        // than one is buffered.
+        let max = self.config.max_message_bytes + REQUEST_OVERHEAD;
+        let mut budget = self.config.lines_per_tick;
+        loop {
+            while budget > 0 {
+                match take_frame(&mut self.buf, max)? {
+                    Some(msg) => self.request(&msg)?,
+                    None => break,
+                }
+                budget -= 1;
+            }
+            if budget == 0 {
+                task::current().notify();
+                break;
+            }
+            match self.body.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    let _ = self.body.release_capacity().release_capacity(chunk.len());
+                    self.buf.extend_from_slice(&chunk);
+                }
+                // The client is done, and so is the call.
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            }
+        }
+
+        let send = match &mut self.send {
+            Some(send) => send,
+            None => return Ok(Async::Ready(())),
+        };
+        // The client cancelled the call.
+        if let Async::Ready(_) = send.poll_reset()? {
+            self.send = None;
+            return Ok(Async::Ready(()));
+        }
+
+        if let Some(events) = &mut self.events {
+            for i in 0..EVENTS_PER_TICK {
+                // Polling an `UnboundedReceiver` cannot fail.
+                match events.poll().unwrap() {
+                    Async::Ready(Some(event)) => {
+                        send.send_data(frame(&chat_event(&event)), false)?
+                    }
+                    _ => break,
+                }
+
+                if i + 1 == EVENTS_PER_TICK {
+                    task::current().notify();
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Future for ChatCall {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let status = match self.poll_call() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => Status::ok(),
+            Err(status) => status,
+        };
+        if let Some(mut send) = self.send.take() {
+            let _ = send.send_trailers(trailers(&status));
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for ChatCall {
+    fn drop(&mut self) {
+        if let Some((side, name)) = self.joined.take() {
+            if let Some(conn) = &self.conn {
+                self.state.release_name(&name, &conn.id());
+            }
+            self.state.publish(ChatEvent::Left { side, name });
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Peers that are gone without closing their connection.
+//!
+//! A peer that never sends may not be reading either: its program hung, or
+//! its host went away. Waiting for it to send would keep it among the
+//! connected peers forever, and every message buffered for it.
+//!
+//! Every `heartbeat.idle_secs`, a connection without traffic since the last
+//! look gets a probe, an empty line. Servers drop empty messages rather
+//! than relay them (see `wire`), so a probe of a link or a dialed server
+//! goes no further; a client printing what it gets prints an empty line.
+//! A peer that closed its end answers it with a reset, and the next read
+//! fails. The probe, or anything else written to a peer, must then get
+//! through within `heartbeat.timeout_secs`:
+//!
+//! - the socket has to take it, which a peer that stopped reading keeps it
+//!   from once the buffers on the way are full, and
+//! - on Linux, the other host has to acknowledge it, see `set_user_timeout`.
+//!
+//! Otherwise the peer is disconnected.
+
+use building_blocks::wheel::{Timeout, Wheel};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::HeartbeatConfig;
+
+/// What `Heartbeat::poll` found.
+pub enum Beat {
+    /// Write a probe.
+    Probe,
+    /// Writing to the peer got nowhere in time.
+    Dead,
+}
+
+/// Watches the traffic of one connection.
+pub struct Heartbeat {
+    idle: Duration,
+    timeout: Duration,
+    /// When to look at the traffic next, on the wheel of the state.
+    next: Timeout,
+    /// The bytes read and written at the last look.
+    seen: (u64, u64),
+    /// Whether the socket took nothing of what waited at the last look.
+    stalled: bool,
+}
+
+impl Heartbeat {
+    /// `None` if connections are never probed.
+    pub fn new(config: &HeartbeatConfig, timers: &Wheel) -> Option<Heartbeat> {
+        if config.idle_secs == 0 {
+            return None;
+        }
+        let idle = Duration::from_secs(config.idle_secs);
+        Some(Heartbeat {
+            idle,
+            timeout: Duration::from_secs(config.timeout_secs),
+            next: timers.timeout(Instant::now() + idle),
+            seen: (0, 0),
+            stalled: false,
+        })
+    }
+
+    /// Look at the connection once it is time to, given the bytes `read`
+    /// and `written` so far and whether any are `waiting` for the socket.
+    pub fn poll(&mut self, read: u64, written: u64, waiting: bool) -> Poll<Beat, io::Error> {
+        loop {
+            try_ready!(self
+                .next
+                .poll()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+            let (moved, wrote) = ((read, written) != self.seen, written != self.seen.1);
+            self.seen = (read, written);
+            let now = Instant::now();
+
+            if waiting && !wrote {
+                // Once may be a full buffer drained a moment later, twice
+                // in a row is a peer that stopped reading.
+                if self.stalled {
+                    return Ok(Async::Ready(Beat::Dead));
+                }
+                self.stalled = true;
+                self.next.reset(now + self.timeout);
+                continue;
+            }
+            self.stalled = false;
+
+            if waiting || moved {
+                let wait = if waiting { self.timeout } else { self.idle };
+                self.next.reset(now + wait);
+                continue;
+            }
+            // The probe is checked on like anything else written.
+            self.next.reset(now + self.timeout);
+            return Ok(Async::Ready(Beat::Probe));
+        }
+    }
+}
+
+/// Have the kernel give up on `socket` once data written to it goes
+/// unacknowledged for `config.timeout_secs`, so that a probe to a host that
+/// is gone fails without waiting for TCP to retransmit for minutes.
+#[cfg(target_os = "linux")]
+pub fn set_user_timeout(socket: &TcpStream, config: &HeartbeatConfig) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if config.idle_secs == 0 {
+        return Ok(());
+    }
+    let millis = config.timeout_secs.saturating_mul(1000);
+    let millis = millis.min(libc::c_uint::MAX.into()) as libc::c_uint;
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            &millis as *const libc::c_uint as *const libc::c_void,
+            std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Only the socket taking nothing is noticed elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn set_user_timeout(_socket: &TcpStream, _config: &HeartbeatConfig) -> io::Result<()> {
+    Ok(())
+}
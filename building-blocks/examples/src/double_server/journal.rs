@@ -0,0 +1,60 @@
+//! The history written ahead to a log, so that a crash does not lose it.
+//!
+//! ```json
+//! "wal": { "path": "/var/lib/double_server/wal", "fsync": "interval", "fsync_interval_ms": 100 }
+//! ```
+//!
+//! Every message kept in the history is appended to a
+//! `building_blocks::wal` in the directory `path`, and the server started
+//! again reads them back before it accepts peers. `fsync` is one of:
+//!
+//! * `always`, the default: every write is synced, those of the messages
+//!   relayed at the same time together. A crash loses nothing.
+//! * `interval`: synced at most every `fsync_interval_ms`. A crash of the
+//!   machine loses up to that much, one of the server nothing.
+//! * `never`: syncing is left to the OS.
+//!
+//! Messages are relayed without waiting for them to be written, a failure
+//! is logged. The log would only grow, so every `snapshot` starts a new
+//! file first, and removes the older ones once it is saved: recovery reads
+//! the snapshot and what was written after it.
+
+use building_blocks::wal::{Fsync, Wal};
+use futures::Future;
+
+use std::io;
+use std::time::Duration;
+
+use crate::config::{FsyncPolicy, WalConfig};
+use crate::logging;
+use crate::state::StoredMessage;
+
+/// Open the log of `config`, returning it with the messages it has.
+pub fn open(config: &WalConfig) -> io::Result<(Wal, Vec<StoredMessage>)> {
+    let fsync = match config.fsync {
+        FsyncPolicy::Always => Fsync::Always,
+        FsyncPolicy::Interval => Fsync::Interval(Duration::from_millis(config.fsync_interval_ms)),
+        FsyncPolicy::Never => Fsync::Never,
+    };
+    let (wal, records) = Wal::open(&config.path, fsync)?;
+    let mut messages = Vec::with_capacity(records.len());
+    for record in records {
+        let message = serde_json::from_slice(&record).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", config.path.display(), e),
+            )
+        })?;
+        messages.push(message);
+    }
+    Ok((wal, messages))
+}
+
+/// Append `message` to the log of `wal`, on the runtime.
+pub fn append(wal: &Wal, message: &StoredMessage) {
+    let record = serde_json::to_vec(message).expect("messages serialize");
+    let id = message.id;
+    tokio::spawn(wal.append(record).map_err(move |e| {
+        logging::warn!("wal_write_failed"; "message #{} wal write error = {:?}", id, e);
+    }));
+}
@@ -0,0 +1,503 @@
+//! Kafka sink for the message firehose.
+//!
+//! Every message a peer sends is produced to the configured topic as a JSON
+//! document, for analytics pipelines to consume:
+//!
+//! ```json
+//! {"id": 42, "side": "c", "room": null, "sender": "alice", "body": "hi", "ts": 1565000000123}
+//! ```
+//!
+//! `ts` is in milliseconds, like Kafka's own timestamps. The server has no
+//! rooms, so `room` is always null for now.
+//!
+//! Messages are collected into batches of up to `batch_size` messages, or as
+//! many as arrived within `linger_ms` of the first one, and one batch is in
+//! flight at a time. Failed batches are retried after reconnecting, with
+//! the backoff of `building_blocks::retry`, until `max_attempts`. The
+//! outcome of every batch is counted in `Metrics`.
+//!
+//! The producer has a circuit breaker, exported as `kafka`. While it is open
+//! new messages are dropped instead of queued, and the broker is not
+//! connected to. Once it half-opens the next connection is the probe, and
+//! the first batch delivered on it closes it.
+//!
+//! Only the produce API (version 3, so Kafka 0.11 or newer) is implemented.
+//! There is no metadata lookup: the configured broker must be the leader of
+//! the configured partition.
+
+use building_blocks::breaker::{Breaker, Circuit};
+use building_blocks::retry::Backoff;
+use bytes::{BufMut, Bytes};
+use futures::sync::mpsc;
+use serde_json::json;
+use tokio::codec::{Framed, LengthDelimitedCodec};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{CircuitBreakerConfig, KafkaConfig};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::resolve::{self, Connect, Refresh};
+use crate::state::{now_ms, ChatEvent, State};
+
+/// Messages kept while the broker is unreachable. Older ones are dropped
+/// first.
+const MAX_QUEUED: usize = 10_000;
+
+/// How many chat events are queued per poll, the same fairness rule as
+/// `LINES_PER_TICK` for the peers.
+const EVENTS_PER_TICK: usize = 10;
+
+/// How long the broker may take to reach the requested acknowledgements.
+const PRODUCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// A message waiting to be produced.
+struct Record {
+    /// Milliseconds since the epoch.
+    ts: i64,
+    value: Bytes,
+}
+
+fn put_str(dst: &mut Vec<u8>, s: &str) {
+    dst.put_i16_be(s.len() as i16);
+    dst.put_slice(s.as_bytes());
+}
+
+/// Zigzag varint, used for the fields of a record.
+fn put_varint(dst: &mut Vec<u8>, v: i64) {
+    let mut v = ((v << 1) ^ (v >> 63)) as u64;
+    while v >= 0x80 {
+        dst.put_u8(v as u8 | 0x80);
+        v >>= 7;
+    }
+    dst.put_u8(v as u8);
+}
+
+/// CRC-32C (Castagnoli), the checksum of record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Encode `records` as a record batch (magic 2).
+fn record_batch(records: &[Record]) -> Vec<u8> {
+    let first_ts = records.first().map_or(0, |r| r.ts);
+    let max_ts = records.iter().map(|r| r.ts).max().unwrap_or(0);
+
+    // Everything from the attributes on is covered by the CRC.
+    let mut tail = Vec::new();
+    tail.put_i16_be(0); // attributes: no compression, no transaction
+    tail.put_i32_be(records.len() as i32 - 1); // last offset delta
+    tail.put_i64_be(first_ts);
+    tail.put_i64_be(max_ts);
+    tail.put_i64_be(-1); // producer id
+    tail.put_i16_be(-1); // producer epoch
+    tail.put_i32_be(-1); // base sequence
+    tail.put_i32_be(records.len() as i32);
+
+    for (i, record) in records.iter().enumerate() {
+        let mut body = Vec::new();
+        body.put_u8(0); // attributes
+        put_varint(&mut body, record.ts - first_ts);
+        put_varint(&mut body, i as i64);
+        put_varint(&mut body, -1); // no key
+        put_varint(&mut body, record.value.len() as i64);
+        body.put_slice(&record.value);
+        put_varint(&mut body, 0); // no headers
+
+        put_varint(&mut tail, body.len() as i64);
+        tail.extend_from_slice(&body);
+    }
+
+    let mut batch = Vec::with_capacity(tail.len() + 21);
+    batch.put_i64_be(0); // base offset, assigned by the broker
+    batch.put_i32_be(tail.len() as i32 + 9); // length of the rest
+    batch.put_i32_be(-1); // partition leader epoch
+    batch.put_u8(2); // magic
+    batch.put_u32_be(crc32c(&tail));
+    batch.extend_from_slice(&tail);
+    batch
+}
+
+/// A produce request (version 3) for one batch.
+fn produce_request(config: &KafkaConfig, correlation_id: i32, records: &[Record]) -> Vec<u8> {
+    let batch = record_batch(records);
+
+    let mut req = Vec::with_capacity(batch.len() + 64);
+    req.put_i16_be(0); // api key: produce
+    req.put_i16_be(3); // api version
+    req.put_i32_be(correlation_id);
+    put_str(&mut req, &config.client_id);
+    req.put_i16_be(-1); // no transactional id
+    req.put_i16_be(config.acks);
+    req.put_i32_be(PRODUCE_TIMEOUT.as_millis() as i32);
+    req.put_i32_be(1); // topics
+    put_str(&mut req, &config.topic);
+    req.put_i32_be(1); // partitions
+    req.put_i32_be(config.partition);
+    req.put_i32_be(batch.len() as i32);
+    req.extend_from_slice(&batch);
+    req
+}
+
+/// Read the correlation id and the error code of the single partition out
+/// of a produce response.
+fn parse_produce_response(frame: &[u8]) -> io::Result<(i32, i16)> {
+    fn get<'a>(frame: &'a [u8], pos: &mut usize, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = frame
+            .get(*pos..*pos + n)
+            .ok_or_else(|| invalid("truncated produce response"))?;
+        *pos += n;
+        Ok(bytes)
+    }
+    fn get_i32(frame: &[u8], pos: &mut usize) -> io::Result<i32> {
+        let b = get(frame, pos, 4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    let mut pos = 0;
+    let correlation_id = get_i32(frame, &mut pos)?;
+    if get_i32(frame, &mut pos)? != 1 {
+        return Err(invalid("expected one topic in produce response"));
+    }
+    let topic_len = get(frame, &mut pos, 2)?;
+    let topic_len = i16::from_be_bytes([topic_len[0], topic_len[1]]);
+    get(frame, &mut pos, cmp::max(topic_len, 0) as usize)?;
+    if get_i32(frame, &mut pos)? != 1 {
+        return Err(invalid("expected one partition in produce response"));
+    }
+    get_i32(frame, &mut pos)?; // partition
+    let error = get(frame, &mut pos, 2)?;
+
+    Ok((correlation_id, i16::from_be_bytes([error[0], error[1]])))
+}
+
+/// Human readable name of the errors a produce request commonly runs into.
+fn error_name(code: i16) -> String {
+    match code {
+        2 => "CORRUPT_MESSAGE".to_string(),
+        3 => "UNKNOWN_TOPIC_OR_PARTITION".to_string(),
+        6 => "NOT_LEADER_FOR_PARTITION".to_string(),
+        7 => "REQUEST_TIMED_OUT".to_string(),
+        10 => "MESSAGE_TOO_LARGE".to_string(),
+        19 => "NOT_ENOUGH_REPLICAS".to_string(),
+        29 => "TOPIC_AUTHORIZATION_FAILED".to_string(),
+        code => format!("error code {}", code),
+    }
+}
+
+/// Messages waiting for a batch.
+struct Queue {
+    records: VecDeque<Record>,
+    batch_size: usize,
+    linger: Duration,
+    /// When the oldest queued message has lingered long enough. `None`
+    /// means it already has.
+    deadline: Option<Delay>,
+}
+
+impl Queue {
+    fn push(&mut self, record: Record, metrics: &Metrics) {
+        if self.records.is_empty() {
+            self.deadline = Some(Delay::new(Instant::now() + self.linger));
+        }
+        self.records.push_back(record);
+        if self.records.len() > MAX_QUEUED {
+            self.records.pop_front();
+            metrics.kafka_messages_dropped.add(1);
+        }
+    }
+
+    /// The next batch, once it is full or its oldest message lingered long
+    /// enough.
+    fn poll_batch(&mut self) -> Async<Vec<Record>> {
+        if self.records.is_empty() {
+            return Async::NotReady;
+        }
+        if self.records.len() < self.batch_size {
+            // A broken timer only means the batch goes out early.
+            if let Some(Ok(Async::NotReady)) = self.deadline.as_mut().map(Delay::poll) {
+                return Async::NotReady;
+            }
+        }
+
+        let n = cmp::min(self.records.len(), self.batch_size);
+        // Whatever is left has waited at least as long as what was taken.
+        self.deadline = None;
+        Async::Ready(self.records.drain(..n).collect())
+    }
+}
+
+/// A batch being delivered.
+struct Batch {
+    records: Vec<Record>,
+    attempts: u32,
+    /// Correlation id of the request in flight, `None` while the batch waits
+    /// to be (re)sent.
+    sent: Option<i32>,
+}
+
+/// Connection state of the producer.
+enum Conn {
+    /// Waiting before the next connection attempt.
+    Idle(Delay),
+    Connecting(Connect),
+    /// Connected, and watching whether the name of the broker moved.
+    Connected(Framed<TcpStream, LengthDelimitedCodec>, Refresh),
+}
+
+/// The producer, a future that runs for the lifetime of the server.
+pub struct Producer {
+    config: KafkaConfig,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+    metrics: Arc<Metrics>,
+    conn: Conn,
+    queue: Queue,
+    inflight: Option<Batch>,
+    correlation_id: i32,
+    /// When to reconnect, and when to give up on a batch.
+    backoff: Backoff,
+    /// Connections that failed in a row.
+    failures: u32,
+    /// Whether the broker is called at all.
+    breaker: Breaker,
+}
+
+impl Producer {
+    pub fn new(
+        mut config: KafkaConfig,
+        circuit_breaker: &CircuitBreakerConfig,
+        state: State,
+    ) -> Producer {
+        // With acks 0 the broker does not answer at all, and failures could
+        // not be counted.
+        if config.acks != 1 && config.acks != -1 {
+            logging::warn!(
+                "kafka_acks_unsupported";
+                "kafka acks {} is not supported, using 1", config.acks
+            );
+            config.acks = 1;
+        }
+
+        let queue = Queue {
+            records: VecDeque::new(),
+            batch_size: cmp::max(config.batch_size, 1),
+            linger: Duration::from_millis(config.linger_ms),
+            deadline: None,
+        };
+        let conn = Conn::Connecting(resolve::connect(&config.broker));
+        let backoff = Backoff::new(Duration::from_millis(config.reconnect_delay_ms))
+            .max(Duration::from_millis(config.max_reconnect_delay_ms))
+            .jitter(config.reconnect_jitter)
+            .max_attempts(config.max_attempts);
+        let breaker = circuit_breaker.breaker();
+        state.metrics.circuits.register("kafka", &breaker);
+        Producer {
+            config,
+            events: state.subscribe(),
+            metrics: state.metrics.clone(),
+            conn,
+            queue,
+            inflight: None,
+            correlation_id: 0,
+            backoff,
+            failures: 0,
+            breaker,
+        }
+    }
+
+    /// Queue new chat messages.
+    fn poll_events(&mut self) {
+        for _ in 0..EVENTS_PER_TICK {
+            // Polling an `UnboundedReceiver` cannot fail.
+            let (id, side, name, body) = match self.events.poll().unwrap() {
+                Async::Ready(Some(ChatEvent::Message {
+                    id,
+                    side,
+                    name,
+                    body,
+                })) => (id, side, name, body),
+                Async::Ready(Some(_)) => continue,
+                _ => return,
+            };
+
+            if self.breaker.circuit() == Circuit::Open {
+                self.metrics.kafka_messages_dropped.add(1);
+                continue;
+            }
+
+            let ts = now_ms() as i64;
+            let value = json!({
+                "id": id,
+                "side": side.as_str(),
+                "room": null,
+                "sender": name,
+                "body": body,
+                "ts": ts,
+            });
+            let record = Record {
+                ts,
+                value: Bytes::from(value.to_string()),
+            };
+            self.queue.push(record, &self.metrics);
+        }
+        // Out of budget, with events maybe left for the next tick.
+        task::current().notify();
+    }
+
+    /// Drive the connection state machine. Errors are handled by the caller,
+    /// which schedules a reconnect.
+    fn poll_conn(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let next = match self.conn {
+                Conn::Idle(ref mut delay) => {
+                    try_ready!(delay
+                        .poll()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+                    if self.breaker.allow() {
+                        Conn::Connecting(resolve::connect(&self.config.broker))
+                    } else {
+                        let delay = self.backoff.delay(self.failures);
+                        Conn::Idle(Delay::new(Instant::now() + delay))
+                    }
+                }
+                Conn::Connecting(ref mut connect) => {
+                    let socket = try_ready!(connect.poll());
+                    logging::info!(
+                        "kafka_connected", addr = &self.config.broker;
+                        "kafka connected to {}", self.config.broker
+                    );
+                    self.failures = 0;
+                    let refresh = Refresh::new(&self.config.broker, socket.peer_addr()?);
+                    Conn::Connected(Framed::new(socket, LengthDelimitedCodec::new()), refresh)
+                }
+                Conn::Connected(ref mut framed, ref mut refresh) => {
+                    if refresh.poll()?.is_ready() {
+                        return Err(invalid("broker moved to another address"));
+                    }
+
+                    if self.inflight.is_none() {
+                        if let Async::Ready(records) = self.queue.poll_batch() {
+                            self.inflight = Some(Batch {
+                                records,
+                                attempts: 0,
+                                sent: None,
+                            });
+                        }
+                    }
+
+                    if let Some(batch) = &mut self.inflight {
+                        if batch.sent.is_none() {
+                            let id = self.correlation_id.wrapping_add(1);
+                            let request = produce_request(&self.config, id, &batch.records);
+                            if let AsyncSink::Ready = framed.start_send(Bytes::from(request))? {
+                                self.correlation_id = id;
+                                batch.sent = Some(id);
+                                batch.attempts += 1;
+                            }
+                        }
+                    }
+                    framed.poll_complete()?;
+
+                    while let Async::Ready(frame) = framed.poll()? {
+                        let frame = frame.ok_or_else(|| invalid("broker closed the connection"))?;
+                        let (id, error) = parse_produce_response(&frame)?;
+                        match self.inflight {
+                            Some(ref batch) if batch.sent == Some(id) => {}
+                            _ => return Err(invalid("response to an unknown request")),
+                        }
+                        if error != 0 {
+                            return Err(invalid(&error_name(error)));
+                        }
+
+                        let batch = self.inflight.take().unwrap();
+                        self.metrics
+                            .kafka_messages_delivered
+                            .add(batch.records.len() as u64);
+                        self.metrics.kafka_batches_delivered.add(1);
+                        self.breaker.success();
+
+                        // The next batch can go out right away.
+                        task::current().notify();
+                    }
+                    return Ok(Async::NotReady);
+                }
+            };
+            self.conn = next;
+        }
+    }
+
+    /// Account for a failed attempt of the batch in flight, if there was one.
+    fn fail_inflight(&mut self) {
+        let mut batch = match self.inflight.take() {
+            Some(batch) => batch,
+            None => return,
+        };
+        if batch.sent.take().is_some() {
+            self.metrics.kafka_delivery_failures.add(1);
+        }
+
+        if self.backoff.gives_up(batch.attempts) {
+            logging::error!(
+                "kafka_batch_dropped";
+                "kafka batch of {} messages failed, giving up after {} attempts",
+                batch.records.len(),
+                batch.attempts
+            );
+            self.metrics
+                .kafka_messages_dropped
+                .add(batch.records.len() as u64);
+        } else {
+            self.inflight = Some(batch);
+        }
+    }
+}
+
+impl Future for Producer {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_events();
+
+        if let Err(e) = self.poll_conn() {
+            self.breaker.failure();
+            self.failures += 1;
+            let delay = self.backoff.delay(self.failures);
+            logging::warn!(
+                "kafka_disconnected", addr = &self.config.broker;
+                "kafka error = {:?}, reconnecting in {:?}",
+                e, delay
+            );
+            self.fail_inflight();
+            self.conn = Conn::Idle(Delay::new(Instant::now() + delay));
+            // Poll the new delay so that this task is woken up when it
+            // expires.
+            let _ = self.poll_conn();
+        }
+
+        // The producer never finishes on its own.
+        Ok(Async::NotReady)
+    }
+}
@@ -0,0 +1,508 @@
+//! What the server tells peers, in their language.
+//!
+//! ```json
+//! "locales": { "default": "de", "catalogs": { "de": "locales/de.json" } }
+//! ```
+//!
+//! Every line the server itself writes to a peer (notices, the replies to
+//! commands, why its name was turned away) is a message of the catalog,
+//! like `message_too_long` or `name_taken`, with placeholders in braces:
+//!
+//! ```json
+//! { "message_too_long": "Nachricht zu lang, verworfen", "name_taken": "{name} ist vergeben" }
+//! ```
+//!
+//! English is built in, see `ENGLISH` for every message and its
+//! placeholders. A catalog translates any of them, the others stay
+//! English. The server refuses to start on a catalog with a message it does
+//! not know, or a placeholder its message does not have.
+//!
+//! The notices of peers joining and leaving, `announce_join` and
+//! `announce_leave`, are only sent with a template in
+//! `Config::announcements`, which is their English wording:
+//!
+//! ```json
+//! "announcements": { "join": "-> {name} joined the {side} side", "leave": "<- {name} left" }
+//! ```
+//!
+//! A peer gets the `default` locale until it picks another with
+//! `/locale de`, and `/locale` lists them. Names, file names and the
+//! messages of peers are shown as they are, and so are the lines other
+//! programs read, like `SERVER_CLOSING` and the `SLOWDOWN` in front of a
+//! notice.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use crate::config::Config;
+
+/// The index of the locale a peer picked in the catalog, shared with the
+/// rest of the server so that notices of other peers are translated too.
+pub type SharedLocale = Arc<AtomicUsize>;
+
+/// Every message, in English.
+const ENGLISH: &[(&str, &str)] = &[
+    ("retry_in", "in {secs}s"),
+    ("retry_later", "later"),
+    ("list_comma", "{first}, {rest}"),
+    ("list_and", "{first} and {rest}"),
+    ("list_none", "nothing"),
+    (
+        "server_shutting_down",
+        "server is shutting down, try again later",
+    ),
+    (
+        "drain_restarting",
+        "server is restarting, please reconnect within {secs}s",
+    ),
+    (
+        "drain_shutting_down",
+        "server is shutting down in {secs}s, please reconnect later",
+    ),
+    ("line_too_long", "line too long, disconnecting"),
+    ("message_too_long", "message too long, dropped"),
+    (
+        "sending_too_fast",
+        "you are sending too fast, message dropped, try again {retry}",
+    ),
+    (
+        "throttle_dropped",
+        "server is busy, message dropped, try again {retry}",
+    ),
+    (
+        "throttle_delayed",
+        "server is busy, your messages are delayed",
+    ),
+    (
+        "quota_exceeded",
+        "daily quota exceeded ({used} of {limit} bytes used), message dropped",
+    ),
+    ("attach_usage", "usage: /attach <name> <base64> [text]"),
+    ("attach_not_base64", "the data of {name} is not base64"),
+    (
+        "attach_too_large",
+        "attachments may be at most {max}, message dropped",
+    ),
+    (
+        "attach_too_fast",
+        "you are sending too many attachments, message dropped, try again {retry}",
+    ),
+    ("name_not_utf8", "names are UTF-8"),
+    ("name_length", "names are {min} to {max} characters"),
+    ("name_slash", "names may not start with /"),
+    (
+        "name_whitespace",
+        "names may not start or end with whitespace",
+    ),
+    ("name_classes", "names may only have {classes}"),
+    ("class_letters", "letters"),
+    ("class_digits", "digits"),
+    ("class_punctuation", "punctuation"),
+    ("class_spaces", "spaces"),
+    (
+        "name_confusable",
+        "{name} looks too much like {taken}, who is here",
+    ),
+    ("name_taken", "{name} is taken"),
+    ("name_banned", "{name} is banned"),
+    ("unknown_command", "unknown command {command}"),
+    (
+        "command_rate_limited",
+        "{command} is rate limited, try again {retry}",
+    ),
+    ("history_usage", "usage: /history [count]"),
+    ("search_usage", "usage: /search <text>"),
+    ("send_usage", "usage: /send <peer> <file> <size> [port]"),
+    ("accept_usage", "usage: /accept <id>"),
+    ("reject_usage", "usage: /reject <id>"),
+    ("who_side", "{side}: {names}"),
+    ("who_server", "{side} on {server}: {names}"),
+    ("history_message", "#{id} {name} ({side}): {body}"),
+    ("history_empty", "no messages yet"),
+    ("search_empty", "no messages contain {text}"),
+    (
+        "quota_usage",
+        "{used} of {limit} bytes used today, resets at 00:00 UTC",
+    ),
+    ("quota_none", "no quota"),
+    (
+        "stats_peers",
+        "{peers} peers, heaviest first (per second over the last minute, and in total):",
+    ),
+    (
+        "stats_peer",
+        "{name} ({side}) in {in_rate}/s out {out_rate}/s, {in_total} in {out_total} out",
+    ),
+    (
+        "stats_latency",
+        "latency of {side} messages: {quantiles} ({delivered} delivered)",
+    ),
+    (
+        "compress_codecs",
+        "compression codecs: {codecs}, /compress <codec> to pick one",
+    ),
+    (
+        "compress_unknown",
+        "codec {codec} is not offered, try {codecs}",
+    ),
+    (
+        "compress_already",
+        "the connection is compressed with {codec} already",
+    ),
+    ("compress_none", "the connection is not compressed"),
+    (
+        "compress_encoded",
+        "the connection speaks {encoding}, which is not compressed",
+    ),
+    ("compress_started", "compressing with {codec}"),
+    (
+        "locale_list",
+        "locales: {locales}, /locale <locale> to pick one",
+    ),
+    (
+        "locale_unknown",
+        "locale {locale} is not offered, try {locales}",
+    ),
+    ("locale_picked", "messages are in {locale} from here on"),
+    ("announce_join", "{name} joined the {side} side"),
+    ("announce_leave", "{name} left the {side} side"),
+    ("transfer_too_large", "files may be at most {max} long"),
+    (
+        "transfer_needs_port",
+        "files are not relayed, offer them with a port",
+    ),
+    (
+        "transfer_no_peer",
+        "no peer called {name} on the {side} side",
+    ),
+    (
+        "transfer_offer",
+        "{from} offers {file} ({size}) as #{id}, /accept {id} or /reject {id}",
+    ),
+    (
+        "transfer_offered",
+        "offered {file} ({size}) to {to} as #{id}",
+    ),
+    ("transfer_not_yours", "no offer #{id} to you"),
+    (
+        "transfer_expect",
+        "{to} accepted #{id}, expect a connection from {ip}",
+    ),
+    ("transfer_connect", "connect to {ip}:{port} for {file}"),
+    (
+        "transfer_send",
+        "{to} accepted #{id}, send {file} to {listen} with token {token}",
+    ),
+    (
+        "transfer_receive",
+        "receive {file} from {listen} with token {token}",
+    ),
+    ("transfer_not_involved", "no offer #{id} of or to you"),
+    ("transfer_called_off", "#{id} was called off"),
+    ("transfer_calling_off", "called #{id} off"),
+    ("transfer_progress", "#{id} {file} {percent}%"),
+    ("transfer_done", "#{id} {file} transferred ({size})"),
+    (
+        "transfer_ended",
+        "#{id} {file} ended after {sent} of {size} bytes",
+    ),
+    ("transfer_failed", "#{id} {file} failed: {error}"),
+    (
+        "remind_usage",
+        "usage: /remind <delay like 90s, 10m, 2h or 1d> <peer, or #c or #go> <text>",
+    ),
+    ("remind_off", "reminders are turned off"),
+    ("remind_too_far", "reminders may be at most {max} ahead"),
+    (
+        "remind_too_many",
+        "you have {max} reminders waiting already",
+    ),
+    ("remind_set", "reminder #{id} for {to} in {delay}"),
+    ("remind_delivered", "reminder from {name}: {text}"),
+    ("msg_usage", "usage: /msg <peer, or peer@server> <text>"),
+    ("msg_sent", "sent to {to}"),
+    ("msg_unknown", "{to} is not connected"),
+    ("msg_no_route", "no link leads to {server}"),
+    ("msg_received", "{from} to you: {text}"),
+    ("topic_none", "there is no topic"),
+    ("topic_is", "the topic is: {topic} (set by {by})"),
+    ("topic_changed", "{by} set the topic: {topic}"),
+    ("moderation_usage", "usage: {command} <peer>"),
+    ("moderation_denied", "only moderators may use {command}"),
+    ("moderation_done", "{command} {name}: done"),
+    ("moderation_unchanged", "{command} {name}: nothing to do"),
+    (
+        "moderation_banned",
+        "you are banned, your messages are dropped",
+    ),
+    (
+        "moderation_list",
+        "moderators: {moderators}; banned: {banned}",
+    ),
+    (
+        "once_off",
+        "exactly-once delivery is not set up on this server",
+    ),
+    (
+        "once_usage",
+        "usage: /once [id of the last message taken in]",
+    ),
+    (
+        "once_started",
+        "exactly-once delivery on, put an id in front of every message",
+    ),
+    (
+        "once_no_id",
+        "put an id in front of the message, message dropped",
+    ),
+    (
+        "once_failed",
+        "the message could not be taken note of, send it again",
+    ),
+    (
+        "once_gap",
+        "messages #{from} to #{to} are no longer kept, some of them may be lost",
+    ),
+    (
+        "batch_started",
+        "batch started, the messages wait for /commit or /abort",
+    ),
+    (
+        "batch_already",
+        "a batch is open already, /commit or /abort it first",
+    ),
+    (
+        "batch_none",
+        "{command}: no batch is open, /begin one first",
+    ),
+    ("batch_aborted", "batch aborted, nothing of it was relayed"),
+    ("batch_empty", "the batch was empty, nothing was relayed"),
+    (
+        "batch_attachment",
+        "attachments cannot be batched, nothing of the batch will be relayed",
+    ),
+    (
+        "batch_too_large",
+        "the batch got longer than {max} bytes, nothing of it will be relayed",
+    ),
+    ("batch_dropped", "nothing of the batch was relayed"),
+    (
+        "replica_read_only",
+        "this server only answers reads, messages are not relayed here",
+    ),
+    (
+        "replica_command",
+        "this server only answers reads, not {command}",
+    ),
+];
+
+/// A message of the catalog with its placeholders filled in, rendered in
+/// the locale of whoever gets it.
+#[derive(Debug, Clone)]
+pub struct Message {
+    key: &'static str,
+    args: Vec<(&'static str, Arg)>,
+}
+
+#[derive(Debug, Clone)]
+enum Arg {
+    Text(String),
+    /// Rendered in the same locale, like the `{retry}` of a rate limit.
+    Message(Message),
+}
+
+impl Message {
+    pub fn new(key: &'static str) -> Message {
+        debug_assert!(english(key).is_some(), "no message {}", key);
+        Message {
+            key,
+            args: Vec::new(),
+        }
+    }
+
+    /// Fill the placeholder `{name}` with `value`.
+    pub fn with(mut self, name: &'static str, value: impl fmt::Display) -> Message {
+        self.args.push((name, Arg::Text(value.to_string())));
+        self
+    }
+
+    /// Fill the placeholder `{name}` with another message.
+    pub fn with_message(mut self, name: &'static str, value: Message) -> Message {
+        self.args.push((name, Arg::Message(value)));
+        self
+    }
+}
+
+/// The locales the server speaks.
+pub struct Catalog {
+    /// English first, whose templates only replace those of `ENGLISH`.
+    locales: Vec<(String, HashMap<String, String>)>,
+    default: usize,
+}
+
+impl Catalog {
+    /// Read the catalogs of `Config::locales`, and take the templates of
+    /// `Config::announcements`. Fails if a catalog cannot be read or one of
+    /// them does not fit the messages, see the module docs, or the default
+    /// locale is not one of them.
+    pub fn load(config: &Config) -> io::Result<Catalog> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let announcements = &config.announcements;
+        let mut reworded = HashMap::new();
+        for (key, option, template) in &[
+            ("announce_join", "join", &announcements.join),
+            ("announce_leave", "leave", &announcements.leave),
+        ] {
+            if let Some(template) = template {
+                if let Some(unknown) = unknown_placeholder(key, template) {
+                    let message = format!(
+                        "announcements.{} has no placeholder {{{}}}",
+                        option, unknown
+                    );
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+                }
+                reworded.insert(key.to_string(), template.clone());
+            }
+        }
+
+        let config = &config.locales;
+        let mut locales = vec![("en".to_string(), reworded)];
+        let mut tags: Vec<&String> = config.catalogs.keys().collect();
+        tags.sort();
+        for tag in tags {
+            let path = &config.catalogs[tag];
+            let text = fs::read_to_string(path)?;
+            let templates: HashMap<String, String> = serde_json::from_str(&text)
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            for (key, template) in &templates {
+                if english(key).is_none() {
+                    let message = format!("{}: there is no message {}", path.display(), key);
+                    return Err(invalid(message));
+                }
+                if let Some(unknown) = unknown_placeholder(key, template) {
+                    let message = format!(
+                        "{}: {} has no placeholder {{{}}}",
+                        path.display(),
+                        key,
+                        unknown
+                    );
+                    return Err(invalid(message));
+                }
+            }
+            // A catalog for `en` rewords the built in messages.
+            if tag == "en" {
+                locales[0].1.extend(templates);
+            } else {
+                locales.push((tag.clone(), templates));
+            }
+        }
+
+        let default = locales
+            .iter()
+            .position(|(tag, _)| *tag == config.default)
+            .ok_or_else(|| {
+                let message = format!("locales.default {} has no catalog", config.default);
+                io::Error::new(io::ErrorKind::InvalidInput, message)
+            })?;
+        Ok(Catalog { locales, default })
+    }
+
+    /// The locale of peers that did not pick one.
+    pub fn default_locale(&self) -> usize {
+        self.default
+    }
+
+    /// The locale called `tag`, if there is a catalog for it.
+    pub fn find(&self, tag: &str) -> Option<usize> {
+        self.locales.iter().position(|(known, _)| known == tag)
+    }
+
+    pub fn tag(&self, locale: usize) -> &str {
+        &self.locales[locale].0
+    }
+
+    /// Every locale, like `en, de`.
+    pub fn tags(&self) -> String {
+        let tags: Vec<&str> = self.locales.iter().map(|(tag, _)| tag.as_str()).collect();
+        tags.join(", ")
+    }
+
+    /// `message` in `locale`, in English where it has no translation.
+    pub fn render(&self, locale: usize, message: &Message) -> String {
+        fill(message, &|key| {
+            let translated = self
+                .locales
+                .get(locale)
+                .and_then(|(_, templates)| templates.get(key));
+            match translated {
+                Some(template) => template,
+                None => english(key).unwrap_or(key),
+            }
+        })
+    }
+}
+
+/// In English, as logged.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&fill(self, &|key| english(key).unwrap_or(key)))
+    }
+}
+
+/// The template `template` picks for `message`, with the placeholders
+/// filled in. Placeholders without a value are left as they are.
+fn fill<'a>(message: &Message, template: &dyn Fn(&'static str) -> &'a str) -> String {
+    let mut text = String::new();
+    let mut rest = template(message.key);
+    while let Some(open) = rest.find('{') {
+        text.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let arg = after.find('}').and_then(|close| {
+            let name = &after[..close];
+            let (_, arg) = message.args.iter().find(|(n, _)| *n == name)?;
+            Some((close, arg))
+        });
+        match arg {
+            Some((close, arg)) => {
+                match arg {
+                    Arg::Text(value) => text.push_str(value),
+                    Arg::Message(value) => text.push_str(&fill(value, template)),
+                }
+                rest = &after[close + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = after;
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn english(key: &str) -> Option<&'static str> {
+    ENGLISH
+        .iter()
+        .find(|(known, _)| *known == key)
+        .map(|(_, template)| *template)
+}
+
+/// A placeholder of `template` that the message `key` does not have.
+fn unknown_placeholder<'a>(key: &str, template: &'a str) -> Option<&'a str> {
+    let known = placeholders(english(key).unwrap_or(""));
+    let unknown = placeholders(template).difference(&known).next().cloned();
+    unknown
+}
+
+/// The names in braces of `template`.
+fn placeholders(template: &str) -> HashSet<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.find('}').map(|close| &part[..close]))
+        .collect()
+}
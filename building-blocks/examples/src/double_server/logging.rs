@@ -0,0 +1,278 @@
+//! What the server logs to stdout.
+//!
+//! ```text
+//! double_server --log-format json
+//! ```
+//!
+//! Logs are lines of text unless `log_format` (or `--log-format`) is
+//! `json`, which prints one JSON object per event instead, for log
+//! collectors to take apart without patterns:
+//!
+//! ```json
+//! {"ts":"2019-08-05T10:14:07.311Z","level":"info","event":"peer_joined","side":"c","conn":"0.7","addr":"127.0.0.1:50312","name":"alice","detail":"`b\"alice\"` is joining the c side"}
+//! ```
+//!
+//! `event` names what happened, and stays the same when the wording of
+//! `detail`, the text line, changes. `side`, `conn`, `addr` and `name` are
+//! only there for events about a peer or a connection. `conn` tells apart
+//! the connections of one address, see `ConnId`.
+//!
+//! Messages are too many to log each. One in `log_sample_every` (across all
+//! peers) is logged as a `line_received` event, and every message of the
+//! peers an admin traces, see `Sampler` and `admin`.
+//!
+//! The spans of the `profiling` feature are logged by `tracing`, as text
+//! either way.
+//!
+//! Events are logged with `info!`, `warn!` and `error!`, like
+//! `info!("peer_joined", side = side, name = name; "{} joined", name)`.
+
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use crate::conn::ConnId;
+use crate::names;
+use crate::state::{now_ms, Side};
+
+/// Whether events are printed as JSON, set once at startup.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The text of every event, one per line.
+    Text,
+    /// One JSON object per event.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, text or json", s)),
+        }
+    }
+}
+
+pub fn init(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// One event, built by the macros.
+pub struct Event {
+    level: Level,
+    event: &'static str,
+    side: Option<Side>,
+    conn: Option<ConnId>,
+    addr: Option<String>,
+    name: Option<String>,
+}
+
+impl Event {
+    pub fn new(level: Level, event: &'static str) -> Event {
+        Event {
+            level,
+            event,
+            side: None,
+            conn: None,
+            addr: None,
+            name: None,
+        }
+    }
+
+    pub fn side(mut self, side: Side) -> Event {
+        self.side = Some(side);
+        self
+    }
+
+    pub fn conn(mut self, conn: ConnId) -> Event {
+        self.conn = Some(conn);
+        self
+    }
+
+    pub fn addr(mut self, addr: impl fmt::Display) -> Event {
+        self.addr = Some(addr.to_string());
+        self
+    }
+
+    pub fn name(mut self, name: impl fmt::Display) -> Event {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn log(self, detail: fmt::Arguments) {
+        if !JSON.load(Ordering::Relaxed) {
+            println!("{}", detail);
+            return;
+        }
+        let mut line = Map::new();
+        line.insert("ts".into(), rfc3339(now_ms()).into());
+        line.insert("level".into(), self.level.as_str().into());
+        line.insert("event".into(), self.event.into());
+        if let Some(side) = self.side {
+            line.insert("side".into(), side.as_str().into());
+        }
+        if let Some(conn) = self.conn {
+            line.insert("conn".into(), conn.to_string().into());
+        }
+        if let Some(addr) = self.addr {
+            line.insert("addr".into(), addr.into());
+        }
+        if let Some(name) = self.name {
+            line.insert("name".into(), name.into());
+        }
+        line.insert("detail".into(), detail.to_string().into());
+        println!("{}", Value::Object(line));
+    }
+}
+
+/// Log an event at `$level`, see the module docs.
+macro_rules! event {
+    ($level:ident, $event:expr $(, $field:ident = $value:expr)* ; $($arg:tt)+) => {
+        $crate::logging::Event::new($crate::logging::Level::$level, $event)
+            $(.$field($value))*
+            .log(format_args!($($arg)+))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::logging::event!(Info, $($arg)+) };
+}
+
+/// Something failed that the server works around, like by retrying.
+/// (Exported as `warn`, which names a builtin attribute too.)
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::logging::event!(Warn, $($arg)+) };
+}
+
+/// Something failed for good.
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::logging::event!(Error, $($arg)+) };
+}
+
+pub(crate) use error;
+pub(crate) use event;
+pub(crate) use info;
+pub(crate) use warning as warn;
+
+/// Picks the messages that are logged.
+pub struct Sampler {
+    every: u64,
+    seen: AtomicU64,
+    /// Whether `traced` has anyone in it, so that the set is only looked
+    /// at while someone is traced.
+    tracing: AtomicBool,
+    /// The side and name (by `names::key`) of the peers of which every
+    /// message is logged.
+    traced: RwLock<HashSet<(Side, String)>>,
+}
+
+impl Sampler {
+    pub fn new(every: u64) -> Sampler {
+        Sampler {
+            every,
+            seen: AtomicU64::new(0),
+            tracing: AtomicBool::new(false),
+            traced: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Whether to log a message of the peer called `name` on `side`.
+    pub fn sample(&self, side: Side, name: &str) -> bool {
+        if self.seen.fetch_add(1, Ordering::Relaxed) % self.every == 0 {
+            return true;
+        }
+        self.tracing.load(Ordering::Relaxed)
+            && self
+                .traced
+                .read()
+                .unwrap()
+                .contains(&(side, names::key(name)))
+    }
+
+    /// Log every message of the peers called `name` on `side` (in any
+    /// case), or stop to. Applies to peers connecting later too. Answers
+    /// whether that changed anything.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn trace(&self, side: Side, name: &str, on: bool) -> bool {
+        let mut traced = self.traced.write().unwrap();
+        let changed = if on {
+            traced.insert((side, names::key(name)))
+        } else {
+            traced.remove(&(side, names::key(name)))
+        };
+        self.tracing.store(!traced.is_empty(), Ordering::Relaxed);
+        changed
+    }
+
+    /// The peers traced, by side and name.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn traced(&self) -> Vec<(Side, String)> {
+        let mut traced: Vec<_> = self.traced.read().unwrap().iter().cloned().collect();
+        traced.sort_by(|a, b| (a.0.as_str(), &a.1).cmp(&(b.0.as_str(), &b.1)));
+        traced
+    }
+}
+
+/// Milliseconds since the epoch as an RFC 3339 UTC time, like
+/// `2019-08-05T10:14:07.311Z`.
+pub fn rfc3339(ms: u64) -> String {
+    let (days, ms) = (ms / 86_400_000, ms % 86_400_000);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// The date `days` after 1970-01-01, in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Counted from 0000-03-01, so that leap days end the year.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
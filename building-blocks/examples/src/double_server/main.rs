@@ -0,0 +1,2075 @@
+extern crate tokio;
+#[macro_use]
+extern crate futures;
+extern crate bytes;
+
+mod accept;
+mod access;
+#[cfg(feature = "gateway")]
+mod admin;
+mod attachment;
+mod audit;
+mod backfill;
+mod batch;
+mod bridge;
+mod cap;
+mod causal;
+mod commands;
+mod compression;
+mod config;
+mod conn;
+mod dial;
+mod drain;
+mod durability;
+mod encoding;
+mod filter;
+mod frames;
+mod gate;
+#[cfg(feature = "gateway")]
+mod gateway;
+mod geoip;
+#[cfg(feature = "gateway")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod heartbeat;
+mod journal;
+mod kafka;
+mod locale;
+mod logging;
+#[cfg(feature = "gateway")]
+mod matrix;
+mod membership;
+mod memory;
+mod meter;
+mod metrics;
+mod moderation;
+mod mqtt;
+mod names;
+mod nats;
+mod once;
+mod profiling;
+mod protocol;
+mod quota;
+mod ratelimit;
+mod remind;
+mod replica;
+mod reporting;
+mod resolve;
+mod restart;
+mod roster;
+mod schedule;
+mod secret;
+mod snapshot;
+mod sniff;
+mod state;
+mod statsd;
+#[cfg(feature = "tls")]
+mod tls;
+mod transfer;
+mod version;
+mod watchdog;
+#[cfg(feature = "gateway")]
+mod webhook;
+mod wire;
+
+use building_blocks::wheel::Timeout;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future::{self, Either};
+use futures::sync::mpsc;
+use std::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::runtime::Runtime;
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+use crate::accept::Acceptor;
+use crate::attachment::Attachment;
+use crate::batch::Batch;
+use crate::bridge::{Dropped, Hop, Link};
+use crate::cap::{Capabilities, Offer};
+use crate::causal::Relayed;
+use crate::commands::{Command, Reply, Transcript};
+use crate::compression::{Codec, Decoder, Encoder};
+use crate::config::{
+    Admission, Config, InstanceState, NewConnections, ReadBufferConfig, WritePolicy,
+};
+use crate::conn::{ConnId, Phase};
+use crate::encoding::Encoding;
+use crate::gate::retry_after;
+use crate::geoip::Location;
+use crate::heartbeat::{Beat, Heartbeat};
+use crate::locale::{Catalog, Message, SharedLocale};
+use crate::memory::SharedBuffers;
+use crate::meter::SharedTraffic;
+use crate::once::Session;
+use crate::protocol::{Connection, Event};
+use crate::ratelimit::Limiter;
+use crate::restart::{Listeners, Restarter};
+use crate::state::{ChatEvent, Delivery, Member, Rx, Side, State};
+use crate::wire::{chat_line, server_line, Control};
+
+/// Counts the heap for `/metrics`, see `memory`.
+#[cfg(feature = "heap-stats")]
+#[global_allocator]
+static ALLOCATOR: memory::Counting = memory::Counting;
+
+/// Sent instead of a name, asks for the gauges, see `metrics::Stats`.
+const STATS: &[u8] = b"STATS";
+
+/// Most bytes of lines `Peer::hold` lets wait for more.
+const BATCH_BYTES: usize = 64 * 1024;
+
+/// Bytes waiting for the socket below which a transcript takes its next
+/// page, see `Peer::stream_transcript`.
+const TRANSCRIPT_BYTES: usize = 16 * 1024;
+
+/// What a peer is connected over: a `TcpStream`, unless it was accepted
+/// some other way.
+trait Transport: AsyncRead + AsyncWrite + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + 'static> Transport for T {}
+
+/// The state for each connected client.
+struct Peer<S: Transport = TcpStream> {
+    /// Name of the peer.
+    ///
+    /// When a client connects, the first line sent is treated as the client's
+    /// name (like alice or bob). The name is used to preface all messages that
+    /// arrive from the client so that we can simulate a real chat server:
+    ///
+    /// ```text
+    /// alice: Hello everyone.
+    /// bob: Welcome to telnet chat!
+    /// ```
+    name: BytesMut,
+
+    /// Which program the peer is connected as.
+    ///
+    /// The peer is registered in the map of its own side, and everything it
+    /// sends is broadcasted to the peers of the other side.
+    side: Side,
+
+    /// The TCP socket wrapped with the `Lines` codec, defined below.
+    ///
+    /// This handles sending and receiving data on the socket. When using
+    /// `Lines`, we can work at the line level instead of having to manage the
+    /// raw byte operations.
+    lines: Lines<S>,
+
+    /// Handle to the shared chat state.
+    ///
+    /// This is used to broadcast messages read off the socket to all connected
+    /// peers.
+    state: State,
+
+    /// Receive half of the message channel.
+    ///
+    /// This is used to receive messages from peers. When a message is received
+    /// off of this `Rx`, it will be written to the socket.
+    rx: Rx,
+
+    /// Receive half of the control channel, see `State::announce`. Control
+    /// lines are written before the messages waiting for the socket.
+    control: Rx,
+
+    /// The connection of the peer.
+    ///
+    /// The id is used as the key in the `peers` HashMap. It is saved so that
+    /// the `Peer` drop implementation can clean up its entry.
+    id: ConnId,
+
+    /// Keeps the connection listed, in the phase it is in, see `conn`.
+    conn: conn::Handle,
+
+    /// Client socket address, only told in logs and listings.
+    addr: SocketAddr,
+
+    /// How fast the peer may send messages and commands.
+    limiter: Limiter,
+
+    /// A message held back by the global throttle, with the tag of the link
+    /// in front of it, when it was decoded, and when to try it again. No
+    /// more lines are read from the peer until it is relayed.
+    throttled: Option<(BytesMut, Option<Hop>, Instant, Timeout)>,
+
+    /// Whether the peer was told its messages are delayed.
+    slowed_down: bool,
+
+    /// Bandwidth used by the peer, also reachable through its `Member`.
+    traffic: SharedTraffic,
+
+    /// Bytes read and written by `lines` that `traffic` accounts for.
+    metered: (u64, u64),
+
+    /// Capacity of the buffers of `lines`, also reachable through its
+    /// `Member`.
+    buffers: SharedBuffers,
+
+    /// Bytes waiting in the write buffers of `lines` that
+    /// `Metrics::queued_outbound_bytes` accounts for.
+    queued: u64,
+
+    /// The instance the peer connected through, if instances are
+    /// partitioned. The peer only talks to peers of the same one.
+    partition: Option<usize>,
+
+    /// What the peer is told things in, also reachable through its
+    /// `Member`.
+    locale: SharedLocale,
+
+    /// Set if the peer is another server, see `bridge`.
+    link: Option<Arc<Link>>,
+
+    /// Set once the peer asked for exactly-once delivery, see `once`.
+    once: Option<Session>,
+
+    /// What the peer was granted before its name, see `cap`.
+    capabilities: Capabilities,
+
+    /// The messages waiting for `/commit`, see `batch`.
+    batch: Option<Batch>,
+
+    /// Whether the others are told when the peer leaves, see
+    /// `Config::announcements`.
+    announce_leave: bool,
+
+    /// Most lines taken off `rx` per tick, see `Config::lines_per_tick`.
+    lines_per_tick: usize,
+
+    /// How long lines wait to be written together, see
+    /// `Config::flush_delay_ms`, for the throughput write policy. `None`
+    /// writes them right away.
+    flush_delay: Option<Duration>,
+
+    /// When the lines waiting in `lines` get written, if they wait.
+    flush_at: Option<Delay>,
+
+    /// Whether the last flush did not get everything out. The rest is
+    /// written as soon as the socket takes it, without waiting again.
+    flushing: bool,
+
+    /// Messages of other peers buffered in `lines`, with the end of each in
+    /// `bytes_buffered`. They count as delivered once `wr_written` gets
+    /// there.
+    unflushed: VecDeque<(u64, Arc<Delivery>)>,
+
+    /// The reply to `/history` still being sent, if any.
+    transcript: Option<Transcript>,
+
+    /// Most bytes of a frame, see `Config::max_unframed_bytes`, if the peer
+    /// knows of frames, see `frames`.
+    max_frame: Option<usize>,
+
+    /// Most bytes of a message, see `Config::max_message_bytes`.
+    max_message: usize,
+
+    /// Longest attachment, see `Config::attachments`.
+    max_attachment: u64,
+
+    /// Probes the connection while it is idle, unless disabled.
+    heartbeat: Option<Heartbeat>,
+
+    /// Resolves once the server closes the connections of all peers, see
+    /// `drain`.
+    shutdown: Box<dyn Future<Item = (), Error = ()> + Send>,
+
+    /// Whether the connection is being closed, see `close`.
+    closing: bool,
+
+    /// When the peer is told to reconnect after a shutdown, see
+    /// `drain::goodbye`.
+    retry_after: Duration,
+
+    /// Whether the peer closed its half of the connection.
+    read_closed: bool,
+
+    /// Whether the server closed its own.
+    write_closed: bool,
+}
+
+/// Where a new `Peer` came from.
+struct Arrival {
+    /// Opened before the handshake, which claims the name for its id.
+    conn: conn::Handle,
+    addr: SocketAddr,
+    /// The instance it connected through, if instances are partitioned.
+    partition: Option<usize>,
+    location: Location,
+    /// Set if it is another server, see `bridge`.
+    link: Option<Link>,
+    /// What it was granted before its name, see `cap`.
+    capabilities: Capabilities,
+}
+
+impl<S: Transport> Peer<S> {
+    /// Create a new instance of `Peer`.
+    fn new(
+        name: BytesMut,
+        side: Side,
+        state: State,
+        lines: Lines<S>,
+        config: &Config,
+        arrival: Arrival,
+    ) -> Peer<S> {
+        let Arrival {
+            conn,
+            addr,
+            partition,
+            location,
+            link,
+            capabilities,
+        } = arrival;
+        let link = link.map(Arc::new);
+        let id = conn.id();
+        conn.enter(Phase::Active);
+
+        // Create a channel for this peer, and one for its control lines
+        let (tx, rx) = mpsc::unbounded();
+        let (control_tx, control) = mpsc::unbounded();
+
+        let display_name = String::from_utf8_lossy(&name).into_owned();
+
+        // Add an entry for this `Peer` in the shared state map of its side.
+        let traffic = SharedTraffic::default();
+        let buffers = SharedBuffers::default();
+        let locale = Arc::new(AtomicUsize::new(state.catalog.default_locale()));
+        let member = Member {
+            name: display_name.clone(),
+            addr,
+            lifecycle: conn.lifecycle().clone(),
+            tx,
+            control: control_tx,
+            traffic: traffic.clone(),
+            buffers: buffers.clone(),
+            partition,
+            location,
+            locale: locale.clone(),
+            link: link.clone(),
+        };
+        state.side(side).insert(id, member);
+        if link.is_some() {
+            bridge::greet(&state, side, &id);
+            roster::digest(&state, side, &id);
+            moderation::digest(&state, side, &id);
+        } else {
+            roster::changed(&state);
+            if let Some(topic) = state.moderation.topic() {
+                let topic = Message::new("topic_is")
+                    .with("topic", topic.text)
+                    .with("by", topic.by);
+                state.tell(side, &id, &topic);
+            }
+        }
+
+        if config.announcements.join.is_some() {
+            let joined = Message::new("announce_join")
+                .with("name", &display_name)
+                .with("side", side);
+            state.announce(partition, Some(&id), &joined);
+        }
+        state.publish(ChatEvent::Joined {
+            side,
+            name: display_name,
+        });
+
+        let shutdown = Box::new(state.drain.closing());
+        let heartbeat = Heartbeat::new(&config.heartbeat, &state.timers);
+        let max_frame = if lines.connection.knows_frames() {
+            Some(config.max_unframed_bytes)
+        } else {
+            None
+        };
+        Peer {
+            name,
+            side,
+            lines,
+            state,
+            rx,
+            control,
+            id,
+            conn,
+            addr,
+            limiter: Limiter::new(&config.rate_limits, &config.attachments),
+            throttled: None,
+            slowed_down: false,
+            traffic,
+            metered: (0, 0),
+            buffers,
+            queued: 0,
+            partition,
+            locale,
+            link,
+            once: if capabilities.ack {
+                Some(Session::default())
+            } else {
+                None
+            },
+            capabilities,
+            batch: None,
+            announce_leave: config.announcements.leave.is_some(),
+            lines_per_tick: config.lines_per_tick,
+            flush_delay: match (config.write_policy.of(side), config.flush_delay_ms) {
+                (WritePolicy::Latency, _) | (WritePolicy::Throughput, 0) => None,
+                (WritePolicy::Throughput, ms) => Some(Duration::from_millis(ms)),
+            },
+            flush_at: None,
+            flushing: false,
+            unflushed: VecDeque::new(),
+            transcript: None,
+            max_frame,
+            max_message: config.max_message_bytes,
+            max_attachment: config.attachments.max_bytes,
+            heartbeat,
+            shutdown,
+            closing: false,
+            retry_after: Duration::from_secs(config.drain.retry_after_secs),
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+
+    /// Flush `lines`, then tell the messages written out that they arrived.
+    /// Returns `NotReady` while waiting for more lines, see `hold`.
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        if self.hold()? {
+            return Ok(Async::NotReady);
+        }
+        let flushed = self.lines.poll_flush()?;
+        self.flushing = !flushed.is_ready();
+        while let Some((end, _)) = self.unflushed.front() {
+            if *end > self.lines.wr_written {
+                break;
+            }
+            let (_, delivery) = self.unflushed.pop_front().unwrap();
+            delivery.flushed();
+        }
+        Ok(flushed)
+    }
+
+    /// Whether the lines in `lines` should wait for more to be written
+    /// together. They wait `flush_delay` since the first one arrived, unless
+    /// a line of the server is among them or they fill `BATCH_BYTES`.
+    fn hold(&mut self) -> io::Result<bool> {
+        let delay = match self.flush_delay {
+            Some(delay) if !self.flushing && self.transcript.is_none() => delay,
+            _ => return Ok(false),
+        };
+        let lines = &self.lines;
+        if lines.wr.is_empty() || !lines.urgent.is_empty() || lines.wr.len() >= BATCH_BYTES {
+            self.flush_at = None;
+            return Ok(false);
+        }
+
+        let flush_at = self
+            .flush_at
+            .get_or_insert_with(|| Delay::new(Instant::now() + delay));
+        match flush_at
+            .poll()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        {
+            Async::Ready(()) => {
+                self.flush_at = None;
+                Ok(false)
+            }
+            Async::NotReady => Ok(true),
+        }
+    }
+
+    /// Record the bytes transferred since the last call in `traffic`, the
+    /// current size of the buffers in `buffers`, and the bytes waiting to be
+    /// written in the metrics.
+    fn meter(&mut self) {
+        let (read, written) = (self.lines.bytes_read, self.lines.bytes_written);
+        let mut traffic = self.traffic.lock().unwrap();
+        traffic.ingress.record(read - self.metered.0);
+        traffic.egress.record(written - self.metered.1);
+        self.metered = (read, written);
+
+        let lines = &self.lines;
+        let read = lines.connection.capacity() + lines.raw.capacity();
+        let write = lines.wr.capacity() + lines.urgent.capacity() + lines.out.capacity();
+        self.buffers.record(read, write);
+
+        let queued = (lines.wr.len() + lines.urgent.len() + lines.out.len()) as u64;
+        let gauge = &self.state.metrics.queued_outbound_bytes;
+        if queued > self.queued {
+            gauge.add(queued - self.queued);
+        } else {
+            gauge.sub(self.queued - queued);
+        }
+        self.queued = queued;
+    }
+
+    /// Probe the connection if it was idle, see `heartbeat`. Fails if what
+    /// was written to the peer did not get through.
+    fn heartbeat(&mut self) -> io::Result<()> {
+        let heartbeat = match &mut self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(()),
+        };
+        let lines = &self.lines;
+        let waiting = !lines.wr.is_empty() || !lines.urgent.is_empty() || !lines.out.is_empty();
+        match heartbeat.poll(lines.bytes_read, lines.bytes_written, waiting)? {
+            Async::Ready(Beat::Probe) => {
+                self.state.metrics.heartbeat_probes.add(1);
+                self.lines.buffer_urgent(b"\r\n");
+            }
+            Async::Ready(Beat::Dead) => {
+                self.state.metrics.heartbeat_disconnects.add(1);
+                let message = "writes to the peer did not get through";
+                return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+            }
+            Async::NotReady => {}
+        }
+        Ok(())
+    }
+
+    /// Write what is left for the peer and close our half of the connection,
+    /// then wait for the peer to close its own. The peer closed its half
+    /// first, or the server shuts down.
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.conn.enter(Phase::Draining);
+        if !self.write_closed {
+            self.transcript = None;
+            // A peer that does not read what is left goes like any other.
+            self.heartbeat()?;
+            try_ready!(self.flush());
+            try_ready!(self.lines.socket.shutdown());
+            self.write_closed = true;
+        }
+        // Read on and drop what arrives. Closing the socket with data of
+        // the peer unread would reset the connection, and the peer would
+        // lose what it did not read yet.
+        while !self.read_closed {
+            self.read_closed = try_ready!(self.lines.poll()).is_none();
+        }
+        self.meter();
+        Ok(Async::Ready(()))
+    }
+
+    /// `message` in the locale of the peer.
+    fn render(&self, message: &Message) -> String {
+        let locale = self.locale.load(Ordering::Relaxed);
+        self.state.catalog.render(locale, message)
+    }
+
+    /// `lines` as the peer gets them, split into frames if it knows of
+    /// them, see `frames`.
+    fn fit(&self, lines: &Bytes) -> Bytes {
+        match self.max_frame {
+            Some(max) => frames::fit(lines, max),
+            None => lines.clone(),
+        }
+    }
+
+    /// Send a line from the server itself to this peer only, ahead of the
+    /// messages waiting for the socket.
+    fn notice(&mut self, message: &Message) {
+        let text = self.render(message);
+        self.lines.buffer_urgent(&server_line(&text));
+    }
+
+    /// Like `notice`, for a notice of the global throttle. Programs look
+    /// for the `SLOWDOWN` in front, which is not translated.
+    fn slowdown(&mut self, message: &Message) {
+        let text = format!("SLOWDOWN {}", self.render(message));
+        self.lines.buffer_urgent(&server_line(&text));
+    }
+
+    /// Add `message` to the open batch, if there is one and it is not a
+    /// command. Returns the batch on `/commit`, and `message` itself if it
+    /// is not taken.
+    fn batch(&mut self, message: wire::Message) -> Option<wire::Message> {
+        let batch = match &mut self.batch {
+            Some(batch) => batch,
+            None => return Some(message),
+        };
+        let command = match message {
+            wire::Message::Command(command) => command,
+            wire::Message::Chat { text, .. } => {
+                if let Some(failed) = batch.push(&text) {
+                    self.notice(&failed);
+                }
+                return None;
+            }
+            message => return Some(message),
+        };
+        match String::from_utf8_lossy(&command).trim() {
+            "/commit" => match self.batch.take().unwrap().commit() {
+                Ok(text) => Some(wire::Message::Chat { text, hop: None }),
+                Err(e) => {
+                    self.notice(&e);
+                    None
+                }
+            },
+            "/abort" => {
+                self.batch = None;
+                self.notice(&Message::new("batch_aborted"));
+                None
+            }
+            _ => Some(wire::Message::Command(command)),
+        }
+    }
+
+    /// Acknowledge the message of an exactly-once peer with the id `id`.
+    /// Programs look for the `ACK`, which is not translated.
+    fn ack(&mut self, id: &str) {
+        self.lines
+            .buffer_urgent(&server_line(&format!("ACK {}", id)));
+    }
+
+    /// Take the id off `message` of an exactly-once peer, to acknowledge it
+    /// once it is relayed. `None` if it has none, or was relayed before.
+    fn take_id(&mut self, message: &[u8]) -> Option<BytesMut> {
+        let session = self.once.as_mut()?;
+        session.acking = None;
+        let (id, rest) = match once::split(message) {
+            Some(split) => split,
+            None => {
+                self.notice(&Message::new("once_no_id"));
+                return None;
+            }
+        };
+        let dedup = self.state.exactly_once.as_ref()?;
+        let identity = once::identity(self.side, &String::from_utf8_lossy(&self.name));
+        if dedup.seen(&identity, &id) {
+            self.state.metrics.once_duplicates.add(1);
+            self.ack(&id);
+            return None;
+        }
+        let rest = BytesMut::from(rest);
+        self.once.as_mut()?.acking = Some(id);
+        Some(rest)
+    }
+
+    /// Deliver exactly once from here on. A peer that resumes after the
+    /// message `last` is sent those after it first.
+    fn start_once(&mut self, last: Option<u64>) {
+        let mut session = Session::default();
+        self.notice(&Message::new("once_started"));
+        if let Some(last) = last {
+            let (lines, gap) = once::replay(&self.state, self.side, last, &mut session);
+            if let Some(gap) = gap {
+                self.notice(&gap);
+            }
+            for line in lines {
+                let line = self.fit(&line);
+                self.lines.buffer(&line);
+            }
+        }
+        self.once = Some(session);
+    }
+
+    /// Buffer the next page of `transcript`, unless the socket did not take
+    /// enough of what is waiting yet. The pages go with the messages of
+    /// other peers, so that a long transcript does not hold them back like
+    /// notices would.
+    fn stream_transcript(&mut self) {
+        let transcript = match &mut self.transcript {
+            Some(transcript) => transcript,
+            None => return,
+        };
+        // The socket is not writable, `flush` gets us polled once it is.
+        if self.lines.wr.len() >= TRANSCRIPT_BYTES {
+            return;
+        }
+        match transcript.next_page(&self.state) {
+            Some(page) => {
+                for message in page {
+                    let text = self.render(&message);
+                    self.lines.buffer(&server_line(&text));
+                }
+            }
+            None => self.transcript = None,
+        }
+        // Come back for the next page, or for the lines after the last one.
+        task::current().notify();
+    }
+
+    /// Relay `message`, with the tag `hop` of a link in front of it and
+    /// decoded at `decoded`, unless the global throttle holds it back.
+    /// `held` is whether it was held back before.
+    fn admit(&mut self, message: BytesMut, hop: Option<Hop>, decoded: Instant, held: bool) {
+        let wait = match self.state.throttle.take() {
+            Ok(()) => {
+                if !held {
+                    self.slowed_down = false;
+                }
+                if self.charge(&message) {
+                    self.relay(&message, hop, decoded);
+                }
+                return;
+            }
+            Err(wait) => wait,
+        };
+
+        // A wait past any `Instant` is a limit that never refills: holding
+        // the message back would hold it forever, so drop it instead.
+        let until = Instant::now().checked_add(wait);
+        match (self.state.throttle.admission(), until) {
+            (Admission::Reject, _) | (Admission::Delay, None) => {
+                self.state.metrics.throttle_messages_rejected.add(1);
+                let dropped =
+                    Message::new("throttle_dropped").with_message("retry", retry_after(wait));
+                self.slowdown(&dropped);
+            }
+            (Admission::Delay, Some(until)) => {
+                self.state.metrics.throttle_messages_delayed.add(1);
+                // Once per busy spell rather than for every line.
+                if !self.slowed_down {
+                    self.slowed_down = true;
+                    self.slowdown(&Message::new("throttle_delayed"));
+                }
+                let delay = self.state.timers.timeout(until);
+                self.throttled = Some((message, hop, decoded, delay));
+            }
+        }
+    }
+
+    /// Account for `message` in the daily quota of the peer, returning
+    /// whether it may be relayed.
+    fn charge(&mut self, message: &[u8]) -> bool {
+        let name = String::from_utf8_lossy(&self.name);
+        match gate::quota(&self.state, self.side, &name, message) {
+            Ok(()) => true,
+            Err(dropped) => {
+                self.notice(&dropped);
+                false
+            }
+        }
+    }
+
+    /// Send `message` to the other side and to the integrations, unless the
+    /// tag `hop` of a link in front of it says it went around in circles.
+    fn relay(&mut self, message: &[u8], hop: Option<Hop>, decoded: Instant) {
+        let forwarded = match hop {
+            Some(hop) => match self.state.bridges.forward(hop) {
+                Ok(hop) => Some(hop),
+                Err(dropped) => {
+                    let metrics = &self.state.metrics;
+                    match dropped {
+                        Dropped::Expired => metrics.bridge_messages_expired.add(1),
+                        _ => metrics.bridge_messages_dropped.add(1),
+                    }
+                    let name = String::from_utf8_lossy(&self.name);
+                    if self.state.sampler.sample(self.side, &name) {
+                        logging::info!(
+                            "bridge_dropped", side = self.side, conn = self.id, name = &name;
+                            "dropped a message of {}: {:?}", name, dropped
+                        );
+                    }
+                    return;
+                }
+            },
+            None => None,
+        };
+        // Taken note of first, an exactly-once peer sends it again otherwise.
+        let acking = self.once.as_mut().and_then(|session| session.acking.take());
+        if let (Some(ack), Some(dedup)) = (&acking, &self.state.exactly_once) {
+            let name = String::from_utf8_lossy(&self.name);
+            if let Err(e) = dedup.record(&once::identity(self.side, &name), ack) {
+                logging::error!(
+                    "once_save_failed", side = self.side, conn = self.id, name = &name;
+                    "exactly-once save error = {:?}", e
+                );
+                return self.notice(&Message::new("once_failed"));
+            }
+        }
+        let id = self.state.next_message_id();
+        let hop = forwarded.unwrap_or_else(|| {
+            let mut hop = self.state.bridges.local(id);
+            causal::stamp(&self.state, self.side.other(), &mut hop);
+            hop
+        });
+
+        let name = String::from_utf8_lossy(&self.name).into_owned();
+        let filtered = gate::filter(&self.state, self.side, &name, message);
+        let message = &filtered[..];
+
+        // Append the peer's name to the front of the line, and the line
+        // break. Peers knowing of frames get it split as it is sent.
+        let line = chat_line(&self.name, message);
+
+        // We're using `Bytes`, which allows zero-copy clones (by
+        // storing the data in an Arc internally).
+        //
+        // However, before cloning, we must freeze the data. This
+        // converts it from mutable -> immutable, allowing zero copy
+        // cloning.
+        let line = line.freeze();
+
+        // Integrations get what an attachment is about, not its data.
+        let body = match Attachment::parse(message) {
+            Some(Ok(attachment)) => attachment.summary(),
+            _ => String::from_utf8_lossy(message).into_owned(),
+        };
+        let event = ChatEvent::Message {
+            id,
+            side: self.side,
+            name,
+            body,
+        };
+
+        // Now, send the line to all peers of the other side, once those of
+        // other servers it came after were, see `causal`.
+        let relayed = Relayed {
+            side: self.side.other(),
+            from: self.id,
+            partition: self.partition,
+            line,
+            id,
+            decoded,
+            hop,
+            event,
+        };
+        causal::relay(&self.state, relayed);
+        if let Some(ack) = acking {
+            self.ack(&ack);
+        }
+    }
+
+    /// Take in a line of a link about the bridge.
+    fn control(&mut self, control: Control) {
+        let state = &self.state;
+        match control {
+            Control::Direct(direct, text) => bridge::arrive(state, direct, &text),
+            Control::Routes(line) => {
+                if state.bridges.learn(self.side, self.id, &line) == Some(true) {
+                    bridge::announce(state);
+                }
+            }
+            Control::Roster(line) => roster::receive(state, self.side, self.id, &line),
+            Control::Moderation(line) => moderation::receive(state, self.side, self.id, &line),
+        }
+    }
+
+    /// Reply to a line starting with `/`.
+    fn command(&mut self, line: &[u8]) {
+        let command = match Command::parse(&String::from_utf8_lossy(line)) {
+            Ok(command) => command,
+            Err(e) => return self.notice(&e),
+        };
+        if let Err(wait) = self.limiter.command(command.name()) {
+            let limited = Message::new("command_rate_limited")
+                .with("command", command.name())
+                .with_message("retry", retry_after(wait));
+            return self.notice(&limited);
+        }
+        if self.state.read_only && !replica::serves(command.name()) {
+            let refused = Message::new("replica_command").with("command", command.name());
+            return self.notice(&refused);
+        }
+
+        let name = String::from_utf8_lossy(&self.name);
+        match command.run(
+            &self.state,
+            self.side,
+            &name,
+            self.id,
+            self.addr,
+            self.partition,
+        ) {
+            Reply::Lines(lines) => {
+                for line in lines {
+                    self.notice(&line);
+                }
+            }
+            Reply::Transcript(transcript) => {
+                // A transcript asked for before the last one ended replaces it.
+                self.transcript = Some(transcript);
+                self.stream_transcript();
+            }
+            Reply::Compress(codec) => self.compress(codec),
+            Reply::Locale(locale) => {
+                self.locale.store(locale, Ordering::Relaxed);
+                self.lines.too_long = server_line(&self.render(&Message::new("line_too_long")));
+                let tag = self.state.catalog.tag(locale).to_string();
+                self.notice(&Message::new("locale_picked").with("locale", tag));
+            }
+            Reply::Once(last) => self.start_once(last),
+            Reply::Begin if self.batch.is_some() => self.notice(&Message::new("batch_already")),
+            Reply::Begin => {
+                self.batch = Some(Batch::new(self.max_message));
+                self.notice(&Message::new("batch_started"));
+            }
+        }
+    }
+
+    /// Compress the connection with `codec` from here on, see `compression`.
+    fn compress(&mut self, codec: Option<Arc<dyn Codec>>) {
+        if let Some(encoding) = self.lines.encoding {
+            let encoded = Message::new("compress_encoded").with("encoding", encoding.name());
+            return self.notice(&encoded);
+        }
+        if let Some(current) = self.lines.codec {
+            let compressed = Message::new("compress_already").with("codec", current);
+            return self.notice(&compressed);
+        }
+        let codec = match codec {
+            Some(codec) => codec,
+            None => return self.notice(&Message::new("compress_none")),
+        };
+        let started = Message::new("compress_started").with("codec", codec.name());
+        let notice = server_line(&self.render(&started));
+        self.lines.compress(&*codec, &notice);
+    }
+}
+
+/// This is where a connected client is managed.
+///
+/// A `Peer` is also a future representing completely processing the client.
+///
+/// When a `Peer` is created, the first line (representing the client's name)
+/// has already been read. When the socket closes, the `Peer` future completes.
+///
+/// While processing, the peer future implementation will:
+///
+/// 1) Receive messages on its message channel and write them to the socket.
+/// 2) Receive messages from the socket and broadcast them to all peers of the
+///    other side.
+///
+impl<S: Transport> Future for Peer<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        // Tokio (and futures) use cooperative scheduling without any
+        // preemption. If a task never yields execution back to the executor,
+        // then other tasks may be starved.
+        //
+        // To deal with this, robust applications should not have any unbounded
+        // loops. In this example, we will take at most `lines_per_tick` lines
+        // of other peers off the channel on each tick, and `Lines` reads from
+        // the socket at most `reads_per_tick` times.
+        //
+        // If the limit is hit, the current task is notified, informing the
+        // executor to schedule the task again asap.
+        let lines_per_tick = self.lines_per_tick;
+
+        if !self.closing && self.shutdown.poll() != Ok(Async::NotReady) {
+            self.closing = true;
+            // So that the peer does not take the close for a failure.
+            // Programs read it, it is not translated.
+            let goodbye = drain::goodbye(&self.state.drain, self.retry_after);
+            self.lines.buffer_urgent(&server_line(&goodbye));
+        }
+        if self.closing {
+            return self.close();
+        }
+
+        // Control lines first, so that they are not stuck behind messages.
+        for i in 0..lines_per_tick {
+            match self.control.poll().unwrap() {
+                Async::Ready(Some(v)) => {
+                    self.lines.buffer_urgent(&v.line);
+                    if i + 1 == lines_per_tick {
+                        task::current().notify();
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        // Receive all messages from peers.
+        for i in 0..lines_per_tick {
+            // Polling an `UnboundedReceiver` cannot fail, so `unwrap` here is
+            // safe.
+            match self.rx.poll().unwrap() {
+                Async::Ready(Some(v)) => {
+                    // Buffer the line. Once all lines are buffered, they will
+                    // be flushed to the socket (right below).
+                    // An exactly-once peer gets the id of a message in front,
+                    // unless it was sent from the history when it resumed,
+                    // and so do peers that asked for it, see `cap`.
+                    let replayed = match (&mut self.once, &v.delivery) {
+                        (Some(session), Some(delivery)) => session.replayed.remove(&delivery.id),
+                        _ => false,
+                    };
+                    match &v.delivery {
+                        Some(_) if replayed => {}
+                        Some(delivery) => {
+                            let numbered = self.once.is_some();
+                            let line = self.capabilities.tag(delivery, numbered, &v.line);
+                            let line = self.fit(&line);
+                            self.lines.buffer(&line);
+                        }
+                        None => {
+                            let line = self.fit(&v.line);
+                            self.lines.buffer(&line);
+                        }
+                    }
+                    match v.delivery {
+                        Some(delivery) if replayed => delivery.flushed(),
+                        Some(delivery) => self
+                            .unflushed
+                            .push_back((self.lines.bytes_buffered, delivery)),
+                        None => {}
+                    }
+
+                    // If this is the last iteration, the loop will break even
+                    // though there could still be lines to read. Because we did
+                    // not reach `Async::NotReady`, we have to notify ourselves
+                    // in order to tell the executor to schedule the task again.
+                    if i + 1 == lines_per_tick {
+                        task::current().notify();
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        // Then a page of the transcript being sent, if any.
+        self.stream_transcript();
+
+        // Flush the write buffer to the socket
+        let _ = self.flush()?;
+
+        // Read new lines from the socket, at most `lines_per_tick` of them
+        // like above. A client sending faster than we relay would otherwise
+        // keep this loop going forever.
+        let mut handled = 0;
+        loop {
+            // Same as above, we have to come back for the lines left.
+            if handled == lines_per_tick {
+                task::current().notify();
+                break;
+            }
+            handled += 1;
+
+            // A throttled message goes first.
+            if let Some((_, _, _, delay)) = &mut self.throttled {
+                let ready = delay
+                    .poll()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                    .is_ready();
+                if !ready {
+                    break;
+                }
+                let (message, hop, decoded, _) = self.throttled.take().unwrap();
+                self.admit(message, hop, decoded, true);
+                continue;
+            }
+
+            let event = match self.lines.poll()? {
+                Async::Ready(event) => event,
+                Async::NotReady => break,
+            };
+            let decoded = Instant::now();
+            let name = String::from_utf8_lossy(&self.name);
+            if self.state.sampler.sample(self.side, &name) {
+                logging::info!(
+                    "line_received",
+                    side = self.side,
+                    conn = self.id,
+                    addr = self.addr,
+                    name = name;
+                    "Received message ({:?}) : {:?}",
+                    self.name,
+                    event
+                );
+            }
+
+            let message = match event {
+                Some(Event::Message(message)) => message,
+                Some(Event::TooLarge) => {
+                    self.notice(&Message::new("message_too_long"));
+                    continue;
+                }
+                // Taken by `Lines`.
+                Some(Event::Compress(_)) => continue,
+                None => wire::Message::Leave,
+            };
+            let message = match message {
+                // Only the first line, taken by `process`.
+                wire::Message::Join(_) => continue,
+                wire::Message::Control(control) => {
+                    self.control(control);
+                    continue;
+                }
+                wire::Message::Leave => {
+                    // EOF was reached. The remote client has disconnected, once
+                    // it got what is left for it.
+                    self.read_closed = true;
+                    self.closing = true;
+                    return self.close();
+                }
+                message => message,
+            };
+            if self.link.is_none() {
+                if let Err(e) = gate::banned(&self.state, &String::from_utf8_lossy(&self.name)) {
+                    self.notice(&e);
+                    continue;
+                }
+            }
+            let (message, hop) = match self.batch(message) {
+                Some(wire::Message::Chat { text, hop }) => (text, hop),
+                // Commands are answered by the server and not relayed.
+                Some(wire::Message::Command(command)) => {
+                    self.command(&command);
+                    continue;
+                }
+                _ => continue,
+            };
+            let message = if self.once.is_some() && !message.starts_with(b"/") {
+                match self.take_id(&message) {
+                    Some(message) => message,
+                    None => continue,
+                }
+            } else {
+                message
+            };
+
+            // The steps of `gate`, a peer is told why at the first refusing.
+            let admitted = gate::attached(&message).and_then(|attached| {
+                gate::writable(&self.state)?;
+                gate::rate(&mut self.limiter)?;
+                match attached {
+                    Some(size) => gate::attachment(&mut self.limiter, self.max_attachment, size),
+                    None => Ok(()),
+                }
+            });
+            if let Err(e) = admitted {
+                self.notice(&e);
+                continue;
+            }
+
+            self.admit(message, hop, decoded, false);
+        }
+
+        // Probe the connection if it is idle.
+        self.heartbeat()?;
+
+        // Flush the replies to commands and the probe, if any.
+        let _ = self.flush()?;
+
+        self.meter();
+
+        // As always, it is important to not just return `NotReady` without
+        // ensuring an inner future also returned `NotReady`.
+        //
+        // We know we got a `NotReady` from either `self.rx`, `self.lines` or
+        // the delay of a throttled message (and from the heartbeat), or
+        // notified ourselves after running out of budget, so the contract is
+        // respected.
+        Ok(Async::NotReady)
+    }
+}
+
+impl<S: Transport> Drop for Peer<S> {
+    fn drop(&mut self) {
+        self.state.side(self.side).remove(&self.id);
+        if self.link.is_none() {
+            roster::changed(&self.state);
+        } else if self.state.bridges.forget(&self.id) {
+            bridge::announce(&self.state);
+        }
+        self.state
+            .release_name(&String::from_utf8_lossy(&self.name), &self.id);
+        self.state.metrics.queued_outbound_bytes.sub(self.queued);
+
+        let name = String::from_utf8_lossy(&self.name).into_owned();
+        if self.announce_leave {
+            let left = Message::new("announce_leave")
+                .with("name", &name)
+                .with("side", self.side);
+            self.state.announce(self.partition, None, &left);
+        }
+        self.state.publish(ChatEvent::Left {
+            side: self.side,
+            name,
+        });
+    }
+}
+
+/// Line based codec
+///
+/// This decorates a socket and presents a line based read / write interface.
+///
+/// As a user of `Lines`, we can focus on working at the line level. So, we send
+/// and receive values that represent entire lines. The `Lines` codec will
+/// handle the encoding and decoding as well as reading from and writing to the
+/// socket.
+struct Lines<S: Transport = TcpStream> {
+    /// The socket, or what else the peer is connected over.
+    socket: S,
+
+    /// What the peer sent and what it means, see `protocol`. Data is read
+    /// into its buffer, and not returned until an entire message has been
+    /// read.
+    connection: Connection,
+
+    /// Buffer used to stage data before writing it to the socket.
+    wr: BytesMut,
+
+    /// Lines of the server itself, like notices, written before anything
+    /// waiting in `wr` (once the line being written is complete).
+    urgent: BytesMut,
+
+    /// Length of every line in `wr`, oldest first.
+    wr_lines: VecDeque<usize>,
+
+    /// Bytes of the oldest line in `wr` written already.
+    wr_line_written: usize,
+
+    /// Bytes read from the socket so far.
+    bytes_read: u64,
+
+    /// Bytes written to the socket so far.
+    bytes_written: u64,
+
+    /// Bytes buffered in `wr` so far, and written out of it (or handed to
+    /// the encoder).
+    bytes_buffered: u64,
+    wr_written: u64,
+
+    /// Most reads from the socket per tick, see `Config::reads_per_tick`.
+    reads_per_tick: usize,
+
+    /// How the read buffer adapts to the connection, see
+    /// `Config::read_buffer`.
+    read_buffer: ReadBufferConfig,
+
+    /// Room made in the read buffer before the next read.
+    read_size: usize,
+
+    /// What the peer is told when it goes past
+    /// `Config::max_unframed_bytes`, in its locale.
+    too_long: BytesMut,
+
+    /// Name of the codec the connection is compressed with, if any.
+    codec: Option<&'static str>,
+
+    /// Compresses `urgent` and `wr` onto `out` once there is a codec.
+    encoder: Option<Box<dyn Encoder>>,
+
+    /// Decompresses `raw` for the `connection` once there is a codec.
+    decoder: Option<Box<dyn Decoder>>,
+
+    /// Most bytes decompressed and not taken as lines yet, see
+    /// `Config::max_unframed_bytes`.
+    max_plain: usize,
+
+    /// What is read from the socket of a compressed connection.
+    raw: BytesMut,
+
+    /// What is written to the socket of a compressed connection.
+    out: BytesMut,
+
+    /// What the connection speaks instead of lines, see `encoding`. Its
+    /// ends take the place of those of a codec.
+    encoding: Option<Encoding>,
+}
+
+impl<S: Transport> Lines<S> {
+    /// Create a new `Lines` codec backed by the socket
+    fn new(socket: S, config: &Config, catalog: &Catalog) -> Self {
+        let read_buffer = config.read_buffer.clone();
+        Lines {
+            socket,
+            connection: Connection::new(config.max_unframed_bytes, config.max_message_bytes),
+            wr: BytesMut::new(),
+            urgent: BytesMut::new(),
+            wr_lines: VecDeque::new(),
+            wr_line_written: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            bytes_buffered: 0,
+            wr_written: 0,
+            reads_per_tick: config.reads_per_tick,
+            read_size: read_buffer.initial_bytes,
+            read_buffer,
+            too_long: server_line(
+                &catalog.render(catalog.default_locale(), &Message::new("line_too_long")),
+            ),
+            codec: None,
+            encoder: None,
+            decoder: None,
+            max_plain: config.max_unframed_bytes,
+            raw: BytesMut::new(),
+            out: BytesMut::new(),
+            encoding: None,
+        }
+    }
+
+    /// Speak `encoding` from the start, see `encoding`.
+    fn encode(&mut self, encoding: Encoding, config: &Config) {
+        let (encoder, decoder) = encoding.ends(config.max_unframed_bytes, config.max_message_bytes);
+        // Messages are split into frames under the lines, and joined again.
+        self.connection.frames();
+        self.encoding = Some(encoding);
+        self.encoder = Some(encoder);
+        self.decoder = Some(decoder);
+    }
+
+    /// Buffer a line.
+    ///
+    /// This writes the line to an internal buffer. Calls to `poll_flush` will
+    /// attempt to flush this buffer to the socket.
+    fn buffer(&mut self, line: &[u8]) {
+        // Ensure the buffer has capacity. Ideally this would not be unbounded,
+        // but to keep the example simple, we will not limit this.
+        self.wr.reserve(line.len());
+
+        // Push the line onto the end of the write buffer.
+        //
+        // The `put` function is from the `BufMut` trait.
+        self.wr.put(line);
+        self.wr_lines.push_back(line.len());
+        self.bytes_buffered += line.len() as u64;
+    }
+
+    /// Buffer a line that goes before the ones buffered with `buffer`.
+    fn buffer_urgent(&mut self, line: &[u8]) {
+        self.urgent.reserve(line.len());
+        self.urgent.put(line);
+    }
+
+    /// Compress everything written after `notice`, which is not, and
+    /// everything read after the line that asked for it.
+    fn compress(&mut self, codec: &dyn Codec, notice: &[u8]) {
+        // What is waiting goes first, uncompressed and in the order
+        // `poll_flush` would have written it.
+        self.wr_written += self.wr.len() as u64;
+        let rest_of_line = match self.wr_lines.front() {
+            Some(len) => len - self.wr_line_written,
+            None => 0,
+        };
+        let mut plain = if self.wr_line_written > 0 {
+            self.wr.split_to(rest_of_line)
+        } else {
+            BytesMut::new()
+        };
+        plain.extend_from_slice(&self.urgent.take());
+        plain.extend_from_slice(&self.wr.take());
+        plain.extend_from_slice(notice);
+        self.out = plain;
+        self.wr_lines.clear();
+        self.wr_line_written = 0;
+
+        // The peer compressed what it sent after the line, `fill_read_buf`
+        // decompresses it.
+        self.raw = self.connection.unread();
+        self.codec = Some(codec.name());
+        self.encoder = Some(codec.encoder());
+        self.decoder = Some(codec.decoder(self.max_plain));
+    }
+
+    /// Flush the write buffers to the socket, `urgent` first.
+    fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        if self.encoder.is_some() {
+            return self.poll_flush_compressed();
+        }
+
+        // As long as there is buffered data to write, try to write it.
+        while !self.urgent.is_empty() || !self.wr.is_empty() {
+            // Urgent lines may only go between two lines of `wr`. If one is
+            // half written, only the rest of it goes first.
+            if !self.urgent.is_empty() && self.wr_line_written == 0 {
+                let n = try_ready!(self.socket.poll_write(&self.urgent));
+                assert!(n > 0);
+                let _ = self.urgent.split_to(n);
+                self.bytes_written += n as u64;
+                continue;
+            }
+
+            let end = if self.urgent.is_empty() {
+                self.wr.len()
+            } else {
+                self.wr_lines[0] - self.wr_line_written
+            };
+
+            // Try to write some bytes to the socket
+            let n = try_ready!(self.socket.poll_write(&self.wr[..end]));
+
+            // As long as the wr is not empty, a successful write should
+            // never write 0 bytes.
+            assert!(n > 0);
+
+            // This discards the first `n` bytes of the buffer.
+            let _ = self.wr.split_to(n);
+            self.bytes_written += n as u64;
+            self.wr_written += n as u64;
+
+            // Keep track of where the lines begin.
+            self.wr_line_written += n;
+            while let Some(&len) = self.wr_lines.front() {
+                if self.wr_line_written < len {
+                    break;
+                }
+                self.wr_line_written -= len;
+                self.wr_lines.pop_front();
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    /// Like `poll_flush`, for a compressed connection.
+    fn poll_flush_compressed(&mut self) -> Poll<(), io::Error> {
+        if !self.urgent.is_empty() || !self.wr.is_empty() {
+            // Everything waiting is compressed at once, so no line is half
+            // written and the notices can go first.
+            let mut input = self.urgent.take();
+            input.extend_from_slice(&self.wr.take());
+            self.wr_written = self.bytes_buffered;
+            self.wr_lines.clear();
+
+            let encoder = self.encoder.as_mut().unwrap();
+            encoder.encode(&input, &mut self.out)?;
+        }
+
+        while !self.out.is_empty() {
+            let n = try_ready!(self.socket.poll_write(&self.out));
+            assert!(n > 0);
+            let _ = self.out.split_to(n);
+            self.bytes_written += n as u64;
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    /// Read data from the socket.
+    ///
+    /// This only returns `Ready` when the socket has closed. After
+    /// `reads_per_tick` reads it returns `NotReady` even if more data is
+    /// waiting, and schedules the task again to read the rest.
+    fn fill_read_buf(&mut self) -> Poll<(), io::Error> {
+        // Read before the codec was picked, see `compress`.
+        self.decompress()?;
+
+        for _ in 0..self.reads_per_tick {
+            // A compressed connection reads into `raw`, then decompresses.
+            let buf = if self.decoder.is_some() {
+                &mut self.raw
+            } else {
+                self.connection.read_buf()
+            };
+
+            // Ensure the read buffer has capacity.
+            //
+            // This might result in an internal allocation.
+            buf.reserve(self.read_size);
+            let free = buf.capacity() - buf.len();
+
+            // Read data into the buffer.
+            let n = match self.socket.read_buf(buf)? {
+                Async::Ready(n) => n,
+                Async::NotReady => {
+                    self.shrink_read_buf();
+                    return Ok(Async::NotReady);
+                }
+            };
+
+            if n == 0 {
+                return Ok(Async::Ready(()));
+            }
+            self.bytes_read += n as u64;
+            self.decompress()?;
+
+            // What was decompressed is taken as lines before more is, so
+            // that it does not pile up past `max_plain`.
+            if self.codec.is_some() && self.connection.buffered() > 0 {
+                break;
+            }
+
+            // More was probably waiting, make more room next time.
+            if n == free {
+                let grown = self.read_size as f64 * self.read_buffer.growth_factor;
+                self.read_size = self.read_buffer.max_bytes.min(grown as usize);
+            }
+        }
+
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+
+    /// Decompress what is in `raw` for the `connection`, if the connection
+    /// is compressed.
+    fn decompress(&mut self) -> io::Result<()> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) if !self.raw.is_empty() => decoder,
+            _ => return Ok(()),
+        };
+        let codec = self.codec;
+        let name = match (codec, self.encoding) {
+            (Some(codec), _) => codec,
+            (None, Some(encoding)) => encoding.name(),
+            (None, None) => "",
+        };
+        let mut plain = BytesMut::new();
+        decoder.decode(&self.raw, &mut plain).map_err(|e| {
+            // Decompressing too much is like a line too long, see
+            // `compression`. Anything else is not `InvalidData`, that is for
+            // lines too long.
+            let kind = match (codec, e.kind()) {
+                (Some(_), io::ErrorKind::InvalidData) => io::ErrorKind::InvalidData,
+                _ => io::ErrorKind::Other,
+            };
+            io::Error::new(kind, format!("corrupt {} stream: {}", name, e))
+        })?;
+        self.raw.clear();
+        let buffered = self.connection.buffered() + plain.len();
+        if codec.is_some() && buffered > self.max_plain {
+            let e = format!(
+                "{} stream decompresses to more than {} bytes",
+                name, self.max_plain
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        self.connection.feed_bytes(&plain);
+        Ok(())
+    }
+
+    /// Once everything read was taken, let go of a read buffer bigger than
+    /// the recent lines need, and make room for lines like them from now on.
+    fn shrink_read_buf(&mut self) {
+        let rd = self.connection.read_buf();
+        if !self.read_buffer.shrink_when_idle || !rd.is_empty() {
+            return;
+        }
+        let fits = (self.connection.line_size().ceil() as usize).next_power_of_two();
+        let size = fits
+            .max(self.read_buffer.initial_bytes)
+            .min(self.read_buffer.max_bytes);
+        self.read_size = size;
+        // `reserve` may make up to twice the room asked for.
+        let rd = self.connection.read_buf();
+        if rd.capacity() > 2 * size {
+            *rd = BytesMut::new();
+        }
+    }
+}
+
+impl<S: Transport> Stream for Lines<S> {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // First, read any new data that might have been received off the socket
+        let sock_closed = self.fill_read_buf()?.is_ready();
+
+        // Now, try finding messages
+        loop {
+            let event = self.connection.next_event();
+            // The replies of the handshake go before anything else.
+            let reply = self.connection.bytes_to_send();
+            if !reply.is_empty() {
+                self.buffer_urgent(&reply);
+            }
+            match event {
+                Ok(Some(Event::Compress(codec))) => {
+                    self.compress(&*codec, b"");
+                    self.decompress()?;
+                }
+                Ok(Some(event)) => return Ok(Async::Ready(Some(event))),
+                Ok(None) => break,
+                Err(e @ protocol::Error::Unframed(_)) => {
+                    // Tell the peer why, if the socket takes it right away.
+                    let notice = &self.too_long[..];
+                    match &mut self.encoder {
+                        None => {
+                            let _ = self.socket.write(notice);
+                        }
+                        Some(encoder) if self.out.is_empty() => {
+                            if encoder.encode(notice, &mut self.out).is_ok() {
+                                let _ = self.socket.write(&self.out);
+                            }
+                        }
+                        Some(_) => {}
+                    }
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if sock_closed {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Set the options of the socket of a peer of `side` at `addr`.
+fn tune(socket: &TcpStream, side: Side, config: &Config, addr: SocketAddr) {
+    let nodelay = config.write_policy.of(side) == WritePolicy::Latency;
+    if let Err(e) = socket.set_nodelay(nodelay) {
+        logging::warn!(
+            "set_nodelay_failed", side = side, addr = addr;
+            "set_nodelay error = {:?}", e
+        );
+    }
+    if let Err(e) = heartbeat::set_user_timeout(socket, &config.heartbeat) {
+        logging::warn!(
+            "set_user_timeout_failed", side = side, addr = addr;
+            "set_user_timeout error = {:?}", e
+        );
+    }
+}
+
+/// Spawn a task to manage the socket.
+///
+/// This will read the first line from the socket to identify the client, then
+/// add the client to the set of connected peers of `side`.
+fn process(
+    socket: TcpStream,
+    side: Side,
+    state: State,
+    config: Arc<Config>,
+    partition: Option<usize>,
+) {
+    // Only fails once the peer is gone.
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    tune(&socket, side, &config, addr);
+    spawn_peer(socket, addr, side, state, config, partition);
+}
+
+/// Spawn a task speaking the protocol on `socket`, a peer of `side` at
+/// `addr`, whatever it is connected over.
+fn spawn_peer<S: Transport>(
+    socket: S,
+    addr: SocketAddr,
+    side: Side,
+    state: State,
+    config: Arc<Config>,
+    partition: Option<usize>,
+) {
+    let conn = state.connections.open(side, addr);
+    let id = conn.id();
+    let span = profiling::span!("peer", side = %side, conn = %id, addr = %addr);
+    let mut arrival = Arrival {
+        conn,
+        addr,
+        partition,
+        location: state.geoip.locate(addr.ip()),
+        link: None,
+        capabilities: Capabilities::default(),
+    };
+
+    // Wrap the socket with the `Lines` codec that we wrote above.
+    //
+    // By doing this, we can operate at the line level instead of doing raw byte
+    // manipulation.
+    let mut lines = Lines::new(socket, &config, &state.catalog);
+    match config.encoding.of(side) {
+        Some(encoding) => lines.encode(encoding, &config),
+        None => lines.connection.negotiate(Offer::of(&state)),
+    }
+    let metrics = state.metrics.clone();
+    let reporter = state.reporter.clone();
+
+    // The first line is treated as the client's name, once the client asked
+    // for what it wants, see `cap`. The client is not added to the set of
+    // connected peers until this line is received.
+    //
+    // The handshake is a future of `(name, lines)`, where `lines` is the
+    // original stream instance to read the rest from.
+    let connection = cap::handshake(lines)
+        // Process the first line after it as the client's name.
+        .and_then(move |(name, mut lines)| {
+            arrival.capabilities = lines.connection.capabilities().clone();
+            let mut name = match name {
+                Some(name) => name,
+                // The remote client closed the connection without sending
+                // any data.
+                None => return Either::A(Either::A(future::ok(()))),
+            };
+
+            // A monitoring script polling the gauges rather than a peer gets
+            // a line of `Stats`, and a peer with a name breaking the rules
+            // why. Either is disconnected right after.
+            let reply = if &name[..] == STATS {
+                Some(format!("{}\r\n", state.stats()))
+            } else {
+                // A link has the id of its server in front of its name.
+                let link = bridge::parse_login(&name).map(|(remote, rest)| {
+                    let link = Link {
+                        remote: Some(remote),
+                    };
+                    (link, BytesMut::from(rest))
+                });
+                if let Some((link, rest)) = link {
+                    arrival.link = Some(link);
+                    lines.connection.link();
+                    name = rest;
+                }
+                match gate::name(&config.names, &state, side, addr, &name, id) {
+                    Ok(normalized) => {
+                        name = BytesMut::from(normalized.as_bytes());
+                        None
+                    }
+                    Err(e) => {
+                        let text = state.catalog.render(state.catalog.default_locale(), &e);
+                        Some(format!("* {}\r\n", text))
+                    }
+                }
+            };
+            if let Some(reply) = reply {
+                // In the encoding of the connection, if it has one.
+                lines.buffer_urgent(reply.as_bytes());
+                let reply = future::poll_fn(move || lines.poll_flush());
+                return Either::A(Either::B(reply));
+            }
+
+            let from = if arrival.location.is_known() {
+                format!(" from {}", arrival.location)
+            } else {
+                String::new()
+            };
+            logging::info!(
+                "peer_joined",
+                side = side,
+                conn = id,
+                addr = addr,
+                name = String::from_utf8_lossy(&name);
+                "`{:?}` is joining the {} side{}",
+                name,
+                side,
+                from
+            );
+
+            // Create the peer.
+            //
+            // This is also a future that processes the connection, only
+            // completing when the socket closes.
+            let peer = Peer::new(name, side, state, lines, &config, arrival);
+
+            // Wrap `peer` with `Either::B` to make the return type fit.
+            Either::B(peer)
+        })
+        // Task futures have an error of type `()`, this ensures we handle the
+        // error. We do this by printing the error to STDOUT.
+        .map_err(move |e| {
+            // Only `Lines` fails with this kind, the socket does not.
+            if e.kind() == io::ErrorKind::InvalidData {
+                metrics.unframed_disconnects.add(1);
+            }
+            logging::warn!(
+                "connection_failed", side = side, addr = addr;
+                "connection error = {:?}", e
+            );
+            if reporting::unexpected(&e) {
+                reporter.peer_error(side, addr, &e);
+            }
+        });
+
+    // Spawn the task. Internally, this submits the task to a thread pool.
+    tokio::spawn(profiling::instrument(connection, span));
+}
+
+/// Turn a connection made while the server drains away with a notice.
+fn refuse<S: Transport>(socket: S, state: &State) {
+    state.metrics.drain_connections_refused.add(1);
+    let catalog = &state.catalog;
+    let text = catalog.render(
+        catalog.default_locale(),
+        &Message::new("server_shutting_down"),
+    );
+    let refused = tokio::io::write_all(socket, server_line(&text)).then(|_| Ok(()));
+    tokio::spawn(refused);
+}
+
+/// Accept connections on `socket` and hand them to `process` as peers of
+/// `side` in `partition`.
+fn serve(
+    socket: TcpListener,
+    side: Side,
+    state: State,
+    config: Arc<Config>,
+    partition: Option<usize>,
+) -> impl Future<Item = (), Error = ()> {
+    // The listener is dropped once draining starts in `Close` mode, and
+    // once another process took over in any case.
+    let stop = match config.drain.new_connections {
+        NewConnections::Close => Either::A(state.drain.started()),
+        NewConnections::Refuse => Either::B(state.drain.handed_over().map(|_| Instant::now())),
+    }
+    .map(move |_| {
+        logging::info!("listener_stopped", side = side; "{} server stopped accepting", side)
+    });
+    let span = profiling::span!("accept", side = %side, partition = ?partition);
+
+    let accept = Acceptor::new(socket, config.accepts_per_tick)
+        .for_each(move |socket| {
+            if !state.access.admits(&socket) {
+                state.metrics.access_connections_denied.add(1);
+                return Ok(());
+            }
+            if state.drain.is_draining() {
+                refuse(socket, &state);
+                return Ok(());
+            }
+            // Spawn a task to process the connection
+            process(socket, side, state.clone(), config.clone(), partition);
+            Ok(())
+        })
+        .map_err(move |err| {
+            logging::warn!("accept_failed", side = side; "accept error = {:?}", err);
+        })
+        .select(stop)
+        .map(|_| ())
+        .map_err(|_| ());
+    profiling::instrument(accept, span)
+}
+
+pub fn main() -> Result<(), Box<std::error::Error>> {
+    let config = Arc::new(Config::from_args()?);
+    logging::init(config.log_format);
+    profiling::init();
+
+    // Only print the registration the homeserver needs, then stop.
+    #[cfg(feature = "gateway")]
+    if let Some(path) = &config.matrix_registration {
+        matrix::write_registration(&config, path)?;
+        logging::info!(
+            "matrix_registration_written";
+            "wrote matrix registration to {}", path.display()
+        );
+        return Ok(());
+    }
+
+    // Either bound right here, or passed on by the process restarting into
+    // this one, see `restart`.
+    let (listeners, takeover) = Listeners::open(&config)?;
+    logging::info!(
+        "listening", side = Side::C, addr = config.c_listen;
+        "Listening on: {}", config.c_listen
+    );
+    logging::info!(
+        "listening", side = Side::Go, addr = config.go_listen;
+        "Listening on: {}", config.go_listen
+    );
+
+    let state = State::new(&config)?;
+    // The announcements of a snapshot are those the config started with,
+    // as the admin endpoints left them.
+    let restored = match &config.snapshot {
+        Some(saved) => snapshot::restore(&state, &saved.path)?,
+        None => None,
+    };
+    if restored.is_none() {
+        state
+            .schedule
+            .load(&state, &config.scheduled)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    reporting::report_panics(state.reporter.clone());
+
+    // Create the runtime
+    let mut rt = Runtime::new().unwrap();
+
+    // Nothing is served before what the bridge has is taken in, not even
+    // by the integrations.
+    if let Some(backfill) = &config.backfill {
+        let _ = rt.block_on(backfill::run(state.clone(), backfill.clone()));
+    }
+    let deadline = Duration::from_secs(config.drain.deadline_secs);
+    let restarter = Restarter::new(&listeners, state.clone(), deadline);
+
+    // The integrations run on their own thread, see `gateway`.
+    #[cfg(feature = "gateway")]
+    if gateway::needed(&config) {
+        if let Some(addr) = config.http_listen {
+            logging::info!("listening", addr = addr; "Listening on: {} (http)", addr);
+        }
+        if let Some(addr) = config.tls.as_ref().and_then(|tls| tls.http_listen) {
+            logging::info!("listening", addr = addr; "Listening on: {} (https)", addr);
+        }
+        gateway::spawn(
+            config.clone(),
+            state.clone(),
+            listeners.http,
+            listeners.https,
+            restarter.clone(),
+        )?;
+    }
+
+    // Spawn the server tasks, one per side and instance. The kernel spreads
+    // the connections over the instances.
+    let instances = listeners.c.into_iter().zip(listeners.go);
+    for (instance, (c_socket, go_socket)) in instances.enumerate() {
+        let partition = match config.instance_state {
+            InstanceState::Shared => None,
+            InstanceState::Partitioned => Some(instance),
+        };
+        let c_socket = TcpListener::from_std(c_socket, &Handle::default())?;
+        let go_socket = TcpListener::from_std(go_socket, &Handle::default())?;
+        rt.spawn(serve(
+            c_socket,
+            Side::C,
+            state.clone(),
+            config.clone(),
+            partition,
+        ));
+        rt.spawn(serve(
+            go_socket,
+            Side::Go,
+            state.clone(),
+            config.clone(),
+            partition,
+        ));
+    }
+
+    logging::info!(
+        "serving", side = Side::C, addr = config.c_listen;
+        "c server running on {}", config.c_listen
+    );
+    logging::info!(
+        "serving", side = Side::Go, addr = config.go_listen;
+        "go server running on {}", config.go_listen
+    );
+    if config.instances > 1 {
+        logging::info!(
+            "instances";
+            "{} instances per side, {:?}",
+            config.instances, config.instance_state
+        );
+    }
+
+    for dialed in &config.dial {
+        let span = profiling::span!("dial", side = %dialed.side, addr = %dialed.addr);
+        let peer = dial::run(dialed.clone(), state.clone(), config.clone());
+        rt.spawn(profiling::instrument(peer, span));
+    }
+
+    rt.spawn(drain::on_signals(state.clone(), deadline));
+    rt.spawn(restart::on_signal(restarter));
+    if let Some(path) = &config.config_path {
+        let (access, audit) = (state.access.clone(), state.audit.clone());
+        rt.spawn(access::reload_on_signal(access, audit, path.clone()));
+    }
+
+    let watchdog = watchdog::Watchdog::new(config.watchdog.clone(), state.clone());
+    rt.spawn(profiling::instrument(
+        watchdog,
+        profiling::span!("watchdog"),
+    ));
+    rt.spawn(profiling::instrument(
+        state.schedule.run(),
+        profiling::span!("schedule"),
+    ));
+    let timers = state.timers.run().map_err(|e| {
+        logging::error!("timers_stopped"; "timer wheel error = {:?}", e);
+    });
+    rt.spawn(profiling::instrument(timers, profiling::span!("timers")));
+
+    if let Some(quotas) = &state.quotas {
+        rt.spawn(quotas.persist());
+    }
+    rt.spawn(state.reminders.deliver(state.clone()));
+    rt.spawn(state.reminders.persist());
+    if let Some(snapshot) = config.snapshot.as_ref().filter(|s| s.every_secs > 0) {
+        rt.spawn(snapshot::checkpoint(state.clone(), snapshot.clone()));
+    }
+    if let Some(mqtt) = &config.mqtt {
+        let bridge = mqtt::Bridge::new(mqtt.clone(), state.clone());
+        rt.spawn(profiling::instrument(bridge, profiling::span!("mqtt")));
+    }
+    if let Some(kafka) = &config.kafka {
+        let producer = kafka::Producer::new(kafka.clone(), &config.circuit_breaker, state.clone());
+        rt.spawn(profiling::instrument(producer, profiling::span!("kafka")));
+    }
+    if let Some(nats) = &config.nats {
+        let fanout = nats::Fanout::new(nats.clone(), state.clone());
+        rt.spawn(profiling::instrument(fanout, profiling::span!("nats")));
+    }
+    let every = Duration::from_secs(config.bridge.sync_secs.max(1));
+    rt.spawn(roster::sync(state.clone(), every));
+    rt.spawn(moderation::sync(state.clone(), every));
+    if state.causal.enabled() {
+        rt.spawn(causal::expire(state.clone()));
+    }
+    if let Some(membership) = &config.membership {
+        let members = membership::start(membership, &state)?;
+        rt.spawn(profiling::instrument(
+            members,
+            profiling::span!("membership"),
+        ));
+    }
+    if let Some(statsd) = &config.statsd {
+        let exporter = statsd::Exporter::new(statsd.clone(), state.clone());
+        rt.spawn(profiling::instrument(exporter, profiling::span!("statsd")));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(listener) = listeners.grpc {
+        let addr = listener.local_addr()?;
+        logging::info!("listening", addr = addr; "Listening on: {} (grpc)", addr);
+        rt.spawn(grpc::serve(
+            listener,
+            state.clone(),
+            config.clone(),
+            config.accepts_per_tick,
+        )?);
+    }
+    if let Some(listener) = listeners.transfer {
+        let addr = listener.local_addr()?;
+        logging::info!("listening", addr = addr; "Listening on: {} (transfers)", addr);
+        rt.spawn(transfer::serve(
+            listener,
+            state.clone(),
+            config.accepts_per_tick,
+        )?);
+    }
+
+    if let Some(listener) = listeners.replication {
+        let addr = listener.local_addr()?;
+        logging::info!("listening", addr = addr; "Listening on: {} (replication)", addr);
+        rt.spawn(replica::serve(
+            listener,
+            state.clone(),
+            config.accepts_per_tick,
+        )?);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(listener), Some(tls)) = (listeners.tls, &config.tls) {
+        let addr = listener.local_addr()?;
+        logging::info!("listening", addr = addr; "Listening on: {} (tls)", addr);
+        rt.spawn(tls::serve(
+            listener,
+            tls::Acceptor::new(tls)?,
+            state.clone(),
+            config.clone(),
+        )?);
+    }
+    if let (Some(listener), Some(single)) = (listeners.single, &config.single_port) {
+        let addr = listener.local_addr()?;
+        logging::info!("listening", addr = addr; "Listening on: {} (single port)", addr);
+        rt.spawn(sniff::serve(
+            listener,
+            single.clone(),
+            state.clone(),
+            config.clone(),
+        )?);
+    }
+    if let Some(replica) = &config.replica {
+        rt.spawn(replica::follow(state.clone(), replica.clone()));
+    }
+
+    // Everything is serving, the old process can let go.
+    if let Some(takeover) = takeover {
+        takeover.ready()?;
+    }
+
+    // Serve until draining is over, then stop every task before saving
+    // what they left behind.
+    let linger = Duration::from_secs(config.drain.linger_secs);
+    let _ = rt.block_on(drain::run(state.clone(), linger));
+    drop(rt);
+    if let Some(quotas) = &state.quotas {
+        quotas.save()?;
+    }
+    state.reminders.save()?;
+    Ok(())
+}
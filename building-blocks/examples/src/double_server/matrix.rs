@@ -0,0 +1,472 @@
+//! Matrix application service bridge.
+//!
+//! The server registers with a homeserver as an application service and
+//! mirrors the conversation into one Matrix room:
+//!
+//! * every c and go peer gets a puppet user (`@chat_c_alice:example.org`)
+//!   that joins the room when the peer connects, speaks for it, and leaves
+//!   when it disconnects;
+//! * messages posted in the room by real Matrix users are relayed to the
+//!   peers of both sides.
+//!
+//! Run the server once with `--matrix-registration <path>` to produce the
+//! registration file that has to be added to the homeserver's
+//! configuration.
+
+use actix_web::client::{Client, ClientRequest};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use bytes::{BufMut, BytesMut};
+use futures::sync::mpsc;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tokio::prelude::*;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, MatrixConfig};
+use crate::logging;
+use crate::secret;
+use crate::state::{ChatEvent, Side, State};
+
+/// How many transaction IDs are remembered to drop the homeserver's retries.
+const SEEN_TRANSACTIONS: usize = 256;
+
+/// Largest transaction body accepted from the homeserver.
+const MAX_TRANSACTION: usize = 1 << 20;
+
+/// Write the registration file the homeserver needs to know about the bridge.
+pub fn write_registration(config: &Config, path: &Path) -> Result<(), Box<dyn Error>> {
+    let matrix = config
+        .matrix
+        .as_ref()
+        .ok_or("the config has no matrix section")?;
+    let url = config
+        .http_listen
+        .ok_or("the matrix bridge needs http_listen to be set")?;
+
+    let registration = format!(
+        "id: double_server\n\
+         url: http://{url}\n\
+         as_token: {as_token}\n\
+         hs_token: {hs_token}\n\
+         sender_localpart: {sender}\n\
+         namespaces:\n  \
+           users:\n    \
+             - exclusive: true\n      \
+               regex: '@{prefix}.*:{server}'\n  \
+           aliases: []\n  \
+           rooms: []\n",
+        url = url,
+        as_token = matrix.as_token,
+        hs_token = matrix.hs_token,
+        sender = matrix.sender_localpart,
+        prefix = regex_escape(&matrix.user_prefix),
+        server = regex_escape(&matrix.server_name),
+    );
+
+    fs::write(path, registration)?;
+    Ok(())
+}
+
+/// The user ID of the puppet standing in for peer `name` of `side`.
+fn puppet_id(config: &MatrixConfig, side: Side, name: &str) -> String {
+    let mut localpart = format!("{}{}_", config.user_prefix, side);
+
+    // Localparts are restricted to lowercase ASCII and a few symbols. Escape
+    // everything else the way most bridges do, as `=` followed by the hex
+    // value of the byte.
+    for b in name.bytes() {
+        match b {
+            b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => localpart.push(b as char),
+            _ => localpart.push_str(&format!("={:02x}", b)),
+        }
+    }
+
+    format!("@{}:{}", localpart, config.server_name)
+}
+
+/// Whether `user_id` belongs to the bridge itself, so that the echo of our
+/// own messages is not relayed back to the peers.
+fn is_bridged(config: &MatrixConfig, user_id: &str) -> bool {
+    let server = format!(":{}", config.server_name);
+    let bot = format!("@{}{}", config.sender_localpart, server);
+    let prefix = format!("@{}", config.user_prefix);
+
+    user_id == bot || (user_id.starts_with(&prefix) && user_id.ends_with(&server))
+}
+
+/// Percent-encode `s` for use in a URL path segment or query value.
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Outbound half: turns chat events into Matrix API calls.
+///
+/// This future has to run on the gateway's actix system, the HTTP client is
+/// bound to the thread it was created on.
+pub fn relay(
+    config: MatrixConfig,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+) -> impl Future<Item = (), Error = ()> {
+    let relay = Rc::new(Relay::new(config));
+
+    // Events are handled one at a time so that the messages show up in the
+    // room in the order the peers sent them.
+    events.for_each(move |event| {
+        relay.handle(event).then(|res| {
+            if let Err(e) = res {
+                logging::warn!("matrix_relay_failed"; "matrix relay error = {}", e);
+            }
+            Ok(())
+        })
+    })
+}
+
+type Call = Box<dyn Future<Item = (), Error = String>>;
+
+struct Relay {
+    client: Client,
+    config: MatrixConfig,
+
+    /// Puppets that are registered and joined to the room.
+    joined: RefCell<HashSet<String>>,
+
+    /// Transaction IDs only need to be unique per access token. Prefixing
+    /// them with the start time keeps them unique across restarts.
+    epoch: u64,
+    next_txn: Cell<u64>,
+}
+
+impl Relay {
+    fn new(config: MatrixConfig) -> Relay {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Relay {
+            client: Client::default(),
+            config,
+            joined: RefCell::new(HashSet::new()),
+            epoch,
+            next_txn: Cell::new(0),
+        }
+    }
+
+    fn handle(self: &Rc<Self>, event: ChatEvent) -> Call {
+        match event {
+            ChatEvent::Joined { side, name } => self.ensure_puppet(side, &name),
+            ChatEvent::Message {
+                side, name, body, ..
+            } => {
+                let this = self.clone();
+                let user_id = puppet_id(&self.config, side, &name);
+                Box::new(
+                    self.ensure_puppet(side, &name)
+                        .and_then(move |()| this.send(&user_id, &body)),
+                )
+            }
+            ChatEvent::Left { side, name } => self.leave(side, &name),
+        }
+    }
+
+    /// Register the puppet for a peer, set its display name and join it to
+    /// the room, unless that already happened.
+    fn ensure_puppet(self: &Rc<Self>, side: Side, name: &str) -> Call {
+        let user_id = puppet_id(&self.config, side, name);
+        if !self.joined.borrow_mut().insert(user_id.clone()) {
+            return Box::new(future::ok(()));
+        }
+
+        let localpart = user_id[1..user_id.find(':').unwrap()].to_string();
+        let register = self.client.post(self.url("/register", None));
+        let register = call(
+            register,
+            &self.config.as_token,
+            json!({ "type": "m.login.application_service", "username": localpart }),
+        )
+        // Registering a puppet that exists from an earlier run fails with
+        // `M_USER_IN_USE`, which is fine.
+        .and_then(|status| expect(status, "register", &[StatusCode::BAD_REQUEST]));
+
+        let profile = self.client.put(self.url(
+            &format!("/profile/{}/displayname", encode(&user_id)),
+            Some(&user_id),
+        ));
+        let profile = call(
+            profile,
+            &self.config.as_token,
+            json!({ "displayname": name }),
+        );
+
+        let join = self.client.post(self.url(
+            &format!("/rooms/{}/join", encode(&self.config.room_id)),
+            Some(&user_id),
+        ));
+        let join = call(join, &self.config.as_token, json!({}));
+
+        let this = self.clone();
+        Box::new(
+            register
+                .and_then(move |()| profile.and_then(|status| expect(status, "displayname", &[])))
+                .and_then(move |()| join.and_then(|status| expect(status, "join", &[])))
+                .map_err(move |e| {
+                    // Try again with the next event of this peer.
+                    this.joined.borrow_mut().remove(&user_id);
+                    e
+                }),
+        )
+    }
+
+    /// Post `body` to the room as `user_id`.
+    fn send(&self, user_id: &str, body: &str) -> Call {
+        let txn = self.next_txn.get();
+        self.next_txn.set(txn + 1);
+
+        let request = self.client.put(self.url(
+            &format!(
+                "/rooms/{}/send/m.room.message/{}-{}",
+                encode(&self.config.room_id),
+                self.epoch,
+                txn
+            ),
+            Some(user_id),
+        ));
+        Box::new(
+            call(
+                request,
+                &self.config.as_token,
+                json!({ "msgtype": "m.text", "body": body }),
+            )
+            .and_then(|status| expect(status, "send", &[])),
+        )
+    }
+
+    /// Take the puppet of a disconnected peer out of the room.
+    fn leave(&self, side: Side, name: &str) -> Call {
+        let user_id = puppet_id(&self.config, side, name);
+        if !self.joined.borrow_mut().remove(&user_id) {
+            return Box::new(future::ok(()));
+        }
+
+        let request = self.client.post(self.url(
+            &format!("/rooms/{}/leave", encode(&self.config.room_id)),
+            Some(&user_id),
+        ));
+        Box::new(
+            call(request, &self.config.as_token, json!({}))
+                .and_then(|status| expect(status, "leave", &[])),
+        )
+    }
+
+    /// URL of a client-server API endpoint, optionally acting as `user_id`.
+    fn url(&self, endpoint: &str, user_id: Option<&str>) -> String {
+        let mut url = format!(
+            "{}/_matrix/client/r0{}",
+            self.config.homeserver.trim_end_matches('/'),
+            endpoint
+        );
+        if let Some(user_id) = user_id {
+            url.push_str("?user_id=");
+            url.push_str(&encode(user_id));
+        }
+        url
+    }
+}
+
+/// Send `body` with the bridge's credentials and resolve to the status code.
+fn call(
+    request: ClientRequest,
+    token: &str,
+    body: Value,
+) -> impl Future<Item = StatusCode, Error = String> {
+    request
+        .bearer_auth(token)
+        .send_json(&body)
+        .map(|response| response.status())
+        .map_err(|e| e.to_string())
+}
+
+/// Turn unexpected status codes into an error naming the failed `step`.
+fn expect(status: StatusCode, step: &str, tolerated: &[StatusCode]) -> Result<(), String> {
+    if status.is_success() || tolerated.contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("{} failed with {}", step, status))
+    }
+}
+
+/// Inbound half: the endpoints the homeserver pushes events to.
+///
+/// This is shared by all gateway workers, cloning only bumps reference
+/// counts.
+#[derive(Clone)]
+pub struct Appservice {
+    config: Arc<MatrixConfig>,
+    state: State,
+
+    /// Recently handled transaction IDs. The homeserver resends a
+    /// transaction until it was acknowledged, so it may arrive twice.
+    seen: Arc<Mutex<VecDeque<String>>>,
+}
+
+#[derive(Deserialize)]
+struct Auth {
+    access_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    events: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    room_id: Option<String>,
+    sender: String,
+    #[serde(default)]
+    content: Value,
+}
+
+impl Appservice {
+    pub fn new(config: MatrixConfig, state: State) -> Appservice {
+        Appservice {
+            config: Arc::new(config),
+            state,
+            seen: Arc::new(Mutex::new(VecDeque::with_capacity(SEEN_TRANSACTIONS))),
+        }
+    }
+
+    /// Mount the application service API.
+    ///
+    /// Both the current `/_matrix/app/v1` paths and the legacy unprefixed
+    /// ones are served, older homeservers only know the latter.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.data(self.clone());
+        for prefix in &["/_matrix/app/v1", ""] {
+            cfg.service(
+                web::resource(&format!("{}/transactions/{{txn}}", prefix))
+                    .data(web::JsonConfig::default().limit(MAX_TRANSACTION))
+                    .route(web::put().to(transaction)),
+            )
+            .service(web::resource(&format!("{}/users/{{id}}", prefix)).to(not_found))
+            .service(web::resource(&format!("{}/rooms/{{alias}}", prefix)).to(not_found));
+        }
+    }
+
+    fn authorized(&self, auth: &Auth) -> bool {
+        auth.access_token.as_ref().map_or(false, |token| {
+            secret::same_token(&self.config.hs_token, token)
+        })
+    }
+
+    /// Relay a room message from a real Matrix user to the peers.
+    fn relay_event(&self, event: &RoomEvent) {
+        if event.kind != "m.room.message"
+            || event.room_id.as_ref() != Some(&self.config.room_id)
+            || is_bridged(&self.config, &event.sender)
+        {
+            return;
+        }
+
+        let body = match event.content.get("body").and_then(Value::as_str) {
+            Some(body) => body,
+            None => return,
+        };
+        let emote = event.content.get("msgtype").and_then(Value::as_str) == Some("m.emote");
+
+        // `@alice:example.org` is shown as plain `alice`
+        let name = event
+            .sender
+            .trim_start_matches('@')
+            .split(':')
+            .next()
+            .unwrap_or("");
+
+        // The line protocol cannot carry line breaks, so a multi-line
+        // message becomes several lines.
+        for text in body.lines() {
+            let mut line = BytesMut::with_capacity(name.len() + text.len() + 4);
+            if emote {
+                line.put("* ");
+                line.put(name);
+                line.put(" ");
+            } else {
+                line.put(name);
+                line.put(": ");
+            }
+            line.put(text);
+            line.put("\r\n");
+            self.state.inject(line.freeze());
+        }
+    }
+}
+
+fn transaction(
+    service: web::Data<Appservice>,
+    txn: web::Path<String>,
+    auth: web::Query<Auth>,
+    body: web::Json<Transaction>,
+) -> HttpResponse {
+    if !service.authorized(&auth) {
+        return HttpResponse::Forbidden().json(json!({ "errcode": "M_FORBIDDEN" }));
+    }
+
+    {
+        let mut seen = service.seen.lock().unwrap();
+        if seen.contains(&txn) {
+            return HttpResponse::Ok().json(json!({}));
+        }
+        if seen.len() == SEEN_TRANSACTIONS {
+            seen.pop_front();
+        }
+        seen.push_back(txn.into_inner());
+    }
+
+    for event in &body.events {
+        service.relay_event(event);
+    }
+
+    HttpResponse::Ok().json(json!({}))
+}
+
+/// Answer user and room queries.
+///
+/// Puppets are created when their peer connects, never on demand, so there
+/// is nothing to be found.
+fn not_found(service: web::Data<Appservice>, auth: web::Query<Auth>) -> HttpResponse {
+    if !service.authorized(&auth) {
+        return HttpResponse::Forbidden().json(json!({ "errcode": "M_FORBIDDEN" }));
+    }
+    HttpResponse::NotFound().json(json!({ "errcode": "M_NOT_FOUND" }))
+}
@@ -0,0 +1,80 @@
+//! Which servers of the cluster are up, see `building_blocks::swim`.
+//!
+//! Servers bridged to each other, or sharing a conversation over NATS, can
+//! also keep track of which of them are up, over UDP:
+//!
+//! ```json
+//! "membership": { "listen": "0.0.0.0:7946", "seeds": ["chat-2.example.org:7946"] }
+//! ```
+//!
+//! A server joins by pinging `seeds`, any of the others, and from then on
+//! hears of every server that joins, stops answering or leaves from the
+//! others. It goes by its bridge id unless given a `name`, see `bridge`.
+//! The members are logged as they change and counted by status in
+//! `/metrics`, as `cluster_members`.
+
+use building_blocks::swim::{Status, Swim};
+use futures::future;
+use tokio::prelude::*;
+
+use std::io;
+use std::time::Duration;
+
+use crate::config::MembershipConfig;
+use crate::logging;
+use crate::state::State;
+
+/// Join the cluster, a future that runs until the socket fails. The socket
+/// is bound right away, so that a taken address fails at startup.
+pub fn start(
+    config: &MembershipConfig,
+    state: &State,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let name = config.name.as_ref().unwrap_or(&state.bridges.id);
+    let swim = Swim::bind(&config.listen, name)?
+        .period(Duration::from_millis(config.period_ms))
+        .ping_timeout(Duration::from_millis(config.ping_timeout_ms))
+        .indirect_probes(config.indirect_probes)
+        .suspect_for(Duration::from_millis(config.suspect_ms))
+        .on_change(|member| {
+            let event = match member.status {
+                Status::Alive => "member_alive",
+                Status::Suspect => "member_suspect",
+                Status::Dead => "member_dead",
+            };
+            logging::info!(
+                event, addr = member.addr, name = &member.name;
+                "member {} at {} is {}", member.name, member.addr, member.status.as_str()
+            );
+        });
+    state.metrics.cluster.watch(swim.members());
+    logging::info!(
+        "listening", addr = config.listen;
+        "Listening on: {} (membership, as {})", config.listen, name
+    );
+
+    // A seed that does not resolve is left out, the others may do.
+    let seeds: Vec<_> = config
+        .seeds
+        .iter()
+        .map(|seed| {
+            let target = seed.clone();
+            seed.resolve().then(move |result| match result {
+                Ok(addrs) => Ok(Some(addrs[0])),
+                Err(e) => {
+                    logging::warn!(
+                        "lookup_failed", addr = &target;
+                        "lookup of seed {} failed = {:?}", target, e
+                    );
+                    Ok::<_, ()>(None)
+                }
+            })
+        })
+        .collect();
+    Ok(future::join_all(seeds).and_then(move |seeds| {
+        swim.join(seeds.into_iter().flatten().collect())
+            .map_err(|e| {
+                logging::error!("membership_failed"; "membership error = {:?}", e);
+            })
+    }))
+}
@@ -0,0 +1,139 @@
+//! Memory held by the server, to catch buffers growing without bound.
+//!
+//! Every peer reports the capacity of its read and write buffers whenever it
+//! records its traffic. With the `heap-stats` feature, a global allocator
+//! that counts what is allocated wraps the system one:
+//!
+//! ```text
+//! cargo run --features heap-stats --bin double_server
+//! ```
+//!
+//! Both are exported at `/metrics`, see `metrics::render_memory`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Capacity of the buffers of one peer, in bytes.
+#[derive(Debug, Default)]
+pub struct Buffers {
+    read: AtomicUsize,
+    write: AtomicUsize,
+}
+
+impl Buffers {
+    pub fn record(&self, read: usize, write: usize) {
+        self.read.store(read, Ordering::Relaxed);
+        self.write.store(write, Ordering::Relaxed);
+    }
+
+    pub fn read(&self) -> usize {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn write(&self) -> usize {
+        self.write.load(Ordering::Relaxed)
+    }
+}
+
+/// Shared between a peer, which records its buffers, and its `Member`
+/// entry, through which they are reported.
+pub type SharedBuffers = Arc<Buffers>;
+
+/// What the server holds on to, see `State::memory`.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct Usage {
+    /// Capacity of the read buffers of all peers together.
+    pub read_buffers: usize,
+    /// Capacity of the write buffers of all peers together.
+    pub write_buffers: usize,
+    pub history_messages: usize,
+    /// Names and bodies of the messages in the history.
+    pub history_bytes: usize,
+    /// Only with the `heap-stats` feature.
+    pub heap: Option<Heap>,
+}
+
+/// What the global allocator handed out.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct Heap {
+    /// Bytes allocated and not freed yet.
+    pub allocated: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+#[cfg(feature = "heap-stats")]
+pub use self::counting::Counting;
+
+#[cfg(feature = "heap-stats")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::Heap;
+
+    static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    /// The system allocator, counting what goes through it.
+    pub struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc_zeroed(layout);
+            if !ptr.is_null() {
+                ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new = System.realloc(ptr, layout, new_size);
+            if !new.is_null() {
+                // The old block is gone, the new one takes its place.
+                ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+                ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            }
+            new
+        }
+    }
+
+    pub fn heap() -> Option<Heap> {
+        Some(Heap {
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// What the global allocator handed out, if it is counted.
+#[cfg(feature = "heap-stats")]
+pub fn heap() -> Option<Heap> {
+    counting::heap()
+}
+
+/// What the global allocator handed out, if it is counted.
+#[cfg(not(feature = "heap-stats"))]
+pub fn heap() -> Option<Heap> {
+    None
+}
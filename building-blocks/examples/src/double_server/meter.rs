@@ -0,0 +1,130 @@
+//! Bandwidth meters of the peers.
+//!
+//! A `Meter` keeps an exponentially weighted moving average of a byte rate.
+//! Every byte adds to the rate when it is recorded, and the rate decays
+//! continuously, so that bursts long ago weigh less and less. With no timer
+//! involved, a meter costs nothing while its peer is idle.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::conn::ConnId;
+use crate::geoip::Location;
+use crate::state::Side;
+
+/// Time constant of the average in seconds. Traffic this old weighs about a
+/// third of current traffic.
+const AVERAGE_OVER: f64 = 60.0;
+
+#[derive(Debug)]
+pub struct Meter {
+    /// Bytes per second, as of `last`.
+    rate: f64,
+    last: Instant,
+    total: u64,
+}
+
+impl Default for Meter {
+    fn default() -> Meter {
+        Meter {
+            rate: 0.0,
+            last: Instant::now(),
+            total: 0,
+        }
+    }
+}
+
+impl Meter {
+    /// The factor the rate decayed by since `last`.
+    fn decay(&self, now: Instant) -> f64 {
+        let elapsed = now - self.last;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        (-elapsed / AVERAGE_OVER).exp()
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.rate = self.rate * self.decay(now) + bytes as f64 / AVERAGE_OVER;
+        self.last = now;
+        self.total += bytes;
+    }
+
+    /// Average bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.rate * self.decay(Instant::now())
+    }
+
+    /// Bytes recorded in total.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+/// The traffic of one peer, as seen by the server.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// Bytes read from the peer.
+    pub ingress: Meter,
+    /// Bytes written to the peer.
+    pub egress: Meter,
+}
+
+impl Traffic {
+    /// Read both meters at once.
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            ingress_rate: self.ingress.rate(),
+            egress_rate: self.egress.rate(),
+            ingress_total: self.ingress.total(),
+            egress_total: self.egress.total(),
+        }
+    }
+}
+
+/// Shared between a peer, which records its traffic, and its `Member` entry,
+/// through which it is reported.
+pub type SharedTraffic = Arc<Mutex<Traffic>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrafficSnapshot {
+    pub ingress_rate: f64,
+    pub egress_rate: f64,
+    pub ingress_total: u64,
+    pub egress_total: u64,
+}
+
+impl TrafficSnapshot {
+    /// Both directions together, what heavy users are ranked by.
+    pub fn rate(&self) -> f64 {
+        self.ingress_rate + self.egress_rate
+    }
+}
+
+/// The traffic of a connected peer, see `State::traffic`.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub struct PeerTraffic {
+    pub side: Side,
+    pub id: ConnId,
+    pub addr: SocketAddr,
+    pub name: String,
+    pub location: Location,
+    pub traffic: TrafficSnapshot,
+}
+
+/// Format a byte count for people, like `12.3 KiB`.
+pub fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,619 @@
+//! Counters describing what the server is doing.
+//!
+//! The HTTP gateway exports them at `/metrics` in the Prometheus text format:
+//!
+//! ```text
+//! kafka_messages_delivered_total 1200
+//! kafka_delivery_failures_total 2
+//! circuit_state{integration="kafka",state="open"} 1
+//! message_latency_seconds{side="c",quantile="0.99"} 0.000831
+//! peer_ingress_bytes_per_second{side="c",name="alice",conn="0.7",addr="127.0.0.1:50312"} 12.5
+//! peer_egress_bytes_total{side="go",name="bob",conn="0.12",addr="198.51.100.7:40022",country="DE",asn="3320"} 4096
+//! peer_read_buffer_bytes 16384
+//! peers{side="c"} 12
+//! connections{phase="draining"} 3
+//! queued_outbound_bytes 2048
+//! ```
+
+use building_blocks::breaker::{Breaker, Circuit};
+use building_blocks::swim::{Members, Status};
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::conn::Connections;
+use crate::memory::Usage;
+use crate::meter::PeerTraffic;
+use crate::state::Side;
+
+/// Significant bits of a recorded value. Values are exact below
+/// `2^SUB_BUCKET_BITS` microseconds and within 1/64 above.
+const SUB_BUCKET_BITS: u32 = 7;
+
+/// Bits of the largest recorded value, in microseconds. Anything longer
+/// (about 19 hours) is recorded as `MAX_MICROS`.
+const MAX_BITS: u32 = 36;
+
+const MAX_MICROS: u64 = (1 << MAX_BITS) - 1;
+
+/// Buckets of a `Histogram`: the exact ones, then half as many for every
+/// further power of two.
+const BUCKETS: usize =
+    (1 << SUB_BUCKET_BITS) + (((MAX_BITS - SUB_BUCKET_BITS) as usize) << (SUB_BUCKET_BITS - 1));
+
+/// The quantiles exported of every histogram.
+pub const QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// A number that only goes up.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A number that goes up and down.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: u64) {
+        self.0.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Durations bucketed like an HDR histogram, recorded without locking.
+///
+/// Every power of two of microseconds is split into the same number of
+/// linear buckets, so that the error relative to the value stays the same
+/// from microseconds to hours.
+pub struct Histogram {
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, value: Duration) {
+        let micros = value.as_secs() * 1_000_000 + u64::from(value.subsec_micros());
+        let micros = micros.min(MAX_MICROS);
+        self.counts[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of the recorded values.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// The value `quantile` (like 0.99) of the recorded ones is at most,
+    /// or zero if nothing was recorded.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let counts: Vec<u64> = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return Duration::from_micros(0);
+        }
+
+        let rank = ((quantile * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(highest_in(index));
+            }
+        }
+        Duration::from_micros(MAX_MICROS)
+    }
+}
+
+/// Index of the bucket of `micros`.
+fn bucket(micros: u64) -> usize {
+    let sub_buckets = 1 << SUB_BUCKET_BITS;
+    if micros < sub_buckets {
+        return micros as usize;
+    }
+    // How far `micros` has to be shifted to fit into the upper half of the
+    // exact buckets.
+    let shift = 64 - micros.leading_zeros() - SUB_BUCKET_BITS;
+    let half = sub_buckets / 2;
+    let sub = (micros >> shift) - half;
+    (sub_buckets + u64::from(shift - 1) * half + sub) as usize
+}
+
+/// Largest value that falls into bucket `index`.
+fn highest_in(index: usize) -> u64 {
+    let sub_buckets = 1 << SUB_BUCKET_BITS;
+    if index < sub_buckets {
+        return index as u64;
+    }
+    let half = sub_buckets / 2;
+    let shift = (index - sub_buckets) / half + 1;
+    let sub = ((index - sub_buckets) % half + half) as u64;
+    ((sub + 1) << shift) - 1
+}
+
+/// Format `value` for humans, like `1.25ms`.
+pub fn human_duration(value: Duration) -> String {
+    let micros = value.as_secs() * 1_000_000 + u64::from(value.subsec_micros());
+    if micros < 1000 {
+        format!("{}us", micros)
+    } else if micros < 1_000_000 {
+        format!("{:.2}ms", micros as f64 / 1000.0)
+    } else {
+        format!("{:.2}s", micros as f64 / 1_000_000.0)
+    }
+}
+
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+fn secs(value: Duration) -> f64 {
+    value.as_secs() as f64 + f64::from(value.subsec_micros()) / 1_000_000.0
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Messages the Kafka broker acknowledged.
+    pub kafka_messages_delivered: Counter,
+
+    /// Produce requests the Kafka broker acknowledged.
+    pub kafka_batches_delivered: Counter,
+
+    /// Produce attempts that failed, whether or not they were retried.
+    pub kafka_delivery_failures: Counter,
+
+    /// Messages given up on, because their batch ran out of attempts, the
+    /// queue overflowed while the broker was unreachable or its circuit was
+    /// open.
+    pub kafka_messages_dropped: Counter,
+
+    /// Messages held back by the global throttle.
+    pub throttle_messages_delayed: Counter,
+
+    /// Messages dropped by the global throttle.
+    pub throttle_messages_rejected: Counter,
+
+    /// Connections turned away because the server was draining.
+    pub drain_connections_refused: Counter,
+
+    /// Peers disconnected for sending more than `max_unframed_bytes`
+    /// without a line break.
+    pub unframed_disconnects: Counter,
+
+    /// Probes written to idle connections, see `heartbeat`.
+    pub heartbeat_probes: Counter,
+
+    /// Peers disconnected because what was written to them did not get
+    /// through.
+    pub heartbeat_disconnects: Counter,
+
+    /// Connections closed because of where they came from, see `access`.
+    pub access_connections_denied: Counter,
+
+    /// Peers disconnected for a name that breaks `Config::names`.
+    pub names_rejected: Counter,
+
+    /// Scheduled announcements made, see `schedule`.
+    pub announcements_sent: Counter,
+
+    /// Reminders delivered, see `remind`.
+    pub reminders_delivered: Counter,
+
+    /// Chat events not posted to an outgoing webhook because its circuit
+    /// was open.
+    pub webhook_deliveries_shed: Counter,
+
+    /// Messages of other servers dropped since they went around in circles,
+    /// see `bridge`.
+    pub bridge_messages_dropped: Counter,
+
+    /// Messages of other servers, or for a peer of another server, dropped
+    /// since their `ttl` ran out, see `bridge`. Any of them points at a
+    /// bridge that is set up wrong.
+    pub bridge_messages_expired: Counter,
+
+    /// Entries of who is connected to another server taken in, see
+    /// `roster`.
+    pub roster_updates: Counter,
+
+    /// Changes of the moderation taken in from links, see `moderation`.
+    pub moderation_updates: Counter,
+
+    /// Messages of other servers held back for the messages they came
+    /// after, see `causal`.
+    pub causal_messages_held: Counter,
+
+    /// Messages relayed when they were held back too long, before what
+    /// they came after.
+    pub causal_messages_expired: Counter,
+
+    /// Messages of exactly-once peers acknowledged again without being
+    /// relayed, see `once`.
+    pub once_duplicates: Counter,
+
+    /// Bytes waiting in the write buffers of all peers, see `Stats`.
+    pub queued_outbound_bytes: Gauge,
+
+    /// The circuit breakers of the integrations.
+    pub circuits: Circuits,
+
+    /// The servers of the cluster, see `membership`.
+    pub cluster: Cluster,
+
+    /// Time from decoding a message of a C peer to the last peer it went
+    /// to flushing it.
+    pub message_latency_c: Histogram,
+
+    /// The same for the messages of Go peers.
+    pub message_latency_go: Histogram,
+}
+
+impl Metrics {
+    /// Name and current value of every counter.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            (
+                "kafka_messages_delivered_total",
+                self.kafka_messages_delivered.get(),
+            ),
+            (
+                "kafka_batches_delivered_total",
+                self.kafka_batches_delivered.get(),
+            ),
+            (
+                "kafka_delivery_failures_total",
+                self.kafka_delivery_failures.get(),
+            ),
+            (
+                "kafka_messages_dropped_total",
+                self.kafka_messages_dropped.get(),
+            ),
+            (
+                "throttle_messages_delayed_total",
+                self.throttle_messages_delayed.get(),
+            ),
+            (
+                "throttle_messages_rejected_total",
+                self.throttle_messages_rejected.get(),
+            ),
+            (
+                "drain_connections_refused_total",
+                self.drain_connections_refused.get(),
+            ),
+            (
+                "unframed_disconnects_total",
+                self.unframed_disconnects.get(),
+            ),
+            ("heartbeat_probes_total", self.heartbeat_probes.get()),
+            (
+                "heartbeat_disconnects_total",
+                self.heartbeat_disconnects.get(),
+            ),
+            (
+                "access_connections_denied_total",
+                self.access_connections_denied.get(),
+            ),
+            ("names_rejected_total", self.names_rejected.get()),
+            ("announcements_sent_total", self.announcements_sent.get()),
+            ("reminders_delivered_total", self.reminders_delivered.get()),
+            (
+                "webhook_deliveries_shed_total",
+                self.webhook_deliveries_shed.get(),
+            ),
+            (
+                "bridge_messages_dropped_total",
+                self.bridge_messages_dropped.get(),
+            ),
+            (
+                "bridge_messages_expired_total",
+                self.bridge_messages_expired.get(),
+            ),
+            ("roster_updates_total", self.roster_updates.get()),
+            ("moderation_updates_total", self.moderation_updates.get()),
+            (
+                "causal_messages_held_total",
+                self.causal_messages_held.get(),
+            ),
+            (
+                "causal_messages_expired_total",
+                self.causal_messages_expired.get(),
+            ),
+            ("once_duplicates_total", self.once_duplicates.get()),
+        ]
+    }
+
+    /// The message latency of the peers of `side`.
+    pub fn message_latency(&self, side: Side) -> &Histogram {
+        match side {
+            Side::C => &self.message_latency_c,
+            Side::Go => &self.message_latency_go,
+        }
+    }
+
+    /// Render every counter and histogram in the Prometheus text format.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.counters() {
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        let name = "message_latency_seconds";
+        writeln!(out, "# TYPE {} summary", name).unwrap();
+        for &side in &[Side::C, Side::Go] {
+            let latency = self.message_latency(side);
+            for &quantile in QUANTILES.iter() {
+                writeln!(
+                    out,
+                    "{}{{side=\"{}\",quantile=\"{}\"}} {}",
+                    name,
+                    side,
+                    quantile,
+                    secs(latency.quantile(quantile))
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}_sum{{side=\"{}\"}} {}",
+                name,
+                side,
+                secs(latency.sum())
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}_count{{side=\"{}\"}} {}",
+                name,
+                side,
+                latency.count()
+            )
+            .unwrap();
+        }
+        self.circuits.render(&mut out);
+        self.cluster.render(&mut out);
+        out
+    }
+}
+
+/// The circuit breakers of the integrations, by name.
+#[derive(Default)]
+pub struct Circuits(Mutex<Vec<(String, Breaker)>>);
+
+impl Circuits {
+    /// Export the state of `breaker` as the integration `name`.
+    pub fn register(&self, name: &str, breaker: &Breaker) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((name.to_string(), breaker.clone()));
+    }
+
+    /// Render the state of every breaker, one series per state that is 1
+    /// for the current one, and how many times each opened.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    fn render(&self, out: &mut String) {
+        let circuits = self.0.lock().unwrap();
+        writeln!(out, "# TYPE circuit_state gauge").unwrap();
+        for (name, breaker) in circuits.iter() {
+            let current = breaker.circuit();
+            for &circuit in &[Circuit::Closed, Circuit::Open, Circuit::HalfOpen] {
+                writeln!(
+                    out,
+                    "circuit_state{{integration=\"{}\",state=\"{}\"}} {}",
+                    name,
+                    circuit.as_str(),
+                    (circuit == current) as u8
+                )
+                .unwrap();
+            }
+        }
+        writeln!(out, "# TYPE circuit_trips_total counter").unwrap();
+        for (name, breaker) in circuits.iter() {
+            writeln!(
+                out,
+                "circuit_trips_total{{integration=\"{}\"}} {}",
+                name,
+                breaker.trips()
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// The members of the cluster, once the server joined one.
+#[derive(Default)]
+pub struct Cluster(Mutex<Option<Members>>);
+
+impl Cluster {
+    pub fn watch(&self, members: Members) {
+        *self.0.lock().unwrap() = Some(members);
+    }
+
+    /// Render how many servers there are of every status, this one among
+    /// the alive.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    fn render(&self, out: &mut String) {
+        let members = match &*self.0.lock().unwrap() {
+            Some(members) => members.others(),
+            None => return,
+        };
+        writeln!(out, "# TYPE cluster_members gauge").unwrap();
+        for &status in &[Status::Alive, Status::Suspect, Status::Dead] {
+            let mut count = members.iter().filter(|m| m.status == status).count();
+            if status == Status::Alive {
+                count += 1;
+            }
+            writeln!(
+                out,
+                "cluster_members{{status=\"{}\"}} {}",
+                status.as_str(),
+                count
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Escape a label value of the text format.
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the bandwidth of every connected peer, labelled with its side,
+/// name and address, and its country and autonomous system if known, see
+/// `geoip`.
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub fn render_traffic(out: &mut String, peers: &[PeerTraffic]) {
+    let series: [(&str, &str, fn(&PeerTraffic) -> String); 4] = [
+        ("peer_ingress_bytes_per_second", "gauge", |p| {
+            p.traffic.ingress_rate.to_string()
+        }),
+        ("peer_egress_bytes_per_second", "gauge", |p| {
+            p.traffic.egress_rate.to_string()
+        }),
+        ("peer_ingress_bytes_total", "counter", |p| {
+            p.traffic.ingress_total.to_string()
+        }),
+        ("peer_egress_bytes_total", "counter", |p| {
+            p.traffic.egress_total.to_string()
+        }),
+    ];
+
+    for (name, kind, value) in series.iter() {
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for peer in peers {
+            let mut location = String::new();
+            if let Some(country) = &peer.location.country {
+                write!(location, ",country=\"{}\"", escape_label(country)).unwrap();
+            }
+            if let Some(asn) = peer.location.asn {
+                write!(location, ",asn=\"{}\"", asn).unwrap();
+            }
+            writeln!(
+                out,
+                "{}{{side=\"{}\",name=\"{}\",conn=\"{}\",addr=\"{}\"{}}} {}",
+                name,
+                peer.side,
+                escape_label(&peer.name),
+                peer.id,
+                peer.addr,
+                location,
+                value(peer)
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Render the memory held by the server. The heap is only there with the
+/// `heap-stats` feature.
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub fn render_memory(out: &mut String, usage: &Usage) {
+    let mut series = vec![
+        ("peer_read_buffer_bytes", "gauge", usage.read_buffers),
+        ("peer_write_buffer_bytes", "gauge", usage.write_buffers),
+        ("history_messages", "gauge", usage.history_messages),
+        ("history_bytes", "gauge", usage.history_bytes),
+    ];
+    if let Some(heap) = &usage.heap {
+        series.push(("heap_allocated_bytes", "gauge", heap.allocated));
+        series.push(("heap_allocations_total", "counter", heap.allocations));
+        series.push(("heap_deallocations_total", "counter", heap.deallocations));
+    }
+
+    for (name, kind, value) in series {
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+}
+
+/// The gauges a monitoring script polls, exported at `/metrics` and
+/// answered to a `STATS` line on the chat ports.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub peers_c: usize,
+    pub peers_go: usize,
+    /// Bytes buffered for peers and not written to them yet.
+    pub queued_outbound_bytes: u64,
+    pub history_messages: usize,
+    pub history_bytes: usize,
+}
+
+impl fmt::Display for Stats {
+    /// Like `peers_c=12 peers_go=3 queued_outbound_bytes=2048
+    /// history_messages=1000 history_bytes=48213`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "peers_c={} peers_go={} queued_outbound_bytes={} history_messages={} history_bytes={}",
+            self.peers_c,
+            self.peers_go,
+            self.queued_outbound_bytes,
+            self.history_messages,
+            self.history_bytes
+        )
+    }
+}
+
+/// Render how many connections are in each phase, see `conn`, and how
+/// many closed.
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub fn render_connections(out: &mut String, connections: &Connections) {
+    writeln!(out, "# TYPE connections gauge").unwrap();
+    for (phase, count) in connections.counts() {
+        writeln!(out, "connections{{phase=\"{}\"}} {}", phase, count).unwrap();
+    }
+    writeln!(out, "# TYPE connections_closed_total counter").unwrap();
+    writeln!(out, "connections_closed_total {}", connections.closed.get()).unwrap();
+}
+
+/// The gauges of `stats` that `render_memory` does not export.
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub fn render_stats(out: &mut String, stats: &Stats) {
+    writeln!(out, "# TYPE peers gauge").unwrap();
+    writeln!(out, "peers{{side=\"{}\"}} {}", Side::C, stats.peers_c).unwrap();
+    writeln!(out, "peers{{side=\"{}\"}} {}", Side::Go, stats.peers_go).unwrap();
+    writeln!(out, "# TYPE queued_outbound_bytes gauge").unwrap();
+    writeln!(out, "queued_outbound_bytes {}", stats.queued_outbound_bytes).unwrap();
+}
@@ -0,0 +1,368 @@
+//! The topic, who is banned and who moderates, the same on every server of
+//! a bridge.
+//!
+//! ```text
+//! /topic [text]        the topic, or set it
+//! /ban <peer>          drop what a peer sends, and turn the name away
+//! /unban <peer>
+//! /mod [peer]          make a peer a moderator, or list the moderators
+//!                      and the banned
+//! /unmod <peer>
+//! ```
+//!
+//! Only moderators set the topic, ban and make moderators. The peers named
+//! in the config are moderators on this server whatever the others do,
+//! going by `names::key`:
+//!
+//! ```json
+//! "moderation": { "moderators": ["alice"] }
+//! ```
+//!
+//! Servers cut off from each other keep taking changes, and agree again
+//! once the links are back, see `building_blocks::crdt`: the topic set last
+//! wins, and a ban or a moderator added on one server while removed on
+//! another stays. A change is sent to every link as the whole of it, in
+//! JSON, and a server passes on what changed what it had:
+//!
+//! ```text
+//! @moderation={"topic":{"value":[{"time":1565000000123,"replica":"eu-1"},{"text":"releases","by":"alice"}]},"banned":{...},"moderators":{...}}
+//! ```
+//!
+//! A server that had more than what it was sent sends it back. Lines lost
+//! on the way are made up for like those of the `roster`: every
+//! `sync_secs`, and when a link connects, the server sends a hash of what
+//! it has, and a link with another one answers with what it has.
+//!
+//! ```text
+//! @moderation_digest=4f1c0a9e2b7d3c55
+//! ```
+//!
+//! A banned peer that is connected is told, and nothing it sends is relayed
+//! or answered from then on. The changes made on this server are in the
+//! `audit` file.
+
+use building_blocks::crdt::{Clock, LwwRegister, OrSet};
+use bytes::Bytes;
+use futures::{Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::timer::Interval;
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::audit::Action;
+use crate::bridge;
+use crate::config::ModerationConfig;
+use crate::conn::ConnId;
+use crate::locale::Message;
+use crate::logging;
+use crate::names;
+use crate::state::{Side, State};
+
+const MODERATION_TAG: &[u8] = b"@moderation=";
+const DIGEST_TAG: &[u8] = b"@moderation_digest=";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Topic {
+    pub text: String,
+    /// Name of the moderator who set it.
+    pub by: String,
+}
+
+/// What moderators change, the same on every server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shared {
+    topic: LwwRegister<Topic>,
+    /// By `names::key`.
+    banned: OrSet<String>,
+    /// By `names::key`, those of the config left out.
+    moderators: OrSet<String>,
+}
+
+impl Shared {
+    fn line(&self) -> Bytes {
+        let json = serde_json::to_string(self).expect("moderation serializes");
+        Bytes::from(format!("@moderation={}\r\n", json))
+    }
+
+    fn digest(&self) -> String {
+        let json = serde_json::to_string(self).expect("moderation serializes");
+        let hash = Sha256::digest(json.as_bytes());
+        hash.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Take in what `theirs` has that this has not.
+    fn merge(&mut self, clock: &mut Clock, theirs: &Shared) {
+        self.topic.merge(&theirs.topic);
+        self.banned.merge(&theirs.banned);
+        self.moderators.merge(&theirs.moderators);
+        clock.witness(self.topic.stamp());
+        clock.witness(self.banned.stamp());
+        clock.witness(self.moderators.stamp());
+    }
+}
+
+/// A moderator's change of who is banned or moderates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Ban,
+    Unban,
+    Mod,
+    Unmod,
+}
+
+impl Change {
+    pub fn command(self) -> &'static str {
+        match self {
+            Change::Ban => "/ban",
+            Change::Unban => "/unban",
+            Change::Mod => "/mod",
+            Change::Unmod => "/unmod",
+        }
+    }
+}
+
+struct Replica {
+    clock: Clock,
+    shared: Shared,
+}
+
+pub struct Moderation {
+    /// The moderators of the config, by `names::key`.
+    configured: HashSet<String>,
+    replica: Mutex<Replica>,
+}
+
+impl Moderation {
+    /// The moderation of the server with the bridge id `id`.
+    pub fn new(config: &ModerationConfig, id: &str) -> Moderation {
+        Moderation {
+            configured: config
+                .moderators
+                .iter()
+                .map(|name| names::key(name))
+                .collect(),
+            replica: Mutex::new(Replica {
+                clock: Clock::new(id),
+                shared: Shared::default(),
+            }),
+        }
+    }
+
+    pub fn topic(&self) -> Option<Topic> {
+        self.replica.lock().unwrap().shared.topic.get().cloned()
+    }
+
+    pub fn is_banned(&self, name: &str) -> bool {
+        let replica = self.replica.lock().unwrap();
+        replica.shared.banned.contains(&names::key(name))
+    }
+
+    pub fn is_moderator(&self, name: &str) -> bool {
+        let key = names::key(name);
+        self.configured.contains(&key) || {
+            let replica = self.replica.lock().unwrap();
+            replica.shared.moderators.contains(&key)
+        }
+    }
+
+    /// The moderators, those of the config included, and the banned.
+    pub fn list(&self) -> (Vec<String>, Vec<String>) {
+        let replica = self.replica.lock().unwrap();
+        let shared = &replica.shared;
+        let mut moderators: Vec<String> = self.configured.iter().cloned().collect();
+        moderators.extend(shared.moderators.iter().cloned());
+        moderators.sort();
+        moderators.dedup();
+        (moderators, shared.banned.iter().cloned().collect())
+    }
+
+    /// All of it, for a `snapshot`.
+    pub fn shared(&self) -> Shared {
+        self.replica.lock().unwrap().shared.clone()
+    }
+
+    /// Take back what a `snapshot` had, or a `backfill` was sent, before
+    /// the links are told.
+    pub fn restore(&self, saved: &Shared) {
+        let mut replica = self.replica.lock().unwrap();
+        let Replica { clock, shared } = &mut *replica;
+        shared.merge(clock, saved);
+    }
+
+    /// Make a change, `None` if it changed nothing. Returns what there was
+    /// before and what there is now.
+    fn update<F: FnOnce(&mut Shared, &mut Clock) -> bool>(&self, f: F) -> Option<(Shared, Shared)> {
+        let mut replica = self.replica.lock().unwrap();
+        let before = replica.shared.clone();
+        let Replica { clock, shared } = &mut *replica;
+        if !f(shared, clock) {
+            return None;
+        }
+        Some((before, replica.shared.clone()))
+    }
+}
+
+/// Set the topic, for the peer `by`.
+pub fn set_topic(state: &State, by: &str, text: &str) -> Option<Message> {
+    if !state.moderation.is_moderator(by) {
+        return Some(Message::new("moderation_denied").with("command", "/topic"));
+    }
+    let topic = Topic {
+        text: text.to_string(),
+        by: by.to_string(),
+    };
+    let changed = state.moderation.update(|shared, clock| {
+        shared.topic.set(topic, clock.now());
+        true
+    });
+    let actor = format!("peer {}", by);
+    state.audit.record(&actor, Action::Moderate, "topic", text);
+    if let Some((before, after)) = changed {
+        send(state, None, &before, &after);
+    }
+    // Everyone, the moderator included, is told of the topic.
+    None
+}
+
+/// Make `change` to the peer `name`, for the peer `by`.
+pub fn change(state: &State, by: &str, change: Change, name: &str) -> Message {
+    let command = change.command();
+    if !state.moderation.is_moderator(by) {
+        return Message::new("moderation_denied").with("command", command);
+    }
+    let key = names::key(name);
+    let changed = state.moderation.update(|shared, clock| match change {
+        Change::Ban if shared.banned.contains(&key) => false,
+        Change::Ban => {
+            shared.banned.add(key.clone(), clock.now());
+            true
+        }
+        Change::Unban => shared.banned.remove(&key),
+        Change::Mod if shared.moderators.contains(&key) => false,
+        Change::Mod => {
+            shared.moderators.add(key.clone(), clock.now());
+            true
+        }
+        Change::Unmod => shared.moderators.remove(&key),
+    });
+    let (before, after) = match changed {
+        Some(changed) => changed,
+        None => {
+            return Message::new("moderation_unchanged")
+                .with("command", command)
+                .with("name", name)
+        }
+    };
+    let actor = format!("peer {}", by);
+    state
+        .audit
+        .record(&actor, Action::Moderate, name, &command[1..]);
+    send(state, None, &before, &after);
+    Message::new("moderation_done")
+        .with("command", command)
+        .with("name", name)
+}
+
+/// Send the digest of what this server has to the link `link` of `side`.
+pub fn digest(state: &State, side: Side, link: &ConnId) {
+    let digest = state.moderation.replica.lock().unwrap().shared.digest();
+    let line = format!("@moderation_digest={}\r\n", digest);
+    state.tell_line(side, link, Bytes::from(line));
+}
+
+/// Send the digest to every link every `sync_secs`.
+pub fn sync(state: State, every: Duration) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now() + every, every)
+        .map_err(|e| logging::error!("timer_failed"; "moderation timer error = {:?}", e))
+        .for_each(move |_| {
+            for (side, link) in bridge::links(&state) {
+                digest(&state, side, &link);
+            }
+            Ok(())
+        })
+}
+
+/// Whether `line` of a link is one of the moderation.
+pub fn is_line(line: &[u8]) -> bool {
+    line.starts_with(MODERATION_TAG) || line.starts_with(DIGEST_TAG)
+}
+
+/// Take in `line` of the link `link` of `side`, one of the moderation.
+pub fn receive(state: &State, side: Side, link: ConnId, line: &[u8]) {
+    if line.starts_with(DIGEST_TAG) {
+        let theirs = &line[DIGEST_TAG.len()..];
+        let replica = state.moderation.replica.lock().unwrap();
+        if theirs != replica.shared.digest().as_bytes() {
+            state.tell_line(side, &link, replica.shared.line());
+        }
+        return;
+    }
+    let theirs: Shared = match serde_json::from_slice(&line[MODERATION_TAG.len()..]) {
+        Ok(theirs) => theirs,
+        Err(e) => {
+            logging::warn!(
+                "moderation_invalid", side = side, conn = link;
+                "moderation of the link {} error = {:?}", link, e
+            );
+            return;
+        }
+    };
+    let merged = state.moderation.update(|shared, clock| {
+        shared.merge(clock, &theirs);
+        true
+    });
+    let (before, after) = match merged {
+        Some(merged) => merged,
+        None => return,
+    };
+    if after != before {
+        state.metrics.moderation_updates.add(1);
+        send(state, Some(link), &before, &after);
+    }
+    // The link missed some of it.
+    if after != theirs {
+        state.tell_line(side, &link, after.line());
+    }
+}
+
+/// Pass on `after`, which was `before`, to every link but `from`, and
+/// tell the peers of this server what changed for them.
+fn send(state: &State, from: Option<ConnId>, before: &Shared, after: &Shared) {
+    let line = after.line();
+    for (side, link) in bridge::links(state) {
+        if Some(link) != from {
+            state.tell_line(side, &link, line.clone());
+        }
+    }
+
+    // Links left out, their servers tell their own peers.
+    let topic = match after.topic.get() {
+        Some(topic) if after.topic != before.topic => Some(
+            Message::new("topic_changed")
+                .with("by", &topic.by)
+                .with("topic", &topic.text),
+        ),
+        _ => None,
+    };
+    let mut told = Vec::new();
+    for &side in &[Side::C, Side::Go] {
+        state.side(side).for_each(|id, member| {
+            if member.link.is_some() {
+                return;
+            }
+            if let Some(topic) = &topic {
+                told.push((side, *id, topic.clone()));
+            }
+            let key = names::key(&member.name);
+            if after.banned.contains(&key) && !before.banned.contains(&key) {
+                told.push((side, *id, Message::new("moderation_banned")));
+            }
+        });
+    }
+    for (side, id, message) in told {
+        state.tell(side, &id, &message);
+    }
+}
@@ -0,0 +1,599 @@
+//! MQTT bridge.
+//!
+//! The server connects to an MQTT broker as a client and mirrors the chat
+//! onto topics below the configured prefix (`chat` by default):
+//!
+//! * every line a peer sends is published to `chat/<side>/<name>`;
+//! * anything published to `chat/in/<name>` is relayed to the peers of both
+//!   sides as a line from `<name>`.
+//!
+//! QoS 0 and 1 are supported. With QoS 1, publishes that were not yet
+//! acknowledged when the connection dropped are sent again after
+//! reconnecting. Only the small subset of MQTT 3.1.1 needed for this is
+//! implemented, see `Codec`.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::sync::mpsc;
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::{Delay, Interval};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::MqttConfig;
+use crate::logging;
+use crate::resolve::{self, Connect, Refresh};
+use crate::state::{ChatEvent, State};
+
+/// Largest packet accepted from the broker.
+const MAX_PACKET: usize = 1 << 20;
+
+/// Publishes kept while the broker is unreachable. Older ones are dropped
+/// first.
+const MAX_QUEUED: usize = 1024;
+
+/// How many chat events are turned into publishes per poll, the same
+/// fairness rule as `LINES_PER_TICK` for the peers.
+const EVENTS_PER_TICK: usize = 10;
+
+/// Wait between reconnection attempts.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The MQTT control packets the bridge sends or understands.
+#[derive(Debug, Clone)]
+enum Packet {
+    Connect {
+        client_id: String,
+        keep_alive: u16,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    ConnAck {
+        code: u8,
+    },
+    Publish {
+        dup: bool,
+        qos: u8,
+        topic: String,
+        id: u16,
+        payload: Bytes,
+    },
+    PubAck {
+        id: u16,
+    },
+    Subscribe {
+        id: u16,
+        topic: String,
+        qos: u8,
+    },
+    SubAck {
+        code: u8,
+    },
+    PingReq,
+    PingResp,
+    /// Anything valid we have no use for.
+    Other,
+}
+
+/// MQTT 3.1.1 framing.
+///
+/// Every packet starts with a fixed header: one byte holding the packet type
+/// and flags, followed by the length of the rest of the packet encoded in one
+/// to four bytes, seven bits at a time.
+struct Codec;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn put_str(dst: &mut Vec<u8>, s: &str) {
+    dst.put_u16_be(s.len() as u16);
+    dst.put(s);
+}
+
+fn get_u16(src: &[u8], pos: &mut usize) -> io::Result<u16> {
+    if src.len() < *pos + 2 {
+        return Err(invalid("truncated packet"));
+    }
+    let v = u16::from(src[*pos]) << 8 | u16::from(src[*pos + 1]);
+    *pos += 2;
+    Ok(v)
+}
+
+fn get_str(src: &[u8], pos: &mut usize) -> io::Result<String> {
+    let len = get_u16(src, pos)? as usize;
+    if src.len() < *pos + len {
+        return Err(invalid("truncated packet"));
+    }
+    let s = String::from_utf8(src[*pos..*pos + len].to_vec())
+        .map_err(|_| invalid("topic is not utf-8"))?;
+    *pos += len;
+    Ok(s)
+}
+
+impl Encoder for Codec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> io::Result<()> {
+        // Encode the variable header and payload first, the fixed header
+        // needs to know their length. Unlike `BytesMut`, a `Vec` grows as
+        // it is written to.
+        let mut body = Vec::new();
+        let first = match packet {
+            Packet::Connect {
+                client_id,
+                keep_alive,
+                username,
+                password,
+            } => {
+                put_str(&mut body, "MQTT");
+                // Protocol level 4 is MQTT 3.1.1
+                body.put_u8(4);
+                // Clean session, plus the credential flags.
+                let mut flags = 0b0000_0010;
+                if username.is_some() {
+                    flags |= 0b1000_0000;
+                }
+                if password.is_some() {
+                    flags |= 0b0100_0000;
+                }
+                body.put_u8(flags);
+                body.put_u16_be(keep_alive);
+                put_str(&mut body, &client_id);
+                if let Some(username) = username {
+                    put_str(&mut body, &username);
+                }
+                if let Some(password) = password {
+                    put_str(&mut body, &password);
+                }
+                0x10
+            }
+            Packet::Publish {
+                dup,
+                qos,
+                topic,
+                id,
+                payload,
+            } => {
+                put_str(&mut body, &topic);
+                if qos > 0 {
+                    body.put_u16_be(id);
+                }
+                body.put(payload);
+                0x30 | (dup as u8) << 3 | qos << 1
+            }
+            Packet::PubAck { id } => {
+                body.put_u16_be(id);
+                0x40
+            }
+            Packet::Subscribe { id, topic, qos } => {
+                body.put_u16_be(id);
+                put_str(&mut body, &topic);
+                body.put_u8(qos);
+                0x82
+            }
+            Packet::PingReq => 0xc0,
+            _ => return Err(invalid("packet is never sent by a client")),
+        };
+
+        dst.reserve(body.len() + 5);
+        dst.put_u8(first);
+        let mut len = body.len();
+        loop {
+            let mut byte = (len % 128) as u8;
+            len /= 128;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            dst.put_u8(byte);
+            if len == 0 {
+                break;
+            }
+        }
+        dst.put(body);
+        Ok(())
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        // Decode the remaining length. It may not have fully arrived yet.
+        let mut len = 0;
+        let mut header = 1;
+        loop {
+            if src.len() <= header {
+                return Ok(None);
+            }
+            let byte = src[header];
+            len |= ((byte & 0x7f) as usize) << (7 * (header - 1));
+            header += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header > 4 {
+                return Err(invalid("malformed remaining length"));
+            }
+        }
+
+        if len > MAX_PACKET {
+            return Err(invalid("packet too large"));
+        }
+        if src.len() < header + len {
+            src.reserve(header + len - src.len());
+            return Ok(None);
+        }
+
+        let first = src[0];
+        let body = src.split_to(header + len).split_off(header).freeze();
+        let mut pos = 0;
+
+        let packet = match first >> 4 {
+            2 => {
+                if body.len() < 2 {
+                    return Err(invalid("truncated packet"));
+                }
+                Packet::ConnAck { code: body[1] }
+            }
+            3 => {
+                let qos = (first >> 1) & 0b11;
+                let topic = get_str(&body, &mut pos)?;
+                let id = if qos > 0 {
+                    get_u16(&body, &mut pos)?
+                } else {
+                    0
+                };
+                Packet::Publish {
+                    dup: first & 0b1000 != 0,
+                    qos,
+                    topic,
+                    id,
+                    payload: body.slice_from(pos),
+                }
+            }
+            4 => Packet::PubAck {
+                id: get_u16(&body, &mut pos)?,
+            },
+            9 => {
+                // Only one topic is ever subscribed to, so the id carries no
+                // information and the first return code is the interesting part.
+                get_u16(&body, &mut pos)?;
+                Packet::SubAck {
+                    code: *body.get(pos).ok_or_else(|| invalid("short SUBACK"))?,
+                }
+            }
+            13 => Packet::PingResp,
+            _ => Packet::Other,
+        };
+
+        Ok(Some(packet))
+    }
+}
+
+/// Connection state of the bridge.
+enum Conn {
+    /// Waiting before the next connection attempt.
+    Idle(Delay),
+    Connecting(Connect),
+    /// Connected, waiting for the broker's CONNACK.
+    Handshaking(Framed<TcpStream, Codec>),
+    Running {
+        framed: Framed<TcpStream, Codec>,
+        /// `None` when keep alive is disabled (set to 0).
+        keep_alive: Option<Interval>,
+        /// Set when a PINGREQ is unanswered. If the next keep alive tick
+        /// comes around before the PINGRESP, the broker is gone.
+        ping_pending: bool,
+        /// Whether the name of the broker moved elsewhere.
+        refresh: Refresh,
+    },
+}
+
+/// The bridge, a future that runs for the lifetime of the server.
+pub struct Bridge {
+    config: MqttConfig,
+    state: State,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+    conn: Conn,
+
+    /// Packets waiting to be written to the broker.
+    outbox: VecDeque<Packet>,
+
+    /// QoS 1 publishes sent but not yet acknowledged, by packet ID.
+    inflight: BTreeMap<u16, Packet>,
+
+    /// Last packet ID handed out. IDs must be non-zero.
+    last_id: u16,
+}
+
+impl Bridge {
+    pub fn new(mut config: MqttConfig, state: State) -> Bridge {
+        if config.qos > 1 {
+            logging::warn!(
+                "mqtt_qos_unsupported";
+                "mqtt QoS {} is not supported, using 1", config.qos
+            );
+            config.qos = 1;
+        }
+
+        let events = state.subscribe();
+        let conn = Conn::Connecting(resolve::connect(&config.broker));
+        Bridge {
+            config,
+            state,
+            events,
+            conn,
+            outbox: VecDeque::new(),
+            inflight: BTreeMap::new(),
+            last_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.last_id = self.last_id.wrapping_add(1);
+            if self.last_id != 0 && !self.inflight.contains_key(&self.last_id) {
+                return self.last_id;
+            }
+        }
+    }
+
+    /// Turn new chat messages into publishes.
+    fn poll_events(&mut self) {
+        for _ in 0..EVENTS_PER_TICK {
+            // Polling an `UnboundedReceiver` cannot fail.
+            let (side, name, body) = match self.events.poll().unwrap() {
+                Async::Ready(Some(ChatEvent::Message {
+                    side, name, body, ..
+                })) => (side, name, body),
+                Async::Ready(Some(_)) => continue,
+                _ => return,
+            };
+
+            let connected = match self.conn {
+                Conn::Running { .. } => true,
+                _ => false,
+            };
+            // Without a connection QoS 0 publishes are simply lost, that is
+            // what QoS 0 means.
+            if connected || self.config.qos > 0 {
+                let id = if self.config.qos > 0 {
+                    self.next_id()
+                } else {
+                    0
+                };
+                let publish = Packet::Publish {
+                    dup: false,
+                    qos: self.config.qos,
+                    topic: format!("{}/{}/{}", self.config.topic, side, name),
+                    id,
+                    payload: Bytes::from(body),
+                };
+                if self.config.qos > 0 {
+                    self.inflight.insert(id, publish.clone());
+                    if self.inflight.len() > MAX_QUEUED {
+                        let oldest = *self.inflight.keys().next().unwrap();
+                        self.inflight.remove(&oldest);
+                    }
+                }
+                if connected {
+                    self.outbox.push_back(publish);
+                }
+            }
+        }
+        // Out of budget, with events maybe left for the next tick.
+        task::current().notify();
+    }
+
+    /// Handle a packet received while running.
+    fn received(&mut self, packet: Packet) {
+        match packet {
+            Packet::Publish {
+                qos,
+                topic,
+                id,
+                payload,
+                ..
+            } => {
+                if qos > 0 {
+                    self.outbox.push_back(Packet::PubAck { id });
+                }
+
+                // `chat/in/<name>`
+                let name = match topic.rsplit('/').next() {
+                    Some(name) if !name.is_empty() => name,
+                    _ => return,
+                };
+                for text in String::from_utf8_lossy(&payload).lines() {
+                    let mut line = BytesMut::with_capacity(name.len() + text.len() + 4);
+                    line.put(name);
+                    line.put(": ");
+                    line.put(text);
+                    line.put("\r\n");
+                    self.state.inject(line.freeze());
+                }
+            }
+            Packet::PubAck { id } => {
+                self.inflight.remove(&id);
+            }
+            Packet::SubAck { code: 0x80 } => {
+                logging::error!(
+                    "mqtt_subscribe_refused";
+                    "mqtt broker refused the subscription to {}/in/+",
+                    self.config.topic
+                );
+            }
+            Packet::PingResp => {
+                if let Conn::Running {
+                    ref mut ping_pending,
+                    ..
+                } = self.conn
+                {
+                    *ping_pending = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drive the connection state machine. Errors are handled by the caller,
+    /// which schedules a reconnect.
+    fn poll_conn(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let next = match self.conn {
+                Conn::Idle(ref mut delay) => {
+                    try_ready!(delay
+                        .poll()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+                    Conn::Connecting(resolve::connect(&self.config.broker))
+                }
+                Conn::Connecting(ref mut connect) => {
+                    let socket = try_ready!(connect.poll());
+                    let mut framed = Framed::new(socket, Codec);
+                    // The CONNECT always fits the empty write buffer.
+                    framed.start_send(Packet::Connect {
+                        client_id: self.config.client_id.clone(),
+                        keep_alive: self.config.keep_alive,
+                        username: self.config.username.clone(),
+                        password: self.config.password.clone(),
+                    })?;
+                    Conn::Handshaking(framed)
+                }
+                Conn::Handshaking(ref mut framed) => {
+                    framed.poll_complete()?;
+                    match try_ready!(framed.poll()) {
+                        Some(Packet::ConnAck { code: 0 }) => {}
+                        Some(Packet::ConnAck { code }) => {
+                            return Err(invalid(&format!("broker refused connection ({})", code)));
+                        }
+                        _ => return Err(invalid("expected CONNACK")),
+                    }
+
+                    logging::info!(
+                        "mqtt_connected", addr = &self.config.broker;
+                        "mqtt connected to {}", self.config.broker
+                    );
+                    let id = self.next_id();
+                    self.outbox.push_back(Packet::Subscribe {
+                        id,
+                        topic: format!("{}/in/+", self.config.topic),
+                        qos: self.config.qos,
+                    });
+                    // Messages the broker never acknowledged are sent again,
+                    // flagged as duplicates.
+                    for packet in self.inflight.values_mut() {
+                        if let Packet::Publish { ref mut dup, .. } = packet {
+                            *dup = true;
+                        }
+                        self.outbox.push_back(packet.clone());
+                    }
+
+                    let keep_alive = match self.config.keep_alive {
+                        0 => None,
+                        secs => {
+                            let period = Duration::from_secs(u64::from(secs));
+                            Some(Interval::new(Instant::now() + period, period))
+                        }
+                    };
+                    let framed = match std::mem::replace(
+                        &mut self.conn,
+                        Conn::Idle(Delay::new(Instant::now())),
+                    ) {
+                        Conn::Handshaking(framed) => framed,
+                        _ => unreachable!(),
+                    };
+                    let refresh = Refresh::new(&self.config.broker, framed.get_ref().peer_addr()?);
+                    Conn::Running {
+                        framed,
+                        keep_alive,
+                        ping_pending: false,
+                        refresh,
+                    }
+                }
+                Conn::Running {
+                    ref mut framed,
+                    ref mut keep_alive,
+                    ref mut ping_pending,
+                    ref mut refresh,
+                } => {
+                    if refresh.poll()?.is_ready() {
+                        return Err(invalid("broker moved to another address"));
+                    }
+
+                    let tick = match keep_alive {
+                        Some(keep_alive) => keep_alive
+                            .poll()
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+                        None => Async::NotReady,
+                    };
+                    if let Async::Ready(Some(_)) = tick {
+                        if *ping_pending {
+                            return Err(invalid("broker stopped answering pings"));
+                        }
+                        *ping_pending = true;
+                        self.outbox.push_back(Packet::PingReq);
+                    }
+
+                    // Write as much of the outbox as the socket takes.
+                    while let Some(packet) = self.outbox.pop_front() {
+                        if let AsyncSink::NotReady(packet) = framed.start_send(packet)? {
+                            self.outbox.push_front(packet);
+                            break;
+                        }
+                    }
+                    framed.poll_complete()?;
+
+                    let mut received = Vec::new();
+                    while let Async::Ready(packet) = framed.poll()? {
+                        match packet {
+                            Some(packet) => received.push(packet),
+                            None => return Err(invalid("broker closed the connection")),
+                        }
+                    }
+                    for packet in received {
+                        self.received(packet);
+                    }
+
+                    // Acknowledgements queued while handling the received
+                    // packets still need to be written.
+                    if !self.outbox.is_empty() {
+                        task::current().notify();
+                    }
+                    return Ok(Async::NotReady);
+                }
+            };
+            self.conn = next;
+        }
+    }
+}
+
+impl Future for Bridge {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_events();
+
+        if let Err(e) = self.poll_conn() {
+            logging::warn!(
+                "mqtt_disconnected", addr = &self.config.broker;
+                "mqtt error = {:?}, reconnecting in {:?}",
+                e, RECONNECT_DELAY
+            );
+            self.outbox.clear();
+            self.conn = Conn::Idle(Delay::new(Instant::now() + RECONNECT_DELAY));
+            // Poll the new delay so that this task is woken up when it
+            // expires.
+            let _ = self.poll_conn();
+        }
+
+        // The bridge never finishes on its own.
+        Ok(Async::NotReady)
+    }
+}
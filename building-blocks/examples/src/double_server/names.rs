@@ -0,0 +1,157 @@
+//! What peers may call themselves.
+//!
+//! ```json
+//! "names": {
+//!     "min_chars": 2,
+//!     "max_chars": 24,
+//!     "allow": ["letters", "digits", "punctuation"],
+//!     "reject_confusable": true
+//! }
+//! ```
+//!
+//! The first line of a peer is its name, which has to be UTF-8. It is put in
+//! Unicode normalization form C, so that `é` typed as one character or as
+//! `e` and an accent is the same name, and then has to be between
+//! `min_chars` and `max_chars` characters long, made of the classes in
+//! `allow` only. Whatever the classes, a name may not start with `/`, which
+//! would read like a command, nor start or end with whitespace.
+//!
+//! Names are unique across both sides, and not told apart by case: while
+//! `Alice` is connected, nobody else can go by `alice`. Peers are shown
+//! with their name as they wrote it, and found by it in any case, like by
+//! `/send ALICE notes.txt 1024`.
+//!
+//! With `reject_confusable`, a name that looks like the name of a connected
+//! peer without being it, like `аlice` with a Cyrillic `а` while `alice` is
+//! connected, is not taken either. Names are compared by their skeleton, as
+//! Unicode Technical Standard #39 defines it.
+//!
+//! Names banned by a moderator are not taken either, see `moderation`.
+//!
+//! A peer whose name is turned away is told why, like
+//! `* names are 1 to 32 characters` and disconnected, before it joins.
+
+use serde_derive::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+
+use crate::config::NameRules;
+use crate::conn::ConnId;
+use crate::locale::Message;
+use crate::state::{Side, State};
+
+/// Characters names may be made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CharClass {
+    /// Of any script.
+    Letters,
+    /// Of any script, too.
+    Digits,
+    /// ASCII punctuation, like `-`, `_` and `.`.
+    Punctuation,
+    /// The space character. Other whitespace is never allowed.
+    Spaces,
+}
+
+impl CharClass {
+    fn contains(self, c: char) -> bool {
+        match self {
+            CharClass::Letters => c.is_alphabetic(),
+            CharClass::Digits => c.is_numeric(),
+            CharClass::Punctuation => c.is_ascii_punctuation(),
+            CharClass::Spaces => c == ' ',
+        }
+    }
+
+    fn message(self) -> Message {
+        Message::new(match self {
+            CharClass::Letters => "class_letters",
+            CharClass::Digits => "class_digits",
+            CharClass::Punctuation => "class_punctuation",
+            CharClass::Spaces => "class_spaces",
+        })
+    }
+}
+
+/// The name that the peer sending `name` goes by, or why it cannot.
+pub fn check(rules: &NameRules, state: &State, name: &[u8]) -> Result<String, Message> {
+    let name = std::str::from_utf8(name).map_err(|_| Message::new("name_not_utf8"))?;
+    let name: String = name.nfc().collect();
+    let chars = name.chars().count();
+    if chars < rules.min_chars || chars > rules.max_chars {
+        return Err(Message::new("name_length")
+            .with("min", rules.min_chars)
+            .with("max", rules.max_chars));
+    }
+    if name.starts_with('/') {
+        return Err(Message::new("name_slash"));
+    }
+    if name.starts_with(char::is_whitespace) || name.ends_with(char::is_whitespace) {
+        return Err(Message::new("name_whitespace"));
+    }
+    let allowed = |c: char| rules.allow.iter().any(|class| class.contains(c));
+    if !name.chars().all(allowed) {
+        let classes: Vec<Message> = rules.allow.iter().map(|class| class.message()).collect();
+        return Err(Message::new("name_classes").with_message("classes", join(classes)));
+    }
+    if state.moderation.is_banned(&name) {
+        return Err(Message::new("name_banned").with("name", name));
+    }
+    if rules.reject_confusable {
+        if let Some(taken) = confusable(state, &name) {
+            return Err(Message::new("name_confusable")
+                .with("name", name)
+                .with("taken", taken));
+        }
+    }
+    Ok(name)
+}
+
+/// What names are compared by, see the module docs.
+pub fn key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// Claim `name`, which `check` took, for the peer `id`.
+pub fn claim(state: &State, name: String, id: ConnId) -> Result<String, Message> {
+    if state.claim_name(&name, id) {
+        Ok(name)
+    } else {
+        Err(Message::new("name_taken").with("name", name))
+    }
+}
+
+/// The name of a connected peer that `name` could be mistaken for, other
+/// than `name` itself.
+fn confusable(state: &State, name: &str) -> Option<String> {
+    let looks: String = skeleton(name).collect();
+    let mut found = None;
+    for &side in &[Side::C, Side::Go] {
+        state.side(side).for_each(|_, member| {
+            let alike =
+                found.is_none() && member.name != name && skeleton(&member.name).eq(looks.chars());
+            if alike {
+                found = Some(member.name.clone());
+            }
+        });
+    }
+    found
+}
+
+/// Like `letters, digits and punctuation`.
+fn join(mut words: Vec<Message>) -> Message {
+    if words.len() < 2 {
+        return words.pop().unwrap_or_else(|| Message::new("list_none"));
+    }
+    let first = words.remove(0);
+    let key = if words.len() == 1 {
+        "list_and"
+    } else {
+        "list_comma"
+    };
+    Message::new(key)
+        .with_message("first", first)
+        .with_message("rest", join(words))
+}
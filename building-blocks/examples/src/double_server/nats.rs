@@ -0,0 +1,459 @@
+//! Fan-out between servers through a NATS cluster.
+//!
+//! Several servers, for example one per datacenter, can share their
+//! conversation without connecting to each other directly. Every server
+//! publishes the lines its own peers send to `<subject>.<name>`, and
+//! subscribes to `<subject>.>` for everybody else's:
+//!
+//! ```json
+//! {"origin": "eu-1", "id": 42, "side": "c", "sender": "alice", "body": "hi"}
+//! ```
+//!
+//! A line published by a c peer of one server reaches the go peers of every
+//! server, exactly as if all of them were connected to the same one.
+//!
+//! NATS delivers at most once: lines sent while a server is disconnected from
+//! the cluster are not seen by the others.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::sync::mpsc;
+use serde_derive::{Deserialize, Serialize};
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::{Delay, Interval};
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::NatsConfig;
+use crate::logging;
+use crate::resolve::{self, Connect, Refresh};
+use crate::state::{ChatEvent, Side, State};
+
+/// Longest protocol line accepted from the server.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Largest message payload accepted from the server.
+const MAX_PAYLOAD: usize = 1 << 20;
+
+/// How many chat events are turned into publishes per poll, the same
+/// fairness rule as `LINES_PER_TICK` for the peers.
+const EVENTS_PER_TICK: usize = 10;
+
+/// Wait between reconnection attempts.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the server is pinged to find out whether it is still there.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What servers publish to each other.
+#[derive(Serialize, Deserialize)]
+struct Remote {
+    /// `name` of the publishing server.
+    origin: String,
+    id: u64,
+    side: Side,
+    sender: String,
+    body: String,
+}
+
+/// The parts of the NATS client protocol the fan-out uses.
+#[derive(Debug)]
+enum Op {
+    Info,
+    Connect(String),
+    Pub { subject: String, payload: Bytes },
+    Sub { subject: String, sid: u32 },
+    Msg { payload: Bytes },
+    Ping,
+    Pong,
+    Ok,
+    Err(String),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// NATS framing: every operation is a `\r\n` terminated line, `MSG` and
+/// `PUB` are followed by a payload of the length given in the line.
+struct Codec;
+
+impl Encoder for Codec {
+    type Item = Op;
+    type Error = io::Error;
+
+    fn encode(&mut self, op: Op, dst: &mut BytesMut) -> io::Result<()> {
+        match op {
+            Op::Connect(options) => {
+                dst.reserve(options.len() + 10);
+                dst.put("CONNECT ");
+                dst.put(options);
+                dst.put("\r\n");
+            }
+            Op::Pub { subject, payload } => {
+                let len = payload.len().to_string();
+                dst.reserve(subject.len() + len.len() + payload.len() + 10);
+                dst.put("PUB ");
+                dst.put(subject);
+                dst.put(" ");
+                dst.put(len);
+                dst.put("\r\n");
+                dst.put(payload);
+                dst.put("\r\n");
+            }
+            Op::Sub { subject, sid } => {
+                let sid = sid.to_string();
+                dst.reserve(subject.len() + sid.len() + 8);
+                dst.put("SUB ");
+                dst.put(subject);
+                dst.put(" ");
+                dst.put(sid);
+                dst.put("\r\n");
+            }
+            Op::Ping => {
+                dst.reserve(6);
+                dst.put("PING\r\n");
+            }
+            Op::Pong => {
+                dst.reserve(6);
+                dst.put("PONG\r\n");
+            }
+            _ => return Err(invalid("operation is never sent by a client")),
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Op;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Op>> {
+        let end = match src.windows(2).position(|w| w == b"\r\n") {
+            Some(end) => end,
+            None if src.len() > MAX_LINE => return Err(invalid("line too long")),
+            None => return Ok(None),
+        };
+        let line = String::from_utf8_lossy(&src[..end]).into_owned();
+        let mut words = line.split_whitespace();
+        let verb = words.next().unwrap_or("").to_ascii_uppercase();
+
+        if verb == "MSG" {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let words: Vec<&str> = words.collect();
+            // The origin is in the payload, the subject is not needed.
+            let len = match words.last() {
+                Some(len) if words.len() >= 3 => len,
+                _ => return Err(invalid("malformed MSG")),
+            };
+            let len: usize = len.parse().map_err(|_| invalid("malformed MSG"))?;
+            if len > MAX_PAYLOAD {
+                return Err(invalid("message too large"));
+            }
+            let total = end + 2 + len + 2;
+            if src.len() < total {
+                src.reserve(total - src.len());
+                return Ok(None);
+            }
+
+            let mut msg = src.split_to(total);
+            msg.split_to(end + 2);
+            msg.truncate(len);
+            return Ok(Some(Op::Msg {
+                payload: msg.freeze(),
+            }));
+        }
+
+        let op = match verb.as_str() {
+            "INFO" => Op::Info,
+            "PING" => Op::Ping,
+            "PONG" => Op::Pong,
+            "+OK" => Op::Ok,
+            "-ERR" => Op::Err(line[4..].trim().trim_matches('\'').to_string()),
+            _ => return Err(invalid(&format!("unexpected {:?}", line))),
+        };
+        src.split_to(end + 2);
+        Ok(Some(op))
+    }
+}
+
+/// Connection state of the fan-out.
+enum Conn {
+    /// Waiting before the next connection attempt.
+    Idle(Delay),
+    Connecting(Connect),
+    /// Connected, waiting for the server's INFO.
+    Handshaking(Framed<TcpStream, Codec>),
+    Running {
+        framed: Framed<TcpStream, Codec>,
+        ping: Interval,
+        /// Set when a PING is unanswered. If the next tick comes around
+        /// before the PONG, the server is gone.
+        ping_pending: bool,
+        /// Whether the name of the server moved elsewhere.
+        refresh: Refresh,
+    },
+}
+
+/// The fan-out, a future that runs for the lifetime of the server.
+pub struct Fanout {
+    config: NatsConfig,
+    state: State,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+    conn: Conn,
+
+    /// Subject this server publishes to.
+    subject: String,
+
+    /// Operations waiting to be written to the server.
+    outbox: VecDeque<Op>,
+}
+
+impl Fanout {
+    pub fn new(config: NatsConfig, state: State) -> Fanout {
+        // The name becomes a single subject token, which may not contain
+        // separators, wildcards or whitespace.
+        let token: String = config
+            .name
+            .chars()
+            .map(|c| match c {
+                '.' | '*' | '>' => '_',
+                c if c.is_whitespace() => '_',
+                c => c,
+            })
+            .collect();
+        let subject = format!("{}.{}", config.subject, token);
+
+        let events = state.subscribe();
+        let conn = Conn::Connecting(resolve::connect(&config.server));
+        Fanout {
+            config,
+            state,
+            events,
+            conn,
+            subject,
+            outbox: VecDeque::new(),
+        }
+    }
+
+    /// Turn new chat messages into publishes.
+    fn poll_events(&mut self) {
+        for _ in 0..EVENTS_PER_TICK {
+            // Polling an `UnboundedReceiver` cannot fail.
+            let remote = match self.events.poll().unwrap() {
+                Async::Ready(Some(ChatEvent::Message {
+                    id,
+                    side,
+                    name,
+                    body,
+                })) => Remote {
+                    origin: self.config.name.clone(),
+                    id,
+                    side,
+                    sender: name,
+                    body,
+                },
+                Async::Ready(Some(_)) => continue,
+                _ => return,
+            };
+
+            // Without a connection the line is lost for the other servers.
+            if let Conn::Running { .. } = self.conn {
+                // Serializing a struct of strings and numbers cannot fail.
+                let payload = serde_json::to_vec(&remote).unwrap();
+                self.outbox.push_back(Op::Pub {
+                    subject: self.subject.clone(),
+                    payload: Bytes::from(payload),
+                });
+            }
+        }
+        // Out of budget, with events maybe left for the next tick.
+        task::current().notify();
+    }
+
+    /// Handle an operation received from the server.
+    fn received(&mut self, op: Op) -> io::Result<()> {
+        match op {
+            Op::Msg { payload, .. } => {
+                let remote: Remote = match serde_json::from_slice(&payload) {
+                    Ok(remote) => remote,
+                    Err(e) => {
+                        logging::warn!(
+                            "nats_message_malformed";
+                            "nats dropped malformed message: {}", e
+                        );
+                        return Ok(());
+                    }
+                };
+                // Our own lines come back too, the local peers already have
+                // them.
+                if remote.origin == self.config.name {
+                    return Ok(());
+                }
+
+                for text in remote.body.lines() {
+                    let mut line = BytesMut::with_capacity(remote.sender.len() + text.len() + 4);
+                    line.put(remote.sender.as_str());
+                    line.put(": ");
+                    line.put(text);
+                    line.put("\r\n");
+                    self.state
+                        .broadcast(remote.side.other(), None, None, &line.freeze());
+                }
+            }
+            Op::Ping => self.outbox.push_back(Op::Pong),
+            Op::Pong => {
+                if let Conn::Running {
+                    ref mut ping_pending,
+                    ..
+                } = self.conn
+                {
+                    *ping_pending = false;
+                }
+            }
+            Op::Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Drive the connection state machine. Errors are handled by the caller,
+    /// which schedules a reconnect.
+    fn poll_conn(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let next = match self.conn {
+                Conn::Idle(ref mut delay) => {
+                    try_ready!(delay
+                        .poll()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+                    Conn::Connecting(resolve::connect(&self.config.server))
+                }
+                Conn::Connecting(ref mut connect) => {
+                    let socket = try_ready!(connect.poll());
+                    Conn::Handshaking(Framed::new(socket, Codec))
+                }
+                Conn::Handshaking(ref mut framed) => {
+                    match try_ready!(framed.poll()) {
+                        Some(Op::Info) => {}
+                        _ => return Err(invalid("expected INFO")),
+                    }
+
+                    let mut options = serde_json::json!({
+                        "verbose": false,
+                        "pedantic": false,
+                        "name": self.config.name,
+                        "lang": "rust",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    });
+                    if let Some(user) = &self.config.username {
+                        options["user"] = user.as_str().into();
+                    }
+                    if let Some(pass) = &self.config.password {
+                        options["pass"] = pass.as_str().into();
+                    }
+                    self.outbox.push_back(Op::Connect(options.to_string()));
+                    self.outbox.push_back(Op::Sub {
+                        subject: format!("{}.>", self.config.subject),
+                        sid: 1,
+                    });
+                    logging::info!(
+                        "nats_connected", addr = &self.config.server;
+                        "nats connected to {}", self.config.server
+                    );
+
+                    let framed = match std::mem::replace(
+                        &mut self.conn,
+                        Conn::Idle(Delay::new(Instant::now())),
+                    ) {
+                        Conn::Handshaking(framed) => framed,
+                        _ => unreachable!(),
+                    };
+                    let refresh = Refresh::new(&self.config.server, framed.get_ref().peer_addr()?);
+                    Conn::Running {
+                        framed,
+                        ping: Interval::new(Instant::now() + PING_INTERVAL, PING_INTERVAL),
+                        ping_pending: false,
+                        refresh,
+                    }
+                }
+                Conn::Running {
+                    ref mut framed,
+                    ref mut ping,
+                    ref mut ping_pending,
+                    ref mut refresh,
+                } => {
+                    if refresh.poll()?.is_ready() {
+                        return Err(invalid("server moved to another address"));
+                    }
+
+                    let tick = ping
+                        .poll()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    if let Async::Ready(Some(_)) = tick {
+                        if *ping_pending {
+                            return Err(invalid("server stopped answering pings"));
+                        }
+                        *ping_pending = true;
+                        self.outbox.push_back(Op::Ping);
+                    }
+
+                    // Write as much of the outbox as the socket takes.
+                    while let Some(op) = self.outbox.pop_front() {
+                        if let AsyncSink::NotReady(op) = framed.start_send(op)? {
+                            self.outbox.push_front(op);
+                            break;
+                        }
+                    }
+                    framed.poll_complete()?;
+
+                    let mut received = Vec::new();
+                    while let Async::Ready(op) = framed.poll()? {
+                        match op {
+                            Some(op) => received.push(op),
+                            None => return Err(invalid("server closed the connection")),
+                        }
+                    }
+                    for op in received {
+                        self.received(op)?;
+                    }
+
+                    // PONGs queued while handling the received operations
+                    // still need to be written.
+                    if !self.outbox.is_empty() {
+                        task::current().notify();
+                    }
+                    return Ok(Async::NotReady);
+                }
+            };
+            self.conn = next;
+        }
+    }
+}
+
+impl Future for Fanout {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_events();
+
+        if let Err(e) = self.poll_conn() {
+            logging::warn!(
+                "nats_disconnected", addr = &self.config.server;
+                "nats error = {:?}, reconnecting in {:?}",
+                e, RECONNECT_DELAY
+            );
+            self.outbox.clear();
+            self.conn = Conn::Idle(Delay::new(Instant::now() + RECONNECT_DELAY));
+            // Poll the new delay so that this task is woken up when it
+            // expires.
+            let _ = self.poll_conn();
+        }
+
+        // The fan-out never finishes on its own.
+        Ok(Async::NotReady)
+    }
+}
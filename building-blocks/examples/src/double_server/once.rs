@@ -0,0 +1,335 @@
+//! Exactly-once delivery, for programs that cannot afford to lose a message
+//! or to get one twice, like one sending commands to another.
+//!
+//! ```json
+//! "exactly_once": { "path": "/var/lib/double_server/once.log", "ids_per_peer": 1024 }
+//! ```
+//!
+//! A peer asks for it with `/once`, right after its name. From then on it
+//! puts an id of its own in front of every message, and the server answers
+//! with an `ACK` once the message is relayed. Programs read it, it is not
+//! translated:
+//!
+//! ```text
+//! 17 restart worker-3
+//! * ACK 17
+//! ```
+//!
+//! A peer that crashed sends again what was not acknowledged. The server
+//! keeps the last `ids_per_peer` ids of the last `max_peers` peers, by side
+//! and name, and acknowledges an id it had again without relaying the
+//! message. Ids are written to `path`, and synced to disk, before they are
+//! acknowledged, so that a restart of the server does not forget them
+//! either. Ids are up to 64 letters, digits, `-` and `_`.
+//!
+//! The messages relayed to the peer come with their id in front, see
+//! `/history`:
+//!
+//! ```text
+//! #4711 alice: deploying
+//! ```
+//!
+//! The peer keeps the id of the last message it took in, and once back
+//! asks for `/once 4711`: it is sent the messages of the history after
+//! that one first, then the others, without any of them twice. Ids go on
+//! from one run of the server to the next. A gap, like the messages of a
+//! run that crashed or those that dropped out of the history, is told, and
+//! the messages lost. Lines of the server itself and of the integrations
+//! carry no id, and attachments are replayed as what they are about.
+
+use bytes::{Bytes, BytesMut};
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config::ExactlyOnceConfig;
+use crate::durability::{self, Durability};
+use crate::locale::Message;
+use crate::logging;
+use crate::names;
+use crate::state::{Side, State};
+
+/// Longest id of a message of a peer.
+const MAX_ID_LEN: usize = 64;
+
+/// Message ids reserved at once, so that a run that crashed did not hand
+/// out those the next one starts with.
+const RESERVE_IDS: u64 = 10_000;
+
+/// Lines the file may have beyond twice the ids kept before it is written
+/// anew.
+const REWRITE_SLACK: usize = 10_000;
+
+/// A line of the file.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    /// Ids of messages below it may have been handed out.
+    Reserved { reserved: u64 },
+    /// A message of `peer` with the id `id` was relayed.
+    Relayed { peer: String, id: String },
+}
+
+/// The ids of the messages of one peer that were relayed.
+#[derive(Default)]
+struct Relayed {
+    /// When the peer last sent one, to forget those who did not longest.
+    used: u64,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+struct Inner {
+    peers: HashMap<String, Relayed>,
+    /// Ids kept, of all peers.
+    kept: usize,
+    /// Bumped whenever a peer sends a message.
+    uses: u64,
+    reserved: u64,
+    file: File,
+    durability: Durability,
+    /// Lines in the file, to rewrite it once most are forgotten.
+    lines: usize,
+}
+
+impl Inner {
+    fn add(&mut self, peer: &str, id: &str, ids_per_peer: usize, max_peers: usize) {
+        self.uses += 1;
+        if !self.peers.contains_key(peer) && self.peers.len() >= max_peers {
+            let oldest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, relayed)| relayed.used)
+                .map(|(peer, _)| peer.clone());
+            if let Some(oldest) = oldest {
+                self.kept -= self.peers.remove(&oldest).unwrap().order.len();
+            }
+        }
+        let relayed = self.peers.entry(peer.to_string()).or_default();
+        relayed.used = self.uses;
+        if relayed.ids.insert(id.to_string()) {
+            relayed.order.push_back(id.to_string());
+            self.kept += 1;
+        }
+        if relayed.order.len() > ids_per_peer {
+            let forgotten = relayed.order.pop_front().unwrap();
+            relayed.ids.remove(&forgotten);
+            self.kept -= 1;
+        }
+    }
+
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.durability.sync(&self.file)?;
+        self.lines += 1;
+        Ok(())
+    }
+}
+
+/// The ids of the messages relayed for exactly-once peers.
+pub struct Dedup {
+    inner: Mutex<Inner>,
+    first_id: u64,
+    path: PathBuf,
+    ids_per_peer: usize,
+    max_peers: usize,
+}
+
+impl Dedup {
+    /// Read the ids back from the file, if there is one, and reserve the
+    /// message ids of this run.
+    pub fn open(config: &ExactlyOnceConfig) -> io::Result<Dedup> {
+        let mut inner = Inner {
+            peers: HashMap::new(),
+            kept: 0,
+            uses: 0,
+            reserved: 1,
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)?,
+            durability: config.durability,
+            lines: 0,
+        };
+        for line in BufReader::new(File::open(&config.path)?).lines() {
+            // A line cut short by a crash is the last one, and was never
+            // acknowledged.
+            match serde_json::from_str(&line?) {
+                Ok(Entry::Reserved { reserved }) => inner.reserved = inner.reserved.max(reserved),
+                Ok(Entry::Relayed { peer, id }) => {
+                    inner.add(&peer, &id, config.ids_per_peer, config.max_peers)
+                }
+                Err(e) => logging::warn!(
+                    "once_line_invalid";
+                    "skipped a line of {}: {}", config.path.display(), e
+                ),
+            }
+            inner.lines += 1;
+        }
+        let first_id = inner.reserved;
+        let reserved = first_id + RESERVE_IDS;
+        inner.append(&Entry::Reserved { reserved })?;
+        inner.reserved = reserved;
+        Ok(Dedup {
+            inner: Mutex::new(inner),
+            first_id,
+            path: config.path.clone(),
+            ids_per_peer: config.ids_per_peer,
+            max_peers: config.max_peers,
+        })
+    }
+
+    /// The id of the first message of this run.
+    pub fn first_id(&self) -> u64 {
+        self.first_id
+    }
+
+    /// Reserve more message ids once `id` was handed out, if it was the
+    /// last one reserved.
+    pub fn issued(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if id + 1 < inner.reserved {
+            return;
+        }
+        let reserved = id + 1 + RESERVE_IDS;
+        match inner.append(&Entry::Reserved { reserved }) {
+            Ok(()) => inner.reserved = reserved,
+            // The ids after it may be handed out again by the next run.
+            Err(e) => logging::error!("once_save_failed"; "exactly-once save error = {:?}", e),
+        }
+    }
+
+    /// Whether the message of `peer` with the id `id` was relayed.
+    pub fn seen(&self, peer: &str, id: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .peers
+            .get(peer)
+            .map_or(false, |relayed| relayed.ids.contains(id))
+    }
+
+    /// Take note that the message of `peer` with the id `id` is relayed,
+    /// once it is on disk.
+    pub fn record(&self, peer: &str, id: &str) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry::Relayed {
+            peer: peer.to_string(),
+            id: id.to_string(),
+        };
+        inner.append(&entry)?;
+        inner.add(peer, id, self.ids_per_peer, self.max_peers);
+        if inner.lines > 2 * inner.kept + REWRITE_SLACK {
+            if let Err(e) = self.rewrite(&mut inner) {
+                // The file only stays longer.
+                logging::warn!("once_rewrite_failed"; "exactly-once rewrite error = {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the file anew with what is kept.
+    fn rewrite(&self, inner: &mut Inner) -> io::Result<()> {
+        let mut text = String::new();
+        let mut lines = 1;
+        let reserved = Entry::Reserved {
+            reserved: inner.reserved,
+        };
+        text.push_str(&serde_json::to_string(&reserved)?);
+        text.push('\n');
+        for (peer, relayed) in &inner.peers {
+            for id in &relayed.order {
+                let entry = Entry::Relayed {
+                    peer: peer.clone(),
+                    id: id.clone(),
+                };
+                text.push_str(&serde_json::to_string(&entry)?);
+                text.push('\n');
+                lines += 1;
+            }
+        }
+        durability::replace(&self.path, text.as_bytes(), inner.durability)?;
+        inner.file = OpenOptions::new().append(true).open(&self.path)?;
+        inner.lines = lines;
+        Ok(())
+    }
+}
+
+/// What the server knows of an exactly-once peer.
+#[derive(Default)]
+pub struct Session {
+    /// The id of the message of the peer being relayed.
+    pub acking: Option<String>,
+    /// The messages sent to the peer from the history, not to be sent
+    /// again as they come.
+    pub replayed: HashSet<u64>,
+}
+
+/// Who the messages of the peer `name` of `side` are kept for.
+pub fn identity(side: Side, name: &str) -> String {
+    format!("{}:{}", side, names::key(name))
+}
+
+/// The id in front of a message of an exactly-once peer, and the rest.
+pub fn split(message: &[u8]) -> Option<(String, &[u8])> {
+    let space = message.iter().position(|&b| b == b' ')?;
+    let id = &message[..space];
+    let valid = |b: &u8| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_';
+    if id.is_empty() || id.len() > MAX_ID_LEN || !id.iter().all(valid) {
+        return None;
+    }
+    let id = String::from_utf8(id.to_vec()).ok()?;
+    Some((id, &message[space + 1..]))
+}
+
+/// A message relayed to an exactly-once peer, with its id in front.
+pub fn numbered(id: u64, line: &[u8]) -> Bytes {
+    let prefix = format!("#{} ", id);
+    let mut numbered = BytesMut::with_capacity(prefix.len() + line.len());
+    numbered.extend_from_slice(prefix.as_bytes());
+    numbered.extend_from_slice(line);
+    numbered.freeze()
+}
+
+/// The messages of the history relayed to `side` after the one with the
+/// id `last`, numbered, and the gap before them, if there is one.
+pub fn replay(
+    state: &State,
+    side: Side,
+    last: u64,
+    session: &mut Session,
+) -> (Vec<Bytes>, Option<Message>) {
+    // Nothing comes after the last id there is, whatever the peer says.
+    let next = match last.checked_add(1) {
+        Some(next) => next,
+        None => return (Vec::new(), None),
+    };
+    let history = state.history(None, None);
+    let first = history
+        .first()
+        .map_or_else(|| state.last_message_id() + 1, |m| m.id);
+    let gap = if next < first {
+        Some(
+            Message::new("once_gap")
+                .with("from", next)
+                .with("to", first - 1),
+        )
+    } else {
+        None
+    };
+    let mut lines = Vec::new();
+    for m in history
+        .iter()
+        .filter(|m| m.id > last && m.side == side.other())
+    {
+        let line = format!("{}: {}\r\n", m.name, m.body);
+        lines.push(numbered(m.id, line.as_bytes()));
+        session.replayed.insert(m.id);
+    }
+    (lines, gap)
+}
@@ -0,0 +1,123 @@
+//! Instrumentation for finding hot paths, with the `profiling` feature.
+//!
+//! ```text
+//! cargo run --features profiling --bin double_server
+//! ```
+//!
+//! Every task (peers, listeners, integrations) then runs in a `tracing`
+//! span that logs how long the task was busy and idle once it ends. Relaying
+//! a message to the other side runs in a `broadcast` span at the debug
+//! level. `RUST_LOG` picks what is logged, `info` unless set. (tokio-console
+//! needs the instrumentation of tokio 1, which this runtime predates, so the
+//! spans are logged instead.)
+//!
+//! ```text
+//! RUST_LOG=double_server=debug
+//! ```
+//!
+//! CPU profiles are taken with `POST /admin/profile?seconds=30`, which
+//! samples the whole process for that long and answers a flamegraph, see
+//! `admin`.
+//!
+//! Without the feature the spans are a unit struct and cost nothing.
+
+use futures::Future;
+
+#[cfg(feature = "profiling")]
+pub use tracing::Span;
+
+/// Stands in for `tracing::Span` without the `profiling` feature.
+#[cfg(not(feature = "profiling"))]
+#[derive(Clone, Debug)]
+pub struct Span;
+
+#[cfg(not(feature = "profiling"))]
+impl Span {
+    pub fn entered(self) -> Span {
+        self
+    }
+}
+
+/// Create an info level span with `tracing` syntax, like
+/// `span!("peer", side = %side)`. The fields are not evaluated without the
+/// `profiling` feature.
+macro_rules! span {
+    ($name:expr $(, $($fields:tt)*)?) => {{
+        #[cfg(feature = "profiling")]
+        let span = tracing::info_span!($name $(, $($fields)*)?);
+        #[cfg(not(feature = "profiling"))]
+        let span = $crate::profiling::Span;
+        span
+    }};
+}
+
+/// Like `span!`, at the debug level, for the spans entered per message.
+macro_rules! debug_span {
+    ($name:expr $(, $($fields:tt)*)?) => {{
+        #[cfg(feature = "profiling")]
+        let span = tracing::debug_span!($name $(, $($fields)*)?);
+        #[cfg(not(feature = "profiling"))]
+        let span = $crate::profiling::Span;
+        span
+    }};
+}
+
+pub(crate) use debug_span;
+pub(crate) use span;
+
+/// Run `future` in `span`.
+#[cfg(feature = "profiling")]
+pub fn instrument<F: Future>(
+    future: F,
+    span: Span,
+) -> impl Future<Item = F::Item, Error = F::Error> {
+    tracing_futures::Instrument::instrument(future, span)
+}
+
+/// Run `future` in `span`.
+#[cfg(not(feature = "profiling"))]
+pub fn instrument<F: Future>(
+    future: F,
+    _span: Span,
+) -> impl Future<Item = F::Item, Error = F::Error> {
+    future
+}
+
+/// Log the spans, as configured by `RUST_LOG`.
+#[cfg(feature = "profiling")]
+pub fn init() {
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn init() {}
+
+/// Sample the CPU of the whole process for `duration` and render the
+/// result as a flamegraph SVG. Blocks for `duration`.
+#[cfg(feature = "profiling")]
+pub fn cpu_profile(duration: std::time::Duration) -> std::io::Result<Vec<u8>> {
+    use std::io;
+
+    /// Samples per second.
+    const FREQUENCY: i32 = 99;
+
+    let error = |e: pprof::Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+    let guard = pprof::ProfilerGuard::new(FREQUENCY).map_err(error)?;
+    std::thread::sleep(duration);
+    let report = guard.report().build().map_err(error)?;
+
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg).map_err(error)?;
+    if svg.is_empty() {
+        let message = "no samples, the process was idle";
+        return Err(io::Error::new(io::ErrorKind::Other, message));
+    }
+    Ok(svg)
+}
@@ -0,0 +1,378 @@
+//! The protocol of one peer with no socket: bytes in, what they mean and
+//! what to answer out.
+//!
+//! A `Connection` splits what a peer sends into lines, takes the `CAP`
+//! lines of the handshake (see `cap`), and makes messages of the lines
+//! after it (see `wire`):
+//!
+//! ```text
+//! feed_bytes("CAP LS\r\n")    bytes_to_send() is "CAP * LS :ids ...\r\n"
+//! feed_bytes("alice\r\nhi")   next_event() is Message(Join("alice"))
+//! feed_bytes("\r\n")          next_event() is Message(Chat("hi"))
+//! ```
+//!
+//! It has no socket, task or timer, so it does the same under any of them.
+//! Here `Lines` drives it: it reads the socket right into `read_buf`, or
+//! feeds it what it decompressed, and writes `bytes_to_send` before
+//! anything else. The commands are answered by the `Peer`, which holds the
+//! state they need.
+//!
+//! Events are made one at a time, as they are asked for. A driver changing
+//! what the bytes are after one of them, like `Event::Compress` or
+//! `/compress`, takes the bytes after it as they came with `unread`.
+
+use bytes::BytesMut;
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::cap::{Capabilities, Handshake, Offer, Step};
+use crate::compression::Codec;
+use crate::wire::{Decoder, Message, TooLarge};
+
+/// What a peer did, as far as the protocol goes.
+pub enum Event {
+    /// The peer ended a handshake granting `compress`: everything after
+    /// the line break is compressed with the codec, both ways.
+    Compress(Arc<dyn Codec>),
+    /// A message, or the name first.
+    Message(Message),
+    /// A message got longer than `max_message_bytes`, and is dropped.
+    TooLarge,
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Compress(codec) => write!(f, "Compress({})", codec.name()),
+            Event::Message(message) => write!(f, "{:?}", message),
+            Event::TooLarge => write!(f, "TooLarge"),
+        }
+    }
+}
+
+/// Why the peer cannot be understood, and is disconnected.
+#[derive(Debug)]
+pub enum Error {
+    /// More than `max_unframed` bytes came without a line break.
+    Unframed(usize),
+    /// The handshake went wrong, see `cap`.
+    Handshake(String),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let message = match e {
+            Error::Unframed(max) => format!("more than {} bytes without a line break", max),
+            Error::Handshake(message) => message,
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
+
+/// The protocol state of one peer.
+pub struct Connection {
+    /// What was fed and is not a line yet.
+    rd: BytesMut,
+    /// Most bytes in `rd` without a line break, see
+    /// `Config::max_unframed_bytes`.
+    max_unframed: usize,
+    /// Average length of the recent lines.
+    line_size: f64,
+    /// The handshake, until the name came, if there is one.
+    handshake: Option<Handshake>,
+    /// What the handshake granted.
+    capabilities: Capabilities,
+    decoder: Decoder,
+    /// What to send the peer.
+    out: BytesMut,
+}
+
+impl Connection {
+    /// A connection whose first line is the name, with lines of at most
+    /// `max_unframed` bytes and messages of at most `max_message`.
+    pub fn new(max_unframed: usize, max_message: usize) -> Connection {
+        Connection {
+            rd: BytesMut::new(),
+            max_unframed,
+            line_size: 0.0,
+            handshake: None,
+            capabilities: Capabilities::default(),
+            decoder: Decoder::new(max_message),
+            out: BytesMut::new(),
+        }
+    }
+
+    /// Take a handshake before the name, with what `offer` has.
+    pub fn negotiate(&mut self, offer: Offer) {
+        self.handshake = Some(Handshake::new(offer));
+    }
+
+    /// Take the lines as messages from the first, for a peer named without
+    /// a line of its own, see `dial`.
+    pub fn named(&mut self) {
+        self.handshake = None;
+        self.decoder.named();
+    }
+
+    /// Take the lines of the bridge from here on, once the peer logged in
+    /// as a link.
+    pub fn link(&mut self) {
+        self.decoder.link();
+    }
+
+    /// Join the frames of a message, for a connection with an encoding,
+    /// which sends its messages as frames, see `frames`.
+    pub fn frames(&mut self) {
+        self.decoder.frames();
+    }
+
+    /// Whether the peer knows of frames, and is sent messages split into
+    /// them, see `frames`.
+    pub fn knows_frames(&self) -> bool {
+        self.decoder.knows_frames()
+    }
+
+    /// What the handshake granted, nothing before it ended.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Take `bytes`, the next the peer sent.
+    pub fn feed_bytes(&mut self, bytes: &[u8]) {
+        self.rd.extend_from_slice(bytes);
+    }
+
+    /// Where the next bytes of the peer go, for a driver reading right into
+    /// it rather than calling `feed_bytes`.
+    pub fn read_buf(&mut self) -> &mut BytesMut {
+        &mut self.rd
+    }
+
+    /// Bytes fed and not taken yet.
+    pub fn buffered(&self) -> usize {
+        self.rd.len()
+    }
+
+    /// Room in `read_buf`, for the metrics.
+    pub fn capacity(&self) -> usize {
+        self.rd.capacity()
+    }
+
+    /// Average length of the recent lines, for sizing `read_buf`.
+    pub fn line_size(&self) -> f64 {
+        self.line_size
+    }
+
+    /// What was fed and not taken yet.
+    pub fn unread(&mut self) -> BytesMut {
+        self.rd.take()
+    }
+
+    /// What to send the peer, now.
+    pub fn bytes_to_send(&mut self) -> BytesMut {
+        self.out.take()
+    }
+
+    /// The next event of what was fed, `None` until more bytes come.
+    pub fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        while let Some(line) = self.next_line()? {
+            if let Some(handshake) = &mut self.handshake {
+                match handshake.take(&line).map_err(Error::Handshake)? {
+                    Step::Reply(reply) => {
+                        let reply = format!("CAP * {}\r\n", reply);
+                        self.out.extend_from_slice(reply.as_bytes());
+                        continue;
+                    }
+                    Step::End => match &handshake.granted().compress {
+                        Some(codec) => return Ok(Some(Event::Compress(codec.clone()))),
+                        None => continue,
+                    },
+                    Step::Name => {
+                        self.capabilities = handshake.granted().clone();
+                        self.decoder.speak(self.capabilities.version);
+                        self.handshake = None;
+                    }
+                }
+            }
+            match self.decoder.decode(line) {
+                Ok(Some(message)) => return Ok(Some(Event::Message(message))),
+                Ok(None) => {}
+                Err(TooLarge) => return Ok(Some(Event::TooLarge)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// The next line fed, without its line break.
+    fn next_line(&mut self) -> Result<Option<BytesMut>, Error> {
+        match self.rd.windows(2).position(|bytes| bytes == b"\r\n") {
+            Some(pos) => {
+                let mut line = self.rd.split_to(pos + 2);
+                line.truncate(pos);
+                // Recent lines weigh the most.
+                self.line_size = (self.line_size * 7.0 + line.len() as f64) / 8.0;
+                Ok(Some(line))
+            }
+            None if self.rd.len() > self.max_unframed => Err(Error::Unframed(self.max_unframed)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::Version;
+
+    /// The events of what was fed so far, as text.
+    fn events(connection: &mut Connection) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Some(event) = connection.next_event().unwrap() {
+            let event = match event {
+                Event::Message(Message::Join(name)) => {
+                    format!("join {}", String::from_utf8_lossy(&name))
+                }
+                Event::Message(Message::Chat { text, .. }) => {
+                    format!("chat {}", String::from_utf8_lossy(&text))
+                }
+                Event::Message(Message::Command(text)) => {
+                    format!("command {}", String::from_utf8_lossy(&text))
+                }
+                event => format!("{:?}", event),
+            };
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn name_then_messages() {
+        let mut connection = Connection::new(64, 1024);
+        connection.feed_bytes(b"alice\r\nhi\r\n/who\r\n");
+        assert_eq!(events(&mut connection), ["join alice", "chat hi", "command /who"]);
+        assert!(connection.bytes_to_send().is_empty());
+    }
+
+    #[test]
+    fn lines_split_across_feeds() {
+        let mut connection = Connection::new(64, 1024);
+        let mut seen = Vec::new();
+        for &byte in b"alice\r\nhello there\r\n" {
+            connection.feed_bytes(&[byte]);
+            seen.extend(events(&mut connection));
+        }
+        assert_eq!(seen, ["join alice", "chat hello there"]);
+    }
+
+    #[test]
+    fn line_break_split_across_feeds() {
+        let mut connection = Connection::new(64, 1024);
+        connection.feed_bytes(b"alice\r");
+        assert!(events(&mut connection).is_empty());
+        connection.feed_bytes(b"\nhi\r");
+        assert_eq!(events(&mut connection), ["join alice"]);
+        connection.feed_bytes(b"\n");
+        assert_eq!(events(&mut connection), ["chat hi"]);
+    }
+
+    #[test]
+    fn frames_of_a_v2_client_are_joined() {
+        let mut connection = Connection::new(64, 1024);
+        connection.negotiate(Offer::plain());
+        connection.feed_bytes(b"CAP REQ :v2\r\nCAP END\r\nalice\r\n");
+        assert_eq!(events(&mut connection), ["join alice"]);
+        assert_eq!(connection.capabilities().version, Version::V2);
+        connection.feed_bytes(b"a long\\\r\n");
+        assert!(events(&mut connection).is_empty());
+        connection.feed_bytes(b" message\r\n");
+        assert_eq!(events(&mut connection), ["chat a long message"]);
+    }
+
+    #[test]
+    fn frames_of_a_v1_client_are_lines() {
+        let mut connection = Connection::new(64, 1024);
+        connection.feed_bytes(b"alice\r\ndir C:\\\r\nhi\r\n");
+        assert_eq!(events(&mut connection), ["join alice", "chat dir C:\\", "chat hi"]);
+    }
+
+    #[test]
+    fn oversized_line_fails() {
+        let mut connection = Connection::new(8, 1024);
+        connection.feed_bytes(b"alice\r\n0123456789");
+        assert!(connection.next_event().unwrap().is_some());
+        match connection.next_event() {
+            Err(Error::Unframed(8)) => {}
+            event => panic!("got {:?}", event.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn line_within_the_limit_passes() {
+        let mut connection = Connection::new(8, 1024);
+        connection.feed_bytes(b"alice\r\n012345");
+        assert_eq!(events(&mut connection), ["join alice"]);
+        connection.feed_bytes(b"\r\n");
+        assert_eq!(events(&mut connection), ["chat 012345"]);
+    }
+
+    #[test]
+    fn oversized_message_is_dropped() {
+        let mut connection = Connection::new(64, 8);
+        connection.named();
+        connection.link();
+        connection.feed_bytes(b"aaaaaa\\\r\nbbbbbb\\\r\ncc\r\nhi\r\n");
+        assert_eq!(events(&mut connection), ["TooLarge", "chat hi"]);
+    }
+
+    #[test]
+    fn empty_lines_are_no_events() {
+        let mut connection = Connection::new(64, 1024);
+        connection.feed_bytes(b"alice\r\n\r\n\r\nhi\r\n\r\n");
+        assert_eq!(events(&mut connection), ["join alice", "chat hi"]);
+    }
+
+    #[test]
+    fn eof_mid_line_leaves_it_unread() {
+        let mut connection = Connection::new(64, 1024);
+        connection.feed_bytes(b"alice\r\nhal");
+        assert_eq!(events(&mut connection), ["join alice"]);
+        assert_eq!(&connection.unread()[..], b"hal");
+        assert!(events(&mut connection).is_empty());
+    }
+
+    #[test]
+    fn eof_mid_message_relays_nothing() {
+        let mut connection = Connection::new(64, 1024);
+        connection.named();
+        connection.link();
+        connection.feed_bytes(b"first part\\\r\n");
+        assert!(events(&mut connection).is_empty());
+        assert!(connection.unread().is_empty());
+    }
+
+    #[test]
+    fn handshake_is_answered_before_the_name() {
+        let mut connection = Connection::new(64, 1024);
+        connection.negotiate(Offer::plain());
+        connection.feed_bytes(b"CAP LS\r\n");
+        assert!(events(&mut connection).is_empty());
+        assert_eq!(&connection.bytes_to_send()[..], &b"CAP * LS :ids timestamps v2\r\n"[..]);
+
+        connection.feed_bytes(b"CAP REQ :ids bogus\r\nCAP REQ :ids\r\nCAP END\r\nbob\r\n");
+        assert_eq!(events(&mut connection), ["join bob"]);
+        let replies = connection.bytes_to_send();
+        assert_eq!(&replies[..], &b"CAP * NAK :ids bogus\r\nCAP * ACK :ids\r\n"[..]);
+        assert!(connection.capabilities().ids);
+    }
+
+    #[test]
+    fn name_without_handshake() {
+        let mut connection = Connection::new(64, 1024);
+        connection.negotiate(Offer::plain());
+        connection.feed_bytes(b"carol\r\nCAP LS\r\n");
+        assert_eq!(events(&mut connection), ["join carol", "chat CAP LS"]);
+        assert!(connection.bytes_to_send().is_empty());
+    }
+}
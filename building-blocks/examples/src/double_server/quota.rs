@@ -0,0 +1,176 @@
+//! Daily quotas of the bytes each peer may relay.
+//!
+//! Usage is kept per identity, the side and name a peer joined with in any
+//! case (see `names`), and resets at midnight UTC. It is saved to a file
+//! every few seconds and read back at startup, so that reconnecting or
+//! restarting the server does not give a peer a fresh quota.
+
+use futures::{Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Interval;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::QuotaConfig;
+use crate::durability::{self, Durability};
+use crate::logging;
+use crate::names;
+use crate::state::{now_ms, Side};
+
+/// How often changed usage is saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// What is saved to the file.
+#[derive(Default, Serialize, Deserialize)]
+struct Usage {
+    /// Days since the epoch the usage belongs to.
+    day: u64,
+    /// Bytes relayed by each identity on `day`.
+    bytes: HashMap<String, u64>,
+}
+
+struct Inner {
+    usage: Usage,
+    /// Whether `usage` changed since it was last saved.
+    dirty: bool,
+}
+
+impl Inner {
+    /// Start over if the day changed since the last use.
+    fn roll_over(&mut self) {
+        let today = now_ms() / DAY_MS;
+        if self.usage.day != today {
+            self.usage = Usage {
+                day: today,
+                bytes: HashMap::new(),
+            };
+            self.dirty = true;
+        }
+    }
+}
+
+/// A message that would exceed the quota of its peer.
+#[derive(Debug)]
+pub struct Exceeded {
+    pub used: u64,
+    pub limit: u64,
+}
+
+/// The quotas of all peers. Cloning is cheap.
+#[derive(Clone)]
+pub struct Quotas {
+    inner: Arc<Mutex<Inner>>,
+    daily_bytes: u64,
+    path: PathBuf,
+    durability: Durability,
+}
+
+fn identity(side: Side, name: &str) -> String {
+    format!("{}:{}", side, names::key(name))
+}
+
+/// `usage` by the identities of now. Files saved before names were told
+/// apart by case have a key per way a name was written, whose usage adds up
+/// to that of the name.
+fn rekey(usage: Usage) -> Usage {
+    let mut bytes = HashMap::new();
+    for (saved, used) in usage.bytes {
+        let key = match saved.find(':') {
+            Some(at) => format!("{}{}", &saved[..=at], names::key(&saved[at + 1..])),
+            None => saved,
+        };
+        *bytes.entry(key).or_insert(0) += used;
+    }
+    Usage {
+        day: usage.day,
+        bytes,
+    }
+}
+
+impl Quotas {
+    /// Read the saved usage, if any.
+    pub fn load(config: &QuotaConfig) -> io::Result<Quotas> {
+        let usage = match File::open(&config.path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", config.path.display(), e),
+                )
+            })?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Usage::default(),
+            Err(e) => return Err(e),
+        };
+        let usage = rekey(usage);
+
+        Ok(Quotas {
+            inner: Arc::new(Mutex::new(Inner {
+                usage,
+                dirty: false,
+            })),
+            daily_bytes: config.daily_bytes,
+            path: config.path.clone(),
+            durability: config.durability,
+        })
+    }
+
+    /// Account for `bytes` relayed by a peer, unless they exceed its quota.
+    pub fn charge(&self, side: Side, name: &str, bytes: u64) -> Result<(), Exceeded> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.roll_over();
+
+        let used = inner.usage.bytes.entry(identity(side, name)).or_insert(0);
+        if *used + bytes > self.daily_bytes {
+            return Err(Exceeded {
+                used: *used,
+                limit: self.daily_bytes,
+            });
+        }
+        *used += bytes;
+        inner.dirty = true;
+        Ok(())
+    }
+
+    /// Bytes used today by a peer, and its quota.
+    pub fn usage(&self, side: Side, name: &str) -> (u64, u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.roll_over();
+        let used = inner.usage.bytes.get(&identity(side, name)).cloned();
+        (used.unwrap_or(0), self.daily_bytes)
+    }
+
+    /// Write the usage to the file if it changed.
+    pub fn save(&self) -> io::Result<()> {
+        let json = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            inner.dirty = false;
+            serde_json::to_vec(&inner.usage)?
+        };
+
+        durability::replace(&self.path, &json, self.durability)
+    }
+
+    /// Save the usage every `SAVE_INTERVAL`.
+    pub fn persist(&self) -> impl Future<Item = (), Error = ()> {
+        let quotas = self.clone();
+        Interval::new_interval(SAVE_INTERVAL)
+            .map_err(|e| logging::error!("timer_failed"; "quota timer error = {:?}", e))
+            .for_each(move |_| {
+                if let Err(e) = quotas.save() {
+                    // Try again at the next tick.
+                    quotas.inner.lock().unwrap().dirty = true;
+                    logging::warn!("quota_save_failed"; "quota save error = {:?}", e);
+                }
+                Ok(())
+            })
+    }
+}
@@ -0,0 +1,95 @@
+//! Rate limits of the peers.
+//!
+//! Every peer gets a token bucket for its messages and one per command, so
+//! that expensive commands can be limited much tighter than chatting. On top
+//! of that, the `Throttle` limits the messages of all peers together, and
+//! the bytes of attachments a peer sends have a bucket of their own. The
+//! buckets are the ones of `building_blocks::shaping`.
+
+use building_blocks::shaping::{Shaper, TokenBucket};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{Admission, AttachmentConfig, Rate, RateLimits};
+
+fn bucket(rate: Rate) -> TokenBucket {
+    TokenBucket::new(rate.per_second, u64::from(rate.burst))
+}
+
+/// The buckets of one peer.
+#[derive(Debug)]
+pub struct Limiter {
+    messages: Option<TokenBucket>,
+    commands: HashMap<String, TokenBucket>,
+    default_command: Rate,
+    /// Refilled with `per_minute_bytes` every minute.
+    attachments: TokenBucket,
+}
+
+impl Limiter {
+    pub fn new(limits: &RateLimits, attachments: &AttachmentConfig) -> Limiter {
+        let per_minute = attachments.per_minute_bytes;
+        Limiter {
+            messages: limits.messages.map(bucket),
+            commands: limits
+                .commands
+                .iter()
+                .map(|(name, &rate)| (name.clone(), bucket(rate)))
+                .collect(),
+            default_command: limits.default_command,
+            attachments: TokenBucket::new(per_minute as f64 / 60.0, per_minute),
+        }
+    }
+
+    /// Account for a message.
+    pub fn message(&mut self) -> Result<(), Duration> {
+        match &mut self.messages {
+            Some(bucket) => bucket.try_acquire(1),
+            None => Ok(()),
+        }
+    }
+
+    /// Account for `bytes` of attachments.
+    pub fn attachment(&mut self, bytes: u64) -> Result<(), Duration> {
+        self.attachments.try_acquire(bytes)
+    }
+
+    /// Account for a command, like `/history`.
+    pub fn command(&mut self, name: &str) -> Result<(), Duration> {
+        let default = self.default_command;
+        self.commands
+            .entry(name.to_string())
+            .or_insert_with(|| bucket(default))
+            .try_acquire(1)
+    }
+}
+
+/// The server wide limit, shared by every peer.
+#[derive(Clone)]
+pub struct Throttle {
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+    admission: Admission,
+}
+
+impl Throttle {
+    pub fn new(limits: &RateLimits) -> Throttle {
+        Throttle {
+            bucket: limits.global.map(|rate| Arc::new(Mutex::new(bucket(rate)))),
+            admission: limits.admission,
+        }
+    }
+
+    /// Account for a message.
+    pub fn take(&self) -> Result<(), Duration> {
+        match &self.bucket {
+            Some(bucket) => bucket.lock().unwrap().try_acquire(1),
+            None => Ok(()),
+        }
+    }
+
+    pub fn admission(&self) -> Admission {
+        self.admission
+    }
+}
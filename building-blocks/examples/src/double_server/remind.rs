@@ -0,0 +1,293 @@
+//! Messages peers leave for later with `/remind`.
+//!
+//! ```json
+//! "reminders": { "path": "/var/lib/double_server/reminders.json", "max_per_peer": 20 }
+//! ```
+//!
+//! `/remind 10m alice stand-up` has `stand-up` delivered to `alice` ten
+//! minutes later, as a notice like `* reminder from bob: stand-up`. The
+//! delay is a number of seconds, minutes, hours or days, like `90s`, `10m`,
+//! `2h` or `1d`, and the recipient a peer of either side, or `#c` or `#go`
+//! for every peer of a side. There are no rooms. A peer not connected when
+//! its reminder is due misses it.
+//!
+//! The reminders wait in a `building_blocks::delay_queue`. With a `path`,
+//! they are saved to it every few seconds and read back at startup, so that
+//! they survive a restart, like the quotas. Reminders that came due while
+//! the server was down are delivered once it is up.
+
+use building_blocks::delay_queue::DelayQueue;
+use futures::{Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Interval;
+
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::RemindersConfig;
+use crate::durability::{self, Durability};
+use crate::locale::Message;
+use crate::logging;
+use crate::names;
+use crate::state::{Side, State};
+
+/// How often changed reminders are saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Who a reminder is for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Recipient {
+    Peer(String),
+    Side(Side),
+}
+
+impl Recipient {
+    /// `#c` and `#go` are the sides, anything else a peer.
+    fn parse(to: &str) -> Option<Recipient> {
+        match to {
+            "#c" => Some(Recipient::Side(Side::C)),
+            "#go" => Some(Recipient::Side(Side::Go)),
+            _ if to.starts_with('#') => None,
+            _ => Some(Recipient::Peer(to.to_string())),
+        }
+    }
+}
+
+/// A reminder waiting to be delivered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reminder {
+    side: Side,
+    /// Who set it.
+    name: String,
+    /// The instance its peer connected through, if instances are
+    /// partitioned. It is only delivered to peers of the same one.
+    partition: Option<usize>,
+    to: Recipient,
+    text: String,
+}
+
+/// What is saved to the file, a reminder with when it is due.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    due_ms: u64,
+    #[serde(flatten)]
+    reminder: Reminder,
+}
+
+/// The reminders of all peers. Cloning is cheap.
+#[derive(Clone)]
+pub struct Reminders {
+    queue: DelayQueue<Reminder>,
+    /// Whether the reminders changed since they were last saved.
+    dirty: Arc<AtomicBool>,
+    path: Option<PathBuf>,
+    durability: Durability,
+    max_per_peer: usize,
+    max_delay: Duration,
+}
+
+impl Reminders {
+    /// Read the saved reminders, if they are saved.
+    pub fn load(config: &RemindersConfig) -> io::Result<Reminders> {
+        let queue = DelayQueue::new();
+        if let Some(path) = &config.path {
+            let saved: Vec<Saved> = match File::open(path) {
+                Ok(file) => serde_json::from_reader(file).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), e),
+                    )
+                })?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            for saved in saved {
+                let due = UNIX_EPOCH + Duration::from_millis(saved.due_ms);
+                queue.insert(due, saved.reminder);
+            }
+        }
+
+        Ok(Reminders {
+            queue,
+            dirty: Arc::new(AtomicBool::new(false)),
+            path: config.path.clone(),
+            durability: config.durability,
+            max_per_peer: config.max_per_peer,
+            max_delay: Duration::from_secs(config.max_delay_secs),
+        })
+    }
+
+    /// Have the peer `name` of `side` in `partition` remind `to` of `text`
+    /// after `delay`, returning the reply to the peer.
+    pub fn add(
+        &self,
+        side: Side,
+        name: &str,
+        partition: Option<usize>,
+        delay: Duration,
+        to: &str,
+        text: &str,
+    ) -> Message {
+        if self.max_per_peer == 0 {
+            return Message::new("remind_off");
+        }
+        let recipient = match Recipient::parse(to) {
+            Some(recipient) => recipient,
+            None => return Message::new("remind_usage"),
+        };
+        if delay > self.max_delay {
+            return Message::new("remind_too_far").with("max", format_delay(self.max_delay));
+        }
+        let key = names::key(name);
+        let pending = self
+            .queue
+            .pending()
+            .into_iter()
+            .filter(|(_, _, r)| r.side == side && names::key(&r.name) == key)
+            .count();
+        if pending >= self.max_per_peer {
+            return Message::new("remind_too_many").with("max", self.max_per_peer);
+        }
+
+        let reminder = Reminder {
+            side,
+            name: name.to_string(),
+            partition,
+            to: recipient,
+            text: text.to_string(),
+        };
+        let id = self.queue.insert(SystemTime::now() + delay, reminder);
+        self.dirty.store(true, Ordering::Relaxed);
+        Message::new("remind_set")
+            .with("id", id)
+            .with("to", to)
+            .with("delay", format_delay(delay))
+    }
+
+    /// Deliver the reminders as they come due, a future that runs for the
+    /// lifetime of the server.
+    pub fn deliver(&self, state: State) -> impl Future<Item = (), Error = ()> {
+        let dirty = self.dirty.clone();
+        self.queue
+            .expired()
+            .map_err(|e| logging::error!("timer_failed"; "reminder timer error = {:?}", e))
+            .for_each(move |reminder| {
+                dirty.store(true, Ordering::Relaxed);
+                deliver(&state, reminder);
+                Ok(())
+            })
+    }
+
+    /// Write the reminders to the file if they are saved and changed.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let saved: Vec<Saved> = self
+            .queue
+            .pending()
+            .into_iter()
+            .map(|(_, due, reminder)| Saved {
+                due_ms: due
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0),
+                reminder,
+            })
+            .collect();
+        let json = serde_json::to_vec(&saved)?;
+
+        durability::replace(path, &json, self.durability)
+    }
+
+    /// Save the reminders every `SAVE_INTERVAL`.
+    pub fn persist(&self) -> impl Future<Item = (), Error = ()> {
+        let reminders = self.clone();
+        Interval::new_interval(SAVE_INTERVAL)
+            .map_err(|e| logging::error!("timer_failed"; "reminder timer error = {:?}", e))
+            .for_each(move |_| {
+                if let Err(e) = reminders.save() {
+                    // Try again at the next tick.
+                    reminders.dirty.store(true, Ordering::Relaxed);
+                    logging::warn!("reminder_save_failed"; "reminder save error = {:?}", e);
+                }
+                Ok(())
+            })
+    }
+}
+
+fn deliver(state: &State, reminder: Reminder) {
+    let message = Message::new("remind_delivered")
+        .with("name", &reminder.name)
+        .with("text", &reminder.text);
+    match &reminder.to {
+        Recipient::Side(side) => {
+            state.metrics.reminders_delivered.add(1);
+            state.announce_to(&[*side], reminder.partition, None, &message);
+        }
+        Recipient::Peer(to) => {
+            let key = names::key(to);
+            let mut recipient = None;
+            for &side in &[Side::C, Side::Go] {
+                state.side(side).for_each(|id, member| {
+                    let named = names::key(&member.name) == key;
+                    let reachable =
+                        reminder.partition.is_none() || member.partition == reminder.partition;
+                    if named && reachable {
+                        recipient = Some((side, *id));
+                    }
+                });
+            }
+            match recipient {
+                Some((side, id)) => {
+                    state.metrics.reminders_delivered.add(1);
+                    state.tell(side, &id, &message);
+                }
+                None => {
+                    logging::info!(
+                        "reminder_missed", side = reminder.side;
+                        "reminder of {} for {} missed, not connected", reminder.name, to
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// A delay like `90s`, `10m`, `2h` or `1d`.
+pub fn parse_delay(text: &str) -> Option<Duration> {
+    let split = text.len().checked_sub(1)?;
+    if !text.is_char_boundary(split) {
+        return None;
+    }
+    let (count, unit) = text.split_at(split);
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = count.parse().ok()?;
+    Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+/// `delay` in the largest unit it is a whole number of.
+fn format_delay(delay: Duration) -> String {
+    let secs = delay.as_secs();
+    for &(unit, suffix) in &[(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")] {
+        if secs >= unit && secs % unit == 0 {
+            return format!("{}{}", secs / unit, suffix);
+        }
+    }
+    format!("{}s", secs)
+}
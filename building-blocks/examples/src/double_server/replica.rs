@@ -0,0 +1,274 @@
+//! Servers answering the reads of the history for another one.
+//!
+//! `/history`, `/search` and the GraphQL `history` query and
+//! `messageAdded` subscription only read the history. A replica answers
+//! them with the history of a primary, so that the primary is left with the
+//! messages to relay. The primary listens for replicas:
+//!
+//! ```json
+//! "replication_listen": "10.0.0.1:8090"
+//! ```
+//!
+//! and a replica follows it:
+//!
+//! ```json
+//! "replica": { "primary": "chat-1.example.org:8090" }
+//! ```
+//!
+//! The replica asks for the messages after the last one it has. The primary
+//! answers with the id of its own last one, and sends what it still has of
+//! them, then every message as it is kept, one line of JSON each like the
+//! `snapshot` has them:
+//!
+//! ```text
+//! SINCE 8110
+//! LAST 8111
+//! {"id":8111,"side":"go","name":"alice","body":"hi","ts":1564999990001}
+//! ```
+//!
+//! The ids of the primary have to go on after a restart, from its
+//! `journal`, `snapshot` or exactly-once file. A primary whose last id is
+//! behind the replica started over, and the replica does not follow it
+//! but logs why, until it is restarted with no history.
+//!
+//! The messages are then in its history, its `journal` and snapshots, and
+//! go to its integrations, like its own would. A connection that dropped is
+//! made again after a delay, twice as long after every failure in a row up
+//! to `max_reconnect_delay_ms`, and takes up again after the last message
+//! the replica has.
+//!
+//! Peers of a replica are read only: it answers `/who`, `/history`,
+//! `/search`, `/stats`, `/compress` and `/locale`, and tells them that
+//! their messages and other commands are not served there. Messages from
+//! gRPC clients, webhooks and the other integrations are not relayed
+//! either: the history of a replica only has those of its primary. A
+//! primary can itself be the replica of another.
+//!
+//! Servers joining a bridge ask the same address for what they should
+//! start with, see `backfill`.
+
+use building_blocks::retry::Backoff;
+use futures::future::{self, Either, Loop};
+use futures::{Future, Sink, Stream};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::timer::Delay;
+
+use std::io;
+use std::net;
+use std::time::{Duration, Instant};
+
+use crate::accept::Acceptor;
+use crate::backfill;
+use crate::config::ReplicaConfig;
+use crate::logging;
+use crate::resolve;
+use crate::state::{ChatEvent, State, StoredMessage};
+
+/// Messages of the history sent to a replica at once, while it catches up.
+const CATCH_UP_PAGE: usize = 256;
+
+/// Serve the replicas connecting to `listener`, a future that runs until
+/// the listener is handed over.
+pub fn serve(
+    listener: net::TcpListener,
+    state: State,
+    per_tick: usize,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::from_std(listener, &Handle::default())?;
+    let handed_over = state.drain.handed_over();
+
+    Ok(Acceptor::new(listener, per_tick)
+        .map_err(|e| logging::warn!("accept_failed"; "replication accept error = {:?}", e))
+        .for_each(move |socket| {
+            if !state.access.admits(&socket) {
+                state.metrics.access_connections_denied.add(1);
+                return Ok(());
+            }
+            let addr = socket.peer_addr().ok();
+            tokio::spawn(feed(state.clone(), socket).then(move |fed| {
+                match (fed, addr) {
+                    (Err(e), Some(addr)) => logging::info!(
+                        "replica_closed", addr = addr;
+                        "replica at {} error = {:?}", addr, e
+                    ),
+                    (_, Some(addr)) => logging::info!(
+                        "replica_closed", addr = addr;
+                        "replica at {} hung up", addr
+                    ),
+                    _ => {}
+                }
+                Ok(())
+            }));
+            Ok(())
+        })
+        .select(handed_over)
+        .map(|_| ())
+        .map_err(|_| ()))
+}
+
+/// Send the replica on `socket` what it asks for, then every message kept.
+fn feed(state: State, socket: TcpStream) -> impl Future<Item = (), Error = io::Error> {
+    let addr = socket.peer_addr();
+    let (sink, stream) = Framed::new(socket, LinesCodec::new()).split();
+    stream
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(line, _)| {
+            // A server joining the bridge, see `backfill`.
+            if let Some(request) = line
+                .as_ref()
+                .and_then(|line| line.strip_prefix("BACKFILL "))
+            {
+                let lines = backfill::answer(&state, request)?;
+                let sent = sink
+                    .send_all(futures::stream::iter_ok::<_, io::Error>(lines))
+                    .map(|_| ());
+                return Ok(Either::A(sent));
+            }
+            let since = line
+                .as_ref()
+                .and_then(|line| line.strip_prefix("SINCE "))
+                .and_then(|id| id.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    let e = format!("bad request {:?}", line.unwrap_or_default());
+                    io::Error::new(io::ErrorKind::InvalidData, e)
+                })?;
+            if let Ok(addr) = addr {
+                logging::info!(
+                    "replica_connected", addr = addr;
+                    "replica at {} follows after #{}", addr, since
+                );
+            }
+
+            // Subscribed first, so that nothing kept while it catches up
+            // is missed. What it was sent already is left out after.
+            let events = state.subscribe();
+            let mut caught_up = vec![format!("LAST {}", state.last_message_id())];
+            let mut next = since + 1;
+            loop {
+                let page = state.history_range(&(next..u64::MAX), CATCH_UP_PAGE);
+                match page.last() {
+                    Some(last) => next = last.id + 1,
+                    None => break,
+                }
+                caught_up.extend(page.iter().map(json_line));
+            }
+            let kept = events.filter_map(move |event| match event {
+                ChatEvent::Message { id, .. } if id >= next => {
+                    let kept = state.history_range(&(id..id + 1), 1);
+                    kept.first().map(json_line)
+                }
+                _ => None,
+            });
+            let lines = futures::stream::iter_ok(caught_up)
+                .chain(kept.map_err(|()| io::Error::new(io::ErrorKind::Other, "unsubscribed")));
+            Ok(Either::B(sink.send_all(lines).map(|_| ())))
+        })
+        .flatten()
+}
+
+/// A message as a line of JSON, the way replicas are sent it.
+pub fn json_line(message: &StoredMessage) -> String {
+    serde_json::to_string(message).expect("messages serialize")
+}
+
+/// The id of the last message of the primary, from its first answer to a
+/// server that has the messages up to `since`.
+pub fn last(line: Option<String>, since: u64) -> io::Result<u64> {
+    let last = line
+        .as_ref()
+        .and_then(|line| line.strip_prefix("LAST "))
+        .and_then(|id| id.trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            let e = format!("bad answer {:?}", line.unwrap_or_default());
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })?;
+    if last < since {
+        let e = format!("the primary is at #{}, its ids started over", last);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    Ok(last)
+}
+
+/// Follow the primary of `config`, a future that runs until the server
+/// drains.
+pub fn follow(state: State, config: ReplicaConfig) -> impl Future<Item = (), Error = ()> {
+    let backoff = Backoff::new(Duration::from_millis(config.reconnect_delay_ms))
+        .max(Duration::from_millis(config.max_reconnect_delay_ms));
+
+    future::loop_fn(0, move |failures: u32| {
+        let (config, state) = (config.clone(), state.clone());
+        tail(&config, state.clone()).then(move |result| {
+            let failures = match result {
+                Ok(()) => {
+                    logging::info!(
+                        "replica_disconnected", addr = &config.primary;
+                        "primary {} closed the connection", config.primary
+                    );
+                    1
+                }
+                Err(e) => {
+                    logging::warn!(
+                        "replica_failed", addr = &config.primary;
+                        "primary {} error = {:?}", config.primary, e
+                    );
+                    failures + 1
+                }
+            };
+            if state.drain.is_draining() {
+                return Either::A(future::ok(Loop::Break(())));
+            }
+            // A broken timer only means the next attempt is early.
+            let delay = backoff.delay(failures);
+            let wait =
+                Delay::new(Instant::now() + delay).then(move |_| Ok(Loop::Continue(failures)));
+            Either::B(wait)
+        })
+    })
+}
+
+/// Take in the messages of the primary until the connection ends.
+fn tail(config: &ReplicaConfig, state: State) -> impl Future<Item = (), Error = io::Error> {
+    let primary = config.primary.clone();
+    resolve::connect(&config.primary)
+        .and_then({
+            let state = state.clone();
+            move |socket| {
+                let framed = Framed::new(socket, LinesCodec::new());
+                let since = state.last_message_id();
+                logging::info!(
+                    "replica_following", addr = &primary;
+                    "following {} after #{}", primary, since
+                );
+                framed
+                    .send(format!("SINCE {}", since))
+                    .map(move |framed| (framed, since))
+            }
+        })
+        .and_then(|(framed, since)| {
+            framed
+                .into_future()
+                .map_err(|(e, _)| e)
+                .map(move |(line, framed)| (line, framed, since))
+        })
+        .and_then(move |(line, framed, since)| {
+            last(line, since)?;
+            Ok(framed.for_each(move |line| {
+                let message: StoredMessage = serde_json::from_str(&line)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                state.replicate(message);
+                Ok(())
+            }))
+        })
+        .flatten()
+}
+
+/// Whether a peer of a replica may run the command called `name`.
+pub fn serves(name: &str) -> bool {
+    matches!(
+        name,
+        "/who" | "/history" | "/search" | "/stats" | "/compress" | "/locale"
+    )
+}
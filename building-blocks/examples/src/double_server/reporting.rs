@@ -0,0 +1,160 @@
+//! Where unexpected failures are reported, to collect them from every server
+//! in one place.
+//!
+//! ```text
+//! cargo run --features sentry-reports --bin double_server -- --config server.json
+//! ```
+//!
+//! ```json
+//! "sentry": { "dsn": "https://key@sentry.example.org/42", "environment": "production" }
+//! ```
+//!
+//! An `ErrorReporter` is told of connections of peers that failed with
+//! something other than the peer going away (a reset, a timeout, an
+//! unframed line), and of every panic, on any thread. Both are still logged
+//! (panics by the default hook, to stderr) whether or not anything is
+//! reported.
+//!
+//! With the `sentry-reports` feature and `Config::sentry`, they are sent to
+//! Sentry, tagged with the side and address of the peer or where the panic
+//! happened. Without a reporter they are only logged.
+
+use std::io;
+use std::net::SocketAddr;
+use std::panic;
+use std::sync::Arc;
+
+use crate::config::Config;
+#[cfg(feature = "sentry-reports")]
+use crate::config::SentryConfig;
+use crate::state::Side;
+
+/// How long a panic waits for its report to be sent.
+#[cfg(feature = "sentry-reports")]
+const FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Told of the failures the server does not expect.
+pub trait ErrorReporter: Send + Sync {
+    /// The connection of the peer on `side` at `addr` failed with `error`,
+    /// see `unexpected`.
+    fn peer_error(&self, side: Side, addr: SocketAddr, error: &io::Error);
+
+    /// A thread panicked with `message`, at `location` if it is known (like
+    /// `src/double_server/state.rs:42:9`).
+    fn panic(&self, message: &str, location: Option<&str>);
+}
+
+/// Reports nothing.
+pub struct Unreported;
+
+impl ErrorReporter for Unreported {
+    fn peer_error(&self, _side: Side, _addr: SocketAddr, _error: &io::Error) {}
+
+    fn panic(&self, _message: &str, _location: Option<&str>) {}
+}
+
+/// The reporter `config` asks for. Fails if the Sentry DSN is not one, or
+/// Sentry is asked for without the feature.
+pub fn open(config: &Config) -> io::Result<Arc<dyn ErrorReporter>> {
+    match &config.sentry {
+        #[cfg(feature = "sentry-reports")]
+        Some(sentry) => Ok(Arc::new(Sentry::init(sentry)?)),
+        #[cfg(not(feature = "sentry-reports"))]
+        Some(_) => {
+            let message = "sentry needs the sentry-reports feature";
+            Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+        }
+        None => Ok(Arc::new(Unreported)),
+    }
+}
+
+/// Whether a connection failing with `error` is worth reporting. Peers go
+/// away in all kinds of ways, which are not.
+pub fn unexpected(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::UnexpectedEof
+        // Lines too long, see `Config::max_unframed_bytes`.
+        | io::ErrorKind::InvalidData => false,
+        _ => true,
+    }
+}
+
+/// Report every panic to `reporter`, before the default hook prints it.
+pub fn report_panics(reporter: Arc<dyn ErrorReporter>) {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+        let location = info.location().map(|l| l.to_string());
+        reporter.panic(&message, location.as_deref());
+        default(info);
+    }));
+}
+
+/// Sends the failures to Sentry.
+#[cfg(feature = "sentry-reports")]
+pub struct Sentry {
+    /// Keeps the client, which sends from a thread of its own.
+    _guard: sentry::ClientInitGuard,
+}
+
+#[cfg(feature = "sentry-reports")]
+impl Sentry {
+    fn init(config: &SentryConfig) -> io::Result<Sentry> {
+        let dsn = config.dsn.parse().map_err(|e| {
+            let message = format!("sentry dsn {}: {}", config.dsn, e);
+            io::Error::new(io::ErrorKind::InvalidInput, message)
+        })?;
+        let guard = sentry::init(sentry::ClientOptions {
+            dsn: Some(dsn),
+            release: sentry::release_name!(),
+            environment: config.environment.clone().map(Into::into),
+            ..Default::default()
+        });
+        Ok(Sentry { _guard: guard })
+    }
+}
+
+#[cfg(feature = "sentry-reports")]
+impl ErrorReporter for Sentry {
+    fn peer_error(&self, side: Side, addr: SocketAddr, error: &io::Error) {
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("side", side);
+                scope.set_tag("addr", addr);
+            },
+            || {
+                sentry::capture_message(
+                    &format!("connection error = {:?}", error),
+                    sentry::Level::Error,
+                )
+            },
+        );
+    }
+
+    fn panic(&self, message: &str, location: Option<&str>) {
+        sentry::with_scope(
+            |scope| {
+                if let Some(location) = location {
+                    scope.set_tag("location", location);
+                }
+            },
+            || sentry::capture_message(message, sentry::Level::Fatal),
+        );
+        // The thread may be about to take the process down.
+        if let Some(client) = sentry::Hub::current().client() {
+            client.flush(Some(FLUSH_TIMEOUT));
+        }
+    }
+}
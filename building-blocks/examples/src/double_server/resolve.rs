@@ -0,0 +1,258 @@
+//! Host names in configured addresses.
+//!
+//! Every address of the configuration may name a host instead of an IP
+//! address, like `"chat.internal:8081"` or `"nats:4222"`.
+//!
+//! Listen addresses are resolved once, when the configuration is read. A
+//! failed lookup is retried for a few seconds, so that a server started
+//! with its network still coming up does not give up on the first try.
+//!
+//! The addresses the server connects to (brokers, the NATS server) are
+//! resolved again for every connection, and every `REFRESH_INTERVAL` while
+//! connected. A connection whose address dropped out of the answer is
+//! closed and made again, so that moving a name to another host fails over
+//! without restarting the server. Every address a name resolves to is
+//! tried in turn.
+//!
+//! Lookups block, as `getaddrinfo` does, so each runs on a thread of its
+//! own. (This runtime has no resolver of its own.)
+
+use futures::sync::oneshot;
+use serde::de::{self, Deserialize, Deserializer};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::logging;
+
+/// How often the address of a connection is looked up again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Lookups of a listen address before the server gives up.
+const LISTEN_ATTEMPTS: u32 = 5;
+
+/// Wait between them.
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An address that may name a host, resolved when it is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPort {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for HostPort {
+    type Err = String;
+
+    /// `host:port`, with IPv6 addresses in brackets.
+    fn from_str(s: &str) -> Result<HostPort, String> {
+        let invalid = || format!("{} is not a host and port", s);
+        let colon = s.rfind(':').ok_or_else(invalid)?;
+        let (host, port) = (&s[..colon], &s[colon + 1..]);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HostPort {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPort {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HostPort, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl HostPort {
+    /// The address, if it is one already.
+    fn literal(&self) -> Option<SocketAddr> {
+        let ip: IpAddr = self.host.parse().ok()?;
+        Some(SocketAddr::new(ip, self.port))
+    }
+
+    /// Look the host up, blocking.
+    fn lookup(&self) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addr) = self.literal() {
+            return Ok(vec![addr]);
+        }
+        let addrs: Vec<_> = (self.host.as_str(), self.port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            let message = format!("{} has no addresses", self.host);
+            return Err(io::Error::new(io::ErrorKind::NotFound, message));
+        }
+        Ok(addrs)
+    }
+
+    /// Look the host up on a thread of its own.
+    pub fn resolve(&self) -> impl Future<Item = Vec<SocketAddr>, Error = io::Error> + Send {
+        if let Some(addr) = self.literal() {
+            return future::Either::A(future::ok(vec![addr]));
+        }
+        let (tx, rx) = oneshot::channel();
+        let target = self.clone();
+        thread::spawn(move || {
+            let _ = tx.send(target.lookup());
+        });
+        future::Either::B(rx.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "lookup thread died")),
+        }))
+    }
+}
+
+/// Connecting to a `HostPort`, see `connect`.
+pub type Connect = Box<dyn Future<Item = TcpStream, Error = io::Error> + Send>;
+
+/// Resolve `target` and connect to the first of its addresses that takes
+/// the connection.
+pub fn connect(target: &HostPort) -> Connect {
+    Box::new(
+        target
+            .resolve()
+            .and_then(|addrs| first_of(addrs.into_iter())),
+    )
+}
+
+/// Connect to the first of `addrs` that takes the connection, failing like
+/// the last one if none does. `addrs` is not empty.
+fn first_of(mut addrs: std::vec::IntoIter<SocketAddr>) -> Connect {
+    let addr = addrs.next().expect("an address to connect to");
+    Box::new(TcpStream::connect(&addr).or_else(move |e| {
+        if addrs.len() == 0 {
+            return future::Either::A(future::err(e));
+        }
+        logging::warn!(
+            "connect_failed", addr = addr;
+            "connect to {} failed = {:?}, trying the next address",
+            addr, e
+        );
+        future::Either::B(first_of(addrs))
+    }))
+}
+
+/// Looks the address of a connection up again every `REFRESH_INTERVAL`.
+pub struct Refresh {
+    target: HostPort,
+    /// Where the connection went.
+    connected: SocketAddr,
+    ticks: Option<Interval>,
+    lookup: Option<Box<dyn Future<Item = Vec<SocketAddr>, Error = io::Error> + Send>>,
+}
+
+impl Refresh {
+    /// For a connection to `target` at `connected`. IP addresses never
+    /// change, and are not looked up.
+    pub fn new(target: &HostPort, connected: SocketAddr) -> Refresh {
+        let ticks = match target.literal() {
+            Some(_) => None,
+            None => Some(Interval::new(
+                Instant::now() + REFRESH_INTERVAL,
+                REFRESH_INTERVAL,
+            )),
+        };
+        Refresh {
+            target: target.clone(),
+            connected,
+            ticks,
+            lookup: None,
+        }
+    }
+
+    /// Resolves once the target no longer resolves to where the connection
+    /// went. A failed lookup keeps the connection.
+    pub fn poll(&mut self) -> Poll<(), io::Error> {
+        let connected = self.connected;
+        let ticks = match &mut self.ticks {
+            Some(ticks) => ticks,
+            None => return Ok(Async::NotReady),
+        };
+        loop {
+            if let Some(lookup) = &mut self.lookup {
+                let addrs = match lookup.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(addrs)) => addrs,
+                    Err(e) => {
+                        logging::warn!(
+                            "lookup_failed", addr = &self.target;
+                            "lookup of {} failed = {:?}", self.target, e
+                        );
+                        vec![connected]
+                    }
+                };
+                self.lookup = None;
+                if !addrs.contains(&connected) {
+                    logging::info!(
+                        "address_moved", addr = &self.target;
+                        "{} moved away from {}", self.target, connected
+                    );
+                    return Ok(Async::Ready(()));
+                }
+            }
+            match ticks
+                .poll()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            {
+                Async::Ready(_) => self.lookup = Some(Box::new(self.target.resolve())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Resolve a listen address, retrying for a while. The first address of
+/// the answer is listened on.
+pub fn listen_addr(s: &str) -> Result<SocketAddr, String> {
+    let target: HostPort = s.parse()?;
+    let mut attempt = 1;
+    loop {
+        match target.lookup() {
+            Ok(addrs) => return Ok(addrs[0]),
+            Err(e) if attempt == LISTEN_ATTEMPTS => {
+                return Err(format!("cannot resolve {}: {}", target, e));
+            }
+            Err(e) => logging::warn!(
+                "lookup_failed", addr = &target;
+                "lookup of {} failed = {:?}, retrying", target, e
+            ),
+        }
+        attempt += 1;
+        thread::sleep(LISTEN_RETRY_DELAY);
+    }
+}
+
+/// Deserialize a listen address, see `listen_addr`.
+pub fn listen<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddr, D::Error> {
+    listen_addr(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+/// Deserialize an optional listen address, see `listen_addr`.
+pub fn listen_opt<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SocketAddr>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => listen_addr(&s).map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,426 @@
+//! Restarting without downtime.
+//!
+//! On SIGUSR2, or `POST /admin/restart` (see `admin`), the server starts a
+//! new copy of itself with the same arguments, and `DOUBLE_SERVER_TAKEOVER`
+//! set to the path of a unix socket. The new process connects to it and
+//! receives the bound listeners as file descriptors (`SCM_RIGHTS`) instead
+//! of binding its own, so no connection attempt is refused in between. Once
+//! it is serving it says so, and the old process hands over: its listeners
+//! stop accepting and its peers drain, see `drain`. Should the new process
+//! fail before that, the old one keeps serving as if nothing happened.
+//!
+//! The program is started the way the old one was, so a binary replaced on
+//! disk is picked up. It is of the next generation, so that the ids of its
+//! connections are not those of the old one, see `conn`. Messages and
+//! history do not carry over, the peers reconnect to an empty room.
+
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
+use tokio::prelude::*;
+use tokio_signal::unix::{Signal, SIGUSR2};
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{self, Child, Command};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audit::Action;
+use crate::config::{Config, MAX_INSTANCES};
+use crate::conn;
+use crate::logging;
+use crate::state::State;
+
+/// Environment variable pointing a new process at the old one.
+const TAKEOVER_ENV: &str = "DOUBLE_SERVER_TAKEOVER";
+
+/// How long the new process gets to start serving.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the new process sends once it is serving.
+const READY: &[u8] = b"ready\n";
+
+/// Most listeners passed: the instances of both sides, HTTP, gRPC,
+/// transfers, replication, the single port, TLS and HTTP over TLS.
+const MAX_FDS: usize = 2 * MAX_INSTANCES + 7;
+
+/// Every listener of the server.
+pub struct Listeners {
+    /// One for each instance, see `Config::instances`.
+    pub c: Vec<TcpListener>,
+    pub go: Vec<TcpListener>,
+    pub http: Option<TcpListener>,
+    pub grpc: Option<TcpListener>,
+    pub transfer: Option<TcpListener>,
+    pub replication: Option<TcpListener>,
+    pub single: Option<TcpListener>,
+    pub tls: Option<TcpListener>,
+    pub https: Option<TcpListener>,
+}
+
+impl Listeners {
+    /// Take the listeners over from the process restarting into this one,
+    /// or bind them if there is none.
+    ///
+    /// Only listeners still on the configured address are taken over, the
+    /// others are bound anew.
+    pub fn open(config: &Config) -> io::Result<(Listeners, Option<Takeover>)> {
+        let (mut inherited, takeover) = match env::var_os(TAKEOVER_ENV) {
+            Some(path) => {
+                // Children of this process must not take it for the old one.
+                env::remove_var(TAKEOVER_ENV);
+                let (inherited, takeover) = receive(path)?;
+                (inherited, Some(takeover))
+            }
+            None => (HashMap::new(), None),
+        };
+
+        let backlog = config.listen_backlog;
+        let mut take = |name: &str, addr: SocketAddr, reuseport: bool| {
+            let listener = inherited.get_mut(name).and_then(Vec::pop);
+            match listener {
+                Some(listener) if listener.local_addr().ok() == Some(addr) => {
+                    logging::info!(
+                        "listener_taken_over", addr = addr;
+                        "took over {} listener on {}", name, addr
+                    );
+                    // Listening again only changes the backlog.
+                    let fd = listener.as_raw_fd();
+                    if unsafe { libc::listen(fd, backlog as libc::c_int) } < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(listener)
+                }
+                _ => bind(addr, reuseport, backlog),
+            }
+        };
+
+        let instances = 0..config.instances;
+        let listeners = Listeners {
+            c: instances
+                .clone()
+                .map(|_| take("c", config.c_listen, config.reuseport))
+                .collect::<io::Result<_>>()?,
+            go: instances
+                .map(|_| take("go", config.go_listen, config.reuseport))
+                .collect::<io::Result<_>>()?,
+            http: config
+                .http_listen
+                .map(|a| take("http", a, false))
+                .transpose()?,
+            grpc: config
+                .grpc_listen
+                .map(|a| take("grpc", a, false))
+                .transpose()?,
+            transfer: config
+                .transfer_listen
+                .map(|a| take("transfer", a, false))
+                .transpose()?,
+            replication: config
+                .replication_listen
+                .map(|a| take("replication", a, false))
+                .transpose()?,
+            single: config
+                .single_port
+                .as_ref()
+                .map(|single| take("single", single.listen, false))
+                .transpose()?,
+            tls: config
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.listen)
+                .map(|a| take("tls", a, false))
+                .transpose()?,
+            https: config
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.http_listen)
+                .map(|a| take("https", a, false))
+                .transpose()?,
+        };
+        Ok((listeners, takeover))
+    }
+
+    fn fds(&self) -> Vec<(&'static str, RawFd)> {
+        let mut fds = Vec::new();
+        fds.extend(self.c.iter().map(|c| ("c", c.as_raw_fd())));
+        fds.extend(self.go.iter().map(|go| ("go", go.as_raw_fd())));
+        if let Some(http) = &self.http {
+            fds.push(("http", http.as_raw_fd()));
+        }
+        if let Some(grpc) = &self.grpc {
+            fds.push(("grpc", grpc.as_raw_fd()));
+        }
+        if let Some(transfer) = &self.transfer {
+            fds.push(("transfer", transfer.as_raw_fd()));
+        }
+        if let Some(replication) = &self.replication {
+            fds.push(("replication", replication.as_raw_fd()));
+        }
+        if let Some(single) = &self.single {
+            fds.push(("single", single.as_raw_fd()));
+        }
+        if let Some(tls) = &self.tls {
+            fds.push(("tls", tls.as_raw_fd()));
+        }
+        if let Some(https) = &self.https {
+            fds.push(("https", https.as_raw_fd()));
+        }
+        fds
+    }
+}
+
+/// Bind a listener, with `SO_REUSEPORT` if `reuseport`.
+fn bind(addr: SocketAddr, reuseport: bool, backlog: u32) -> io::Result<TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    // Like std, so that a restarted server can bind while connections of
+    // the last one linger in TIME_WAIT.
+    builder.reuse_address(true)?;
+    if reuseport {
+        builder.reuse_port(true)?;
+    }
+    builder.bind(addr)?;
+    builder.listen(backlog as i32)
+}
+
+/// The connection to the old process, held by a new one until it serves.
+pub struct Takeover(UnixStream);
+
+impl Takeover {
+    /// Tell the old process to hand over.
+    pub fn ready(mut self) -> io::Result<()> {
+        self.0.write_all(READY)
+    }
+}
+
+/// Connect to the old process at `path` and receive its listeners, by name.
+fn receive(path: OsString) -> io::Result<(HashMap<String, Vec<TcpListener>>, Takeover)> {
+    let socket = UnixStream::connect(&path)?;
+    let mut names = vec![0; 8 * MAX_FDS];
+    let (n, fds) = recv_fds(&socket, &mut names)?;
+
+    let names = String::from_utf8_lossy(&names[..n]).into_owned();
+    let mut listeners = HashMap::new();
+    for (name, fd) in names.split_whitespace().zip(fds) {
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listeners
+            .entry(name.to_string())
+            .or_insert_with(Vec::new)
+            .push(listener);
+    }
+    Ok((listeners, Takeover(socket)))
+}
+
+/// Starts the new process and hands the listeners to it. Cloning is cheap.
+#[derive(Clone)]
+pub struct Restarter {
+    fds: Arc<Vec<(&'static str, RawFd)>>,
+    /// Held while a restart is underway.
+    busy: Arc<Mutex<()>>,
+    state: State,
+    /// How long the peers of the old process get to move.
+    deadline: Duration,
+}
+
+impl Restarter {
+    /// `listeners` must stay open until the server drains.
+    pub fn new(listeners: &Listeners, state: State, deadline: Duration) -> Restarter {
+        Restarter {
+            fds: Arc::new(listeners.fds()),
+            busy: Arc::new(Mutex::new(())),
+            state,
+            deadline,
+        }
+    }
+
+    /// Start the new process and wait until it serves, then hand over.
+    /// Returns the pid of the new process.
+    ///
+    /// Blocks for up to `READY_TIMEOUT`.
+    pub fn restart(&self) -> io::Result<u32> {
+        let _busy = self
+            .busy
+            .try_lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "already restarting"))?;
+        if self.state.drain.is_draining() {
+            return Err(io::Error::new(io::ErrorKind::Other, "already draining"));
+        }
+
+        // The new process reads the usage and the reminders at startup.
+        if let Some(quotas) = &self.state.quotas {
+            quotas.save()?;
+        }
+        self.state.reminders.save()?;
+
+        let path = env::temp_dir().join(format!("double_server-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let result = self.spawn(&listener, &path);
+        let _ = fs::remove_file(&path);
+        let pid = result?;
+
+        // `Config::load` turns deadlines away that are too far, and a drain
+        // that started since the check above keeps its own deadline.
+        let _ = self.state.drain.hand_over(self.deadline);
+        logging::info!("handed_over"; "handed over to process {}", pid);
+        Ok(pid)
+    }
+
+    fn spawn(&self, listener: &UnixListener, path: &Path) -> io::Result<u32> {
+        let mut args = env::args_os();
+        let program = args
+            .next()
+            .unwrap_or_else(|| OsString::from("double_server"));
+        let generation = self.state.connections.generation() + 1;
+        let mut child = Command::new(program)
+            .args(args)
+            .env(TAKEOVER_ENV, path)
+            .env(conn::GENERATION_ENV, generation.to_string())
+            .spawn()?;
+
+        match self.pass(listener, &mut child) {
+            Ok(()) => Ok(child.id()),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+
+    /// Pass the listeners to `child` once it connects, and wait until it
+    /// serves.
+    fn pass(&self, listener: &UnixListener, child: &mut Child) -> io::Result<()> {
+        let started = Instant::now();
+
+        // Poll, so that a child dying before it connects is noticed.
+        listener.set_nonblocking(true)?;
+        let mut socket = loop {
+            match listener.accept() {
+                Ok((socket, _)) => break socket,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if let Some(status) = child.try_wait()? {
+                let msg = format!("new process exited with {}", status);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+            if started.elapsed() > READY_TIMEOUT {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no connection"));
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        socket.set_nonblocking(false)?;
+
+        let names: Vec<&str> = self.fds.iter().map(|&(name, _)| name).collect();
+        let fds: Vec<RawFd> = self.fds.iter().map(|&(_, fd)| fd).collect();
+        send_fds(&socket, names.join(" ").as_bytes(), &fds)?;
+
+        let left = READY_TIMEOUT.checked_sub(started.elapsed());
+        socket.set_read_timeout(Some(left.unwrap_or(Duration::from_millis(1))))?;
+        let mut ready = [0; 6];
+        socket.read_exact(&mut ready)?;
+        if &ready[..] != READY {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not ready"));
+        }
+        Ok(())
+    }
+}
+
+/// Restart on SIGUSR2.
+pub fn on_signal(restarter: Restarter) -> impl Future<Item = (), Error = ()> {
+    Signal::new(SIGUSR2)
+        .flatten_stream()
+        .map_err(|e| logging::error!("signal_failed"; "signal error = {:?}", e))
+        .for_each(move |_| {
+            let restarter = restarter.clone();
+            restarter
+                .state
+                .audit
+                .record("signal SIGUSR2", Action::Restart, "server", "");
+            // Restarting blocks until the new process serves.
+            thread::spawn(move || match restarter.restart() {
+                Ok(_) => {}
+                Err(e) => logging::error!("restart_failed"; "restart error = {:?}", e),
+            });
+            Ok(())
+        })
+}
+
+/// Send `data` along with `fds`.
+fn send_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = (fds.len() * mem::size_of::<RawFd>()) as u32;
+    unsafe {
+        let mut control = vec![0u8; libc::CMSG_SPACE(fds_len) as usize];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        if libc::sendmsg(socket.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receive data into `buf` along with up to `MAX_FDS` file descriptors.
+fn recv_fds(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+    let mut fds = Vec::new();
+    unsafe {
+        let space = libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32);
+        let mut control = vec![0u8; space as usize];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        // Close on exec, like every other descriptor std opens.
+        let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((n as usize, fds))
+    }
+}
@@ -0,0 +1,349 @@
+//! Who is connected to the other servers of a bridge, kept the same on
+//! every server even when lines between them are lost.
+//!
+//! Every server has an entry of its own peers, links left out, and a
+//! version it raises whenever a peer joins or leaves. It sends the entry
+//! over its links when it changes, and every server passes on the entries
+//! newer than the one it had, so that all of them know who is on every
+//! server, for `/who`:
+//!
+//! ```text
+//! @roster=eu-1;version=1565000000123;peers=c:alice,go:bob
+//! ```
+//!
+//! A line lost on the way, or a server that was not reached while a link
+//! was down, leaves servers with different entries. So every `sync_secs`,
+//! and when a link connects, each server sends its links a digest of all
+//! the entries it has, of servers the routes lead to (see `bridge`): the
+//! hash of the hashes of every entry.
+//!
+//! ```text
+//! @digest=9c1185a5c5e9fc54
+//! ```
+//!
+//! A server with another digest answers with the version and hash of every
+//! entry, and the two then send each other the entries the other has an
+//! older version of, or none. While the servers agree, keeping them so
+//! takes a line per link every `sync_secs`.
+//!
+//! ```json
+//! "bridge": { "id": "eu-1", "sync_secs": 30 }
+//! ```
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use sha2::{Digest, Sha256};
+use tokio::timer::Interval;
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bridge;
+use crate::conn::ConnId;
+use crate::logging;
+use crate::state::{now_ms, Side, State};
+
+const ROSTER_TAG: &[u8] = b"@roster=";
+const DIGEST_TAG: &[u8] = b"@digest=";
+const DIGESTS_TAG: &[u8] = b"@digests=";
+const WANT_TAG: &[u8] = b"@want=";
+
+/// The peers of one server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Peers {
+    version: u64,
+    peers: BTreeSet<(Side, String)>,
+}
+
+impl Peers {
+    fn hash(&self, server: &str) -> String {
+        let mut hasher = Sha256::new();
+        let version = self.version.to_string();
+        let fields = self.peers.iter().flat_map(|(side, name)| {
+            std::iter::once(side.as_str()).chain(std::iter::once(name.as_str()))
+        });
+        for field in [server, version.as_str()].iter().cloned().chain(fields) {
+            // Length first, so that no two entries hash the same fields.
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        short(&hasher.finalize())
+    }
+
+    fn line(&self, server: &str) -> Bytes {
+        let peers: Vec<String> = self
+            .peers
+            .iter()
+            .map(|(side, name)| format!("{}:{}", side, escape(name)))
+            .collect();
+        let line = format!(
+            "@roster={};version={};peers={}\r\n",
+            server,
+            self.version,
+            peers.join(",")
+        );
+        Bytes::from(line)
+    }
+}
+
+/// The entries of every server, this one's included.
+pub struct Roster(Mutex<BTreeMap<String, Peers>>);
+
+impl Roster {
+    pub fn new() -> Roster {
+        Roster(Mutex::new(BTreeMap::new()))
+    }
+
+    /// The peers of every other server the routes lead to, by server.
+    pub fn remote(&self, state: &State) -> Vec<(String, Vec<(Side, String)>)> {
+        let reached = state.bridges.reached();
+        let entries = self.0.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(server, _)| reached.contains(*server))
+            .map(|(server, entry)| (server.clone(), entry.peers.iter().cloned().collect()))
+            .collect()
+    }
+
+    /// The entries worth comparing: this server's, and those of the
+    /// servers the routes lead to. Those of the servers that are no longer
+    /// reached stay, in case they are again.
+    fn current(&self, state: &State) -> BTreeMap<String, Peers> {
+        let mut reached = state.bridges.reached();
+        reached.insert(state.bridges.id.clone());
+        let entries = self.0.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(server, _)| reached.contains(*server))
+            .map(|(server, entry)| (server.clone(), entry.clone()))
+            .collect()
+    }
+}
+
+/// The entry of this server, after a peer joined or left. Sent to every
+/// link.
+pub fn changed(state: &State) {
+    let mut peers = BTreeSet::new();
+    for &side in &[Side::C, Side::Go] {
+        state.side(side).for_each(|_, member| {
+            if member.link.is_none() {
+                peers.insert((side, member.name.clone()));
+            }
+        });
+    }
+    let id = &state.bridges.id;
+    let line = {
+        let mut entries = state.roster.0.lock().unwrap();
+        // Starting at the time rather than at 0, so that the entry of a
+        // server that restarted is newer than the one it had before.
+        let version = match entries.get(id) {
+            Some(entry) => (entry.version + 1).max(now_ms()),
+            None => now_ms(),
+        };
+        let entry = Peers { version, peers };
+        let line = entry.line(id);
+        entries.insert(id.clone(), entry);
+        line
+    };
+    for (side, link) in bridge::links(state) {
+        state.tell_line(side, &link, line.clone());
+    }
+}
+
+/// Send the digest of the entries to the link `link` of `side`.
+pub fn digest(state: &State, side: Side, link: &ConnId) {
+    let line = format!("@digest={}\r\n", root(&state.roster.current(state)));
+    state.tell_line(side, link, Bytes::from(line));
+}
+
+/// Send the digest to every link every `sync_secs`.
+pub fn sync(state: State, every: Duration) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now() + every, every)
+        .map_err(|e| logging::error!("timer_failed"; "roster timer error = {:?}", e))
+        .for_each(move |_| {
+            for (side, link) in bridge::links(&state) {
+                digest(&state, side, &link);
+            }
+            Ok(())
+        })
+}
+
+/// Whether `line` of a link is one of the roster.
+pub fn is_line(line: &[u8]) -> bool {
+    [ROSTER_TAG, DIGEST_TAG, DIGESTS_TAG, WANT_TAG]
+        .iter()
+        .any(|tag| line.starts_with(tag))
+}
+
+/// Take in `line` of the link `link` of `side`, one of the roster.
+pub fn receive(state: &State, side: Side, link: ConnId, line: &[u8]) {
+    let text = |tag: &[u8]| {
+        if line.starts_with(tag) {
+            std::str::from_utf8(&line[tag.len()..]).ok()
+        } else {
+            None
+        }
+    };
+    if let Some(entry) = text(ROSTER_TAG) {
+        if let Some((server, peers)) = parse_entry(entry) {
+            take(state, link, server, peers);
+        }
+    } else if let Some(theirs) = text(DIGEST_TAG) {
+        let current = state.roster.current(state);
+        if theirs != root(&current) {
+            let leaves: Vec<String> = current
+                .iter()
+                .map(|(server, entry)| {
+                    format!("{}:{}:{}", server, entry.version, entry.hash(server))
+                })
+                .collect();
+            let line = format!("@digests={}\r\n", leaves.join(","));
+            state.tell_line(side, &link, Bytes::from(line));
+        }
+    } else if let Some(leaves) = text(DIGESTS_TAG) {
+        compare(state, side, link, leaves);
+    } else if let Some(wanted) = text(WANT_TAG) {
+        let entries = state.roster.0.lock().unwrap();
+        for server in wanted.split(',') {
+            if let Some(entry) = entries.get(server) {
+                state.tell_line(side, &link, entry.line(server));
+            }
+        }
+    }
+}
+
+/// Send the link the entries it has older versions of, and ask it for the
+/// ones it has newer versions of.
+fn compare(state: &State, side: Side, link: ConnId, leaves: &str) {
+    let mut theirs = BTreeMap::new();
+    for leaf in leaves.split(',') {
+        let mut fields = leaf.splitn(3, ':');
+        if let (Some(server), Some(Ok(version)), Some(hash)) = (
+            fields.next(),
+            fields.next().map(str::parse::<u64>),
+            fields.next(),
+        ) {
+            theirs.insert(server, (version, hash));
+        }
+    }
+    let current = state.roster.current(state);
+    let own = &state.bridges.id;
+    let mut wanted = Vec::new();
+    for (server, entry) in &current {
+        match theirs.get(server.as_str()) {
+            Some(&(version, _)) if version > entry.version => wanted.push(server.as_str()),
+            // This server's own entry is the right one whatever the version.
+            Some(&(version, hash))
+                if version == entry.version && (hash == entry.hash(server) || server != own) => {}
+            _ => state.tell_line(side, &link, entry.line(server)),
+        }
+    }
+    for server in theirs.keys() {
+        if !current.contains_key(*server) && *server != own.as_str() {
+            wanted.push(server);
+        }
+    }
+    if !wanted.is_empty() {
+        let line = format!("@want={}\r\n", wanted.join(","));
+        state.tell_line(side, &link, Bytes::from(line));
+    }
+}
+
+/// Keep the entry of `server` that came from the link `from` if it is
+/// newer, and pass it on to the other links.
+fn take(state: &State, from: ConnId, server: String, peers: Peers) {
+    if server == state.bridges.id {
+        return;
+    }
+    let line = peers.line(&server);
+    {
+        let mut entries = state.roster.0.lock().unwrap();
+        match entries.entry(server) {
+            Entry::Occupied(mut known) if known.get().version < peers.version => {
+                known.insert(peers);
+            }
+            Entry::Occupied(_) => return,
+            Entry::Vacant(vacant) => {
+                vacant.insert(peers);
+            }
+        }
+    }
+    state.metrics.roster_updates.add(1);
+    for (side, link) in bridge::links(state) {
+        if link != from {
+            state.tell_line(side, &link, line.clone());
+        }
+    }
+}
+
+/// The digest of `entries`, the hash of their hashes.
+fn root(entries: &BTreeMap<String, Peers>) -> String {
+    let mut hasher = Sha256::new();
+    for (server, entry) in entries {
+        hasher.update(entry.hash(server).as_bytes());
+    }
+    short(&hasher.finalize())
+}
+
+fn parse_entry(entry: &str) -> Option<(String, Peers)> {
+    let (mut server, mut version, mut list) = (None, None, None);
+    for field in entry.split(';') {
+        let mut split = field.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(id), None) if server.is_none() => server = Some(id),
+            (Some("version"), Some(value)) => version = value.parse().ok(),
+            (Some("peers"), Some(value)) => list = Some(value),
+            _ => {}
+        }
+    }
+    let mut peers = BTreeSet::new();
+    for peer in list?.split(',').filter(|peer| !peer.is_empty()) {
+        let mut split = peer.splitn(2, ':');
+        let side = match split.next()? {
+            "c" => Side::C,
+            "go" => Side::Go,
+            _ => return None,
+        };
+        peers.insert((side, unescape(split.next()?)?));
+    }
+    let peers = Peers {
+        version: version?,
+        peers,
+    };
+    Some((server?.to_string(), peers))
+}
+
+/// The first 8 bytes of a hash, in hex.
+fn short(hash: &[u8]) -> String {
+    hash.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A name with the characters of the line format percent-encoded.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' | ',' | ';' | ':' | '=' => escaped.push_str(&format!("%{:02X}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,129 @@
+//! Announcements the server makes on a schedule.
+//!
+//! ```json
+//! "scheduled": [
+//!     { "cron": "45 8 * * 1-5", "text": "stand-up in 15 minutes", "sides": ["go"] },
+//!     { "cron": "0 */6 * * *", "text": "be nice, see https://example.org/rules" }
+//! ]
+//! ```
+//!
+//! `cron` has the five fields of crontab, in UTC, see
+//! `building_blocks::scheduler`, which makes the announcements.
+//!
+//! At every minute the schedule matches, `text` goes to every peer of
+//! `sides` (both unless given), as a notice like `* stand-up in 15
+//! minutes`. The text is sent as it is, whatever the locale of the peer.
+//!
+//! The announcements of the config are the schedule the server starts
+//! with. The admin endpoints change it while it runs, see `admin`, until the
+//! server restarts.
+
+use building_blocks::scheduler::{Cron, Job, Scheduler};
+use futures::{future, Future};
+use serde_derive::Serialize;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::config::ScheduledConfig;
+use crate::logging;
+use crate::state::{Side, State};
+
+/// An announcement of the schedule.
+#[derive(Debug, Clone, Serialize)]
+pub struct Scheduled {
+    pub id: u64,
+    pub cron: String,
+    pub text: String,
+    pub sides: Vec<Side>,
+}
+
+/// The announcements, shared with the admin endpoints. Each is a job of
+/// `building_blocks::scheduler`, with the same id.
+#[derive(Default)]
+pub struct Schedule {
+    scheduler: Scheduler,
+    announcements: Mutex<BTreeMap<u64, Scheduled>>,
+}
+
+impl Schedule {
+    /// Add the announcements of the config. Fails on the first that is not
+    /// one, see `add`.
+    pub fn load(&self, state: &State, config: &[ScheduledConfig]) -> Result<(), String> {
+        for (index, announcement) in config.iter().enumerate() {
+            self.add(state, announcement.clone())
+                .map_err(|e| format!("scheduled[{}]: {}", index, e))?;
+        }
+        Ok(())
+    }
+
+    /// Add `announcement`, made to the peers of `state`, returning its id.
+    /// Fails if its schedule does not parse, or its text is empty or more
+    /// than one line.
+    pub fn add(&self, state: &State, announcement: ScheduledConfig) -> Result<u64, String> {
+        let cron = Cron::parse(&announcement.cron)?;
+        if announcement.text.trim().is_empty() {
+            return Err("text must not be empty".to_string());
+        }
+        if announcement.text.contains(['\r', '\n']) {
+            return Err("text must be one line".to_string());
+        }
+        if announcement.sides.is_empty() {
+            return Err("sides must not be empty".to_string());
+        }
+        // The lock is held over adding the job so that it is listed before
+        // it can run.
+        let mut announcements = self.announcements.lock().unwrap();
+        let (state, text, sides) = (
+            state.clone(),
+            announcement.text.clone(),
+            announcement.sides.clone(),
+        );
+        let id = self.scheduler.add(Job::new(cron, move || {
+            announce(&state, &text, &sides);
+            Box::new(future::ok(()))
+        }));
+        let scheduled = Scheduled {
+            id,
+            cron: announcement.cron,
+            text: announcement.text,
+            sides: announcement.sides,
+        };
+        announcements.insert(id, scheduled);
+        Ok(id)
+    }
+
+    /// Remove announcement `id`, returning whether there was one.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn remove(&self, id: u64) -> bool {
+        let removed = self.announcements.lock().unwrap().remove(&id).is_some();
+        removed && self.scheduler.remove(id)
+    }
+
+    /// Every announcement, by id.
+    pub fn list(&self) -> Vec<Scheduled> {
+        let announcements = self.announcements.lock().unwrap();
+        announcements.values().cloned().collect()
+    }
+
+    /// Makes the announcements when they are due, a future that runs for
+    /// the lifetime of the server. The minute the server starts in is
+    /// skipped, it may have been announced before a restart.
+    pub fn run(&self) -> impl Future<Item = (), Error = ()> {
+        self.scheduler.run().map_err(|e| {
+            logging::error!("schedule_stopped"; "schedule timer error = {:?}", e);
+        })
+    }
+}
+
+fn announce(state: &State, text: &str, sides: &[Side]) {
+    let names: Vec<&str> = sides.iter().map(|s| s.as_str()).collect();
+    logging::info!(
+        "announcement_sent";
+        "announcing {:?} to {}", text, names.join(", ")
+    );
+    state.metrics.announcements_sent.add(1);
+    for &side in sides {
+        state.announce_text(side, text);
+    }
+}
@@ -0,0 +1,16 @@
+//! Comparing the secrets clients present: the token of the admin API (see
+//! `admin`), those of the incoming webhooks (see `webhook`), the one of the
+//! Matrix homeserver (see `matrix`) and those of transfers (see
+//! `transfer`).
+
+/// Whether `token` is `expected`, taking as long wherever they differ, so
+/// that the time of a guess tells nothing about the token.
+pub fn same_token(expected: &str, token: &str) -> bool {
+    let (expected, token) = (expected.as_bytes(), token.as_bytes());
+    expected.len() == token.len()
+        && expected
+            .iter()
+            .zip(token)
+            .fold(0, |differ, (a, b)| differ | (a ^ b))
+            == 0
+}
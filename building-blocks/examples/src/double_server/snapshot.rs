@@ -0,0 +1,215 @@
+//! The state of the server saved to a file, and read back at startup.
+//!
+//! ```json
+//! "snapshot": { "path": "/var/lib/double_server/snapshot.json", "every_secs": 300 }
+//! ```
+//!
+//! `POST /admin/snapshot` writes what the server would lose on a restart
+//! to `path`, see `admin`, and so does the server every `every_secs` if
+//! anything changed since the last one: the history `/history` answers
+//! from and the id the next message gets, the topic, who is banned and who
+//! moderates (see `moderation`), and the scheduled announcements as the
+//! admin endpoints left them. The file is replaced in one go, like the
+//! quotas.
+//!
+//! ```json
+//! {
+//!     "version": 1,
+//!     "taken_ms": 1565000000123,
+//!     "next_id": 8112,
+//!     "history": [{ "id": 8111, "side": "go", "name": "alice", "body": "hi", "ts": 1564999990001 }],
+//!     "moderation": { "topic": {...}, "banned": {...}, "moderators": {...} },
+//!     "scheduled": [{ "cron": "45 8 * * 1-5", "text": "stand-up in 15 minutes", "sides": ["go"] }]
+//! }
+//! ```
+//!
+//! A server started with a snapshot at `path` takes it back before it
+//! accepts peers. Its announcements replace those of the config, and its
+//! moderation is merged with what the links of the bridge have, like one
+//! of theirs. A restart loses what changed since the last snapshot at most,
+//! and is as quick however long the server ran. With a `journal`, the
+//! messages written ahead after the snapshot are taken back as well, and
+//! those before it are removed from the log. The files that are kept
+//! up to date as things happen, like those of the reminders, the quotas,
+//! the exactly-once ids and the audit, are apart from it and read back as
+//! ever. A snapshot of a version this server does not know is an error,
+//! rather than a state half taken back.
+//!
+//! There are no rooms, and names are only claimed while connected, so
+//! neither is in the snapshot.
+
+use futures::{Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Interval;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{ScheduledConfig, SnapshotConfig};
+use crate::durability::{self, Durability};
+use crate::logging;
+use crate::moderation::Shared;
+use crate::state::{now_ms, State, StoredMessage};
+
+/// The version of the snapshots this server writes, and the only one it
+/// reads.
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    taken_ms: u64,
+    next_id: u64,
+    history: Vec<StoredMessage>,
+    moderation: Shared,
+    scheduled: Vec<ScheduledConfig>,
+}
+
+/// Only the version, read first so that a snapshot of another version is
+/// told apart from a broken one.
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+/// What a snapshot holds, answered by the admin endpoint.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Summary {
+    pub version: u32,
+    pub taken_ms: u64,
+    pub next_id: u64,
+    pub messages: usize,
+    pub scheduled: usize,
+}
+
+impl Snapshot {
+    fn summary(&self) -> Summary {
+        Summary {
+            version: self.version,
+            taken_ms: self.taken_ms,
+            next_id: self.next_id,
+            messages: self.history.len(),
+            scheduled: self.scheduled.len(),
+        }
+    }
+}
+
+/// Write the state of the server to the file of `config`.
+///
+/// The log of the `journal` starts a new file first, so that the older ones
+/// can go once the snapshot is saved.
+pub fn save(state: &State, config: &SnapshotConfig) -> io::Result<Summary> {
+    let segment = match &state.journal {
+        Some(wal) => Some(wal.rotate()?),
+        None => None,
+    };
+    let snapshot = take(state);
+    write(&snapshot, &config.path, config.durability)?;
+    if let (Some(wal), Some(segment)) = (&state.journal, segment) {
+        wal.remove_before(segment)?;
+    }
+    Ok(snapshot.summary())
+}
+
+/// Save a snapshot every `every_secs` of `config`, unless nothing changed
+/// since the last one, a future that runs for the lifetime of the server.
+pub fn checkpoint(state: State, config: SnapshotConfig) -> impl Future<Item = (), Error = ()> {
+    let every = Duration::from_secs(config.every_secs);
+    let mut last = None;
+    Interval::new_interval(every)
+        .map_err(|e| logging::error!("timer_failed"; "snapshot timer error = {:?}", e))
+        .for_each(move |_| {
+            let snapshot = take(&state);
+            // The time it was taken left out, which always changes.
+            let unchanged = (
+                snapshot.next_id,
+                serde_json::to_string(&snapshot.moderation).ok(),
+                serde_json::to_string(&snapshot.scheduled).ok(),
+            );
+            if last.as_ref() == Some(&unchanged) {
+                return Ok(());
+            }
+            match save(&state, &config) {
+                Ok(_) => last = Some(unchanged),
+                // Tried again at the next tick.
+                Err(e) => logging::warn!("snapshot_failed"; "snapshot save error = {:?}", e),
+            }
+            Ok(())
+        })
+}
+
+fn write(snapshot: &Snapshot, path: &Path, durability: Durability) -> io::Result<()> {
+    let json = serde_json::to_vec(snapshot)?;
+    durability::replace(path, &json, durability)
+}
+
+fn take(state: &State) -> Snapshot {
+    let (history, next_id) = state.saved_history();
+    let scheduled = state
+        .schedule
+        .list()
+        .into_iter()
+        .map(|scheduled| ScheduledConfig {
+            cron: scheduled.cron,
+            text: scheduled.text,
+            sides: scheduled.sides,
+        })
+        .collect();
+    Snapshot {
+        version: VERSION,
+        taken_ms: now_ms(),
+        next_id,
+        history,
+        moderation: state.moderation.shared(),
+        scheduled,
+    }
+}
+
+/// Take back the snapshot at `path`, if there is one. The announcements of
+/// the config are left to the caller when there is none.
+pub fn restore(state: &State, path: &Path) -> io::Result<Option<Summary>> {
+    let invalid = |e: serde_json::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let versioned: Versioned = serde_json::from_slice(&json).map_err(invalid)?;
+    if versioned.version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: snapshot version {}, only {} is known",
+                path.display(),
+                versioned.version,
+                VERSION
+            ),
+        ));
+    }
+    let snapshot: Snapshot = serde_json::from_slice(&json).map_err(invalid)?;
+    let summary = snapshot.summary();
+
+    state.restore_history(snapshot.history, snapshot.next_id);
+    state.moderation.restore(&snapshot.moderation);
+    for (index, announcement) in snapshot.scheduled.into_iter().enumerate() {
+        state.schedule.add(state, announcement).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: scheduled[{}]: {}", path.display(), index, e),
+            )
+        })?;
+    }
+    logging::info!(
+        "snapshot_restored";
+        "restored {} messages and {} announcements from the snapshot of {}",
+        summary.messages, summary.scheduled, summary.taken_ms
+    );
+    Ok(Some(summary))
+}
@@ -0,0 +1,366 @@
+//! One port for every kind of connection, told apart by what it sends
+//! first, so that a firewall only has to let that one through.
+//!
+//! The `single_port` listener reads a little of every connection before
+//! deciding who serves it:
+//!
+//! ```text
+//! 16 03 01 ...              TLS ClientHello    see `tls`, refused without it
+//! PRI * HTTP/2.0            gRPC               see `grpc`
+//! GET /metrics HTTP/1.1     HTTP, WebSocket    relayed to `http_listen`
+//! SIDE c                    a peer of c        the line is taken off
+//! alice                     a peer of `side`
+//! ```
+//!
+//! A WebSocket upgrade is an HTTP request like any other, and the relay
+//! carries the socket on once it is upgraded. The gateway sees the relay
+//! connect rather than the client, from the loopback. Whoever serves a
+//! connection reads what was looked at again, see `Rewind`. A client of the
+//! chat that waits for the server to speak first is taken for a peer of
+//! `side` after `sniff_ms`.
+//!
+//! The listeners of the sides and of everything else stay where they are,
+//! and work as before.
+
+use bytes::BytesMut;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::timer::Delay;
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::accept::Acceptor;
+use crate::config::{Config, InstanceState, SinglePortConfig};
+use crate::logging;
+use crate::profiling;
+use crate::state::{Side, State};
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::Transport;
+
+/// Most bytes read before deciding, enough for the request line of HTTP.
+const MAX_SNIFF: usize = 256;
+
+/// What a gRPC client sends first.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0";
+
+/// What HTTP requests start with.
+const METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+];
+
+/// What a connection speaks, as told by its first bytes, or by ALPN, see
+/// `tls`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tls,
+    Grpc,
+    Http,
+    /// Lines of a peer, of the side it picked if it did.
+    Chat(Option<Side>),
+}
+
+/// Whether `bytes` start with `marker`, `None` while they are too short to
+/// tell.
+fn starts(bytes: &[u8], marker: &[u8]) -> Option<bool> {
+    if bytes.len() < marker.len() {
+        return if marker.starts_with(bytes) {
+            None
+        } else {
+            Some(false)
+        };
+    }
+    Some(bytes.starts_with(marker))
+}
+
+/// What `bytes`, the first of a connection, are. `None` while more are
+/// needed to tell, unless `done` because no more are coming.
+fn classify(bytes: &[u8], done: bool) -> Option<Protocol> {
+    // A TLS record of a handshake. No line starts with this byte.
+    if bytes.first() == Some(&0x16) {
+        return Some(Protocol::Tls);
+    }
+    let line = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .map(|end| bytes[..end].strip_suffix(b"\r").unwrap_or(&bytes[..end]));
+
+    match starts(bytes, PREFACE) {
+        Some(true) => return Some(Protocol::Grpc),
+        None if !done => return None,
+        _ => {}
+    }
+    for method in METHODS {
+        match starts(bytes, method) {
+            // A peer may well be called `GET well soon`, but not end its
+            // name with a version of HTTP.
+            Some(true) => {
+                return match line {
+                    Some(line) if is_request_line(line) => Some(Protocol::Http),
+                    Some(_) => Some(Protocol::Chat(None)),
+                    // No name is that long.
+                    None if done => Some(Protocol::Http),
+                    None => None,
+                };
+            }
+            None if !done => return None,
+            _ => {}
+        }
+    }
+    match starts(bytes, b"SIDE ") {
+        Some(true) => match line {
+            Some(line) => match &line[b"SIDE ".len()..] {
+                b"c" => Some(Protocol::Chat(Some(Side::C))),
+                b"go" => Some(Protocol::Chat(Some(Side::Go))),
+                // Then it is the name.
+                _ => Some(Protocol::Chat(None)),
+            },
+            None if done => Some(Protocol::Chat(None)),
+            None => None,
+        },
+        None if !done => None,
+        _ => Some(Protocol::Chat(None)),
+    }
+}
+
+/// Whether `line` ends like the request line of HTTP/1.
+fn is_request_line(line: &[u8]) -> bool {
+    line.len() > b" HTTP/1.1".len() && line[..line.len() - 1].ends_with(b" HTTP/1.")
+}
+
+/// Read the first bytes of `socket` until they tell what it speaks, for
+/// up to `wait`.
+fn sniff(
+    socket: TcpStream,
+    wait: Duration,
+) -> impl Future<Item = (Protocol, Rewind<TcpStream>), Error = io::Error> {
+    let mut socket = Some(socket);
+    let mut read = BytesMut::with_capacity(MAX_SNIFF);
+    let mut deadline = Delay::new(Instant::now() + wait);
+    future::poll_fn(move || {
+        let protocol = loop {
+            if let Some(protocol) = classify(&read, read.len() >= MAX_SNIFF) {
+                break protocol;
+            }
+            match socket.as_mut().unwrap().read_buf(&mut read)? {
+                Async::Ready(0) => break classify(&read, true).unwrap(),
+                Async::Ready(_) => {}
+                Async::NotReady => {
+                    let waited = deadline
+                        .poll()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    match waited {
+                        Async::Ready(()) => break classify(&read, true).unwrap(),
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+                }
+            }
+        };
+        if let Protocol::Chat(Some(_)) = protocol {
+            let end = read.iter().position(|&b| b == b'\n').unwrap();
+            read.advance(end + 1);
+        }
+        let socket = Rewind::new(read.take(), socket.take().unwrap());
+        Ok(Async::Ready((protocol, socket)))
+    })
+}
+
+/// A stream that reads what was read from it already once more, before
+/// reading on.
+#[derive(Debug)]
+pub struct Rewind<S> {
+    prefix: BytesMut,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: BytesMut, inner: S) -> Rewind<S> {
+        Rewind { prefix, inner }
+    }
+}
+
+/// A stream over a `TcpStream`, which is tuned once it is known what the
+/// stream is for.
+pub trait OnTcp: Transport {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl OnTcp for Rewind<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for Rewind<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.inner.read(buf);
+        }
+        let n = cmp::min(buf.len(), self.prefix.len());
+        buf[..n].copy_from_slice(&self.prefix.split_to(n));
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Rewind<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Rewind<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Rewind<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Accept connections of any kind on `listener`, until another process
+/// takes over.
+pub fn serve(
+    listener: net::TcpListener,
+    single: SinglePortConfig,
+    state: State,
+    config: Arc<Config>,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::from_std(listener, &Handle::default())?;
+    let handed_over = state.drain.handed_over();
+    let wait = Duration::from_millis(single.sniff_ms);
+    #[cfg(feature = "tls")]
+    let tls = config.tls.as_ref().map(tls::Acceptor::new).transpose()?;
+
+    Ok(Acceptor::new(listener, config.accepts_per_tick)
+        .map_err(|e| logging::warn!("accept_failed"; "single port accept error = {:?}", e))
+        .for_each(move |socket| {
+            if !state.access.admits(&socket) {
+                state.metrics.access_connections_denied.add(1);
+                return Ok(());
+            }
+            // Only fails once the client is gone.
+            let addr = match socket.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return Ok(()),
+            };
+            let (state, config) = (state.clone(), config.clone());
+            let side = single.side;
+            #[cfg(feature = "tls")]
+            let tls = tls.clone();
+            let route = sniff(socket, wait)
+                .map(move |(protocol, socket)| {
+                    #[cfg(feature = "tls")]
+                    {
+                        if let (Protocol::Tls, Some(tls)) = (protocol, tls) {
+                            return tls.accept(socket, addr, state, config);
+                        }
+                    }
+                    route(protocol, socket, addr, side, state, config)
+                })
+                .map_err(move |e| {
+                    logging::info!(
+                        "sniff_failed", addr = addr;
+                        "reading the first bytes of {} failed: {:?}", addr, e
+                    )
+                });
+            tokio::spawn(route);
+            Ok(())
+        })
+        .select(handed_over)
+        .map(|_| ())
+        .map_err(|_| ()))
+}
+
+/// Hand `socket`, of the client at `addr`, to what serves `protocol`. A
+/// peer that picked no side is one of `side`.
+pub fn route<S: OnTcp>(
+    protocol: Protocol,
+    socket: S,
+    addr: SocketAddr,
+    side: Side,
+    state: State,
+    config: Arc<Config>,
+) {
+    match protocol {
+        Protocol::Chat(picked) => {
+            if state.drain.is_draining() {
+                crate::refuse(socket, &state);
+                return;
+            }
+            let side = picked.unwrap_or(side);
+            // Like the first listener of each side.
+            let partition = match config.instance_state {
+                InstanceState::Shared => None,
+                InstanceState::Partitioned => Some(0),
+            };
+            crate::tune(socket.tcp(), side, &config, addr);
+            crate::spawn_peer(socket, addr, side, state, config, partition);
+        }
+        Protocol::Http => match config.http_listen {
+            Some(gateway) => {
+                tokio::spawn(relay(socket, gateway, addr));
+            }
+            None => logging::info!(
+                "sniff_refused", addr = addr;
+                "no http_listen to relay the HTTP connection of {} to", addr
+            ),
+        },
+        #[cfg(feature = "grpc")]
+        Protocol::Grpc => crate::grpc::connection(socket, addr, state, config),
+        #[cfg(not(feature = "grpc"))]
+        Protocol::Grpc => logging::info!(
+            "sniff_refused", addr = addr;
+            "refused the gRPC connection of {}: gRPC needs the grpc feature", addr
+        ),
+        Protocol::Tls => logging::info!(
+            "sniff_refused", addr = addr;
+            "refused the TLS connection of {}: no tls is configured", addr
+        ),
+    }
+}
+
+/// Copy `client` to the gateway on `gateway` and back, until both are done.
+fn relay<S: Transport>(
+    client: S,
+    mut gateway: SocketAddr,
+    addr: SocketAddr,
+) -> impl Future<Item = (), Error = ()> {
+    // Listening on every address, it is on the loopback too.
+    match gateway.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => gateway.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => gateway.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    let span = profiling::span!("relay", addr = %addr);
+    let relayed = TcpStream::connect(&gateway)
+        .and_then(move |upstream| {
+            let (client_rd, client_wr) = client.split();
+            let (upstream_rd, upstream_wr) = upstream.split();
+            let up = tokio::io::copy(client_rd, upstream_wr)
+                .and_then(|(_, _, upstream_wr)| tokio::io::shutdown(upstream_wr));
+            let down = tokio::io::copy(upstream_rd, client_wr)
+                .and_then(|(_, _, client_wr)| tokio::io::shutdown(client_wr));
+            up.join(down).map(|_| ())
+        })
+        .map_err(move |e| {
+            logging::info!(
+                "relay_failed", addr = addr;
+                "relaying the HTTP connection of {} failed: {:?}", addr, e
+            )
+        });
+    profiling::instrument(relayed, span)
+}
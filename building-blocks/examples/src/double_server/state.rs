@@ -0,0 +1,1049 @@
+use arc_swap::{ArcSwap, Guard};
+use building_blocks::wal::Wal;
+use building_blocks::wheel::Wheel;
+use bytes::{Bytes, BytesMut};
+use futures::sync::mpsc;
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::access::Access;
+use crate::audit::Audit;
+use crate::bridge::{Bridges, Hop, Link};
+use crate::causal::{Causal, Relayed};
+use crate::compression::Codecs;
+use crate::config::Config;
+use crate::conn::{self, ConnId, Connections, Lifecycle};
+use crate::drain::Drain;
+use crate::filter::Filters;
+use crate::geoip::{GeoIp, Location};
+use crate::journal;
+use crate::locale::{Catalog, Message, SharedLocale};
+use crate::logging::Sampler;
+use crate::memory::{self, SharedBuffers, Usage};
+use crate::meter::{PeerTraffic, SharedTraffic};
+use crate::metrics::{Metrics, Stats};
+use crate::moderation::Moderation;
+use crate::names;
+use crate::once::Dedup;
+use crate::profiling;
+use crate::quota::Quotas;
+use crate::ratelimit::Throttle;
+use crate::remind::Reminders;
+use crate::reporting::{self, ErrorReporter};
+use crate::roster::Roster;
+use crate::schedule::Schedule;
+use crate::transfer::Transfers;
+use crate::watchdog::Health;
+
+/// How many messages the history keeps.
+const HISTORY_LEN: usize = 1000;
+
+/// How late the timeouts of `State::timers` may fire.
+const TIMER_TICK: Duration = Duration::from_millis(100);
+
+/// Shorthand for the transmit half of the message channel.
+pub type Tx = mpsc::UnboundedSender<Outgoing>;
+
+/// Shorthand for the receive half of the message channel.
+pub type Rx = mpsc::UnboundedReceiver<Outgoing>;
+
+/// A line on its way to a peer.
+pub struct Outgoing {
+    pub line: Bytes,
+    /// Set for the messages of peers, whose latency is measured. The peer
+    /// calls `Delivery::flushed` once the line is written to its socket.
+    pub delivery: Option<Arc<Delivery>>,
+}
+
+/// One message of a peer, shared by every peer it is sent to.
+///
+/// Once the last of them flushed the message, the time since it was
+/// decoded is recorded in the latency histogram of the side that sent it.
+/// Nothing is recorded if a peer goes away before flushing it.
+pub struct Delivery {
+    /// Id of the message, see `State::next_message_id`.
+    pub id: u64,
+    /// When the message was taken in, see `now_ms`.
+    pub ts: u64,
+    decoded: Instant,
+    side: Side,
+    metrics: Arc<Metrics>,
+    /// Peers that did not flush the message yet, plus one while it is
+    /// still being sent.
+    remaining: AtomicUsize,
+}
+
+impl Delivery {
+    /// Tell that one of the peers flushed the message.
+    pub fn flushed(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.metrics
+                .message_latency(self.side)
+                .record(self.decoded.elapsed());
+        }
+    }
+}
+
+/// The two programs bridged by the server.
+///
+/// Lines read from a peer on one side are delivered to every peer on the
+/// other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    C,
+    Go,
+}
+
+impl Side {
+    /// The side that receives lines sent from this one.
+    pub fn other(self) -> Side {
+        match self {
+            Side::C => Side::Go,
+            Side::Go => Side::C,
+        }
+    }
+
+    /// Short lowercase label, used in logs and in names derived from the
+    /// side (like the Matrix puppets).
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Side::C => "c",
+            Side::Go => "go",
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What the rest of the server knows about a connected peer.
+pub struct Member {
+    pub name: String,
+    /// Where the peer connected from. Only told, never looked up by.
+    pub addr: SocketAddr,
+    /// Whether the connection is still sent to, see `conn`.
+    pub lifecycle: Lifecycle,
+    pub tx: Tx,
+    /// Lines of the server itself, written to the peer before the messages
+    /// waiting for it, see `State::announce`.
+    pub control: Tx,
+    /// Bandwidth used by the peer, recorded by the peer itself.
+    pub traffic: SharedTraffic,
+    /// Capacity of the buffers of the peer, also recorded by the peer.
+    pub buffers: SharedBuffers,
+    /// The instance the peer connected through, if instances are
+    /// partitioned, see `InstanceState`.
+    pub partition: Option<usize>,
+    /// Where the peer connected from, see `geoip`.
+    pub location: Location,
+    /// What the peer is told things in, see `locale`.
+    pub locale: SharedLocale,
+    /// Set if the peer is another server, see `bridge`.
+    pub link: Option<Arc<Link>>,
+}
+
+/// Where a broadcast sends to, see `Peers::targets`.
+#[derive(Clone)]
+pub struct Target {
+    id: ConnId,
+    lifecycle: Lifecycle,
+    tx: Tx,
+    control: Tx,
+    locale: SharedLocale,
+    link: Option<Arc<Link>>,
+}
+
+/// The channels of the peers of a shard, by who gets what.
+#[derive(Default)]
+pub struct Targets {
+    /// Every peer, for lines to all of them.
+    all: Vec<Target>,
+    /// The peers of every partition, see `InstanceState`. A broadcast to a
+    /// partition only goes over its own peers, rather than over all of them
+    /// to pick those of the partition.
+    partitions: HashMap<usize, Vec<Target>>,
+}
+
+impl Targets {
+    /// The peers a broadcast to `partition` (all of them if `None`) goes
+    /// to.
+    fn of(&self, partition: Option<usize>) -> &[Target] {
+        match partition {
+            None => &self.all,
+            Some(partition) => self
+                .partitions
+                .get(&partition)
+                .map_or(&[], |targets| targets),
+        }
+    }
+}
+
+/// Data that is shared between the peers of one shard of a side, see
+/// `Peers`.
+///
+/// This is the set of `Tx` handles for the connected clients. Whenever a
+/// message is received from a client, it is broadcasted to all peers by
+/// iterating over the `targets` and sending a copy of the message on each
+/// `Tx`.
+pub struct Shared {
+    peers: HashMap<ConnId, Member>,
+
+    /// The channels of `peers`, replaced whenever a peer joins or leaves.
+    /// Broadcasts load them without taking the lock, see `Peers::targets`.
+    targets: Arc<ArcSwap<Targets>>,
+}
+
+impl Shared {
+    /// Create a new, empty, instance of `Shared`.
+    pub fn new() -> Self {
+        Shared {
+            peers: HashMap::new(),
+            targets: Arc::new(ArcSwap::from_pointee(Targets::default())),
+        }
+    }
+
+    fn insert(&mut self, id: ConnId, member: Member) {
+        self.peers.insert(id, member);
+        self.rebuild();
+    }
+
+    fn remove(&mut self, id: &ConnId) {
+        if self.peers.remove(id).is_some() {
+            self.rebuild();
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let mut targets = Targets::default();
+        for (id, member) in &self.peers {
+            let target = Target {
+                id: *id,
+                lifecycle: member.lifecycle.clone(),
+                tx: member.tx.clone(),
+                control: member.control.clone(),
+                locale: member.locale.clone(),
+                link: member.link.clone(),
+            };
+            if let Some(partition) = member.partition {
+                targets
+                    .partitions
+                    .entry(partition)
+                    .or_insert_with(Vec::new)
+                    .push(target.clone());
+            }
+            targets.all.push(target);
+        }
+        self.targets.store(Arc::new(targets));
+    }
+}
+
+/// The peers of one side, split into shards by their connection.
+///
+/// Joining and leaving only lock the shard of the peer, and only rebuild
+/// its `targets`, so they cost the same no matter how many peers the side
+/// has. See `Config::peer_shards`, and benches/peers.rs for the shards
+/// behind a `RwLock` rather than a `Mutex`.
+pub struct Peers {
+    shards: Vec<RwLock<Shared>>,
+    /// The `targets` of every shard, to read without locking.
+    targets: Vec<Arc<ArcSwap<Targets>>>,
+}
+
+impl Peers {
+    fn new(shards: usize) -> Peers {
+        let shards: Vec<RwLock<Shared>> = (0..shards).map(|_| RwLock::new(Shared::new())).collect();
+        let targets = shards
+            .iter()
+            .map(|shard| shard.read().unwrap().targets.clone())
+            .collect();
+        Peers { shards, targets }
+    }
+
+    fn shard(&self, id: &ConnId) -> &RwLock<Shared> {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn insert(&self, id: ConnId, member: Member) {
+        self.shard(&id).write().unwrap().insert(id, member);
+    }
+
+    pub fn remove(&self, id: &ConnId) {
+        self.shard(id).write().unwrap().remove(id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().peers.len())
+            .sum()
+    }
+
+    /// The longest wait for a shard, see `State::lock_wait`.
+    fn lock_wait(&self) -> Duration {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let start = Instant::now();
+                drop(shard.read().unwrap());
+                start.elapsed()
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The control channel and locale of the peer `id`, if it is
+    /// connected.
+    fn control(&self, id: &ConnId) -> Option<(Tx, SharedLocale)> {
+        let shard = self.shard(id).read().unwrap();
+        shard
+            .peers
+            .get(id)
+            .map(|member| (member.control.clone(), member.locale.clone()))
+    }
+
+    /// Call `f` with every peer, one shard after the other. A shard is
+    /// locked while its peers are visited.
+    pub fn for_each<F: FnMut(&ConnId, &Member)>(&self, mut f: F) {
+        for shard in &self.shards {
+            for (id, member) in &shard.read().unwrap().peers {
+                f(id, member);
+            }
+        }
+    }
+
+    /// The channels of the peers, as of now, a list per shard. Loading them
+    /// takes no lock, so that broadcasts neither wait for each other nor
+    /// for peers joining and leaving.
+    fn targets(&self) -> impl Iterator<Item = Guard<Arc<Targets>>> + '_ {
+        self.targets.iter().map(|targets| targets.load())
+    }
+}
+
+/// Something that happened in the chat, as seen by the integrations.
+///
+/// Peers only exchange pre-rendered lines with each other. Integrations that
+/// re-publish the conversation elsewhere need to know who said what, so they
+/// get these instead.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+pub enum ChatEvent {
+    /// A peer finished its handshake.
+    Joined { side: Side, name: String },
+
+    /// A peer sent a line.
+    Message {
+        /// Server-wide sequence number, see `State::next_message_id`.
+        id: u64,
+        side: Side,
+        name: String,
+        body: String,
+    },
+
+    /// A peer disconnected.
+    Left { side: Side, name: String },
+}
+
+/// A message kept in the history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub side: Side,
+    pub name: String,
+    pub body: String,
+    /// When the message was sent, see `now_ms`.
+    pub ts: u64,
+}
+
+/// Milliseconds since the epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+/// Handles to everything a peer needs to reach the rest of the server.
+///
+/// Cloning is cheap, every field is reference counted.
+#[derive(Clone)]
+pub struct State {
+    c: Arc<Peers>,
+    go: Arc<Peers>,
+
+    /// Channels of the integrations that want a copy of every `ChatEvent`.
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<ChatEvent>>>>,
+
+    /// Id of the next `ChatEvent::Message`.
+    next_id: Arc<AtomicU64>,
+
+    /// The connection of the peer going by every name, by `names::key`.
+    /// The names as the peers wrote them are those of their `Member`.
+    names: Arc<Mutex<HashMap<String, ConnId>>>,
+
+    /// The last `HISTORY_LEN` messages, oldest first.
+    history: Arc<Mutex<VecDeque<StoredMessage>>>,
+
+    /// Counters exported by the HTTP gateway.
+    pub metrics: Arc<Metrics>,
+
+    /// Limit of the messages of all peers together.
+    pub throttle: Throttle,
+
+    /// Daily byte quotas of the peers, if configured.
+    pub quotas: Option<Quotas>,
+
+    /// The ids of the messages of exactly-once peers, if configured, see
+    /// `once`.
+    pub exactly_once: Option<Arc<Dedup>>,
+
+    /// Where the history is written ahead to, if configured, see
+    /// `journal`.
+    pub journal: Option<Wal>,
+
+    /// Whether this server is a replica, whose peers only read, see
+    /// `replica`. Its messages only come from the primary then: `publish`,
+    /// `relay`, `broadcast` and `inject` leave out those of its own
+    /// peers and integrations, whichever frontend they came in by.
+    pub read_only: bool,
+
+    /// Whether the server is shutting down.
+    pub drain: Drain,
+
+    /// Every connection and the phase it is in, see `conn`.
+    pub connections: Arc<Connections>,
+
+    /// Files offered between peers, see `transfer`.
+    pub transfers: Arc<Transfers>,
+
+    /// What peers may compress their connection with, see `compression`.
+    pub codecs: Arc<Codecs>,
+
+    /// Which networks may connect, see `access`.
+    pub access: Arc<Access>,
+
+    /// Where peers connect from, see `geoip`.
+    pub geoip: Arc<GeoIp>,
+
+    /// Where privileged actions are recorded, see `audit`.
+    pub audit: Arc<Audit>,
+
+    /// Which messages are logged, see `logging`.
+    pub sampler: Arc<Sampler>,
+
+    /// Told of unexpected failures, see `reporting`.
+    pub reporter: Arc<dyn ErrorReporter>,
+
+    /// What the watchdog found wrong, see `watchdog`.
+    pub health: Arc<Health>,
+
+    /// What the server tells peers, in each locale, see `locale`.
+    pub catalog: Arc<Catalog>,
+
+    /// What rewrites messages before they are relayed, see `filter`.
+    pub filters: Arc<Filters>,
+
+    /// Announcements made on a schedule, see `schedule`.
+    pub schedule: Arc<Schedule>,
+
+    /// What peers left for later, see `remind`.
+    pub reminders: Reminders,
+
+    /// The coarse timeouts of the peers, like the heartbeat and the
+    /// throttle, see `building_blocks::wheel`.
+    pub timers: Wheel,
+
+    /// This server among those bridged to it, see `bridge`.
+    pub bridges: Arc<Bridges>,
+    /// Who is connected to the other servers of the bridge, see `roster`.
+    pub roster: Arc<Roster>,
+    /// The topic, who is banned and who moderates, see `moderation`.
+    pub moderation: Arc<Moderation>,
+    /// Messages of other servers held back to keep their order, see
+    /// `causal`.
+    pub causal: Arc<Causal>,
+}
+
+impl State {
+    /// Create the state for a server with no peers and no integrations.
+    ///
+    /// Fails if the saved quota usage cannot be read, a codec of
+    /// `Config::compression` is not known, the GeoIP databases cannot be
+    /// read, the chain of the audit file is broken, the exactly-once file
+    /// or the log of the history cannot be opened, the error reporter
+    /// cannot be set up, the catalog or the saved reminders cannot be
+    /// loaded, or the bridge id is not valid. The announcements of
+    /// `Config::scheduled` are added after, see `Schedule::load`.
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let quotas = match &config.quotas {
+            Some(quotas) => Some(Quotas::load(quotas)?),
+            None => None,
+        };
+        let codecs = Codecs::new(&config.compression)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let geoip = match &config.geoip {
+            Some(geoip) => GeoIp::open(geoip)?,
+            None => GeoIp::default(),
+        };
+        let audit = match &config.audit {
+            Some(audit) => Audit::open(audit)?,
+            None => Audit::default(),
+        };
+        let exactly_once = match &config.exactly_once {
+            Some(once) => Some(Arc::new(Dedup::open(once)?)),
+            None => None,
+        };
+        let first_id = exactly_once.as_ref().map_or(1, |once| once.first_id());
+        let (journal, written) = match &config.wal {
+            Some(wal) => {
+                let (wal, written) = journal::open(wal)?;
+                (Some(wal), written)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let bridges = Bridges::new(&config.bridge)?;
+        let moderation = Moderation::new(&config.moderation, &bridges.id);
+
+        let state = State {
+            c: Arc::new(Peers::new(config.peer_shards)),
+            go: Arc::new(Peers::new(config.peer_shards)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            next_id: Arc::new(AtomicU64::new(first_id)),
+            names: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_LEN))),
+            metrics: Arc::new(Metrics::default()),
+            throttle: Throttle::new(&config.rate_limits),
+            quotas,
+            exactly_once,
+            journal,
+            read_only: config.replica.is_some(),
+            drain: Drain::new(),
+            connections: Arc::new(Connections::new(conn::generation())),
+            transfers: Arc::new(Transfers::new(config)),
+            codecs: Arc::new(codecs),
+            access: Arc::new(Access::new(&config.access)),
+            geoip: Arc::new(geoip),
+            audit: Arc::new(audit),
+            sampler: Arc::new(Sampler::new(config.log_sample_every)),
+            reporter: reporting::open(config)?,
+            health: Arc::new(Health::default()),
+            catalog: Arc::new(Catalog::load(config)?),
+            filters: Arc::new(Filters::new(&config.filters)),
+            schedule: Arc::new(Schedule::default()),
+            reminders: Reminders::load(&config.reminders)?,
+            timers: Wheel::new(TIMER_TICK),
+            bridges: Arc::new(bridges),
+            roster: Arc::new(Roster::new()),
+            moderation: Arc::new(moderation),
+            causal: Arc::new(Causal::new(&config.bridge)),
+        };
+        if let Some(last) = written.last() {
+            let next_id = last.id + 1;
+            state.restore_history(written, next_id);
+        }
+        Ok(state)
+    }
+
+    /// The peers of `side`. Only peers joining and leaving write to them,
+    /// everything else (like `/who` and `/metrics`) reads.
+    pub fn side(&self, side: Side) -> &Peers {
+        match side {
+            Side::C => &self.c,
+            Side::Go => &self.go,
+        }
+    }
+
+    /// Register a new integration and return the receiving end of its event
+    /// stream.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<ChatEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Hand `event` to every integration.
+    ///
+    /// Integrations that went away are dropped from the list rather than
+    /// treated as an error, a broken bridge must not take the chat down.
+    /// A replica leaves out messages, see `read_only`.
+    pub fn publish(&self, event: ChatEvent) {
+        if let ChatEvent::Message { .. } = &event {
+            if self.read_only {
+                return;
+            }
+        }
+        if let ChatEvent::Message {
+            id,
+            side,
+            name,
+            body,
+        } = &event
+        {
+            let mut history = self.history.lock().unwrap();
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
+            }
+            let message = StoredMessage {
+                id: *id,
+                side: *side,
+                name: name.clone(),
+                body: body.clone(),
+                ts: now_ms(),
+            };
+            // Under the lock, so that the log has them in order.
+            if let Some(wal) = &self.journal {
+                journal::append(wal, &message);
+            }
+            history.push_back(message);
+        }
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Keep `message` of the primary this server is a replica of, see
+    /// `replica`, and hand it to every integration like `publish`. A message
+    /// it has already is left out.
+    ///
+    /// The primary allocates ids before it keeps the messages, so two
+    /// peers sending at once may have theirs sent here out of order. The
+    /// older one goes in the history before the newer, unless it is older
+    /// than all of a full history.
+    pub fn replicate(&self, message: StoredMessage) {
+        let event = ChatEvent::Message {
+            id: message.id,
+            side: message.side,
+            name: message.name.clone(),
+            body: message.body.clone(),
+        };
+        {
+            let mut history = self.history.lock().unwrap();
+            let before = history.iter().rposition(|kept| kept.id <= message.id);
+            let at = match before {
+                Some(before) if history[before].id == message.id => return,
+                Some(before) => before + 1,
+                None if history.len() == HISTORY_LEN => return,
+                None => 0,
+            };
+            if let Some(wal) = &self.journal {
+                journal::append(wal, &message);
+            }
+            self.next_id.fetch_max(message.id + 1, Ordering::Relaxed);
+            history.insert(at, message);
+            if history.len() > HISTORY_LEN {
+                history.pop_front();
+            }
+        }
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Allocate the id of a new message. Ids start at 1 and only identify a
+    /// message within one run of the server, unless exactly-once delivery
+    /// is set up, see `once`.
+    pub fn next_message_id(&self) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(once) = &self.exactly_once {
+            once.issued(id);
+        }
+        id
+    }
+
+    /// The id of the last message allocated, 0 before the first.
+    pub fn last_message_id(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed) - 1
+    }
+
+    /// Up to `limit` of the newest messages with an id below `before`,
+    /// oldest first. `None` means no limit or no upper bound.
+    pub fn history(&self, before: Option<u64>, limit: Option<usize>) -> Vec<StoredMessage> {
+        let history = self.history.lock().unwrap();
+        let mut messages: Vec<StoredMessage> = history
+            .iter()
+            .rev()
+            .filter(|m| before.map_or(true, |before| m.id < before))
+            .take(limit.unwrap_or(HISTORY_LEN))
+            .cloned()
+            .collect();
+        messages.reverse();
+        messages
+    }
+
+    /// The history, oldest first, and the id of the next message, for a
+    /// `snapshot`.
+    pub fn saved_history(&self) -> (Vec<StoredMessage>, u64) {
+        let history = self.history.lock().unwrap();
+        let next_id = self.next_id.load(Ordering::Relaxed);
+        (history.iter().cloned().collect(), next_id)
+    }
+
+    /// Take back the history of a `snapshot` or the `journal`, before the
+    /// first message, along with what was taken back already. Ids go on
+    /// after `next_id`, unless those of `once` are further.
+    pub fn restore_history(&self, messages: Vec<StoredMessage>, next_id: u64) {
+        let mut history = self.history.lock().unwrap();
+        let mut merged: BTreeMap<u64, StoredMessage> =
+            messages.into_iter().map(|m| (m.id, m)).collect();
+        merged.extend(history.drain(..).map(|m| (m.id, m)));
+        let skip = merged.len().saturating_sub(HISTORY_LEN);
+        history.extend(merged.into_iter().skip(skip).map(|(_, m)| m));
+        self.next_id.fetch_max(next_id, Ordering::Relaxed);
+    }
+
+    /// Ids of the newest `count` messages in the history, if there are any.
+    pub fn newest(&self, count: usize) -> Option<Range<u64>> {
+        let history = self.history.lock().unwrap();
+        let first = history.iter().rev().take(count).last()?;
+        let last = history.back().unwrap();
+        Some(first.id..last.id + 1)
+    }
+
+    /// Up to `limit` of the messages in the history with an id in `ids`,
+    /// oldest first.
+    pub fn history_range(&self, ids: &Range<u64>, limit: usize) -> Vec<StoredMessage> {
+        let history = self.history.lock().unwrap();
+        history
+            .iter()
+            .filter(|m| ids.contains(&m.id))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Let the peer `id` go by `name`, unless another one does, in any
+    /// case.
+    pub fn claim_name(&self, name: &str, id: ConnId) -> bool {
+        match self.names.lock().unwrap().entry(names::key(name)) {
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Free the name the peer `id` went by.
+    pub fn release_name(&self, name: &str, id: &ConnId) {
+        let mut names = self.names.lock().unwrap();
+        let key = names::key(name);
+        if names.get(&key) == Some(id) {
+            names.remove(&key);
+        }
+    }
+
+    /// Peers connected on both sides.
+    pub fn peer_count(&self) -> usize {
+        self.c.len() + self.go.len()
+    }
+
+    /// The memory held by the peers and the history.
+    pub fn memory(&self) -> Usage {
+        let (mut read_buffers, mut write_buffers) = (0, 0);
+        for &side in &[Side::C, Side::Go] {
+            self.side(side).for_each(|_, member| {
+                read_buffers += member.buffers.read();
+                write_buffers += member.buffers.write();
+            });
+        }
+
+        let history = self.history.lock().unwrap();
+        Usage {
+            read_buffers,
+            write_buffers,
+            history_messages: history.len(),
+            history_bytes: history.iter().map(|m| m.name.len() + m.body.len()).sum(),
+            heap: memory::heap(),
+        }
+    }
+
+    /// What a `STATS` line is answered with.
+    pub fn stats(&self) -> Stats {
+        let history = self.history.lock().unwrap();
+        Stats {
+            peers_c: self.c.len(),
+            peers_go: self.go.len(),
+            queued_outbound_bytes: self.metrics.queued_outbound_bytes.get(),
+            history_messages: history.len(),
+            history_bytes: history.iter().map(|m| m.name.len() + m.body.len()).sum(),
+        }
+    }
+
+    /// The longest it takes to take one of the locks every peer takes, like
+    /// those of the history and of the peers. Taking them blocks.
+    pub fn lock_wait(&self) -> Duration {
+        let start = Instant::now();
+        drop(self.history.lock().unwrap());
+        let history = start.elapsed();
+        let start = Instant::now();
+        drop(self.subscribers.lock().unwrap());
+        let subscribers = start.elapsed();
+        history
+            .max(subscribers)
+            .max(self.c.lock_wait())
+            .max(self.go.lock_wait())
+    }
+
+    /// Traffic of every connected peer, heaviest first.
+    pub fn traffic(&self) -> Vec<PeerTraffic> {
+        let mut peers = Vec::new();
+        for &side in &[Side::C, Side::Go] {
+            self.side(side).for_each(|id, member| {
+                peers.push(PeerTraffic {
+                    side,
+                    id: *id,
+                    addr: member.addr,
+                    name: member.name.clone(),
+                    location: member.location.clone(),
+                    traffic: member.traffic.lock().unwrap().snapshot(),
+                });
+            });
+        }
+        peers.sort_by(|a, b| {
+            b.traffic
+                .rate()
+                .partial_cmp(&a.traffic.rate())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        peers
+    }
+
+    /// Send `line` to every peer on `side` except `from`, and only to those
+    /// of `partition` if given. Nothing is sent by a replica, see
+    /// `read_only`.
+    pub fn broadcast(
+        &self,
+        side: Side,
+        from: Option<ConnId>,
+        partition: Option<usize>,
+        line: &Bytes,
+    ) {
+        if self.read_only {
+            return;
+        }
+        self.send(side, from, partition, line, None, None);
+    }
+
+    /// Like `broadcast`, for a message of a peer of the other side. Its
+    /// latency is recorded once every peer flushed it, see `Delivery`.
+    pub fn relay(&self, message: &Relayed) {
+        if self.read_only {
+            return;
+        }
+        let Relayed {
+            side,
+            from,
+            partition,
+            line,
+            id,
+            decoded,
+            hop,
+            ..
+        } = message;
+        let (side, partition, decoded) = (*side, *partition, *decoded);
+        let delivery = Arc::new(Delivery {
+            id: *id,
+            ts: now_ms(),
+            decoded,
+            side: side.other(),
+            metrics: self.metrics.clone(),
+            remaining: AtomicUsize::new(1),
+        });
+        // A message nobody got has no latency.
+        if self.send(
+            side,
+            Some(*from),
+            partition,
+            line,
+            Some(&delivery),
+            Some(hop),
+        ) > 0
+        {
+            delivery.flushed();
+        }
+    }
+
+    /// Send `line` along with `delivery`, returning to how many peers.
+    /// Links get the tag of `hop` in front, if it has one, unless it passed
+    /// their server.
+    fn send(
+        &self,
+        side: Side,
+        from: Option<ConnId>,
+        partition: Option<usize>,
+        line: &Bytes,
+        delivery: Option<&Arc<Delivery>>,
+        hop: Option<&Hop>,
+    ) -> usize {
+        let _span = profiling::debug_span!("broadcast", side = %side).entered();
+        let tagged = hop.map(|hop| {
+            let mut tagged = BytesMut::from(hop.tag().as_bytes());
+            tagged.extend_from_slice(line);
+            tagged.freeze()
+        });
+        let passed = match hop {
+            Some(hop) => self.bridges.passed(hop),
+            None => Vec::new(),
+        };
+        let mut sent = 0;
+        for targets in self.side(side).targets() {
+            for target in targets.of(partition) {
+                let line = match (&target.link, hop, &tagged) {
+                    (Some(link), Some(hop), Some(tagged)) => {
+                        let remote = link.remote.as_ref();
+                        let passed = passed.contains(&target.id)
+                            || remote.map_or(false, |remote| hop.via.contains(remote));
+                        if passed {
+                            continue;
+                        }
+                        tagged
+                    }
+                    _ => line,
+                };
+                // Don't send the message to ourselves, nor to connections
+                // on their way out
+                if Some(target.id) != from && target.lifecycle.is_active() {
+                    if let Some(delivery) = delivery {
+                        delivery.remaining.fetch_add(1, Ordering::AcqRel);
+                    }
+                    let outgoing = Outgoing {
+                        line: line.clone(),
+                        delivery: delivery.cloned(),
+                    };
+                    // The send fails if the peer left since the targets
+                    // were loaded. It does not get the message then, just
+                    // like if it had left a moment earlier.
+                    match target.tx.unbounded_send(outgoing) {
+                        Ok(()) => sent += 1,
+                        Err(_) => {
+                            if let Some(delivery) = delivery {
+                                delivery.remaining.fetch_sub(1, Ordering::AcqRel);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        sent
+    }
+
+    /// Send a line that did not originate from a peer (for example one
+    /// relayed from an integration) to every peer on both sides.
+    pub fn inject(&self, line: Bytes) {
+        self.broadcast(Side::C, None, None, &line);
+        self.broadcast(Side::Go, None, None, &line);
+    }
+
+    /// Send `message` as a notice to the peer `id` of `side`, in its
+    /// locale and ahead of the messages waiting for it. Nothing is sent if
+    /// it left.
+    pub fn tell(&self, side: Side, id: &ConnId, message: &Message) {
+        if let Some((control, locale)) = self.side(side).control(id) {
+            let text = self.catalog.render(locale.load(Ordering::Relaxed), message);
+            let outgoing = Outgoing {
+                line: Bytes::from(format!("* {}\r\n", text)),
+                delivery: None,
+            };
+            let _ = control.unbounded_send(outgoing);
+        }
+    }
+
+    /// Send `line` of the server itself as it is to the peer `id` of
+    /// `side`, ahead of the messages waiting for it, like an announcement
+    /// to a link. Nothing is sent if it left.
+    pub fn tell_line(&self, side: Side, id: &ConnId, line: Bytes) {
+        if let Some((control, _)) = self.side(side).control(id) {
+            let outgoing = Outgoing {
+                line,
+                delivery: None,
+            };
+            let _ = control.unbounded_send(outgoing);
+        }
+    }
+
+    /// Send a notice of the server itself, like a shutdown notice, to every
+    /// peer on both sides in `partition` (all of them if `None`) but
+    /// `except`, in its locale. It overtakes the messages waiting for a
+    /// peer.
+    pub fn announce(
+        &self,
+        partition: Option<usize>,
+        except: Option<&ConnId>,
+        message: &Message,
+    ) {
+        self.announce_to(&[Side::C, Side::Go], partition, except, message);
+    }
+
+    /// Like `announce`, to the peers of `sides` only.
+    pub fn announce_to(
+        &self,
+        sides: &[Side],
+        partition: Option<usize>,
+        except: Option<&ConnId>,
+        message: &Message,
+    ) {
+        // Rendered once per locale rather than once per peer.
+        let mut lines: HashMap<usize, Bytes> = HashMap::new();
+        self.notify(sides, partition, except, |target| {
+            let locale = target.locale.load(Ordering::Relaxed);
+            let line = lines.entry(locale).or_insert_with(|| {
+                let text = self.catalog.render(locale, message);
+                Bytes::from(format!("* {}\r\n", text))
+            });
+            line.clone()
+        });
+    }
+
+    /// Send `text` of an operator, like a scheduled announcement, to every
+    /// peer of `side` as it is, ahead of the messages waiting for it.
+    pub fn announce_text(&self, side: Side, text: &str) {
+        let line = Bytes::from(format!("* {}\r\n", text));
+        self.notify(&[side], None, None, |_| line.clone());
+    }
+
+    /// Send the `line` of every peer of `sides` in `partition` but `except`
+    /// over its control channel.
+    fn notify<F: FnMut(&Target) -> Bytes>(
+        &self,
+        sides: &[Side],
+        partition: Option<usize>,
+        except: Option<&ConnId>,
+        mut line: F,
+    ) {
+        for &side in sides {
+            for targets in self.side(side).targets() {
+                for target in targets.of(partition) {
+                    if Some(&target.id) == except || !target.lifecycle.is_active() {
+                        continue;
+                    }
+                    let outgoing = Outgoing {
+                        line: line(target),
+                        delivery: None,
+                    };
+                    // Like in `send`, peers that just left miss it.
+                    let _ = target.control.unbounded_send(outgoing);
+                }
+            }
+        }
+    }
+}
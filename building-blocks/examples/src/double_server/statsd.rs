@@ -0,0 +1,215 @@
+//! Metrics pushed to a StatsD agent, for setups that do not scrape
+//! `/metrics`.
+//!
+//! ```json
+//! "statsd": { "server": "127.0.0.1:8125", "prefix": "chat.eu-1", "interval_secs": 10 }
+//! ```
+//!
+//! Every `interval_secs` the server sends what it exports at `/metrics` over
+//! UDP, as a few datagrams of lines like these:
+//!
+//! ```text
+//! chat.eu-1.kafka_messages_delivered:12|c
+//! chat.eu-1.peers.c:40|g
+//! chat.eu-1.message_latency.go:0.412|ms|@0.004
+//! ```
+//!
+//! Counters are sent as what they grew by since the last push, and gauges
+//! as they are. The latency of the messages delivered since the last push
+//! is sent as one timing, their mean, with a sample rate of one over their
+//! number: the agent counts it as that many timings. The series of single
+//! peers (their traffic) are not sent: names that come and go make poor
+//! StatsD buckets.
+//!
+//! Datagrams that do not get through are lost, as StatsD intends. The
+//! server name is looked up again for every push, see `resolve`.
+
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::config::StatsdConfig;
+use crate::logging;
+use crate::state::{Side, State};
+
+/// Most bytes of a datagram, so that it fits in a single Ethernet frame.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Looking the agent up, see `HostPort::resolve`.
+type Lookup = Box<dyn Future<Item = Vec<SocketAddr>, Error = io::Error> + Send>;
+
+/// Pushes the metrics, a future that runs for the lifetime of the server.
+pub struct Exporter {
+    config: StatsdConfig,
+    state: State,
+    ticks: Interval,
+
+    /// Lines of the current push, while the server is looked up.
+    lookup: Option<(Vec<String>, Lookup)>,
+
+    /// Bound to the address family of the agent, once there is one.
+    socket: Option<UdpSocket>,
+
+    /// Value of every counter at the last push, in the order of
+    /// `Metrics::counters`.
+    counters: Vec<u64>,
+
+    /// Latencies recorded of either side at the last push, and their sum.
+    latency: [(u64, Duration); 2],
+}
+
+impl Exporter {
+    pub fn new(config: StatsdConfig, state: State) -> Exporter {
+        let interval = Duration::from_secs(config.interval_secs);
+        Exporter {
+            config,
+            state,
+            ticks: Interval::new(Instant::now() + interval, interval),
+            lookup: None,
+            socket: None,
+            counters: Vec::new(),
+            latency: [(0, Duration::from_secs(0)); 2],
+        }
+    }
+
+    fn name(&self, name: &str) -> String {
+        if self.config.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.config.prefix, name)
+        }
+    }
+
+    /// The lines of a push, taking what the counters and latencies grew by
+    /// since the last.
+    fn lines(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let metrics = self.state.metrics.clone();
+
+        let counters = metrics.counters();
+        for (index, (name, value)) in counters.iter().enumerate() {
+            let last = self.counters.get(index).cloned().unwrap_or(0);
+            if *value > last {
+                let name = self.name(name.trim_end_matches("_total"));
+                lines.push(format!("{}:{}|c", name, value - last));
+            }
+        }
+        self.counters = counters.iter().map(|(_, value)| *value).collect();
+
+        let stats = self.state.stats();
+        let usage = self.state.memory();
+        let mut gauges = vec![
+            ("peers.c", stats.peers_c as u64),
+            ("peers.go", stats.peers_go as u64),
+            ("queued_outbound_bytes", stats.queued_outbound_bytes),
+            ("history_messages", stats.history_messages as u64),
+            ("history_bytes", stats.history_bytes as u64),
+            ("peer_read_buffer_bytes", usage.read_buffers as u64),
+            ("peer_write_buffer_bytes", usage.write_buffers as u64),
+        ];
+        if let Some(heap) = &usage.heap {
+            gauges.push(("heap_allocated_bytes", heap.allocated as u64));
+        }
+        for (name, value) in gauges {
+            lines.push(format!("{}:{}|g", self.name(name), value));
+        }
+
+        for (index, &side) in [Side::C, Side::Go].iter().enumerate() {
+            let latency = metrics.message_latency(side);
+            let (count, sum) = (latency.count(), latency.sum());
+            let (last_count, last_sum) = self.latency[index];
+            self.latency[index] = (count, sum);
+            if count <= last_count {
+                continue;
+            }
+            let delivered = count - last_count;
+            let mean_ms = (sum - last_sum).as_micros() as f64 / 1000.0 / delivered as f64;
+            let name = self.name(&format!("message_latency.{}", side));
+            lines.push(format!(
+                "{}:{:.3}|ms|@{}",
+                name,
+                mean_ms,
+                1.0 / delivered as f64
+            ));
+        }
+        lines
+    }
+
+    /// Send `lines` to `to`, as few datagrams as they fit in. The socket
+    /// does not block: a datagram the kernel has no room for is dropped.
+    fn send(&mut self, to: SocketAddr, lines: &[String]) -> io::Result<()> {
+        let bound = match &self.socket {
+            Some(socket) => socket.local_addr()?.is_ipv4() == to.is_ipv4(),
+            None => false,
+        };
+        if !bound {
+            let any: SocketAddr = if to.is_ipv4() {
+                ([0, 0, 0, 0], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            let socket = UdpSocket::bind(any)?;
+            socket.set_nonblocking(true)?;
+            self.socket = Some(socket);
+        }
+        let socket = self.socket.as_ref().unwrap();
+
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                socket.send_to(datagram.as_bytes(), to)?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            socket.send_to(datagram.as_bytes(), to)?;
+        }
+        Ok(())
+    }
+}
+
+impl Future for Exporter {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some((_, lookup)) = &mut self.lookup {
+                let result = match lookup.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(addrs)) => Ok(addrs[0]),
+                    Err(e) => Err(e),
+                };
+                let (lines, _) = self.lookup.take().unwrap();
+                let sent = result.and_then(|to| self.send(to, &lines));
+                match sent {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => logging::warn!(
+                        "statsd_failed", addr = &self.config.server;
+                        "statsd push to {} failed = {:?}", self.config.server, e
+                    ),
+                }
+            }
+
+            match self.ticks.poll() {
+                Ok(Async::Ready(_)) => {
+                    let lines = self.lines();
+                    self.lookup = Some((lines, Box::new(self.config.server.resolve())));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    logging::error!("statsd_stopped"; "statsd timer error = {:?}", e);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
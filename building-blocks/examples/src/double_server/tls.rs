@@ -0,0 +1,150 @@
+//! TLS, with what a connection speaks and the side of a peer picked by
+//! ALPN, so that one listener takes what needs a port each in plain text.
+//!
+//! ```text
+//! chat-c      a peer of c
+//! chat-go     a peer of go
+//! h2          gRPC, see `grpc`
+//! http/1.1    HTTP and WebSocket, relayed to `http_listen`, see `sniff`
+//! none        a peer of `side`
+//! ```
+//!
+//! A client offering none of these is served as if it offered nothing, so
+//! that clients of the chat only need TLS, not ALPN. The `single_port`
+//! serves TLS the same way, once it sees a ClientHello. The certificate
+//! and the key are read at startup.
+//!
+//! The gateway serves HTTP over TLS on a listener of its own, with HTTP/2
+//! for the clients asking for it, see `gateway`. Here, `h2` is gRPC.
+
+use bytes::BytesMut;
+use openssl::ssl::{self, AlpnError, SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio_openssl::{SslAcceptorExt, SslStream};
+
+use std::io;
+use std::net::{self, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::accept;
+use crate::config::{Config, TlsConfig};
+use crate::logging;
+use crate::sniff::{self, OnTcp, Protocol, Rewind};
+use crate::state::{Side, State};
+
+/// What the server speaks, in the wire format of ALPN.
+const PROTOCOLS: &[u8] = b"\x06chat-c\x07chat-go\x02h2\x08http/1.1";
+
+/// Takes the TLS handshakes. Cloning is cheap.
+#[derive(Clone)]
+pub struct Acceptor {
+    acceptor: SslAcceptor,
+    /// Of peers asking for no side.
+    side: Side,
+}
+
+impl Acceptor {
+    /// An acceptor with the certificate and the key of `config`.
+    pub fn new(config: &TlsConfig) -> io::Result<Acceptor> {
+        let mut builder = builder(config)?;
+        builder.set_alpn_select_callback(|_, offered| {
+            ssl::select_next_proto(PROTOCOLS, offered).ok_or(AlpnError::NOACK)
+        });
+        Ok(Acceptor {
+            acceptor: builder.build(),
+            side: config.side,
+        })
+    }
+
+    /// Take the handshake of the client at `addr` on `socket`, and hand
+    /// the connection to what serves the protocol it asked for.
+    pub fn accept(
+        &self,
+        socket: Rewind<TcpStream>,
+        addr: SocketAddr,
+        state: State,
+        config: Arc<Config>,
+    ) {
+        let side = self.side;
+        let accepted = self
+            .acceptor
+            .accept_async(socket)
+            .map(move |stream| {
+                let protocol = match stream.get_ref().ssl().selected_alpn_protocol() {
+                    Some(b"chat-c") => Protocol::Chat(Some(Side::C)),
+                    Some(b"chat-go") => Protocol::Chat(Some(Side::Go)),
+                    Some(b"h2") => Protocol::Grpc,
+                    Some(b"http/1.1") => Protocol::Http,
+                    _ => Protocol::Chat(None),
+                };
+                sniff::route(protocol, stream, addr, side, state, config)
+            })
+            .map_err(move |e| {
+                logging::info!(
+                    "tls_failed", addr = addr;
+                    "TLS handshake of {} failed: {}", addr, e
+                )
+            });
+        tokio::spawn(accepted);
+    }
+}
+
+impl OnTcp for SslStream<Rewind<TcpStream>> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref().get_ref().tcp()
+    }
+}
+
+/// An acceptor with the certificate and the key of `config`, to be told
+/// what to make of ALPN.
+pub fn builder(config: &TlsConfig) -> io::Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .map_err(|e| invalid(&config.cert, e))?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .map_err(|e| invalid(&config.key, e))?;
+    builder
+        .check_private_key()
+        .map_err(|e| invalid(&config.key, e))?;
+    Ok(builder)
+}
+
+/// `path` did not have what it should, as `e` tells.
+fn invalid(path: &Path, e: openssl::error::ErrorStack) -> io::Error {
+    let message = format!("{}: {}", path.display(), e);
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Accept TLS connections on `listener`, until another process takes over.
+pub fn serve(
+    listener: net::TcpListener,
+    acceptor: Acceptor,
+    state: State,
+    config: Arc<Config>,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::from_std(listener, &Handle::default())?;
+    let handed_over = state.drain.handed_over();
+
+    Ok(accept::Acceptor::new(listener, config.accepts_per_tick)
+        .map_err(|e| logging::warn!("accept_failed"; "tls accept error = {:?}", e))
+        .for_each(move |socket| {
+            if !state.access.admits(&socket) {
+                state.metrics.access_connections_denied.add(1);
+                return Ok(());
+            }
+            // Only fails once the client is gone.
+            if let Ok(addr) = socket.peer_addr() {
+                let socket = Rewind::new(BytesMut::new(), socket);
+                acceptor.accept(socket, addr, state.clone(), config.clone());
+            }
+            Ok(())
+        })
+        .select(handed_over)
+        .map(|_| ())
+        .map_err(|_| ()))
+}
@@ -0,0 +1,400 @@
+//! Files sent from one peer to another, negotiated like DCC.
+//!
+//! ```text
+//! /send <peer> <file> <size> [port]   offer a file to a peer of the other side
+//! /accept <id>                        accept an offer made to you
+//! /reject <id>                        turn an offer down, or take yours back
+//! ```
+//!
+//! An offer with a port is brokered: once it is accepted, the recipient is
+//! told to connect to that port at the address of the sender, and the server
+//! stays out of the transfer.
+//!
+//! Without a port, the file is relayed through the transfer listener, see
+//! `Config::transfer_listen`. Both peers get a token once the offer is
+//! accepted, and connect to the listener sending it as the first line: 32
+//! hex digits and `\r\n`. The sender then writes the file in chunks of at
+//! most 64 KiB, each prefixed with its length as 4 bytes big endian, and
+//! closes the connection after the last one. The recipient reads the same
+//! chunks. Both peers are told at every tenth of the file how far it got,
+//! and once it is complete.
+//!
+//! Files may be at most `transfers.max_bytes` long. Offers are forgotten
+//! after `transfers.offer_secs`, accepted or not.
+
+use bytes::Bytes;
+use tokio::codec::{length_delimited, FramedRead, FramedWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::accept::Acceptor;
+use crate::config::Config;
+use crate::conn::ConnId;
+use crate::locale::Message;
+use crate::logging;
+use crate::meter::human_bytes;
+use crate::names;
+use crate::profiling;
+use crate::secret;
+use crate::state::{Side, State};
+
+/// Longest chunk of a relayed file.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// A token and its line break.
+const TOKEN_LINE: usize = 34;
+
+/// How long a connection to the transfer listener gets to send its token.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer taking part in a transfer.
+#[derive(Debug, Clone)]
+pub struct Party {
+    pub side: Side,
+    pub conn: ConnId,
+    /// Where it connected from, for brokered transfers.
+    pub addr: SocketAddr,
+    pub name: String,
+}
+
+/// Which end of a relayed transfer a connection is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Sender,
+    Recipient,
+}
+
+struct Offer {
+    from: Party,
+    to: Party,
+    file: String,
+    size: u64,
+    /// Where the sender listens, for a brokered transfer.
+    port: Option<u16>,
+    expires: Instant,
+    /// The tokens of the sender and the recipient, once the offer is
+    /// accepted for relaying.
+    tokens: Option<(String, String)>,
+    /// The first of the two that connected to the transfer listener.
+    waiting: Option<(Role, TcpStream)>,
+}
+
+/// The offers between the peers.
+pub struct Transfers {
+    offers: Mutex<HashMap<u64, Offer>>,
+    next_id: AtomicU64,
+    max_bytes: u64,
+    offer_ttl: Duration,
+    /// Where files are relayed through, if they are.
+    listen: Option<SocketAddr>,
+}
+
+impl Transfers {
+    pub fn new(config: &Config) -> Transfers {
+        Transfers {
+            offers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            max_bytes: config.transfers.max_bytes,
+            offer_ttl: Duration::from_secs(config.transfers.offer_secs),
+            listen: config.transfer_listen,
+        }
+    }
+
+    /// The offers, without those that expired.
+    fn offers(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Offer>> {
+        let mut offers = self.offers.lock().unwrap();
+        let now = Instant::now();
+        offers.retain(|_, offer| offer.expires > now);
+        offers
+    }
+}
+
+/// Offer `file` of `size` bytes from `from` to the peer called `to` on the
+/// other side, returning the reply to the sender.
+pub fn offer(
+    state: &State,
+    from: Party,
+    partition: Option<usize>,
+    to: &str,
+    file: &str,
+    size: u64,
+    port: Option<u16>,
+) -> Message {
+    let transfers = &state.transfers;
+    if size > transfers.max_bytes {
+        return Message::new("transfer_too_large")
+            .with("max", human_bytes(transfers.max_bytes as f64));
+    }
+    if port.is_none() && transfers.listen.is_none() {
+        return Message::new("transfer_needs_port");
+    }
+
+    let side = from.side.other();
+    let key = names::key(to);
+    let mut recipient = None;
+    state.side(side).for_each(|conn, member| {
+        let named = names::key(&member.name) == key;
+        if named && (partition.is_none() || member.partition == partition) {
+            recipient = Some((*conn, member.addr, member.name.clone()));
+        }
+    });
+    let to = match recipient {
+        Some((conn, addr, name)) => Party {
+            side,
+            conn,
+            addr,
+            name,
+        },
+        None => {
+            return Message::new("transfer_no_peer")
+                .with("name", to)
+                .with("side", side)
+        }
+    };
+
+    let id = transfers.next_id.fetch_add(1, Ordering::Relaxed);
+    let size_text = human_bytes(size as f64);
+    let offered = Message::new("transfer_offer")
+        .with("from", &from.name)
+        .with("file", file)
+        .with("size", &size_text)
+        .with("id", id);
+    state.tell(to.side, &to.conn, &offered);
+    let reply = Message::new("transfer_offered")
+        .with("file", file)
+        .with("size", size_text)
+        .with("to", &to.name)
+        .with("id", id);
+    let offer = Offer {
+        from,
+        to,
+        file: file.to_string(),
+        size,
+        port,
+        expires: Instant::now() + transfers.offer_ttl,
+        tokens: None,
+        waiting: None,
+    };
+    transfers.offers().insert(id, offer);
+    reply
+}
+
+/// Accept offer `id` for the peer `conn`, returning the reply to it.
+pub fn accept(state: &State, id: u64, conn: &ConnId) -> Message {
+    let transfers = &state.transfers;
+    let mut offers = transfers.offers();
+    let offer = match offers.get_mut(&id) {
+        Some(offer) if offer.to.conn == *conn && offer.tokens.is_none() => offer,
+        _ => return Message::new("transfer_not_yours").with("id", id),
+    };
+
+    if let Some(port) = offer.port {
+        // Brokered, the server is done with it.
+        let offer = offers.remove(&id).unwrap();
+        let accepted = Message::new("transfer_expect")
+            .with("to", &offer.to.name)
+            .with("id", id)
+            .with("ip", offer.to.addr.ip());
+        state.tell(offer.from.side, &offer.from.conn, &accepted);
+        return Message::new("transfer_connect")
+            .with("ip", offer.from.addr.ip())
+            .with("port", port)
+            .with("file", &offer.file);
+    }
+
+    let listen = transfers.listen.expect("relayed offers need the listener");
+    let (sender, recipient) = (token(), token());
+    let accepted = Message::new("transfer_send")
+        .with("to", &offer.to.name)
+        .with("id", id)
+        .with("file", &offer.file)
+        .with("listen", listen)
+        .with("token", &sender);
+    state.tell(offer.from.side, &offer.from.conn, &accepted);
+    let reply = Message::new("transfer_receive")
+        .with("file", &offer.file)
+        .with("listen", listen)
+        .with("token", &recipient);
+    offer.tokens = Some((sender, recipient));
+    offer.expires = Instant::now() + transfers.offer_ttl;
+    reply
+}
+
+/// Reject offer `id`, or take it back, for the peer `conn`.
+pub fn reject(state: &State, id: u64, conn: &ConnId) -> Message {
+    let mut offers = state.transfers.offers();
+    let involved = matches!(offers.get(&id), Some(o) if o.from.conn == *conn || o.to.conn == *conn);
+    if !involved {
+        return Message::new("transfer_not_involved").with("id", id);
+    }
+
+    let offer = offers.remove(&id).unwrap();
+    let other = if offer.from.conn == *conn {
+        &offer.to
+    } else {
+        &offer.from
+    };
+    let called_off = Message::new("transfer_called_off").with("id", id);
+    state.tell(other.side, &other.conn, &called_off);
+    Message::new("transfer_calling_off").with("id", id)
+}
+
+/// A random token, unpredictable to other peers.
+fn token() -> String {
+    let key = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", key(), key())
+}
+
+/// Accept the connections of relayed transfers on `listener`.
+pub fn serve(
+    listener: net::TcpListener,
+    state: State,
+    per_tick: usize,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::from_std(listener, &Handle::default())?;
+    let handed_over = state.drain.handed_over();
+
+    Ok(Acceptor::new(listener, per_tick)
+        .map_err(|e| logging::warn!("accept_failed"; "transfer accept error = {:?}", e))
+        .for_each(move |socket| {
+            if !state.access.admits(&socket) {
+                state.metrics.access_connections_denied.add(1);
+                return Ok(());
+            }
+            let state = state.clone();
+            let joined = tokio::io::read_exact(socket, [0; TOKEN_LINE])
+                .timeout(TOKEN_TIMEOUT)
+                .map_err(
+                    |e| logging::warn!("transfer_token_failed"; "transfer token error = {:?}", e),
+                )
+                .and_then(move |(socket, line)| {
+                    join(&state, &line, socket);
+                    Ok(())
+                });
+            tokio::spawn(joined);
+            Ok(())
+        })
+        .select(handed_over)
+        .map(|_| ())
+        .map_err(|_| ()))
+}
+
+/// Pair `socket`, which sent the token `line`, with the other end of its
+/// transfer, and start relaying if that one is connected already.
+fn join(state: &State, line: &[u8], socket: TcpStream) {
+    let token = String::from_utf8_lossy(&line[..TOKEN_LINE - 2]);
+    let mut offers = state.transfers.offers();
+    let found = offers.iter().find_map(|(id, offer)| {
+        let (sender, recipient) = offer.tokens.as_ref()?;
+        if secret::same_token(sender, &token) {
+            Some((*id, Role::Sender))
+        } else if secret::same_token(recipient, &token) {
+            Some((*id, Role::Recipient))
+        } else {
+            None
+        }
+    });
+    let (id, role) = match found {
+        Some(found) => found,
+        None => {
+            let refused = tokio::io::write_all(socket, &b"unknown token\r\n"[..]);
+            tokio::spawn(refused.then(|_| Ok(())));
+            return;
+        }
+    };
+
+    let offer = offers.get_mut(&id).unwrap();
+    let other = match offer.waiting.take() {
+        Some((waiting, other)) if waiting != role => other,
+        // The same end again replaces the first connection.
+        _ => {
+            offer.waiting = Some((role, socket));
+            return;
+        }
+    };
+    let offer = offers.remove(&id).unwrap();
+    let (upload, download) = match role {
+        Role::Sender => (socket, other),
+        Role::Recipient => (other, socket),
+    };
+    let span = profiling::span!("transfer", id = id);
+    let relay = relay(state.clone(), id, offer, upload, download);
+    tokio::spawn(profiling::instrument(relay, span));
+}
+
+/// Copy the chunks of `upload` to `download`, telling both peers how far
+/// the file got.
+fn relay(
+    state: State,
+    id: u64,
+    offer: Offer,
+    upload: TcpStream,
+    download: TcpStream,
+) -> impl Future<Item = (), Error = ()> {
+    let codec = || {
+        length_delimited::Builder::new()
+            .max_frame_length(MAX_CHUNK)
+            .new_codec()
+    };
+    let sent = Arc::new(AtomicU64::new(0));
+    let tell = {
+        let state = state.clone();
+        let (from, to) = (offer.from.clone(), offer.to.clone());
+        move |message: Message| {
+            state.tell(from.side, &from.conn, &message);
+            state.tell(to.side, &to.conn, &message);
+        }
+    };
+
+    let (file, size) = (offer.file, offer.size);
+    let progress = {
+        let (sent, tell, file) = (sent.clone(), tell.clone(), file.clone());
+        let mut reported = 0;
+        move |chunk: bytes::BytesMut| {
+            let total = sent.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+            if total > size {
+                let message = format!("more than the {} bytes offered", size);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            let tenths = (total * 10).checked_div(size).unwrap_or(10);
+            if tenths > reported && tenths < 10 {
+                reported = tenths;
+                tell(
+                    Message::new("transfer_progress")
+                        .with("id", id)
+                        .with("file", &file)
+                        .with("percent", tenths * 10),
+                );
+            }
+            Ok(Bytes::from(chunk))
+        }
+    };
+
+    FramedRead::new(upload, codec())
+        .and_then(progress)
+        .forward(FramedWrite::new(download, codec()))
+        .then(move |result| {
+            let sent = sent.load(Ordering::Relaxed);
+            let message = match result {
+                Ok(_) if sent == size => {
+                    Message::new("transfer_done").with("size", human_bytes(size as f64))
+                }
+                Ok(_) => Message::new("transfer_ended")
+                    .with("sent", sent)
+                    .with("size", size),
+                Err(e) => Message::new("transfer_failed").with("error", e),
+            };
+            tell(message.with("id", id).with("file", &file));
+            Ok(())
+        })
+}
@@ -0,0 +1,65 @@
+//! The two versions of the protocol, spoken side by side.
+//!
+//! Version 1 is the bare lines every client spoke so far. Version 2 puts
+//! the messages of other peers in an envelope, with their id and when the
+//! server took them in:
+//!
+//! ```text
+//! v1: bob: hi
+//! v2: @id=4711;time=2019-08-05T10:14:07.311Z bob: hi
+//! ```
+//!
+//! A client speaks version 2 once it was granted `v2` with `CAP REQ`, see
+//! `cap`, and version 1 otherwise. Messages are relayed as version 1, and
+//! every peer speaking version 2 puts the envelope around them as it sends
+//! them, so peers of version 1 get what they always did. Clients can move
+//! to version 2 one at a time, and servers too: one that does not offer
+//! `v2` leaves the clients with version 1. Lines of the server itself, and
+//! the messages of the history, are the same in both.
+//!
+//! A client of version 2 may send its messages in an envelope as well. The
+//! server takes it off, and keeps none of the tags in it for now. Messages
+//! longer than a frame are split into frames both ways, see `frames`,
+//! while those of version 1 are one line each.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::logging;
+use crate::state::Delivery;
+
+/// What a client speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Version::V1
+    }
+}
+
+/// `line`, of the message of `delivery`, in an envelope.
+pub fn envelope(delivery: &Delivery, line: &[u8]) -> Bytes {
+    let tags = format!(
+        "@id={};time={} ",
+        delivery.id,
+        logging::rfc3339(delivery.ts)
+    );
+    let mut enveloped = BytesMut::with_capacity(tags.len() + line.len());
+    enveloped.put(tags);
+    enveloped.put(line);
+    enveloped.freeze()
+}
+
+/// `text` of a client of version 2 without its envelope, if it has one.
+pub fn open(mut text: BytesMut) -> BytesMut {
+    if !text.starts_with(b"@") {
+        return text;
+    }
+    if let Some(space) = text.iter().position(|&b| b == b' ') {
+        text.advance(space + 1);
+    }
+    text
+}
@@ -0,0 +1,150 @@
+//! The server checking on itself.
+//!
+//! ```json
+//! "watchdog": {
+//!     "interval_ms": 1000,
+//!     "max_timer_skew_ms": 200,
+//!     "max_queued_bytes": 268435456,
+//!     "max_lock_wait_ms": 50
+//! }
+//! ```
+//!
+//! Every `interval_ms` a task on the runtime of the peers looks at:
+//!
+//! - how late its timer fired, which grows when tasks hog the threads of
+//!   the runtime, for example by blocking;
+//! - the bytes waiting for peers, see `Stats::queued_outbound_bytes`. The
+//!   channels between peers are emptied into these buffers on the next
+//!   tick of the peer, so a backlog shows up here;
+//! - how long the locks every peer takes take to get, see
+//!   `State::lock_wait`.
+//!
+//! A check crossing its threshold is logged as a warning, and `GET /health`
+//! of the HTTP gateway answers `503` with what is wrong until it is back
+//! below:
+//!
+//! ```json
+//! {"status": "degraded", "problems": ["timer fired 840ms late, more than 200ms"]}
+//! ```
+//!
+//! Otherwise it answers `200` with `{"status": "ok"}`.
+
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::config::WatchdogConfig;
+use crate::logging;
+use crate::state::State;
+
+/// What the last checks found, shared with the gateway.
+#[derive(Default)]
+pub struct Health {
+    problems: RwLock<Vec<Problem>>,
+}
+
+impl Health {
+    /// What is wrong, nothing if the server is healthy.
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub fn problems(&self) -> Vec<String> {
+        let problems = self.problems.read().unwrap();
+        problems.iter().map(|p| p.detail.clone()).collect()
+    }
+}
+
+/// A check beyond its threshold.
+struct Problem {
+    check: &'static str,
+    detail: String,
+}
+
+/// Runs the checks, a future that runs for the lifetime of the server.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    state: State,
+    ticks: Interval,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig, state: State) -> Watchdog {
+        let interval = Duration::from_millis(config.interval_ms);
+        Watchdog {
+            config,
+            state,
+            ticks: Interval::new(Instant::now() + interval, interval),
+        }
+    }
+
+    /// Run the checks for the tick due at `due`.
+    fn check(&self, due: Instant) {
+        let millis = |d: Duration| d.as_secs() * 1000 + u64::from(d.subsec_millis());
+        let mut problems = Vec::new();
+
+        let skew = millis(Instant::now().saturating_duration_since(due));
+        if skew > self.config.max_timer_skew_ms {
+            problems.push(Problem {
+                check: "timer_skew",
+                detail: format!(
+                    "timer fired {}ms late, more than {}ms",
+                    skew, self.config.max_timer_skew_ms
+                ),
+            });
+        }
+
+        let queued = self.state.metrics.queued_outbound_bytes.get();
+        if queued > self.config.max_queued_bytes {
+            problems.push(Problem {
+                check: "queued_bytes",
+                detail: format!(
+                    "{} bytes wait for peers, more than {}",
+                    queued, self.config.max_queued_bytes
+                ),
+            });
+        }
+
+        let wait = millis(self.state.lock_wait());
+        if wait > self.config.max_lock_wait_ms {
+            problems.push(Problem {
+                check: "lock_wait",
+                detail: format!(
+                    "waited {}ms for a lock, more than {}ms",
+                    wait, self.config.max_lock_wait_ms
+                ),
+            });
+        }
+
+        // Logged when they start and stop, not on every check.
+        let mut previous = self.state.health.problems.write().unwrap();
+        let known = |problems: &[Problem], check| problems.iter().any(|p| p.check == check);
+        for problem in problems.iter().filter(|p| !known(&previous, p.check)) {
+            logging::warn!("watchdog_degraded"; "watchdog: {}", problem.detail);
+        }
+        for cleared in previous.iter().filter(|p| !known(&problems, p.check)) {
+            logging::info!(
+                "watchdog_recovered";
+                "watchdog: {} is back below its threshold", cleared.check
+            );
+        }
+        *previous = problems;
+    }
+}
+
+impl Future for Watchdog {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            match self.ticks.poll() {
+                Ok(Async::Ready(Some(due))) => self.check(due),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    logging::error!("watchdog_stopped"; "watchdog timer error = {:?}", e);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
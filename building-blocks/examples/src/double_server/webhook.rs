@@ -0,0 +1,317 @@
+//! Incoming and outgoing webhooks.
+//!
+//! Incoming hooks are Slack compatible. Each configured hook has a secret
+//! token. Posting a Slack style payload to
+//! `/hooks/<token>` broadcasts its text to the peers:
+//!
+//! ```text
+//! curl -d '{"text": "build #42 passed", "username": "ci"}' \
+//!     http://127.0.0.1:9000/hooks/<token>
+//! ```
+//!
+//! Slack's own URL shape, `/services/<team>/<bot>/<token>`, is accepted as
+//! well, so existing integrations only need the host changed. Like Slack, the
+//! payload may also be sent form encoded in a `payload` field.
+//!
+//! Outgoing hooks are the other direction: chat events matching a hook's
+//! filters are POSTed to its URL as JSON,
+//!
+//! ```json
+//! {"event": "message", "side": "c", "sender": "alice", "text": "hi", "ts": 1565000000}
+//! ```
+//!
+//! retrying with exponential backoff when the endpoint fails. Each hook has
+//! a circuit breaker, exported as `webhook_<n>` for the hook at index `n`:
+//! while a hook kept failing, its events are dropped rather than piling up
+//! in deliveries that are bound to fail, until a probe gets through.
+
+use actix_web::client::Client;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use building_blocks::breaker::{Breaker, Rejected};
+use building_blocks::retry::{retry, Backoff, Failure};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::sync::mpsc;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tokio::prelude::*;
+
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{CircuitBreakerConfig, EventKind, IncomingWebhook, OutgoingWebhook};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::secret;
+use crate::state::{ChatEvent, State};
+
+/// How long a single delivery attempt may take.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest payload accepted on a hook.
+const MAX_PAYLOAD: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct Payload {
+    text: Option<String>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Form {
+    payload: String,
+}
+
+/// The incoming hook endpoints, shared by all gateway workers.
+#[derive(Clone)]
+pub struct Incoming {
+    hooks: Arc<Vec<IncomingWebhook>>,
+    state: State,
+}
+
+impl Incoming {
+    pub fn new(hooks: Vec<IncomingWebhook>, state: State) -> Incoming {
+        Incoming {
+            hooks: Arc::new(hooks),
+            state,
+        }
+    }
+
+    /// Mount the hook endpoints.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.data(self.clone())
+            .service(
+                web::resource("/hooks/{token}")
+                    .data(web::PayloadConfig::new(MAX_PAYLOAD))
+                    .route(web::post().to(hook)),
+            )
+            .service(
+                web::resource("/services/{team}/{bot}/{token}")
+                    .data(web::PayloadConfig::new(MAX_PAYLOAD))
+                    .route(web::post().to(hook)),
+            );
+    }
+
+    /// Broadcast the text of a payload as lines from `username`.
+    fn post(&self, hook: &IncomingWebhook, payload: Payload) -> HttpResponse {
+        let text = match payload.text {
+            Some(ref text) if !text.trim().is_empty() => text,
+            _ => return HttpResponse::BadRequest().body("no_text"),
+        };
+        if self.state.read_only {
+            return HttpResponse::Conflict().body("replica_read_only");
+        }
+
+        let name = payload
+            .username
+            .as_ref()
+            .filter(|_| hook.allow_username)
+            .unwrap_or(&hook.username);
+
+        // The line protocol cannot carry line breaks, so multi-line texts
+        // (build logs, alerts) become several lines.
+        for text in text.lines() {
+            let text = unescape(text);
+            let mut line = BytesMut::with_capacity(name.len() + text.len() + 4);
+            line.put(name.as_str());
+            line.put(": ");
+            line.put(text.as_str());
+            line.put("\r\n");
+            let line = line.freeze();
+
+            match hook.side {
+                Some(side) => self.state.broadcast(side, None, None, &line),
+                None => self.state.inject(line),
+            }
+        }
+
+        HttpResponse::Ok().body("ok")
+    }
+}
+
+fn hook(incoming: web::Data<Incoming>, req: HttpRequest, body: Bytes) -> HttpResponse {
+    let token = req.match_info().get("token").unwrap_or("");
+    let hook = match incoming
+        .hooks
+        .iter()
+        .find(|hook| secret::same_token(&hook.token, token))
+    {
+        Some(hook) => hook,
+        None => return HttpResponse::NotFound().body("no_service"),
+    };
+
+    let form_encoded = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            value.starts_with("application/x-www-form-urlencoded")
+        });
+
+    let payload = if form_encoded {
+        serde_urlencoded::from_bytes::<Form>(&body)
+            .ok()
+            .and_then(|form| serde_json::from_str::<Payload>(&form.payload).ok())
+    } else {
+        serde_json::from_slice::<Payload>(&body).ok()
+    };
+
+    match payload {
+        Some(payload) => incoming.post(hook, payload),
+        None => HttpResponse::BadRequest().body("invalid_payload"),
+    }
+}
+
+/// Undo the HTML escaping Slack requires for `&`, `<` and `>`.
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Outgoing half: deliver the matching chat events to every hook.
+///
+/// Like the Matrix relay, this runs on the gateway's actix system.
+pub fn relay(
+    hooks: Vec<OutgoingWebhook>,
+    circuit_breaker: &CircuitBreakerConfig,
+    metrics: Arc<Metrics>,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+) -> impl Future<Item = (), Error = ()> {
+    let client = Client::default();
+    let hooks: Vec<(Rc<OutgoingWebhook>, Rc<String>, Breaker)> = hooks
+        .into_iter()
+        .enumerate()
+        .map(|(i, hook)| {
+            // Named after the index, the URL may carry a secret.
+            let name = format!("webhook_{}", i);
+            let breaker = circuit_breaker.breaker();
+            metrics.circuits.register(&name, &breaker);
+            (Rc::new(hook), Rc::new(name), breaker)
+        })
+        .collect();
+
+    events.for_each(move |event| {
+        let payload = Rc::new(payload(&event));
+        for (hook, name, breaker) in hooks.iter().filter(|(hook, ..)| matches(hook, &event)) {
+            if breaker.rejecting() {
+                metrics.webhook_deliveries_shed.add(1);
+                continue;
+            }
+            // Deliveries run independently, a hook that is down and being
+            // retried does not hold up the other hooks.
+            actix_rt::spawn(deliver(
+                client.clone(),
+                hook.clone(),
+                name.clone(),
+                breaker.clone(),
+                payload.clone(),
+            ));
+        }
+        Ok(())
+    })
+}
+
+/// Whether `event` passes the filters of `hook`.
+fn matches(hook: &OutgoingWebhook, event: &ChatEvent) -> bool {
+    let (kind, side, name, body) = match event {
+        ChatEvent::Joined { side, name } => (EventKind::Join, side, name, None),
+        ChatEvent::Message {
+            side, name, body, ..
+        } => (EventKind::Message, side, name, Some(body)),
+        ChatEvent::Left { side, name } => (EventKind::Leave, side, name, None),
+    };
+
+    if !hook.events.contains(&kind) {
+        return false;
+    }
+    if hook.side.map_or(false, |s| s != *side) {
+        return false;
+    }
+    if hook.sender.as_ref().map_or(false, |s| s != name) {
+        return false;
+    }
+
+    match body {
+        Some(body) if !hook.keywords.is_empty() => {
+            let body = body.to_lowercase();
+            hook.keywords
+                .iter()
+                .any(|keyword| body.contains(&keyword.to_lowercase()))
+        }
+        _ => true,
+    }
+}
+
+/// The JSON document describing `event`.
+fn payload(event: &ChatEvent) -> Value {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    match event {
+        ChatEvent::Joined { side, name } => {
+            json!({ "event": "join", "side": side.as_str(), "sender": name, "ts": ts })
+        }
+        ChatEvent::Message {
+            side, name, body, ..
+        } => json!({
+            "event": "message",
+            "side": side.as_str(),
+            "sender": name,
+            "text": body,
+            "ts": ts,
+        }),
+        ChatEvent::Left { side, name } => {
+            json!({ "event": "leave", "side": side.as_str(), "sender": name, "ts": ts })
+        }
+    }
+}
+
+/// POST `payload` to `hook`, retrying until it succeeds or the hook's
+/// attempts are used up. Attempts go through `breaker`, and fail right away
+/// while it is open. Failures are logged with `name`, never the URL.
+fn deliver(
+    client: Client,
+    hook: Rc<OutgoingWebhook>,
+    name: Rc<String>,
+    breaker: Breaker,
+    payload: Rc<Value>,
+) -> impl Future<Item = (), Error = ()> {
+    let backoff = Backoff::new(Duration::from_millis(hook.retry_delay_ms))
+        .max(Duration::from_millis(hook.max_retry_delay_ms))
+        .jitter(hook.retry_jitter)
+        .max_attempts(hook.max_attempts);
+    let attempt = move |_| {
+        breaker
+            .call(|| {
+                client
+                    .post(hook.url.as_str())
+                    .timeout(DELIVERY_TIMEOUT)
+                    .send_json(&*payload)
+                    .then(|res| match res {
+                        Ok(ref response) if response.status().is_success() => Ok(()),
+                        Ok(response) => Err(format!("status {}", response.status())),
+                        Err(e) => Err(e.to_string()),
+                    })
+            })
+            .map_err(|e| match e {
+                Rejected::Open => "circuit open".to_string(),
+                Rejected::Failed(e) => e,
+            })
+    };
+    retry(backoff, attempt, move |failure| match failure {
+        Failure::Retrying { error, delay, .. } => logging::warn!(
+            "webhook_retrying";
+            "{} failed ({}), retrying in {:?}",
+            name, error, delay
+        ),
+        Failure::DeadLetter { error, attempts } => logging::error!(
+            "webhook_dropped";
+            "{} failed ({}), giving up after {} attempts",
+            name, error, attempts
+        ),
+    })
+    .map_err(|_| ())
+}
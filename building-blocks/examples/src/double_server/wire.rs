@@ -0,0 +1,269 @@
+//! What peers send, told apart once as it is read, and what they are sent,
+//! put on the wire at the last moment.
+//!
+//! `protocol` splits what a peer sends into lines, and its `Decoder` makes
+//! `Message`s of them: the first line is the name the peer joins with, the
+//! lines after it are joined into messages (see `frames`), and a message is
+//! a command if it starts with `/` and is not an attachment, see
+//! `attachment`. Links also send lines about the bridge, and tags in front
+//! of the messages they route (see `bridge`), which the decoder takes off
+//! and keeps with the message they are in front of. An empty message, like
+//! the probe of `heartbeat`, is dropped rather than relayed. The peer then
+//! only routes what it is handed, without looking at the bytes again:
+//!
+//! ```text
+//! alice             Join("alice")
+//! hi                Chat("hi")
+//! /who              Command("/who")
+//! @origin=eu-1/42   (kept for the next message)
+//! bob: hi           Chat("bob: hi", from eu-1)
+//! @routes=eu-1:0    Control(Routes)
+//! ```
+//!
+//! The lines going out are made by `chat_line` and `server_line`.
+
+use bytes::{BufMut, BytesMut};
+
+use crate::attachment::Attachment;
+use crate::bridge::{self, Direct, Hop};
+use crate::frames::{Assembled, Reassembler};
+use crate::moderation;
+use crate::roster;
+use crate::version::{self, Version};
+
+/// What a peer sent.
+#[derive(Debug)]
+pub enum Message {
+    /// The first line, the name of the peer.
+    Join(BytesMut),
+    /// A message to relay, with the tag a link sent in front of it.
+    Chat { text: BytesMut, hop: Option<Hop> },
+    /// A line starting with `/`, answered by the server, see `commands`.
+    Command(BytesMut),
+    /// A line of a link about the bridge.
+    Control(Control),
+    /// The peer closed the connection, as its driver tells, see
+    /// `protocol`.
+    Leave,
+}
+
+/// The lines links send each other.
+#[derive(Debug)]
+pub enum Control {
+    /// A message for one peer, with the tag saying who, see `bridge`.
+    Direct(Direct, BytesMut),
+    /// The servers the link reaches, see `bridge`.
+    Routes(BytesMut),
+    /// Who is connected to the other servers, see `roster`.
+    Roster(BytesMut),
+    /// The topic, the banned and the moderators, see `moderation`.
+    Moderation(BytesMut),
+}
+
+/// A message got longer than `max_message_bytes`, and is dropped.
+pub struct TooLarge;
+
+/// Makes the `Message`s of the lines of one peer.
+pub struct Decoder {
+    frames: Reassembler,
+    named: bool,
+    link: bool,
+    version: Version,
+    /// The tag a link sent in front of the message it sends next.
+    hop: Option<Hop>,
+    /// The tag a link sent in front of a message for one peer.
+    direct: Option<Direct>,
+}
+
+impl Decoder {
+    pub fn new(max_message: usize) -> Decoder {
+        Decoder {
+            frames: Reassembler::new(max_message),
+            named: false,
+            link: false,
+            version: Version::V1,
+            hop: None,
+            direct: None,
+        }
+    }
+
+    /// Take the lines as messages from the first, for a peer named without
+    /// a line of its own, see `dial`.
+    pub fn named(&mut self) {
+        self.named = true;
+    }
+
+    /// Take the envelopes off the messages of a client of `version`, see
+    /// `version`, and the frames of one of version 2, see `frames`.
+    pub fn speak(&mut self, version: Version) {
+        self.version = version;
+        if version == Version::V2 {
+            self.frames.continuation();
+        }
+    }
+
+    /// Take the lines of the bridge from here on, once the peer logged in
+    /// as a link, and its frames.
+    pub fn link(&mut self) {
+        self.link = true;
+        self.frames.continuation();
+    }
+
+    /// Join the frames of a message, for a peer that knows of them, see
+    /// `frames`.
+    pub fn frames(&mut self) {
+        self.frames.continuation();
+    }
+
+    /// Whether the peer knows of frames, and is sent them.
+    pub fn knows_frames(&self) -> bool {
+        self.frames.continues()
+    }
+
+    /// The message `line` completes, `None` while more lines are needed.
+    pub fn decode(&mut self, line: BytesMut) -> Result<Option<Message>, TooLarge> {
+        if !self.named {
+            self.named = true;
+            return Ok(Some(Message::Join(line)));
+        }
+        let message = match self.frames.push(line) {
+            Assembled::Complete(message) => message,
+            Assembled::Incomplete => return Ok(None),
+            Assembled::TooLarge => return Err(TooLarge),
+        };
+
+        if self.link {
+            if let Some(direct) = self.direct.take() {
+                return Ok(Some(Message::Control(Control::Direct(direct, message))));
+            }
+            if let Some(hop) = Hop::parse(&message) {
+                self.hop = Some(hop);
+                return Ok(None);
+            }
+            if let Some(direct) = Direct::parse(&message) {
+                self.direct = Some(direct);
+                return Ok(None);
+            }
+            if bridge::is_routes(&message) {
+                return Ok(Some(Message::Control(Control::Routes(message))));
+            }
+            if roster::is_line(&message) {
+                return Ok(Some(Message::Control(Control::Roster(message))));
+            }
+            if moderation::is_line(&message) {
+                return Ok(Some(Message::Control(Control::Moderation(message))));
+            }
+        }
+
+        // A tag only goes with the message right after it.
+        let hop = self.hop.take();
+        let message = match self.version {
+            Version::V2 if !self.link => version::open(message),
+            _ => message,
+        };
+        // Nothing to relay, and how `heartbeat` probes the other servers.
+        if message.is_empty() {
+            return Ok(None);
+        }
+        if message.starts_with(b"/") && Attachment::parse(&message).is_none() {
+            return Ok(Some(Message::Command(message)));
+        }
+        Ok(Some(Message::Chat { text: message, hop }))
+    }
+}
+
+/// The message `text` of the peer `name`, as the peers it is relayed to
+/// get it. Those knowing of frames get it split into them as it is sent,
+/// see `frames::fit`.
+pub fn chat_line(name: &[u8], text: &[u8]) -> BytesMut {
+    let mut line = BytesMut::with_capacity(name.len() + 4 + text.len());
+    line.put(name);
+    line.put(": ");
+    line.put(text);
+    line.put("\r\n");
+    line
+}
+
+/// A line from the server itself, as opposed to a message of another peer.
+pub fn server_line(text: &str) -> BytesMut {
+    let mut line = BytesMut::with_capacity(text.len() + 4);
+    line.put("* ");
+    line.put(text);
+    line.put("\r\n");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `lines`, each without its line break, returning the messages.
+    fn decode(decoder: &mut Decoder, lines: &[&str]) -> Vec<Message> {
+        lines
+            .iter()
+            .filter_map(|line| match decoder.decode(BytesMut::from(*line)) {
+                Ok(message) => message,
+                Err(TooLarge) => panic!("{:?} is too large", line),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn idle_link_relays_no_probes() {
+        let mut decoder = Decoder::new(1024);
+        decoder.named();
+        decoder.link();
+        assert!(decode(&mut decoder, &["", "", ""]).is_empty());
+    }
+
+    #[test]
+    fn empty_lines_of_clients_are_dropped() {
+        let mut decoder = Decoder::new(1024);
+        let messages = decode(&mut decoder, &["alice", "", "hi", ""]);
+        match &messages[..] {
+            [Message::Join(name), Message::Chat { text, hop: None }] => {
+                assert_eq!(&name[..], b"alice");
+                assert_eq!(&text[..], b"hi");
+            }
+            messages => panic!("decoded {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn message_ending_with_backslash_is_not_empty() {
+        let mut decoder = Decoder::new(1024);
+        decoder.named();
+        decoder.link();
+        let lines = crate::frames::split(b"dir C:\\", 64);
+        let lines = std::str::from_utf8(&lines).unwrap();
+        let lines: Vec<&str> = lines.split_terminator("\r\n").collect();
+        match &decode(&mut decoder, &lines)[..] {
+            [Message::Chat { text, .. }] => assert_eq!(&text[..], b"dir C:\\"),
+            messages => panic!("decoded {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn v1_lines_ending_with_backslash_are_not_continued() {
+        let mut decoder = Decoder::new(1024);
+        decoder.named();
+        match &decode(&mut decoder, &["dir C:\\", "hi"])[..] {
+            [Message::Chat { text: first, .. }, Message::Chat { text: second, .. }] => {
+                assert_eq!(&first[..], b"dir C:\\");
+                assert_eq!(&second[..], b"hi");
+            }
+            messages => panic!("decoded {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn v2_frames_are_joined() {
+        let mut decoder = Decoder::new(1024);
+        decoder.named();
+        decoder.speak(Version::V2);
+        match &decode(&mut decoder, &["a long\\", " message"])[..] {
+            [Message::Chat { text, .. }] => assert_eq!(&text[..], b"a long message"),
+            messages => panic!("decoded {:?}", messages),
+        }
+    }
+}
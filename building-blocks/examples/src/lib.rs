@@ -0,0 +1,15 @@
+//! Code shared by the examples.
+
+pub mod breaker;
+pub mod crdt;
+pub mod delay_queue;
+pub mod percolator;
+pub mod pool;
+pub mod retry;
+pub mod runtime;
+pub mod scheduler;
+pub mod shaping;
+pub mod swim;
+pub mod twopc;
+pub mod wal;
+pub mod wheel;
@@ -0,0 +1,487 @@
+//! Transactions over several keys of a store that only writes one at a
+//! time, the way Percolator does it.
+//!
+//! A `Transaction` reads a snapshot of the store, as it was at its start
+//! timestamp, and buffers its writes. At `commit` it either writes all of
+//! them or none:
+//!
+//! ```ignore
+//! let db = Percolator::new(MemStorage::default());
+//! let mut txn = db.begin();
+//! let balance = db.get(&txn, b"alice")?;
+//! txn.set(b"alice", b"90");
+//! txn.set(b"bob", b"110");
+//! let commit_ts = db.commit(txn)?;
+//! ```
+//!
+//! Every key has three columns in the `Storage`, each value kept by a
+//! timestamp the `Tso` handed out:
+//!
+//! - `Data` holds the values written, by the start timestamp of their
+//!   transaction.
+//! - `Lock` holds the lock of a transaction that is committing the key.
+//! - `Write` makes a value visible: by the commit timestamp, the start
+//!   timestamp of the transaction whose value it is.
+//!
+//! Committing goes in two phases. The prewrite locks every key and writes
+//! its value, the first key being the primary, and fails if another
+//! transaction committed the key after this one started or holds a lock on
+//! it. Then the primary is committed, by writing its `Write` and taking
+//! away its lock: from that moment the transaction is committed, and the
+//! other keys follow.
+//!
+//! A transaction that stopped in between, like on a crash, leaves locks
+//! behind. A read that runs into one waits for it, by failing with
+//! `Error::Locked`, until the lock is older than `lock_ttl`. It then looks
+//! at the primary: if that was committed it commits the key too, otherwise
+//! it rolls the transaction back, leaving a rollback in `Write` so that the
+//! transaction cannot commit later.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bits of a timestamp below the milliseconds.
+const LOGICAL_BITS: u32 = 18;
+
+/// The columns every key has, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    Data,
+    Lock,
+    Write,
+}
+
+/// What the transactions are stored in. Every call changes one key of one
+/// column, the rest is up to `Percolator`.
+pub trait Storage {
+    /// The value of `key` in `column` with the highest timestamp of at most
+    /// `ts`, and that timestamp.
+    fn read(&self, column: Column, key: &[u8], ts: u64) -> Option<(u64, Vec<u8>)>;
+
+    fn write(&mut self, column: Column, key: &[u8], ts: u64, value: Vec<u8>);
+
+    fn erase(&mut self, column: Column, key: &[u8], ts: u64);
+}
+
+/// A `Storage` in memory.
+#[derive(Debug, Default)]
+pub struct MemStorage {
+    columns: HashMap<(Column, Vec<u8>), BTreeMap<u64, Vec<u8>>>,
+}
+
+impl Storage for MemStorage {
+    fn read(&self, column: Column, key: &[u8], ts: u64) -> Option<(u64, Vec<u8>)> {
+        let versions = self.columns.get(&(column, key.to_vec()))?;
+        let (ts, value) = versions.range(..=ts).next_back()?;
+        Some((*ts, value.clone()))
+    }
+
+    fn write(&mut self, column: Column, key: &[u8], ts: u64, value: Vec<u8>) {
+        let versions = self.columns.entry((column, key.to_vec())).or_default();
+        versions.insert(ts, value);
+    }
+
+    fn erase(&mut self, column: Column, key: &[u8], ts: u64) {
+        let cell = (column, key.to_vec());
+        if let Some(versions) = self.columns.get_mut(&cell) {
+            versions.remove(&ts);
+            if versions.is_empty() {
+                self.columns.remove(&cell);
+            }
+        }
+    }
+}
+
+/// Hands out timestamps, every one higher than those before: the wall clock
+/// in milliseconds, with a counter below for those of the same
+/// millisecond.
+#[derive(Debug, Default)]
+pub struct Tso {
+    last: AtomicU64,
+}
+
+impl Tso {
+    pub fn next(&self) -> u64 {
+        let physical = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| (since.as_millis() as u64) << LOGICAL_BITS)
+            .unwrap_or(0);
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop {
+            let next = physical.max(last + 1);
+            match self
+                .last
+                .compare_exchange_weak(last, next, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+/// The milliseconds of the wall clock in a timestamp.
+fn millis(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
+
+/// Why a transaction did not commit, or a read did not get through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Another transaction committed the key after this one started, or
+    /// is committing it. The transaction is rolled back.
+    Conflict(Vec<u8>),
+    /// Another transaction is committing the key. The read may be tried
+    /// again.
+    Locked(Vec<u8>),
+    /// The transaction was rolled back by a read that took it for stopped.
+    Aborted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Conflict(key) => write!(f, "conflict on {}", String::from_utf8_lossy(key)),
+            Error::Locked(key) => write!(f, "{} is locked", String::from_utf8_lossy(key)),
+            Error::Aborted => f.write_str("the transaction was rolled back"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What a `Write` says, or a lock is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Put,
+    Delete,
+    Rollback,
+}
+
+impl Kind {
+    fn byte(self) -> u8 {
+        match self {
+            Kind::Put => b'P',
+            Kind::Delete => b'D',
+            Kind::Rollback => b'R',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Kind {
+        match byte {
+            b'P' => Kind::Put,
+            b'D' => Kind::Delete,
+            _ => Kind::Rollback,
+        }
+    }
+}
+
+/// A value of the `Write` column.
+struct WriteRecord {
+    kind: Kind,
+    start_ts: u64,
+}
+
+impl WriteRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut value = vec![self.kind.byte()];
+        value.extend_from_slice(&self.start_ts.to_be_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> WriteRecord {
+        let mut start_ts = [0; 8];
+        start_ts.copy_from_slice(&value[1..9]);
+        WriteRecord {
+            kind: Kind::from_byte(value[0]),
+            start_ts: u64::from_be_bytes(start_ts),
+        }
+    }
+}
+
+/// A value of the `Lock` column, kept by the start timestamp.
+struct Lock {
+    kind: Kind,
+    primary: Vec<u8>,
+}
+
+impl Lock {
+    fn encode(&self) -> Vec<u8> {
+        let mut value = vec![self.kind.byte()];
+        value.extend_from_slice(&self.primary);
+        value
+    }
+
+    fn decode(value: &[u8]) -> Lock {
+        Lock {
+            kind: Kind::from_byte(value[0]),
+            primary: value[1..].to_vec(),
+        }
+    }
+}
+
+/// The writes of a transaction not committed yet, and what it reads.
+#[derive(Debug)]
+pub struct Transaction {
+    start_ts: u64,
+    /// `None` deletes the key. In order, the first key is the primary.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    /// The timestamp of the snapshot the transaction reads.
+    pub fn start_ts(&self) -> u64 {
+        self.start_ts
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+}
+
+/// A transaction whose keys are locked, to be committed or rolled back.
+#[derive(Debug)]
+pub struct Prepared(Transaction);
+
+/// Transactions over a `Storage`, see the module docs.
+pub struct Percolator<S> {
+    storage: Mutex<S>,
+    tso: Tso,
+    lock_ttl: Duration,
+}
+
+impl<S: Storage> Percolator<S> {
+    pub fn new(storage: S) -> Percolator<S> {
+        Percolator {
+            storage: Mutex::new(storage),
+            tso: Tso::default(),
+            lock_ttl: Duration::from_secs(3),
+        }
+    }
+
+    /// How old a lock has to be for a read to take its transaction for
+    /// stopped, 3 seconds unless set.
+    pub fn lock_ttl(mut self, lock_ttl: Duration) -> Percolator<S> {
+        self.lock_ttl = lock_ttl;
+        self
+    }
+
+    pub fn begin(&self) -> Transaction {
+        Transaction {
+            start_ts: self.tso.next(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// The value of `key` for `txn`: what it wrote itself, or what was
+    /// committed before it started.
+    pub fn get(&self, txn: &Transaction, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(write) = txn.writes.get(key) {
+            return Ok(write.clone());
+        }
+        let mut storage = self.storage.lock().unwrap();
+        if let Some((lock_ts, lock)) = storage.read(Column::Lock, key, txn.start_ts) {
+            let lock = Lock::decode(&lock);
+            self.resolve(&mut *storage, key, lock_ts, &lock)?;
+        }
+        let mut ts = txn.start_ts;
+        while let Some((commit_ts, record)) = storage.read(Column::Write, key, ts) {
+            let record = WriteRecord::decode(&record);
+            match record.kind {
+                Kind::Put => {
+                    let data = storage.read(Column::Data, key, record.start_ts);
+                    return Ok(data.map(|(_, value)| value));
+                }
+                Kind::Delete => return Ok(None),
+                Kind::Rollback if commit_ts == 0 => return Ok(None),
+                Kind::Rollback => ts = commit_ts - 1,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Commit `txn`, returning its commit timestamp. Nothing of it is
+    /// written if it fails.
+    pub fn commit(&self, txn: Transaction) -> Result<u64, Error> {
+        let prepared = self.prepare(txn)?;
+        self.commit_prepared(prepared)
+    }
+
+    /// The first phase of `commit`: lock the keys of `txn` and write their
+    /// values, or nothing if one of them cannot be. What is prepared is
+    /// then committed or rolled back, as someone else may decide, like a
+    /// coordinator of `twopc`.
+    pub fn prepare(&self, txn: Transaction) -> Result<Prepared, Error> {
+        let primary = match txn.writes.keys().next() {
+            Some(primary) => primary.clone(),
+            None => return Ok(Prepared(txn)),
+        };
+        for (n, (key, value)) in txn.writes.iter().enumerate() {
+            if let Err(e) = self.prewrite(&txn, key, value, &primary) {
+                for key in txn.writes.keys().take(n) {
+                    let mut storage = self.storage.lock().unwrap();
+                    rollback(&mut *storage, key, txn.start_ts);
+                }
+                return Err(e);
+            }
+        }
+        Ok(Prepared(txn))
+    }
+
+    /// The second phase of `commit`. Fails if a read rolled the
+    /// transaction back, once its locks were older than `lock_ttl`.
+    pub fn commit_prepared(&self, prepared: Prepared) -> Result<u64, Error> {
+        let txn = prepared.0;
+        let primary = match txn.writes.keys().next() {
+            Some(primary) => primary.clone(),
+            None => return Ok(txn.start_ts),
+        };
+        let commit_ts = self.tso.next();
+        {
+            let mut storage = self.storage.lock().unwrap();
+            // A read rolled it back, taking it for stopped.
+            match storage.read(Column::Lock, &primary, txn.start_ts) {
+                Some((ts, _)) if ts == txn.start_ts => {}
+                _ => return Err(Error::Aborted),
+            }
+            commit(
+                &mut *storage,
+                &primary,
+                txn.start_ts,
+                commit_ts,
+                &txn.writes[&primary],
+            );
+        }
+        // Committed. Reads commit what is left behind here themselves.
+        for (key, value) in txn.writes.iter().skip(1) {
+            let mut storage = self.storage.lock().unwrap();
+            commit(&mut *storage, key, txn.start_ts, commit_ts, value);
+        }
+        Ok(commit_ts)
+    }
+
+    /// Take back what `prepared` wrote.
+    pub fn rollback(&self, prepared: Prepared) {
+        let mut storage = self.storage.lock().unwrap();
+        for key in prepared.0.writes.keys() {
+            rollback(&mut *storage, key, prepared.0.start_ts);
+        }
+    }
+
+    /// Lock `key` for `txn` and write its value.
+    fn prewrite(
+        &self,
+        txn: &Transaction,
+        key: &[u8],
+        value: &Option<Vec<u8>>,
+        primary: &[u8],
+    ) -> Result<(), Error> {
+        let mut storage = self.storage.lock().unwrap();
+        if let Some((commit_ts, _)) = storage.read(Column::Write, key, u64::MAX) {
+            if commit_ts >= txn.start_ts {
+                return Err(Error::Conflict(key.to_vec()));
+            }
+        }
+        if storage.read(Column::Lock, key, u64::MAX).is_some() {
+            return Err(Error::Conflict(key.to_vec()));
+        }
+        let kind = match value {
+            Some(value) => {
+                storage.write(Column::Data, key, txn.start_ts, value.clone());
+                Kind::Put
+            }
+            None => Kind::Delete,
+        };
+        let lock = Lock {
+            kind,
+            primary: primary.to_vec(),
+        };
+        storage.write(Column::Lock, key, txn.start_ts, lock.encode());
+        Ok(())
+    }
+
+    /// Get the lock of the transaction started at `lock_ts` on `key` out
+    /// of the way, if that transaction stopped.
+    fn resolve(&self, storage: &mut S, key: &[u8], lock_ts: u64, lock: &Lock) -> Result<(), Error> {
+        if let Some(commit_ts) = committed(storage, &lock.primary, lock_ts) {
+            let value = storage.read(Column::Data, key, lock_ts);
+            let value = match lock.kind {
+                Kind::Put => value.map(|(_, value)| value),
+                _ => None,
+            };
+            commit(storage, key, lock_ts, commit_ts, &value);
+            return Ok(());
+        }
+        let primary_locked = match storage.read(Column::Lock, &lock.primary, lock_ts) {
+            Some((ts, _)) => ts == lock_ts,
+            None => false,
+        };
+        let age = millis(self.tso.next()).saturating_sub(millis(lock_ts));
+        if primary_locked && age < self.lock_ttl.as_millis() as u64 {
+            return Err(Error::Locked(key.to_vec()));
+        }
+        // Stopped, or rolled back already: so is this key.
+        if primary_locked {
+            rollback(storage, &lock.primary, lock_ts);
+        }
+        rollback(storage, key, lock_ts);
+        Ok(())
+    }
+}
+
+/// The commit timestamp of the transaction started at `start_ts`, if it
+/// committed `key`.
+fn committed<S: Storage>(storage: &S, key: &[u8], start_ts: u64) -> Option<u64> {
+    let mut ts = u64::MAX;
+    while let Some((commit_ts, record)) = storage.read(Column::Write, key, ts) {
+        if commit_ts < start_ts {
+            return None;
+        }
+        let record = WriteRecord::decode(&record);
+        if record.start_ts == start_ts && record.kind != Kind::Rollback {
+            return Some(commit_ts);
+        }
+        if commit_ts == 0 {
+            return None;
+        }
+        ts = commit_ts - 1;
+    }
+    None
+}
+
+/// Make the value the transaction started at `start_ts` wrote to `key`
+/// visible from `commit_ts` on, and take its lock away.
+fn commit<S: Storage>(
+    storage: &mut S,
+    key: &[u8],
+    start_ts: u64,
+    commit_ts: u64,
+    value: &Option<Vec<u8>>,
+) {
+    let kind = match value {
+        Some(_) => Kind::Put,
+        None => Kind::Delete,
+    };
+    let record = WriteRecord { kind, start_ts };
+    storage.write(Column::Write, key, commit_ts, record.encode());
+    storage.erase(Column::Lock, key, start_ts);
+}
+
+/// Take away what the transaction started at `start_ts` wrote to `key`,
+/// and keep it from committing it later.
+fn rollback<S: Storage>(storage: &mut S, key: &[u8], start_ts: u64) {
+    storage.erase(Column::Lock, key, start_ts);
+    storage.erase(Column::Data, key, start_ts);
+    let record = WriteRecord {
+        kind: Kind::Rollback,
+        start_ts,
+    };
+    storage.write(Column::Write, key, start_ts, record.encode());
+}
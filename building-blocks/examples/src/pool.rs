@@ -0,0 +1,407 @@
+//! Reusing outbound connections.
+//!
+//! A `Pool` hands out connections to targets, opening them with its
+//! `connect` function, and takes them back when they are dropped, for the
+//! next `get` of the same target:
+//!
+//! ```ignore
+//! let pool = Pool::new(|addr: &SocketAddr| Box::new(TcpStream::connect(addr)) as Connecting<_>)
+//!     .max_per_target(4)
+//!     .max_total(32)
+//!     .idle_timeout(Duration::from_secs(60))
+//!     .check(pool::tcp_alive);
+//! tokio::spawn(pool.reap().map_err(|e| eprintln!("pool timer error = {:?}", e)));
+//!
+//! let request = pool
+//!     .get(upstream)
+//!     .and_then(|conn| tokio::io::write_all(conn, b"PING\r\n"));
+//! ```
+//!
+//! The connections can be anything `connect` makes, TLS streams as well as
+//! plain TCP ones. A connection that broke while it was used should be
+//! `discard`ed rather than dropped, so that it is not handed out again.
+//!
+//! There are at most `max_per_target` connections to a target, and
+//! `max_total` to all of them, counting those in use, idle and being
+//! opened. A `get` beyond these waits for a connection to come back, except
+//! that an idle connection of another target is closed to make room.
+//!
+//! Idle connections are checked before they are handed out again, and
+//! closed once idle for `idle_timeout`: by `get`, and by `reap` for targets
+//! no longer asked for.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::{self, Interval};
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The future of opening a connection.
+pub type Connecting<C> = Box<dyn Future<Item = C, Error = io::Error> + Send>;
+
+type Connect<K, C> = dyn Fn(&K) -> Connecting<C> + Send + Sync;
+
+/// The connections, shared by every clone.
+pub struct Pool<K, C> {
+    inner: Arc<Mutex<Inner<K, C>>>,
+    connect: Arc<Connect<K, C>>,
+    check: Arc<dyn Fn(&mut C) -> bool + Send + Sync>,
+    max_per_target: usize,
+    max_total: usize,
+    idle_timeout: Duration,
+}
+
+impl<K, C> Clone for Pool<K, C> {
+    fn clone(&self) -> Self {
+        Pool {
+            inner: self.inner.clone(),
+            connect: self.connect.clone(),
+            check: self.check.clone(),
+            max_per_target: self.max_per_target,
+            max_total: self.max_total,
+            idle_timeout: self.idle_timeout,
+        }
+    }
+}
+
+struct Inner<K, C> {
+    /// The targets with connections open or being opened.
+    targets: HashMap<K, Target<C>>,
+    /// Connections open or being opened, of all targets.
+    open: usize,
+    /// The `get`s waiting for a connection to come back or be closed.
+    waiting: Vec<Task>,
+}
+
+struct Target<C> {
+    /// Idle connections, the most recently used last.
+    idle: Vec<Idle<C>>,
+    /// Connections in use, idle and being opened.
+    open: usize,
+}
+
+struct Idle<C> {
+    conn: C,
+    since: Instant,
+}
+
+impl<K, C> Inner<K, C> {
+    /// Wake up the `get`s waiting, a connection may be had now.
+    fn wake(&mut self) {
+        for task in self.waiting.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C> Pool<K, C> {
+    /// A pool opening connections with `connect`, at most 8 per target and
+    /// 64 in all, closing them after 90 seconds idle, without checking them.
+    pub fn new<F>(connect: F) -> Pool<K, C>
+    where
+        F: Fn(&K) -> Connecting<C> + Send + Sync + 'static,
+    {
+        Pool {
+            inner: Arc::new(Mutex::new(Inner {
+                targets: HashMap::new(),
+                open: 0,
+                waiting: Vec::new(),
+            })),
+            connect: Arc::new(connect),
+            check: Arc::new(|_: &mut C| true),
+            max_per_target: 8,
+            max_total: 64,
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+
+    /// Connections to one target, at least one.
+    pub fn max_per_target(mut self, max: usize) -> Pool<K, C> {
+        self.max_per_target = max.max(1);
+        self
+    }
+
+    /// Connections to all targets, at least one.
+    pub fn max_total(mut self, max: usize) -> Pool<K, C> {
+        self.max_total = max.max(1);
+        self
+    }
+
+    /// How long a connection may be idle before it is closed.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Pool<K, C> {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Hand out an idle connection only if `check` says it is healthy, and
+    /// close it otherwise. It is called on the task of the `get`.
+    pub fn check<F>(mut self, check: F) -> Pool<K, C>
+    where
+        F: Fn(&mut C) -> bool + Send + Sync + 'static,
+    {
+        self.check = Arc::new(check);
+        self
+    }
+
+    /// A connection to `target`, idle or new.
+    pub fn get(&self, target: K) -> Checkout<K, C> {
+        Checkout {
+            pool: self.clone(),
+            target,
+            connecting: None,
+        }
+    }
+
+    /// Connections open or being opened, of all targets.
+    pub fn open(&self) -> usize {
+        self.inner.lock().unwrap().open
+    }
+
+    /// Connections waiting to be used again.
+    pub fn idle(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.targets.values().map(|target| target.idle.len()).sum()
+    }
+
+    /// Close the connections idle for `idle_timeout`, every `idle_timeout`,
+    /// on the runtime it is spawned on. It fails if the timer does.
+    pub fn reap(&self) -> impl Future<Item = (), Error = timer::Error> {
+        let pool = self.clone();
+        let period = self.idle_timeout.max(Duration::from_secs(1));
+        Interval::new_interval(period).for_each(move |_| {
+            pool.close_expired();
+            Ok(())
+        })
+    }
+
+    fn close_expired(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let now = Instant::now();
+        let mut closed = 0;
+        for target in inner.targets.values_mut() {
+            let before = target.idle.len();
+            target
+                .idle
+                .retain(|idle| now.duration_since(idle.since) < self.idle_timeout);
+            target.open -= before - target.idle.len();
+            closed += before - target.idle.len();
+        }
+        inner.targets.retain(|_, target| target.open > 0);
+        inner.open -= closed;
+        if closed > 0 {
+            inner.wake();
+        }
+    }
+
+    /// Take an idle connection to `key`, or make room to open one. `None`
+    /// when the `get` has to wait.
+    fn checkout(&self, key: &K) -> Option<Reserved<C>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let now = Instant::now();
+        let target = inner.targets.entry(key.clone()).or_insert_with(|| Target {
+            idle: Vec::new(),
+            open: 0,
+        });
+        while let Some(mut idle) = target.idle.pop() {
+            if now.duration_since(idle.since) < self.idle_timeout && (self.check)(&mut idle.conn) {
+                return Some(Reserved::Idle(idle.conn));
+            }
+            target.open -= 1;
+            inner.open -= 1;
+        }
+        if target.open >= self.max_per_target {
+            inner.waiting.push(task::current());
+            return None;
+        }
+
+        if inner.open >= self.max_total {
+            // Close the connection idle the longest, of any target.
+            let oldest = inner
+                .targets
+                .iter()
+                .filter_map(|(key, target)| target.idle.first().map(|idle| (idle.since, key)))
+                .min_by_key(|&(since, _)| since)
+                .map(|(_, key)| key.clone());
+            match oldest {
+                Some(oldest) => {
+                    let target = inner.targets.get_mut(&oldest).unwrap();
+                    target.idle.remove(0);
+                    target.open -= 1;
+                    inner.open -= 1;
+                }
+                None => {
+                    inner.waiting.push(task::current());
+                    return None;
+                }
+            }
+        }
+
+        inner.targets.get_mut(key).unwrap().open += 1;
+        inner.open += 1;
+        Some(Reserved::Connect)
+    }
+
+    /// Give back a connection to `key`, or with `None` count it closed.
+    fn checkin(&self, key: &K, conn: Option<C>) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        if let Some(target) = inner.targets.get_mut(key) {
+            match conn {
+                Some(conn) => target.idle.push(Idle {
+                    conn,
+                    since: Instant::now(),
+                }),
+                None => {
+                    target.open -= 1;
+                    inner.open -= 1;
+                    if target.open == 0 {
+                        inner.targets.remove(key);
+                    }
+                }
+            }
+        }
+        inner.wake();
+    }
+}
+
+/// What `Pool::checkout` got.
+enum Reserved<C> {
+    Idle(C),
+    /// Room for a new connection.
+    Connect,
+}
+
+/// The future of `Pool::get`. It fails if opening the connection does.
+pub struct Checkout<K: Hash + Eq + Clone, C> {
+    pool: Pool<K, C>,
+    target: K,
+    /// The connection being opened, counted in the pool.
+    connecting: Option<Connecting<C>>,
+}
+
+impl<K: Hash + Eq + Clone, C> Future for Checkout<K, C> {
+    type Item = Pooled<K, C>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Pooled<K, C>, io::Error> {
+        loop {
+            if let Some(connecting) = &mut self.connecting {
+                let conn = match connecting.poll() {
+                    Ok(Async::Ready(conn)) => conn,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        self.connecting = None;
+                        self.pool.checkin(&self.target, None);
+                        return Err(e);
+                    }
+                };
+                self.connecting = None;
+                return Ok(Async::Ready(self.pooled(conn)));
+            }
+
+            match self.pool.checkout(&self.target) {
+                Some(Reserved::Idle(conn)) => return Ok(Async::Ready(self.pooled(conn))),
+                Some(Reserved::Connect) => {
+                    self.connecting = Some((self.pool.connect)(&self.target));
+                }
+                None => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C> Checkout<K, C> {
+    fn pooled(&self, conn: C) -> Pooled<K, C> {
+        Pooled {
+            pool: self.pool.clone(),
+            target: self.target.clone(),
+            conn: Some(conn),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C> Drop for Checkout<K, C> {
+    fn drop(&mut self) {
+        if self.connecting.is_some() {
+            self.pool.checkin(&self.target, None);
+        }
+    }
+}
+
+/// A connection of a `Pool`, given back to it when dropped.
+pub struct Pooled<K: Hash + Eq + Clone, C> {
+    pool: Pool<K, C>,
+    target: K,
+    /// `None` once it was discarded.
+    conn: Option<C>,
+}
+
+impl<K: Hash + Eq + Clone, C> Pooled<K, C> {
+    /// Close the connection instead of giving it back, after it broke.
+    pub fn discard(mut self) {
+        self.conn = None;
+        self.pool.checkin(&self.target, None);
+    }
+}
+
+impl<K: Hash + Eq + Clone, C> Drop for Pooled<K, C> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.checkin(&self.target, Some(conn));
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C> Deref for Pooled<K, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<K: Hash + Eq + Clone, C> DerefMut for Pooled<K, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Read> Read for Pooled<K, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buf)
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Write> Write for Pooled<K, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: AsyncRead> AsyncRead for Pooled<K, C> {}
+
+impl<K: Hash + Eq + Clone, C: AsyncWrite> AsyncWrite for Pooled<K, C> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        (**self).shutdown()
+    }
+}
+
+/// A `check` for TCP connections: an idle one is healthy if the other end
+/// neither closed it nor sent anything since.
+pub fn tcp_alive(stream: &mut TcpStream) -> bool {
+    let mut byte = [0; 1];
+    matches!(stream.poll_peek(&mut byte), Ok(Async::NotReady))
+}
@@ -0,0 +1,203 @@
+//! Retrying what fails, waiting longer after every failure.
+//!
+//! A `Backoff` says how long to wait after a failure and when to give up.
+//! `retry` runs an operation until it succeeds or the backoff gives up,
+//! telling `on_failure` of every failure, the last of them the dead letter:
+//!
+//! ```ignore
+//! let backoff = Backoff::new(Duration::from_millis(500))
+//!     .max(Duration::from_secs(60))
+//!     .jitter(0.2)
+//!     .max_attempts(5);
+//! let delivery = retry(backoff, move |_attempt| post(&url, &body), |failure| match failure {
+//!     Failure::Retrying { error, delay, .. } => warn!("{}, retrying in {:?}", error, delay),
+//!     Failure::DeadLetter { error, attempts } => error!("{}, gave up after {}", error, attempts),
+//! });
+//! ```
+//!
+//! The waits are the first delay, then twice that, four times, and so on up
+//! to `max`. With jitter, each is cut by up to that fraction, picked at
+//! random, so that clients that failed together do not all retry together.
+//!
+//! Code polling its own state machine, like a connection that reconnects,
+//! uses `Backoff::delay` and `Backoff::gives_up` directly.
+//!
+//! Nothing needs to be `Send`, so operations on the actix runtime can be
+//! retried as well. The waits are on tokio, or on another `Runtime` with
+//! `retry_on`.
+
+use futures::{Async, Future, IntoFuture, Poll};
+
+use std::time::{Duration, Instant};
+
+use crate::runtime::{Runtime, Tokio};
+use crate::scheduler::random_below;
+
+/// How long to wait between attempts, and how many to make.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+    max_attempts: u32,
+}
+
+impl Backoff {
+    /// Waiting `initial` after the first failure, up to a minute, without
+    /// jitter, and never giving up.
+    pub fn new(initial: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max: Duration::from_secs(60),
+            jitter: 0.0,
+            max_attempts: u32::MAX,
+        }
+    }
+
+    /// The longest wait.
+    pub fn max(mut self, max: Duration) -> Backoff {
+        self.max = max;
+        self
+    }
+
+    /// The fraction of every wait, from 0 to 1, that may be cut off.
+    pub fn jitter(mut self, jitter: f64) -> Backoff {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Attempts made in all before giving up, at least one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Backoff {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// How long to wait after `failures` failures in a row, at least one.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let doubled = self
+            .initial
+            .checked_mul(1 << (failures.clamp(1, 32) - 1))
+            .map_or(self.max, |delay| delay.min(self.max));
+        doubled - random_below(doubled.mul_f64(self.jitter))
+    }
+
+    /// Whether to give up after `attempts` attempts.
+    pub fn gives_up(&self, attempts: u32) -> bool {
+        attempts >= self.max_attempts
+    }
+}
+
+/// A failed attempt of `retry`.
+#[derive(Debug)]
+pub enum Failure<'a, E> {
+    /// Attempt `attempt` failed, the next is made after `delay`.
+    Retrying {
+        error: &'a E,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// The last attempt failed, the operation is given up.
+    DeadLetter { error: &'a E, attempts: u32 },
+}
+
+/// Run `operation` until it succeeds, or fail with its last error once
+/// `backoff` gives up. `operation` gets the number of the attempt, from 1.
+pub fn retry<O, F, L>(backoff: Backoff, operation: O, on_failure: L) -> Retry<O, F::Future, L>
+where
+    O: FnMut(u32) -> F,
+    F: IntoFuture,
+    L: FnMut(Failure<'_, F::Error>),
+{
+    retry_on(Tokio, backoff, operation, on_failure)
+}
+
+/// Like `retry`, waiting on `runtime`.
+pub fn retry_on<R, O, F, L>(
+    runtime: R,
+    backoff: Backoff,
+    operation: O,
+    on_failure: L,
+) -> Retry<O, F::Future, L, R>
+where
+    R: Runtime,
+    O: FnMut(u32) -> F,
+    F: IntoFuture,
+    L: FnMut(Failure<'_, F::Error>),
+{
+    Retry {
+        runtime,
+        backoff,
+        operation,
+        on_failure,
+        attempt: 0,
+        state: State::Waiting(None),
+    }
+}
+
+/// The future of `retry`.
+pub struct Retry<O, F, L, R: Runtime = Tokio> {
+    runtime: R,
+    backoff: Backoff,
+    operation: O,
+    on_failure: L,
+    /// The attempts made so far.
+    attempt: u32,
+    state: State<F, R::Sleep>,
+}
+
+enum State<F, S> {
+    Running(F),
+    /// Before the next attempt, `None` for right away.
+    Waiting(Option<S>),
+}
+
+impl<O, I, F, L, R> Future for Retry<O, F, L, R>
+where
+    O: FnMut(u32) -> I,
+    I: IntoFuture<Future = F, Item = F::Item, Error = F::Error>,
+    F: Future,
+    L: FnMut(Failure<'_, F::Error>),
+    R: Runtime,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        loop {
+            let next = match &mut self.state {
+                State::Waiting(delay) => {
+                    if let Some(delay) = delay {
+                        // A broken timer only means the attempt is early.
+                        if let Ok(Async::NotReady) = delay.poll() {
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                    self.attempt += 1;
+                    State::Running((self.operation)(self.attempt).into_future())
+                }
+                State::Running(future) => match future.poll() {
+                    Ok(Async::Ready(item)) => return Ok(Async::Ready(item)),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(error) => {
+                        if self.backoff.gives_up(self.attempt) {
+                            (self.on_failure)(Failure::DeadLetter {
+                                error: &error,
+                                attempts: self.attempt,
+                            });
+                            return Err(error);
+                        }
+                        let delay = self.backoff.delay(self.attempt);
+                        (self.on_failure)(Failure::Retrying {
+                            error: &error,
+                            attempt: self.attempt,
+                            delay,
+                        });
+                        let deadline = Instant::now() + delay;
+                        State::Waiting(Some(self.runtime.sleep_until(deadline)))
+                    }
+                },
+            };
+            self.state = next;
+        }
+    }
+}
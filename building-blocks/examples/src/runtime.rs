@@ -0,0 +1,182 @@
+//! What the building blocks need of an executor, so that they run on more
+//! than one.
+//!
+//! A `Runtime` spawns futures, sleeps, and listens for TCP connections.
+//! `Tokio` is always there. `AsyncStd` and `Smol` come with the
+//! `async-std` and `smol` features:
+//!
+//! ```ignore
+//! // cargo run --features smol ...
+//! let runtime = Smol;
+//! let backoff = Backoff::new(Duration::from_millis(500));
+//! runtime.spawn(retry_on(runtime, backoff, |_| connect(), |_| ()).then(|_| Ok(())));
+//! ```
+//!
+//! The futures are those of futures 0.1, like everywhere else here. On
+//! async-std and smol they run through the compatibility layer of futures
+//! 0.3, and the streams accepted are wrapped so that they are the
+//! `AsyncRead` and `AsyncWrite` of tokio. A future using the reactor or
+//! the timer of tokio itself, like a `tokio::net::TcpStream` or a `Delay`,
+//! still needs tokio: a block takes what it waits on from its runtime
+//! instead, like `retry_on` does.
+
+use futures::{Future, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// An executor, with its timer and its sockets.
+pub trait Runtime: Clone + Send + Sync + 'static {
+    /// Resolves at a deadline.
+    type Sleep: Future<Item = (), Error = io::Error> + Send + 'static;
+    /// A connection accepted.
+    type Connection: AsyncRead + AsyncWrite + Send + 'static;
+    /// The connections of a listener as they are accepted.
+    type Incoming: Stream<Item = Self::Connection, Error = io::Error> + Send + 'static;
+
+    /// Run `future` in the background.
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static;
+
+    /// Resolve at `deadline`, right away if it passed.
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep;
+
+    /// Accept connections on `addr`.
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Self::Incoming>;
+}
+
+/// The runtime of tokio 0.1, which has to be running.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tokio;
+
+impl Runtime for Tokio {
+    type Sleep = Box<dyn Future<Item = (), Error = io::Error> + Send>;
+    type Connection = tokio::net::TcpStream;
+    type Incoming = tokio::net::tcp::Incoming;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        tokio::spawn(future);
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep {
+        let delay = tokio::timer::Delay::new(deadline);
+        Box::new(delay.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Self::Incoming> {
+        Ok(tokio::net::TcpListener::bind(addr)?.incoming())
+    }
+}
+
+/// The runtime of async-std, with the `async-std` feature.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStd {
+    type Sleep = compat::Sleep;
+    type Connection = futures03::compat::Compat<async_std::net::TcpStream>;
+    type Incoming = compat::Incoming<Self::Connection>;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        async_std::task::spawn(compat::run(future));
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        compat::sleep(async_std::task::sleep(wait))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Self::Incoming> {
+        let listener = async_std::net::TcpListener::from(std::net::TcpListener::bind(addr)?);
+        Ok(compat::incoming(listener, |listener| async move {
+            let (stream, _) = listener.accept().await?;
+            Ok(futures03::compat::Compat::new(stream))
+        }))
+    }
+}
+
+/// The runtime of smol, with the `smol` feature.
+#[cfg(feature = "smol")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Runtime for Smol {
+    type Sleep = compat::Sleep;
+    type Connection = futures03::compat::Compat<smol::Async<std::net::TcpStream>>;
+    type Incoming = compat::Incoming<Self::Connection>;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        smol::spawn(compat::run(future)).detach();
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep {
+        compat::sleep(smol::Timer::at(deadline))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Self::Incoming> {
+        let listener = smol::Async::new(std::net::TcpListener::bind(addr)?)?;
+        Ok(compat::incoming(listener, |listener| async move {
+            let (stream, _) = listener.accept().await?;
+            Ok(futures03::compat::Compat::new(stream))
+        }))
+    }
+}
+
+/// Futures of std as those of futures 0.1, and back, for the runtimes
+/// built on the former.
+#[cfg(any(feature = "async-std", feature = "smol"))]
+mod compat {
+    use futures::Future;
+    use futures03::compat::{Compat, Future01CompatExt};
+    use futures03::{FutureExt, StreamExt};
+
+    use std::future::Future as StdFuture;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    pub type Sleep = Compat<Pin<Box<dyn StdFuture<Output = io::Result<()>> + Send>>>;
+
+    pub type Incoming<S> = Compat<Pin<Box<dyn futures03::Stream<Item = io::Result<S>> + Send>>>;
+
+    /// `future` as a future of std, for spawning.
+    pub fn run<F>(future: F) -> impl StdFuture<Output = ()> + Send
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        future.compat().map(|_| ())
+    }
+
+    /// `timer` as a `Sleep`.
+    pub fn sleep<T: StdFuture + Send + 'static>(timer: T) -> Sleep {
+        Compat::new(Box::pin(timer.map(|_| Ok(()))))
+    }
+
+    /// What `accept` makes of `listener`, over and over, as a stream.
+    pub fn incoming<L, A, F, S>(listener: L, accept: A) -> Incoming<S>
+    where
+        L: Send + Sync + 'static,
+        A: Fn(Arc<L>) -> F + Send + 'static,
+        F: StdFuture<Output = io::Result<S>> + Send + 'static,
+        S: 'static,
+    {
+        let listener = Arc::new(listener);
+        let accepted = futures03::stream::repeat(()).then(move |()| accept(listener.clone()));
+        Compat::new(Box::pin(accepted))
+    }
+}
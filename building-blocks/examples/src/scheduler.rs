@@ -0,0 +1,318 @@
+//! Running futures on a cron schedule.
+//!
+//! A `Scheduler` holds jobs, each a function making the future of one run,
+//! and spawns that future on the runtime whenever the `Cron` of the job
+//! matches the current minute:
+//!
+//! ```ignore
+//! let scheduler = Scheduler::new();
+//! // Every night at 3, within ten minutes, and not while the last one runs.
+//! let job = Job::new(Cron::parse("0 3 * * *")?, || Box::new(compact()))
+//!     .overlap(Overlap::Skip)
+//!     .jitter(Duration::from_secs(600));
+//! let id = scheduler.add(job);
+//! tokio::spawn(scheduler.run());
+//! ```
+//!
+//! Cron expressions have the five fields of crontab, in UTC: minute, hour,
+//! day of the month, month and day of the week (0 or 7 is Sunday). A field
+//! is `*`, a number, a range like `1-5`, any of these with a step like
+//! `*/15`, or a list of them like `0,30`. Like with cron, when both days are
+//! restricted either matching is enough.
+//!
+//! The minute `run` starts in is not run, and minutes missed while the
+//! clock jumped are not made up for. Jobs can be added and removed while it
+//! runs.
+
+use futures::{future, Future, Stream};
+use tokio::timer::{Delay, Interval};
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the clock is looked at, well within a minute.
+const TICK: Duration = Duration::from_secs(1);
+
+/// When a job runs, see the module docs.
+#[derive(Debug, Clone)]
+pub struct Cron {
+    /// Every allowed value of a field is a bit.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the days of the month or of the week are `*`.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron {:?} does not have five fields", text));
+        }
+        let field = |index: usize, min: u32, max: u32| {
+            parse_field(fields[index], min, max)
+                .ok_or_else(|| format!("cron {:?} has a bad field {:?}", text, fields[index]))
+        };
+        let mut weekdays = field(4, 0, 7)?;
+        // Sunday is 0 or 7.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Whether the minute `minute` since the epoch matches.
+    pub fn matches(&self, minute: u64) -> bool {
+        let time = Time::of(minute);
+        let bit = |set: u64, value: u32| set & (1 << value) != 0;
+        let day = bit(self.days, time.day);
+        let weekday = bit(self.weekdays, time.weekday);
+        let days = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        bit(self.minutes, time.minute)
+            && bit(self.hours, time.hour)
+            && bit(self.months, time.month)
+            && days
+    }
+}
+
+/// The values of a field from `min` to `max`, as bits.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let mut split = part.splitn(2, '/');
+        let range = split.next()?;
+        let step = match split.next() {
+            Some(step) => step.parse().ok().filter(|&step| step > 0)?,
+            None => 1,
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some(dash) = range.find('-') {
+            (range[..dash].parse().ok()?, range[dash + 1..].parse().ok()?)
+        } else {
+            let first = range.parse().ok()?;
+            // `5/10` runs from 5 on.
+            (first, if step > 1 { max } else { first })
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        for value in (first..=last).step_by(step) {
+            set |= 1 << value;
+        }
+    }
+    Some(set)
+}
+
+/// A minute in UTC.
+struct Time {
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    /// 0 is Sunday.
+    weekday: u32,
+}
+
+impl Time {
+    fn of(minute: u64) -> Time {
+        let days = (minute / 1440) as i64;
+        // The civil date of `days` since the epoch, after Howard Hinnant's
+        // `civil_from_days`.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        Time {
+            minute: (minute % 60) as u32,
+            hour: (minute / 60 % 24) as u32,
+            day,
+            month,
+            // The epoch was a Thursday.
+            weekday: ((days + 4) % 7) as u32,
+        }
+    }
+}
+
+/// The minutes since the epoch.
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 60)
+        .unwrap_or(0)
+}
+
+/// What happens when a job is due while its last run has not finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlap {
+    /// The job is not run this time.
+    Skip,
+    /// The job runs again alongside.
+    Allow,
+}
+
+/// The future of one run of a job.
+pub type Run = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// A function run on a schedule.
+pub struct Job {
+    cron: Cron,
+    task: Box<dyn FnMut() -> Run + Send>,
+    overlap: Overlap,
+    jitter: Duration,
+    /// Runs that did not finish yet.
+    running: Arc<AtomicUsize>,
+}
+
+impl Job {
+    /// A job running the future of `task` when `cron` matches, skipping a
+    /// run while the last one goes on, without jitter.
+    pub fn new<F: FnMut() -> Run + Send + 'static>(cron: Cron, task: F) -> Job {
+        Job {
+            cron,
+            task: Box::new(task),
+            overlap: Overlap::Skip,
+            jitter: Duration::from_secs(0),
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn overlap(mut self, overlap: Overlap) -> Job {
+        self.overlap = overlap;
+        self
+    }
+
+    /// Start every run up to `jitter` late, picked at random, so that
+    /// servers on the same schedule do not all run at once.
+    pub fn jitter(mut self, jitter: Duration) -> Job {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The future of a run, unless it is skipped.
+    fn start(&mut self) -> Option<Run> {
+        if self.overlap == Overlap::Skip && self.running.load(Ordering::Acquire) > 0 {
+            return None;
+        }
+        self.running.fetch_add(1, Ordering::AcqRel);
+        let running = self.running.clone();
+        let run = (self.task)();
+        let delay = random_below(self.jitter);
+        let delayed: Run = if delay > Duration::from_secs(0) {
+            Box::new(Delay::new(Instant::now() + delay).then(|_| run))
+        } else {
+            run
+        };
+        Some(Box::new(delayed.then(move |result| {
+            running.fetch_sub(1, Ordering::AcqRel);
+            result
+        })))
+    }
+}
+
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("cron", &self.cron)
+            .field("overlap", &self.overlap)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+/// A random duration below `limit`.
+pub(crate) fn random_below(limit: Duration) -> Duration {
+    let millis = limit.as_secs() * 1000 + u64::from(limit.subsec_millis());
+    if millis == 0 {
+        return limit;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % millis)
+}
+
+/// The jobs, shared by every clone.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: BTreeMap<u64, Job>,
+    next_id: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// Add `job`, returning its id to remove it with.
+    pub fn add(&self, job: Job) -> u64 {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.next_id += 1;
+        let id = jobs.next_id;
+        jobs.jobs.insert(id, job);
+        id
+    }
+
+    /// Remove job `id`, returning whether there was one. A run that started
+    /// goes on.
+    pub fn remove(&self, id: u64) -> bool {
+        self.jobs.lock().unwrap().jobs.remove(&id).is_some()
+    }
+
+    /// Runs the jobs when they are due, on the runtime it is spawned on. It
+    /// fails if the timer does.
+    pub fn run(&self) -> impl Future<Item = (), Error = tokio::timer::Error> {
+        let scheduler = self.clone();
+        let mut last = current_minute();
+        Interval::new(Instant::now() + TICK, TICK).for_each(move |_| {
+            let minute = current_minute();
+            if minute != last {
+                last = minute;
+                scheduler.start(minute);
+            }
+            future::ok(())
+        })
+    }
+
+    /// Spawn the runs of the jobs due at `minute`.
+    fn start(&self, minute: u64) {
+        let runs: Vec<Run> = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.jobs
+                .values_mut()
+                .filter(|job| job.cron.matches(minute))
+                .filter_map(Job::start)
+                .collect()
+        };
+        for run in runs {
+            tokio::spawn(run);
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Traffic shaping for streams and sinks.
+//!
+//! Two classic shapers are provided, both implementing `Shaper`:
+//!
+//! * `TokenBucket` allows bursts of up to its capacity, and on average its
+//!   rate.
+//! * `LeakyBucket` fills up with every item and drains at a constant rate.
+//!   Sized for a single item, it spaces the items evenly, which is what a
+//!   leaky bucket is mostly used for: smoothing out bursts.
+//!
+//! `ShapedStream` and `ShapedSink` apply any `Shaper` to a `Stream` or a
+//! `Sink`, delaying the items that do not fit yet rather than dropping them:
+//!
+//! ```ignore
+//! // At most 10 lines per second, after a burst of 20.
+//! let lines = ShapedStream::new(lines, TokenBucket::new(10.0, 20));
+//!
+//! // At most 64 KiB per second, evenly spaced.
+//! let sink = ShapedSink::new(sink, LeakyBucket::new(65536.0, 4096))
+//!     .with_cost(|chunk: &Bytes| chunk.len() as u64);
+//! ```
+
+use futures::{try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use tokio::timer::{self, Delay};
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Decides whether an item may pass now.
+pub trait Shaper {
+    /// Account for an item costing `cost`, or return how long it takes until
+    /// it fits. An item costing more than the capacity counts as costing the
+    /// capacity, so that it passes eventually, and a capacity of 0 lets
+    /// nothing pass.
+    fn try_acquire(&mut self, cost: u64) -> Result<(), Duration>;
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// How long it takes to make up for `missing` at `per_second`.
+fn wait(missing: f64, per_second: f64) -> Duration {
+    if per_second <= 0.0 {
+        // Never replenished, so never again.
+        return Duration::from_secs(u64::max_value());
+    }
+    Duration::from_millis((missing / per_second * 1000.0).ceil() as u64)
+}
+
+/// Holds up to `capacity` tokens, refilled at `per_second`. An item takes as
+/// many tokens as it costs.
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_second: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(per_second: f64, capacity: u64) -> TokenBucket {
+        TokenBucket {
+            per_second,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Shaper for TokenBucket {
+    fn try_acquire(&mut self, cost: u64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let refill = seconds(now - self.last) * self.per_second;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last = now;
+
+        if self.capacity == 0.0 {
+            return Err(wait(1.0, 0.0));
+        }
+        let cost = (cost as f64).min(self.capacity);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            Err(wait(cost - self.tokens, self.per_second))
+        }
+    }
+}
+
+/// Holds up to `capacity`, and drains at `per_second`. An item fills it by
+/// as much as it costs.
+#[derive(Debug)]
+pub struct LeakyBucket {
+    per_second: f64,
+    capacity: f64,
+    level: f64,
+    last: Instant,
+}
+
+impl LeakyBucket {
+    /// An empty bucket.
+    pub fn new(per_second: f64, capacity: u64) -> LeakyBucket {
+        LeakyBucket {
+            per_second,
+            capacity: capacity as f64,
+            level: 0.0,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Shaper for LeakyBucket {
+    fn try_acquire(&mut self, cost: u64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let leaked = seconds(now - self.last) * self.per_second;
+        self.level = (self.level - leaked).max(0.0);
+        self.last = now;
+
+        if self.capacity == 0.0 {
+            return Err(wait(1.0, 0.0));
+        }
+        let cost = (cost as f64).min(self.capacity);
+        if self.level + cost <= self.capacity {
+            self.level += cost;
+            Ok(())
+        } else {
+            Err(wait(self.level + cost - self.capacity, self.per_second))
+        }
+    }
+}
+
+/// The timer failed while an item was delayed.
+///
+/// The shaped stream or sink reports it through its own error type, which
+/// therefore has to implement `From<TimerError>`, like `io::Error` does.
+#[derive(Debug)]
+pub struct TimerError(pub timer::Error);
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shaping timer error: {}", self.0)
+    }
+}
+
+impl std::error::Error for TimerError {}
+
+impl From<TimerError> for io::Error {
+    fn from(e: TimerError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+/// Poll `delay`, if any, clearing it once it elapsed.
+fn poll_delay(delay: &mut Option<Delay>) -> Poll<(), TimerError> {
+    if let Some(d) = delay {
+        try_ready!(d.poll().map_err(TimerError));
+        *delay = None;
+    }
+    Ok(Async::Ready(()))
+}
+
+/// Wait `duration` before trying again.
+fn start_delay(delay: &mut Option<Delay>, duration: Duration) {
+    // Durations that far out would overflow the `Instant`.
+    let duration = duration.min(Duration::from_secs(365 * 24 * 60 * 60));
+    *delay = Some(Delay::new(Instant::now() + duration));
+}
+
+/// A `Stream` whose items are held back until the shaper lets them pass.
+pub struct ShapedStream<S: Stream, B> {
+    inner: S,
+    shaper: B,
+    cost: fn(&S::Item) -> u64,
+    /// An item that did not fit yet, and the delay until it is tried again.
+    pending: Option<S::Item>,
+    delay: Option<Delay>,
+}
+
+impl<S: Stream, B: Shaper> ShapedStream<S, B> {
+    /// Shape `inner`, every item costing 1.
+    pub fn new(inner: S, shaper: B) -> ShapedStream<S, B> {
+        ShapedStream {
+            inner,
+            shaper,
+            cost: |_| 1,
+            pending: None,
+            delay: None,
+        }
+    }
+
+    /// Charge `cost(item)` for every item instead, like its length in bytes.
+    pub fn with_cost(mut self, cost: fn(&S::Item) -> u64) -> ShapedStream<S, B> {
+        self.cost = cost;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, B> Stream for ShapedStream<S, B>
+where
+    S: Stream,
+    S::Error: From<TimerError>,
+    B: Shaper,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        loop {
+            try_ready!(poll_delay(&mut self.delay));
+
+            let item = match self.pending.take() {
+                Some(item) => item,
+                None => match try_ready!(self.inner.poll()) {
+                    Some(item) => item,
+                    None => return Ok(Async::Ready(None)),
+                },
+            };
+
+            match self.shaper.try_acquire((self.cost)(&item)) {
+                Ok(()) => return Ok(Async::Ready(Some(item))),
+                Err(wait) => {
+                    self.pending = Some(item);
+                    start_delay(&mut self.delay, wait);
+                }
+            }
+        }
+    }
+}
+
+/// A `Sink` that only accepts items once the shaper lets them pass.
+pub struct ShapedSink<S: Sink, B> {
+    inner: S,
+    shaper: B,
+    cost: fn(&S::SinkItem) -> u64,
+    delay: Option<Delay>,
+    /// Whether the item offered last was paid for but not accepted by
+    /// `inner`, so that it is not paid for twice.
+    paid: bool,
+}
+
+impl<S: Sink, B: Shaper> ShapedSink<S, B> {
+    /// Shape `inner`, every item costing 1.
+    pub fn new(inner: S, shaper: B) -> ShapedSink<S, B> {
+        ShapedSink {
+            inner,
+            shaper,
+            cost: |_| 1,
+            delay: None,
+            paid: false,
+        }
+    }
+
+    /// Charge `cost(item)` for every item instead, like its length in bytes.
+    pub fn with_cost(mut self, cost: fn(&S::SinkItem) -> u64) -> ShapedSink<S, B> {
+        self.cost = cost;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, B> Sink for ShapedSink<S, B>
+where
+    S: Sink,
+    S::SinkError: From<TimerError>,
+    B: Shaper,
+{
+    type SinkItem = S::SinkItem;
+    type SinkError = S::SinkError;
+
+    fn start_send(&mut self, item: S::SinkItem) -> StartSend<S::SinkItem, S::SinkError> {
+        loop {
+            if poll_delay(&mut self.delay)?.is_not_ready() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+            if self.paid {
+                break;
+            }
+            match self.shaper.try_acquire((self.cost)(&item)) {
+                Ok(()) => self.paid = true,
+                Err(wait) => start_delay(&mut self.delay, wait),
+            }
+        }
+
+        let res = self.inner.start_send(item)?;
+        if res.is_ready() {
+            self.paid = false;
+        }
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), S::SinkError> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), S::SinkError> {
+        self.inner.close()
+    }
+}
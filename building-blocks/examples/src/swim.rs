@@ -0,0 +1,567 @@
+//! Knowing which servers of a cluster are up, without each of them
+//! heartbeating every other.
+//!
+//! A `Swim` is one member of a cluster, talking to the others over UDP the
+//! way SWIM does. Every `period` it pings one other member, going round all
+//! of them in a random order. If no ack comes within `ping_timeout`, it
+//! asks `indirect_probes` others to ping that member for it, in case only
+//! the path between the two is broken. Without an ack by the next period
+//! the member is suspected, and if it does not refute that within
+//! `suspect_for` it is taken for dead:
+//!
+//! ```ignore
+//! let swim = Swim::bind(&"0.0.0.0:7946".parse()?, "eu-1")?
+//!     .period(Duration::from_secs(1))
+//!     .suspect_for(Duration::from_secs(5))
+//!     .join(vec![seed])
+//!     .on_change(|member| println!("{} is {}", member.name, member.status.as_str()));
+//! let members = swim.members();
+//! tokio::spawn(swim.map_err(|e| eprintln!("membership error = {:?}", e)));
+//! ```
+//!
+//! What a member learns, that one joined, is suspected or died, it passes on
+//! by piggybacking it on the pings and acks it sends anyway, each a few
+//! times more than the log of the size of the cluster. Every member hears of
+//! it within a few periods that way, whatever the size of the cluster, and a
+//! member only ever sends a couple of packets per period.
+//!
+//! A suspected member refutes it by saying it is alive with a higher
+//! incarnation, a number only ever raised by the member itself. It starts at
+//! the time the member started, so that a member that restarts is taken
+//! for alive again rather than for the dead one that went by its name.
+//!
+//! Until it has heard of another member, a `Swim` pings the seeds it was
+//! given to `join` every period.
+
+use futures::{Async, Future, Poll, Stream};
+use serde_derive::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::timer::{Delay, Interval};
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest packet sent or received.
+const MAX_PACKET: usize = 64 * 1024;
+
+/// Most updates piggybacked on one packet.
+const MAX_PIGGYBACK: usize = 8;
+
+/// Packets waiting for the socket at most, the oldest are dropped beyond.
+const MAX_OUTBOX: usize = 1024;
+
+/// What a member is taken for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Alive,
+    /// It did not ack a ping, and has `suspect_for` to say it is alive.
+    Suspect,
+    Dead,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Alive => "alive",
+            Status::Suspect => "suspect",
+            Status::Dead => "dead",
+        }
+    }
+}
+
+/// A member of the cluster, as far as this one knows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub incarnation: u64,
+    pub status: Status,
+}
+
+/// The members this one knows of, shared with its `Swim`.
+#[derive(Clone)]
+pub struct Members(Arc<Mutex<Table>>);
+
+struct Table {
+    me: Member,
+    /// The others by name, with when they last changed.
+    others: HashMap<String, (Member, Instant)>,
+}
+
+impl Members {
+    /// This member.
+    pub fn me(&self) -> Member {
+        self.0.lock().unwrap().me.clone()
+    }
+
+    /// The other members, dead ones included, by name.
+    pub fn others(&self) -> Vec<Member> {
+        let table = self.0.lock().unwrap();
+        let mut others: Vec<Member> = table.others.values().map(|(m, _)| m.clone()).collect();
+        others.sort_by(|a, b| a.name.cmp(&b.name));
+        others
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Packet {
+    from: String,
+    incarnation: u64,
+    #[serde(flatten)]
+    kind: Kind,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    updates: Vec<Member>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Kind {
+    Ping {
+        seq: u64,
+    },
+    /// Ping `target` and pass its ack on.
+    PingReq {
+        seq: u64,
+        target: SocketAddr,
+    },
+    /// The ack of `of` to the ping `seq`.
+    Ack {
+        seq: u64,
+        of: String,
+    },
+}
+
+/// The ping of the current period.
+struct Probe {
+    seq: u64,
+    name: String,
+    addr: SocketAddr,
+    acked: bool,
+    /// Until the ping is asked of the others, `None` once it was.
+    timeout: Option<Delay>,
+}
+
+/// A ping sent for another member, whose ack goes back to it.
+struct Relay {
+    to: SocketAddr,
+    seq: u64,
+    sent: Instant,
+}
+
+/// One member of a cluster, see the module docs. Runs until the socket
+/// fails.
+pub struct Swim {
+    socket: UdpSocket,
+    table: Arc<Mutex<Table>>,
+    period: Duration,
+    ticks: Interval,
+    ping_timeout: Duration,
+    indirect_probes: usize,
+    suspect_for: Duration,
+    seeds: Vec<SocketAddr>,
+    on_change: Box<dyn FnMut(&Member) + Send>,
+    /// Members that changed since `on_change` was last called.
+    changed: Vec<Member>,
+    probe: Option<Probe>,
+    /// Names still to ping this round.
+    order: Vec<String>,
+    relays: HashMap<u64, Relay>,
+    seq: u64,
+    /// What is passed on, and how many more times each.
+    updates: Vec<(Member, u32)>,
+    outbox: VecDeque<(Vec<u8>, SocketAddr)>,
+    buf: Vec<u8>,
+}
+
+impl Swim {
+    /// A member called `name`, unique within the cluster, at `addr`.
+    /// Pinging every second, waiting 300ms for an ack before asking 3
+    /// others, and suspecting a member for 5 seconds.
+    pub fn bind(addr: &SocketAddr, name: &str) -> io::Result<Swim> {
+        let socket = UdpSocket::bind(addr)?;
+        let me = Member {
+            name: name.to_string(),
+            addr: socket.local_addr()?,
+            incarnation: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or(0),
+            status: Status::Alive,
+        };
+        let period = Duration::from_secs(1);
+        Ok(Swim {
+            socket,
+            table: Arc::new(Mutex::new(Table {
+                me,
+                others: HashMap::new(),
+            })),
+            period,
+            ticks: Interval::new(Instant::now() + period, period),
+            ping_timeout: Duration::from_millis(300),
+            indirect_probes: 3,
+            suspect_for: Duration::from_secs(5),
+            seeds: Vec::new(),
+            on_change: Box::new(|_| {}),
+            changed: Vec::new(),
+            probe: None,
+            order: Vec::new(),
+            relays: HashMap::new(),
+            seq: 0,
+            updates: Vec::new(),
+            outbox: VecDeque::new(),
+            buf: vec![0; MAX_PACKET],
+        })
+    }
+
+    /// How often a member is pinged.
+    pub fn period(mut self, period: Duration) -> Swim {
+        self.period = period;
+        self.ticks = Interval::new(Instant::now() + period, period);
+        self
+    }
+
+    /// How long to wait for an ack before asking others to ping, less than
+    /// the `period`.
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> Swim {
+        self.ping_timeout = ping_timeout;
+        self
+    }
+
+    /// How many others to ask to ping a member that did not ack.
+    pub fn indirect_probes(mut self, indirect_probes: usize) -> Swim {
+        self.indirect_probes = indirect_probes;
+        self
+    }
+
+    /// How long a suspected member has to refute it.
+    pub fn suspect_for(mut self, suspect_for: Duration) -> Swim {
+        self.suspect_for = suspect_for;
+        self
+    }
+
+    /// Members of the cluster to ping until another one is known.
+    pub fn join(mut self, seeds: Vec<SocketAddr>) -> Swim {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Call `on_change` whenever a member joins, is suspected, dies or
+    /// comes back.
+    pub fn on_change<F: FnMut(&Member) + Send + 'static>(mut self, on_change: F) -> Swim {
+        self.on_change = Box::new(on_change);
+        self
+    }
+
+    pub fn members(&self) -> Members {
+        Members(self.table.clone())
+    }
+
+    /// Start a new period: suspect the member that did not ack, give up on
+    /// the suspected that did not refute it, and ping the next one.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(probe) = self.probe.take() {
+            if !probe.acked {
+                self.suspect(&probe.name, &probe.addr);
+            }
+        }
+        let expired: Vec<Member> = {
+            let table = self.table.lock().unwrap();
+            table
+                .others
+                .values()
+                .filter(|(m, since)| {
+                    m.status == Status::Suspect && now.duration_since(*since) >= self.suspect_for
+                })
+                .map(|(m, _)| Member {
+                    status: Status::Dead,
+                    ..m.clone()
+                })
+                .collect()
+        };
+        for dead in expired {
+            self.apply(dead);
+        }
+        let period = self.period;
+        self.relays
+            .retain(|_, relay| now.duration_since(relay.sent) < period * 2);
+
+        if self.live().is_empty() {
+            for seed in self.seeds.clone() {
+                let seq = self.next_seq();
+                self.send(Kind::Ping { seq }, seed);
+            }
+        }
+
+        if self.order.is_empty() {
+            self.order = self.live();
+            shuffle(&mut self.order);
+        }
+        while let Some(name) = self.order.pop() {
+            let addr = {
+                let table = self.table.lock().unwrap();
+                match table.others.get(&name) {
+                    Some((m, _)) if m.status != Status::Dead => m.addr,
+                    _ => continue,
+                }
+            };
+            let seq = self.next_seq();
+            self.send(Kind::Ping { seq }, addr);
+            self.probe = Some(Probe {
+                seq,
+                name,
+                addr,
+                acked: false,
+                timeout: Some(Delay::new(now + self.ping_timeout)),
+            });
+            break;
+        }
+    }
+
+    /// The ping of this period went unacked for `ping_timeout`, ask others.
+    fn probe_indirectly(&mut self) {
+        let (seq, name, target) = match &self.probe {
+            Some(probe) => (probe.seq, probe.name.clone(), probe.addr),
+            None => return,
+        };
+        let mut others: Vec<String> = self.live().into_iter().filter(|n| *n != name).collect();
+        shuffle(&mut others);
+        others.truncate(self.indirect_probes);
+        for other in others {
+            let addr = match self.table.lock().unwrap().others.get(&other) {
+                Some((m, _)) => m.addr,
+                None => continue,
+            };
+            self.send(Kind::PingReq { seq, target }, addr);
+        }
+    }
+
+    fn receive(&mut self, packet: &[u8], src: SocketAddr) {
+        let packet: Packet = match serde_json::from_slice(packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        // A member taken for dead that is not does not refute it unless it
+        // hears of it, and the news may have stopped going round.
+        let dead = match self.table.lock().unwrap().others.get(&packet.from) {
+            Some((m, _)) if m.status == Status::Dead && m.incarnation >= packet.incarnation => {
+                Some(m.clone())
+            }
+            _ => None,
+        };
+        if let Some(dead) = dead {
+            self.pass_on(dead);
+        }
+        // Hearing from a member directly is as good as hearing it is alive.
+        self.apply(Member {
+            name: packet.from.clone(),
+            addr: src,
+            incarnation: packet.incarnation,
+            status: Status::Alive,
+        });
+        for update in packet.updates {
+            self.apply(update);
+        }
+        match packet.kind {
+            Kind::Ping { seq } => {
+                let of = self.table.lock().unwrap().me.name.clone();
+                self.send(Kind::Ack { seq, of }, src);
+            }
+            Kind::PingReq { seq, target } => {
+                let relayed = self.next_seq();
+                let relay = Relay {
+                    to: src,
+                    seq,
+                    sent: Instant::now(),
+                };
+                self.relays.insert(relayed, relay);
+                self.send(Kind::Ping { seq: relayed }, target);
+            }
+            Kind::Ack { seq, of } => match &mut self.probe {
+                Some(probe) if probe.seq == seq => probe.acked = true,
+                _ => {
+                    if let Some(relay) = self.relays.remove(&seq) {
+                        let seq = relay.seq;
+                        self.send(Kind::Ack { seq, of }, relay.to);
+                    }
+                }
+            },
+        }
+    }
+
+    fn suspect(&mut self, name: &str, addr: &SocketAddr) {
+        let incarnation = match self.table.lock().unwrap().others.get(name) {
+            Some((m, _)) if m.status == Status::Alive => m.incarnation,
+            _ => return,
+        };
+        self.apply(Member {
+            name: name.to_string(),
+            addr: *addr,
+            incarnation,
+            status: Status::Suspect,
+        });
+    }
+
+    /// Take in what was learned of a member, and pass it on if it is news.
+    fn apply(&mut self, update: Member) {
+        let mut table = self.table.lock().unwrap();
+        if update.name == table.me.name {
+            // Refute being taken for suspect or dead.
+            if update.status != Status::Alive && update.incarnation >= table.me.incarnation {
+                table.me.incarnation = update.incarnation + 1;
+                let me = table.me.clone();
+                drop(table);
+                self.pass_on(me);
+            }
+            return;
+        }
+        let news = match table.others.get(&update.name) {
+            None => update.status != Status::Dead,
+            Some((known, _)) => {
+                let newer = update.incarnation > known.incarnation;
+                match (update.status, known.status) {
+                    (Status::Alive, _) => newer,
+                    (Status::Suspect, Status::Alive) => update.incarnation >= known.incarnation,
+                    (Status::Suspect, _) => newer,
+                    (Status::Dead, Status::Dead) => false,
+                    (Status::Dead, _) => update.incarnation >= known.incarnation,
+                }
+            }
+        };
+        if !news {
+            return;
+        }
+        let member = update.clone();
+        table
+            .others
+            .insert(update.name.clone(), (update, Instant::now()));
+        drop(table);
+        self.changed.push(member.clone());
+        self.pass_on(member);
+    }
+
+    /// Piggyback `update` on the next packets, in place of an older one of
+    /// the same member.
+    fn pass_on(&mut self, update: Member) {
+        let members = self.table.lock().unwrap().others.len() + 1;
+        let times = 3 * (64 - (members as u64).leading_zeros());
+        self.updates
+            .retain(|(queued, _)| queued.name != update.name);
+        self.updates.push((update, times));
+    }
+
+    fn send(&mut self, kind: Kind, to: SocketAddr) {
+        self.updates.sort_by_key(|&(_, times)| std::cmp::Reverse(times));
+        let mut updates = Vec::new();
+        for (update, times) in self.updates.iter_mut().take(MAX_PIGGYBACK) {
+            updates.push(update.clone());
+            *times -= 1;
+        }
+        self.updates.retain(|(_, times)| *times > 0);
+
+        let (from, incarnation) = {
+            let table = self.table.lock().unwrap();
+            (table.me.name.clone(), table.me.incarnation)
+        };
+        let packet = Packet {
+            from,
+            incarnation,
+            kind,
+            updates,
+        };
+        let bytes = serde_json::to_vec(&packet).expect("packets serialize");
+        if self.outbox.len() == MAX_OUTBOX {
+            self.outbox.pop_front();
+        }
+        self.outbox.push_back((bytes, to));
+    }
+
+    /// The others that are not dead, by name.
+    fn live(&self) -> Vec<String> {
+        let table = self.table.lock().unwrap();
+        table
+            .others
+            .values()
+            .filter(|(m, _)| m.status != Status::Dead)
+            .map(|(m, _)| m.name.clone())
+            .collect()
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+}
+
+impl Future for Swim {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        while let Async::Ready(Some(_)) = self
+            .ticks
+            .poll()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        {
+            self.tick();
+        }
+
+        let timed_out = match self.probe.as_mut().and_then(|p| p.timeout.as_mut()) {
+            // A broken timer only means the others are asked early.
+            Some(timeout) => !matches!(timeout.poll(), Ok(Async::NotReady)),
+            None => false,
+        };
+        if timed_out {
+            let probe = self.probe.as_mut().unwrap();
+            probe.timeout = None;
+            if !probe.acked {
+                self.probe_indirectly();
+            }
+        }
+
+        loop {
+            match self.socket.poll_recv_from(&mut self.buf) {
+                Ok(Async::Ready((n, src))) => {
+                    let packet = self.buf[..n].to_vec();
+                    self.receive(&packet, src);
+                }
+                Ok(Async::NotReady) => break,
+                // What an unreachable member's host answered to a ping.
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::ConnectionRefused
+                        || e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Some((packet, to)) = self.outbox.front() {
+            match self.socket.poll_send_to(packet, to) {
+                Ok(Async::NotReady) => break,
+                // A packet that cannot be sent is as good as lost, which
+                // the protocol expects anyway.
+                Ok(Async::Ready(_)) | Err(_) => {
+                    self.outbox.pop_front();
+                }
+            }
+        }
+
+        for member in self.changed.drain(..) {
+            (self.on_change)(&member);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Put `items` in a random order.
+fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let random = RandomState::new().build_hasher().finish();
+        items.swap(i, (random % (i as u64 + 1)) as usize);
+    }
+}
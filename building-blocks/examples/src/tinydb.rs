@@ -38,9 +38,45 @@
 //!   set to the value `bar`
 //! * `SET $key $value` - this will set the value of `$key` to `$value`,
 //!   returning the previous value, if any.
+//!
+//! Several keys can be changed at once, or not at all, in a transaction:
+//!
+//!     BEGIN
+//!     begin at 469762540059754496
+//!     GET alice
+//!     alice = 100
+//!     SET alice 90
+//!     SET bob 110
+//!     COMMIT
+//!     committed at 469762540428066816
+//!
+//! * `BEGIN` - this will start a transaction. Its `GET`s see the database as
+//!   it was then, along with its own `SET`s, which nobody else sees until
+//!   `COMMIT`.
+//! * `COMMIT` - this will write the `SET`s of the transaction, unless another
+//!   client changed one of its keys after it began, in which case none are.
+//! * `ROLLBACK` - this will drop the transaction.
+//!
+//! A `GET` or `SET` outside of a transaction is one of its own. The database
+//! is a `building_blocks::percolator` over memory.
+//!
+//! Given a second address, the database also takes part in transactions
+//! over several servers, see `building_blocks::twopc`:
+//!
+//!     cargo run --bin tinydb 127.0.0.1:8080 127.0.0.1:9080
+//!
+//! A coordinator like `tinydb-commit` prepares the `SET`s of a transaction
+//! on every server, given as a JSON object of the keys and their values,
+//! and commits them everywhere or nowhere. The keys of a prepared
+//! transaction stay locked until the coordinator decides, and a `GET` of
+//! one fails until then, for at most `PREPARED_LOCK_SECS`.
+//!
+//! Each client may send `REQUESTS_PER_SECOND` requests per second, after a
+//! burst of `BURST_REQUESTS`. Requests beyond that wait until it is their turn.
 
 #![deny(warnings)]
 
+extern crate building_blocks;
 extern crate tokio;
 
 use std::collections::HashMap;
@@ -48,23 +84,74 @@ use std::env;
 use std::io::BufReader;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use building_blocks::percolator::{MemStorage, Percolator, Prepared, Transaction};
+use building_blocks::shaping::{ShapedStream, TokenBucket};
+use building_blocks::twopc::{self, Participant};
+use futures::future::Either;
 use tokio::io::{lines, write_all};
 use tokio::net::TcpListener;
 use tokio::prelude::*;
 
+/// Requests a client may send per second, on average.
+const REQUESTS_PER_SECOND: f64 = 10.0;
+
+/// Requests a client may send in a burst.
+const BURST_REQUESTS: u64 = 20;
+
+/// How long the locks of a transaction prepared for a coordinator keep it
+/// from being rolled back by a `GET`, see `Percolator::lock_ttl`.
+const PREPARED_LOCK_SECS: u64 = 60;
+
 /// The in-memory database shared amongst all clients.
 ///
-/// This database will be shared via `Arc`, so to mutate the internal map we're
-/// going to use a `Mutex` for interior mutability.
-struct Database {
-    map: Mutex<HashMap<String, String>>,
+/// This database will be shared via `Arc`. It locks what it stores itself,
+/// one key at a time, see `Percolator`.
+type Database = Percolator<MemStorage>;
+
+/// The database as it takes part in transactions over several servers: a
+/// transaction prepared is one of the database with its keys locked.
+struct KvParticipant {
+    db: Arc<Database>,
+    prepared: Mutex<HashMap<u64, Prepared>>,
+}
+
+impl Participant for KvParticipant {
+    fn prepare(&self, id: u64, payload: &str) -> Result<(), String> {
+        let writes: HashMap<String, String> =
+            serde_json::from_str(payload).map_err(|e| format!("bad payload: {}", e))?;
+        let mut txn = self.db.begin();
+        for (key, value) in &writes {
+            txn.set(key.as_bytes(), value.as_bytes());
+        }
+        let prepared = self.db.prepare(txn).map_err(|e| e.to_string())?;
+        self.prepared.lock().unwrap().insert(id, prepared);
+        Ok(())
+    }
+
+    fn commit(&self, id: u64) {
+        if let Some(prepared) = self.prepared.lock().unwrap().remove(&id) {
+            if let Err(e) = self.db.commit_prepared(prepared) {
+                println!("error committing transaction {}: {}", id, e);
+            }
+        }
+    }
+
+    fn abort(&self, id: u64) {
+        if let Some(prepared) = self.prepared.lock().unwrap().remove(&id) {
+            self.db.rollback(prepared);
+        }
+    }
 }
 
 /// Possible requests our clients can send us
 enum Request {
     Get { key: String },
     Set { key: String, value: String },
+    Begin,
+    Commit,
+    Rollback,
 }
 
 /// Responses to the `Request` commands above
@@ -78,6 +165,13 @@ enum Response {
         value: String,
         previous: Option<String>,
     },
+    Begun {
+        start_ts: u64,
+    },
+    Committed {
+        commit_ts: u64,
+    },
+    RolledBack,
     Error {
         msg: String,
     },
@@ -96,10 +190,14 @@ fn main() -> Result<(), Box<std::error::Error>> {
     // structure. Note the usage of `Arc` here which will be used to ensure that
     // each independently spawned client will have a reference to the in-memory
     // database.
-    let mut initial_db = HashMap::new();
-    initial_db.insert("foo".to_string(), "bar".to_string());
-    let db = Arc::new(Database {
-        map: Mutex::new(initial_db),
+    let db = Database::new(MemStorage::default());
+    let db = Arc::new(db.lock_ttl(Duration::from_secs(PREPARED_LOCK_SECS)));
+    let mut initial = db.begin();
+    initial.set(b"foo", b"bar");
+    db.commit(initial)?;
+    let participant = Arc::new(KvParticipant {
+        db: db.clone(),
+        prepared: Mutex::new(HashMap::new()),
     });
 
     let done = listener
@@ -113,7 +211,11 @@ fn main() -> Result<(), Box<std::error::Error>> {
 
             // Since our protocol is line-based we use `tokio_io`'s `lines` utility
             // to convert our stream of bytes, `reader`, into a `Stream` of lines.
+            // The lines are then shaped, so that no client can hog the
+            // database.
             let lines = lines(BufReader::new(reader));
+            let lines =
+                ShapedStream::new(lines, TokenBucket::new(REQUESTS_PER_SECOND, BURST_REQUESTS));
 
             // Here's where the meat of the processing in this server happens. First
             // we see a clone of the database being created, which is creating a
@@ -125,32 +227,51 @@ fn main() -> Result<(), Box<std::error::Error>> {
             // requests (lines) we receive from the client. The actual handling here
             // is pretty simple, first we parse the request and if it's valid we
             // generate a response based on the values in the database.
+            //
+            // The transaction the client began, if any, lives as long as the
+            // closure does.
             let db = db.clone();
+            let mut txn: Option<Transaction> = None;
             let responses = lines.map(move |line| {
                 let request = match Request::parse(&line) {
                     Ok(req) => req,
                     Err(e) => return Response::Error { msg: e },
                 };
 
-                let mut db = db.map.lock().unwrap();
+                let none_open = || Response::Error {
+                    msg: "no transaction is open".to_string(),
+                };
                 match request {
-                    Request::Get { key } => match db.get(&key) {
-                        Some(value) => Response::Value {
-                            key,
-                            value: value.clone(),
-                        },
-                        None => Response::Error {
-                            msg: format!("no key {}", key),
-                        },
+                    Request::Begin if txn.is_some() => Response::Error {
+                        msg: "a transaction is open already".to_string(),
                     },
-                    Request::Set { key, value } => {
-                        let previous = db.insert(key.clone(), value.clone());
-                        Response::Set {
-                            key,
-                            value,
-                            previous,
-                        }
+                    Request::Begin => {
+                        let begun = db.begin();
+                        let start_ts = begun.start_ts();
+                        txn = Some(begun);
+                        Response::Begun { start_ts }
                     }
+                    Request::Commit => match txn.take().map(|open| db.commit(open)) {
+                        Some(Ok(commit_ts)) => Response::Committed { commit_ts },
+                        Some(Err(e)) => Response::Error { msg: e.to_string() },
+                        None => none_open(),
+                    },
+                    Request::Rollback => match txn.take() {
+                        Some(_) => Response::RolledBack,
+                        None => none_open(),
+                    },
+                    request => match &mut txn {
+                        Some(open) => request.run(&db, open),
+                        // A transaction of its own.
+                        None => {
+                            let mut own = db.begin();
+                            let response = request.run(&db, &mut own);
+                            match (&response, db.commit(own)) {
+                                (Response::Error { .. }, _) | (_, Ok(_)) => response,
+                                (_, Err(e)) => Response::Error { msg: e.to_string() },
+                            }
+                        }
+                    },
                 }
             });
 
@@ -172,6 +293,26 @@ fn main() -> Result<(), Box<std::error::Error>> {
             tokio::spawn(msg)
         });
 
+    // Coordinators get a listener of their own, if there is a second
+    // address.
+    let done = match env::args().nth(2) {
+        Some(twopc_addr) => {
+            let twopc_addr = twopc_addr.parse::<SocketAddr>()?;
+            let twopc_listener = TcpListener::bind(&twopc_addr).map_err(|_| "failed to bind")?;
+            println!("Taking part in transactions on: {}", twopc_addr);
+            let participate = twopc_listener
+                .incoming()
+                .map_err(|e| println!("error accepting socket; error = {:?}", e))
+                .for_each(move |socket| {
+                    let served = twopc::serve(participant.clone(), socket)
+                        .map_err(|e| println!("error serving a coordinator; error = {:?}", e));
+                    tokio::spawn(served)
+                });
+            Either::A(done.join(participate).map(|_| ()))
+        }
+        None => Either::B(done),
+    };
+
     tokio::run(done);
     Ok(())
 }
@@ -206,10 +347,40 @@ impl Request {
                     value: value.to_string(),
                 })
             }
+            Some("BEGIN") => Ok(Request::Begin),
+            Some("COMMIT") => Ok(Request::Commit),
+            Some("ROLLBACK") => Ok(Request::Rollback),
             Some(cmd) => Err(format!("unknown command: {}", cmd)),
             None => Err(format!("empty input")),
         }
     }
+
+    /// Run a `GET` or `SET` in `txn`.
+    fn run(self, db: &Database, txn: &mut Transaction) -> Response {
+        let (key, value) = match self {
+            Request::Get { key } => (key, None),
+            Request::Set { key, value } => (key, Some(value)),
+            _ => unreachable!(),
+        };
+        let current = match db.get(txn, key.as_bytes()) {
+            Ok(current) => current.map(|v| String::from_utf8_lossy(&v).into_owned()),
+            Err(e) => return Response::Error { msg: e.to_string() },
+        };
+        match (value, current) {
+            (None, Some(value)) => Response::Value { key, value },
+            (None, None) => Response::Error {
+                msg: format!("no key {}", key),
+            },
+            (Some(value), previous) => {
+                txn.set(key.as_bytes(), value.as_bytes());
+                Response::Set {
+                    key,
+                    value,
+                    previous,
+                }
+            }
+        }
+    }
 }
 
 impl Response {
@@ -221,6 +392,9 @@ impl Response {
                 ref value,
                 ref previous,
             } => format!("set {} = `{}`, previous: {:?}", key, value, previous),
+            Response::Begun { start_ts } => format!("begin at {}", start_ts),
+            Response::Committed { commit_ts } => format!("committed at {}", commit_ts),
+            Response::RolledBack => "rolled back".to_string(),
             Response::Error { ref msg } => format!("error: {}", msg),
         }
     }
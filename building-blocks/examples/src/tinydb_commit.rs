@@ -0,0 +1,63 @@
+//! Set keys on several `tinydb` servers at once, or on none of them.
+//!
+//! This example coordinates a transaction over databases that take part in
+//! them, see `tinydb`. To run, first start two of them:
+//!
+//!     cargo run --bin tinydb 127.0.0.1:8080 127.0.0.1:9080
+//!     cargo run --bin tinydb 127.0.0.1:8081 127.0.0.1:9081
+//!
+//! and then give the log of the coordinator, and for each key the server it
+//! goes to and its value:
+//!
+//!     $ cargo run --bin tinydb-commit coordinator.log 127.0.0.1:9080 alice 90 127.0.0.1:9081 bob 110
+//!     COMMIT
+//!
+//! Either both `alice` and `bob` are set, or neither is, as when a key is
+//! locked by a transaction of the other server or a server does not answer
+//! in time. The coordinator first finishes the transactions a run before it
+//! left in its log, see `building_blocks::twopc`.
+
+#![deny(warnings)]
+
+extern crate building_blocks;
+extern crate tokio;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use building_blocks::twopc::Coordinator;
+use tokio::prelude::*;
+
+/// How long the servers have to vote, and to acknowledge the decision.
+const VOTE_TIMEOUT_SECS: u64 = 2;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 4 || (args.len() - 1) % 3 != 0 {
+        return Err("usage: tinydb-commit <log> (<server> <key> <value>)...".into());
+    }
+
+    // The keys of every server, as the JSON object its part is.
+    let mut parts: BTreeMap<SocketAddr, BTreeMap<&str, &str>> = BTreeMap::new();
+    for set in args[1..].chunks(3) {
+        let server = set[0].parse::<SocketAddr>()?;
+        parts.entry(server).or_default().insert(&set[1], &set[2]);
+    }
+    let mut payloads = Vec::new();
+    for (server, keys) in parts {
+        payloads.push((server, serde_json::to_string(&keys)?));
+    }
+
+    let coordinator =
+        Coordinator::open(&args[0])?.vote_timeout(Duration::from_secs(VOTE_TIMEOUT_SECS));
+    let done = coordinator
+        .recover()
+        .and_then(move |()| coordinator.commit(payloads))
+        .map(|decision| println!("{}", decision))
+        .map_err(|e| println!("error: {}", e));
+
+    tokio::run(done);
+    Ok(())
+}
@@ -0,0 +1,332 @@
+//! Committing on several servers at once, with two-phase commit.
+//!
+//! A `Coordinator` asks every participant to prepare its part of a
+//! transaction, and once all of them voted yes, tells them to commit it.
+//! If one votes no, cannot be reached or does not answer within
+//! `vote_timeout`, they are all told to abort it instead:
+//!
+//! ```ignore
+//! let coordinator = Coordinator::open("coordinator.log")?.vote_timeout(Duration::from_secs(2));
+//! let done = coordinator
+//!     .recover()
+//!     .and_then(move |()| coordinator.commit(vec![(eu, "alice=90".into()), (us, "bob=110".into())]))
+//!     .map(|decision| println!("{}", decision));
+//! ```
+//!
+//! The coordinator connects to a participant for every request, a line,
+//! and `serve` answers it with what the `Participant` says:
+//!
+//! ```text
+//! PREPARE 7 alice=90      YES 7, or NO 7 <reason>
+//! COMMIT 7                ACK 7
+//! ABORT 7                 ACK 7
+//! ```
+//!
+//! A participant that voted yes has to be able to commit, so it keeps what
+//! it prepared until it is told what the coordinator decided, however long
+//! that takes. Being told twice, or about a transaction it does not know,
+//! is answered all the same.
+//!
+//! The coordinator writes what it does to its log, and syncs it, before it
+//! goes on: `BEGIN` with the participants, then the decision, and `DONE`
+//! once every participant acknowledged it. A coordinator opened on the log
+//! of one that stopped in between aborts what was not decided, and
+//! `recover` tells the participants what they were not told yet.
+//! Transactions whose decision did not get through are retried by
+//! `recover` as well.
+
+use futures::future::{self, Either};
+use futures::{Future, Sink, Stream};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the coordinator decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Decision::Commit => "COMMIT",
+            Decision::Abort => "ABORT",
+        })
+    }
+}
+
+/// A server taking part in transactions.
+pub trait Participant: Send + Sync + 'static {
+    /// Get ready to commit `payload` as the transaction `id`, so that
+    /// `commit` cannot fail any more, or say why not.
+    fn prepare(&self, id: u64, payload: &str) -> Result<(), String>;
+
+    fn commit(&self, id: u64);
+
+    fn abort(&self, id: u64);
+}
+
+/// Answer the requests of a coordinator on `socket` until it hangs up.
+pub fn serve<P: Participant>(
+    participant: Arc<P>,
+    socket: TcpStream,
+) -> impl Future<Item = (), Error = io::Error> {
+    let (sink, stream) = Framed::new(socket, LinesCodec::new()).split();
+    let replies = stream.map(move |line| answer(&*participant, &line));
+    sink.send_all(replies).map(|_| ())
+}
+
+fn answer<P: Participant>(participant: &P, line: &str) -> String {
+    let mut parts = line.splitn(3, ' ');
+    let command = parts.next().unwrap_or("");
+    let id = match parts.next().and_then(|id| id.parse().ok()) {
+        Some(id) => id,
+        None => return format!("ERROR bad request {:?}", line),
+    };
+    match command {
+        "PREPARE" => match participant.prepare(id, parts.next().unwrap_or("")) {
+            Ok(()) => format!("YES {}", id),
+            Err(reason) => format!("NO {} {}", id, reason.replace(['\r', '\n'], " ")),
+        },
+        "COMMIT" => {
+            participant.commit(id);
+            format!("ACK {}", id)
+        }
+        "ABORT" => {
+            participant.abort(id);
+            format!("ACK {}", id)
+        }
+        _ => format!("ERROR unknown command {:?}", command),
+    }
+}
+
+/// A transaction whose participants were not all told the decision.
+struct Unfinished {
+    id: u64,
+    decision: Decision,
+    participants: Vec<SocketAddr>,
+}
+
+struct Log {
+    file: File,
+    last_id: u64,
+    unfinished: Vec<Unfinished>,
+}
+
+impl Log {
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+/// Runs transactions over participants, see the module docs.
+#[derive(Clone)]
+pub struct Coordinator {
+    log: Arc<Mutex<Log>>,
+    vote_timeout: Duration,
+}
+
+impl Coordinator {
+    /// Open the log at `path`, aborting the transactions an earlier
+    /// coordinator did not decide. Run `recover` to tell their
+    /// participants.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Coordinator> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut last_id = 0;
+        let mut begun: BTreeMap<u64, (Vec<SocketAddr>, Option<Decision>)> = BTreeMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let mut parts = line.split(' ');
+            let (record, id) = match (parts.next(), parts.next().and_then(|id| id.parse().ok())) {
+                (Some(record), Some(id)) => (record, id),
+                // Cut short by a crash while it was written.
+                _ => continue,
+            };
+            last_id = last_id.max(id);
+            match record {
+                "BEGIN" => {
+                    let participants = parts
+                        .next()
+                        .unwrap_or("")
+                        .split(',')
+                        .filter_map(|addr| addr.parse().ok())
+                        .collect();
+                    begun.insert(id, (participants, None));
+                }
+                "COMMIT" | "ABORT" => {
+                    if let Some((_, decision)) = begun.get_mut(&id) {
+                        *decision = Some(match record {
+                            "COMMIT" => Decision::Commit,
+                            _ => Decision::Abort,
+                        });
+                    }
+                }
+                "DONE" => {
+                    begun.remove(&id);
+                }
+                _ => {}
+            }
+        }
+
+        let mut unfinished = Vec::new();
+        for (id, (participants, decision)) in begun {
+            let decision = match decision {
+                Some(decision) => decision,
+                None => {
+                    file.write_all(format!("ABORT {}\n", id).as_bytes())?;
+                    Decision::Abort
+                }
+            };
+            unfinished.push(Unfinished {
+                id,
+                decision,
+                participants,
+            });
+        }
+        file.sync_data()?;
+        Ok(Coordinator {
+            log: Arc::new(Mutex::new(Log {
+                file,
+                last_id,
+                unfinished,
+            })),
+            vote_timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// How long participants have to vote, and to acknowledge the
+    /// decision, 5 seconds unless set.
+    pub fn vote_timeout(mut self, vote_timeout: Duration) -> Coordinator {
+        self.vote_timeout = vote_timeout;
+        self
+    }
+
+    /// Tell the participants of the unfinished transactions what was
+    /// decided. Those that still cannot be reached are left for the next
+    /// `recover`.
+    pub fn recover(&self) -> impl Future<Item = (), Error = io::Error> {
+        let unfinished = std::mem::take(&mut self.log.lock().unwrap().unfinished);
+        let finished = unfinished.into_iter().map({
+            let coordinator = self.clone();
+            move |txn| coordinator.finish(txn).then(|_| Ok(()))
+        });
+        future::join_all(finished).map(|_| ())
+    }
+
+    /// Run a transaction over the participants at the addresses of
+    /// `parts`, each asked to prepare its payload, returning what was
+    /// decided once every participant acknowledged it.
+    pub fn commit(
+        &self,
+        parts: Vec<(SocketAddr, String)>,
+    ) -> impl Future<Item = Decision, Error = io::Error> {
+        let participants: Vec<SocketAddr> = parts.iter().map(|(addr, _)| *addr).collect();
+        let id = {
+            let mut log = self.log.lock().unwrap();
+            let wall = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or(0);
+            let id = wall.max(log.last_id + 1);
+            let addrs: Vec<String> = participants.iter().map(|addr| addr.to_string()).collect();
+            if let Err(e) = log.append(&format!("BEGIN {} {}", id, addrs.join(","))) {
+                return Either::A(future::err(e));
+            }
+            log.last_id = id;
+            id
+        };
+
+        let votes = parts.into_iter().map(move |(addr, payload)| {
+            request(addr, format!("PREPARE {} {}", id, payload)).and_then(move |reply| {
+                if reply == format!("YES {}", id) {
+                    return Ok(());
+                }
+                let reason = format!("{} voted {:?}", addr, reply);
+                Err(io::Error::new(io::ErrorKind::Other, reason))
+            })
+        });
+        let coordinator = self.clone();
+        let decided = Timeout::new(future::join_all(votes), self.vote_timeout)
+            .then(|voted| {
+                Ok::<_, io::Error>(match voted {
+                    Ok(_) => Decision::Commit,
+                    Err(_) => Decision::Abort,
+                })
+            })
+            .and_then(move |decision| {
+                let txn = Unfinished {
+                    id,
+                    decision,
+                    participants,
+                };
+                let logged = coordinator
+                    .log
+                    .lock()
+                    .unwrap()
+                    .append(&format!("{} {}", decision, id));
+                future::result(logged).and_then(move |()| coordinator.finish(txn))
+            });
+        Either::B(decided)
+    }
+
+    /// Tell the participants of `txn` the decision, and write it `DONE`
+    /// once all of them acknowledged it. It is kept for `recover`
+    /// otherwise.
+    fn finish(&self, txn: Unfinished) -> impl Future<Item = Decision, Error = io::Error> {
+        let line = format!("{} {}", txn.decision, txn.id);
+        let ack = format!("ACK {}", txn.id);
+        let acks: Vec<_> = txn
+            .participants
+            .iter()
+            .map(|&addr| {
+                let ack = ack.clone();
+                request(addr, line.clone()).and_then(move |reply| {
+                    if reply == ack {
+                        return Ok(());
+                    }
+                    let reason = format!("{} answered {:?}", addr, reply);
+                    Err(io::Error::new(io::ErrorKind::Other, reason))
+                })
+            })
+            .collect();
+        let log = self.log.clone();
+        Timeout::new(future::join_all(acks), self.vote_timeout).then(move |acked| {
+            let mut log = log.lock().unwrap();
+            match acked {
+                Ok(_) => {
+                    log.append(&format!("DONE {}", txn.id))?;
+                    Ok(txn.decision)
+                }
+                Err(e) => {
+                    log.unfinished.push(txn);
+                    Err(e.into_inner().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::TimedOut, "not acknowledged in time")
+                    }))
+                }
+            }
+        })
+    }
+}
+
+/// Send `line` to the participant at `addr`, and return its reply.
+fn request(addr: SocketAddr, line: String) -> impl Future<Item = String, Error = io::Error> {
+    TcpStream::connect(&addr)
+        .and_then(|socket| Framed::new(socket, LinesCodec::new()).send(line))
+        .and_then(|framed| framed.into_future().map_err(|(e, _)| e))
+        .and_then(|(reply, _)| {
+            reply.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "hung up"))
+        })
+}
@@ -0,0 +1,373 @@
+//! Records written ahead to a log, and read back after a crash.
+//!
+//! A `Wal` appends records to the files of a directory. Any number of tasks
+//! append, and one thread writes what they appended since it last wrote,
+//! with one `write` and, depending on the `Fsync` policy, one `fsync` for
+//! all of them. The future of an append resolves once its record is as
+//! safe as the policy makes it:
+//!
+//! ```ignore
+//! let (wal, records) = Wal::open("/var/lib/app/wal", Fsync::Always)?;
+//! for record in records {
+//!     apply(&record);
+//! }
+//! tokio::spawn(wal.append(b"set alice 90".to_vec()).map_err(|e| eprintln!("{}", e)));
+//! ```
+//!
+//! * `Fsync::Always` resolves once the record is synced to the disk. A
+//!   crash loses nothing that resolved.
+//! * `Fsync::Interval` syncs at most that often, and resolves once the
+//!   record is written. A crash of the machine loses up to an interval, one
+//!   of the process nothing.
+//! * `Fsync::Never` leaves syncing to the OS.
+//!
+//! Records are framed by their length and an FNV-1a checksum. `open` reads
+//! the records of every file back, oldest first, and cuts a last file torn
+//! by a crash after its last whole record. A bad record before the last one
+//! is an error, the log was damaged otherwise.
+//!
+//! The log only grows. To keep it short, take a checkpoint of what the
+//! records made, like a snapshot: `rotate` first, so that later records go
+//! to a new file, then save the checkpoint, then `remove_before` the file
+//! `rotate` returned. Recovery is the checkpoint and the records after it.
+
+use futures::sync::oneshot;
+use futures::Future;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// When appended records are synced to the disk, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    Always,
+    Interval(Duration),
+    Never,
+}
+
+/// Length and checksum in front of every record.
+const HEADER_LEN: usize = 8;
+
+const EXTENSION: &str = "wal";
+
+enum Op {
+    Append(Vec<u8>, oneshot::Sender<io::Result<()>>),
+    Rotate(mpsc::Sender<io::Result<u64>>),
+}
+
+struct Queue {
+    ops: Vec<Op>,
+    /// Set once every `Wal` is gone, for the writer to stop.
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// Closes the queue once the last `Wal` is dropped.
+struct Closer(Arc<Shared>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().closed = true;
+        self.0.ready.notify_one();
+    }
+}
+
+/// The log, cheap to clone, see the module docs.
+#[derive(Clone)]
+pub struct Wal {
+    shared: Arc<Shared>,
+    dir: Arc<PathBuf>,
+    _closer: Arc<Closer>,
+}
+
+impl Wal {
+    /// Open the log in the directory `dir`, creating it if needed, and
+    /// return it with the records it has.
+    pub fn open<P: AsRef<Path>>(dir: P, fsync: Fsync) -> io::Result<(Wal, Vec<Vec<u8>>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let segments = segments(&dir)?;
+        let mut records = Vec::new();
+        for (index, &segment) in segments.iter().enumerate() {
+            let path = segment_path(&dir, segment);
+            let mut bytes = Vec::new();
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            let whole = read_records(&bytes, &mut records);
+            if whole < bytes.len() {
+                if index + 1 < segments.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: bad record at byte {}", path.display(), whole),
+                    ));
+                }
+                // Torn by a crash while the last records were written.
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(whole as u64)?;
+            }
+        }
+
+        let segment = segments.last().cloned().unwrap_or(1);
+        let file = open_segment(&dir, segment)?;
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                ops: Vec::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let writer = Writer {
+            shared: shared.clone(),
+            dir: dir.clone(),
+            segment,
+            file,
+            fsync,
+            unsynced: false,
+            synced: Instant::now(),
+        };
+        thread::Builder::new()
+            .name("wal".to_string())
+            .spawn(move || writer.run())?;
+
+        let wal = Wal {
+            shared: shared.clone(),
+            dir: Arc::new(dir),
+            _closer: Arc::new(Closer(shared)),
+        };
+        Ok((wal, records))
+    }
+
+    /// Append `record`, resolving once it is as safe as the `Fsync` policy
+    /// makes it. Dropping the future does not take the record back.
+    pub fn append(&self, record: Vec<u8>) -> impl Future<Item = (), Error = io::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.push(Op::Append(record, tx));
+        rx.then(|written| match written {
+            Ok(written) => written,
+            Err(oneshot::Canceled) => Err(closed()),
+        })
+    }
+
+    /// Have the records appended from now on go to a new file, once those
+    /// before are written and synced, and return the number of the new
+    /// file. Blocks until then.
+    pub fn rotate(&self) -> io::Result<u64> {
+        let (tx, rx) = mpsc::channel();
+        self.push(Op::Rotate(tx));
+        rx.recv().unwrap_or_else(|_| Err(closed()))
+    }
+
+    /// Remove the files before `segment`, one `rotate` returned, once a
+    /// checkpoint has what their records made.
+    pub fn remove_before(&self, segment: u64) -> io::Result<()> {
+        for old in segments(&self.dir)? {
+            if old < segment {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&self, op: Op) {
+        self.shared.queue.lock().unwrap().ops.push(op);
+        self.shared.ready.notify_one();
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the wal writer stopped")
+}
+
+/// The thread writing the log.
+struct Writer {
+    shared: Arc<Shared>,
+    dir: PathBuf,
+    segment: u64,
+    file: File,
+    fsync: Fsync,
+    /// Whether records were written since the last sync.
+    unsynced: bool,
+    synced: Instant,
+}
+
+impl Writer {
+    fn run(mut self) {
+        let shared = self.shared.clone();
+        loop {
+            let ops = {
+                let mut queue = shared.queue.lock().unwrap();
+                while queue.ops.is_empty() && !queue.closed {
+                    queue = match (self.fsync, self.unsynced) {
+                        (Fsync::Interval(every), true) => {
+                            let due =
+                                (self.synced + every).saturating_duration_since(Instant::now());
+                            let (queue, waited) = shared.ready.wait_timeout(queue, due).unwrap();
+                            if waited.timed_out() {
+                                drop(queue);
+                                // Failures are seen by the next sync.
+                                let _ = self.sync();
+                                shared.queue.lock().unwrap()
+                            } else {
+                                queue
+                            }
+                        }
+                        _ => shared.ready.wait(queue).unwrap(),
+                    };
+                }
+                if queue.ops.is_empty() {
+                    let _ = self.sync();
+                    return;
+                }
+                mem::take(&mut queue.ops)
+            };
+            self.write(ops);
+        }
+    }
+
+    /// Write the records of `ops` in one go, and rotate in between where
+    /// asked to.
+    fn write(&mut self, ops: Vec<Op>) {
+        let mut buf = Vec::new();
+        let mut waiting = Vec::new();
+        for op in ops {
+            match op {
+                Op::Append(record, tx) => {
+                    frame(&record, &mut buf);
+                    waiting.push(tx);
+                }
+                Op::Rotate(tx) => {
+                    let written = self.flush(&buf, mem::take(&mut waiting)).and_then(|()| {
+                        self.sync()?;
+                        self.file = open_segment(&self.dir, self.segment + 1)?;
+                        self.segment += 1;
+                        Ok(self.segment)
+                    });
+                    buf.clear();
+                    let _ = tx.send(written);
+                }
+            }
+        }
+        let _ = self.flush(&buf, waiting);
+    }
+
+    /// Write `buf` and sync it if the policy says so, then tell `waiting`.
+    fn flush(
+        &mut self,
+        buf: &[u8],
+        waiting: Vec<oneshot::Sender<io::Result<()>>>,
+    ) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let written = self.append(buf).and_then(|()| {
+            self.unsynced = true;
+            match self.fsync {
+                Fsync::Always => self.sync(),
+                Fsync::Interval(every) if self.synced.elapsed() >= every => self.sync(),
+                _ => Ok(()),
+            }
+        });
+        for tx in waiting {
+            let _ = tx.send(match &written {
+                Ok(()) => Ok(()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            });
+        }
+        written
+    }
+
+    /// Write `buf` at the end of the file, or nothing of it.
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let len = self.file.seek(SeekFrom::End(0))?;
+        if let Err(e) = self.file.write_all(buf) {
+            // Records after half of one would not be read back.
+            self.file.set_len(len)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        self.synced = Instant::now();
+        Ok(())
+    }
+}
+
+fn frame(record: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&checksum(record).to_le_bytes());
+    buf.extend_from_slice(record);
+}
+
+/// Push the whole records at the start of `bytes` to `records`, returning
+/// how many bytes they take.
+fn read_records(bytes: &[u8], records: &mut Vec<Vec<u8>>) -> usize {
+    let mut at = 0;
+    while bytes.len() - at >= HEADER_LEN {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[at..at + 4]);
+        let len = u32::from_le_bytes(word) as usize;
+        word.copy_from_slice(&bytes[at + 4..at + 8]);
+        let sum = u32::from_le_bytes(word);
+        let start = at + HEADER_LEN;
+        if bytes.len() - start < len || checksum(&bytes[start..start + len]) != sum {
+            break;
+        }
+        records.push(bytes[start..start + len].to_vec());
+        at = start + len;
+    }
+    at
+}
+
+/// FNV-1a, to tell a torn record from a whole one.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", segment, EXTENSION))
+}
+
+fn open_segment(dir: &Path, segment: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, segment))
+}
+
+/// The numbers of the files of the log in `dir`, oldest first.
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        if let Some(segment) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
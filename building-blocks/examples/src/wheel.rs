@@ -0,0 +1,266 @@
+//! Many coarse timeouts on one timer.
+//!
+//! A `Delay` of tokio per connection is an entry in the timer of the
+//! runtime for each, and a wake-up of the runtime for each when they all
+//! come due at once. A `Wheel` keeps its timeouts itself and looks at them
+//! once per `tick`, on a single `Interval`:
+//!
+//! ```ignore
+//! let wheel = Wheel::new(Duration::from_millis(100));
+//! tokio::spawn(wheel.run().map_err(|e| eprintln!("wheel stopped: {}", e)));
+//!
+//! let mut idle = wheel.timeout(Instant::now() + Duration::from_secs(30));
+//! // In `poll` of a connection, like a `Delay`:
+//! if idle.poll()?.is_ready() {
+//!     // ...
+//!     idle.reset(Instant::now() + Duration::from_secs(30));
+//! }
+//! ```
+//!
+//! A timeout fires at the first tick at or after its deadline, never
+//! before, so up to a tick late. Finer timeouts are better off with a
+//! `Delay`.
+//!
+//! The wheel is hierarchical: the timeouts of the next 64 ticks sit in a
+//! slot per tick, those of the next 64 × 64 in a slot per 64 ticks, and so
+//! on for `LEVELS` levels, and move down a level as their slot comes up.
+//! Adding, resetting and dropping a timeout takes the same time however
+//! many there are, and a tick only looks at the timeouts due.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use tokio::timer::{self, Interval};
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Slots of a level, as bits of a tick.
+const SLOT_BITS: u32 = 6;
+const SLOTS: u64 = 1 << SLOT_BITS;
+/// Levels of the wheel, which spans `SLOTS ^ LEVELS` ticks. Timeouts
+/// further away wait in the last slot of the top level.
+const LEVELS: usize = 6;
+
+/// The failure of a `Timeout` once the wheel stopped.
+#[derive(Debug, Clone)]
+pub struct Stopped;
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the timer wheel stopped")
+    }
+}
+
+impl Error for Stopped {}
+
+/// The timeouts, shared by every clone.
+#[derive(Clone)]
+pub struct Wheel {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    tick: Duration,
+    start: Instant,
+    /// Ticks since `start` that were looked at.
+    now: u64,
+    /// Keys and generations of the timeouts in each slot, by level. Reset
+    /// and dropped timeouts are left behind and skipped.
+    slots: Vec<Vec<Vec<(usize, u64)>>>,
+    /// The timeouts, by key.
+    entries: Vec<Entry>,
+    /// Keys of `entries` not in use.
+    free: Vec<usize>,
+    stopped: bool,
+}
+
+struct Entry {
+    /// Bumped whenever the entry is reset or reused, out of date slot
+    /// entries are no longer it.
+    generation: u64,
+    /// The tick the timeout is due at.
+    deadline: u64,
+    fired: bool,
+    /// The task waiting for it.
+    task: Option<Task>,
+}
+
+impl Wheel {
+    /// A wheel looking at its timeouts every `tick`.
+    pub fn new(tick: Duration) -> Wheel {
+        Wheel {
+            inner: Arc::new(Mutex::new(Inner {
+                tick,
+                start: Instant::now(),
+                now: 0,
+                slots: vec![vec![Vec::new(); SLOTS as usize]; LEVELS],
+                entries: Vec::new(),
+                free: Vec::new(),
+                stopped: false,
+            })),
+        }
+    }
+
+    /// A timeout firing at `deadline`, to the tick.
+    pub fn timeout(&self, deadline: Instant) -> Timeout {
+        let mut inner = self.inner.lock().unwrap();
+        let key = match inner.free.pop() {
+            Some(key) => key,
+            None => {
+                inner.entries.push(Entry {
+                    generation: 0,
+                    deadline: 0,
+                    fired: false,
+                    task: None,
+                });
+                inner.entries.len() - 1
+            }
+        };
+        inner.set(key, deadline);
+        Timeout {
+            wheel: self.clone(),
+            key,
+        }
+    }
+
+    /// Fires the timeouts as they come due, on the runtime it is spawned
+    /// on. It fails if the timer does, and every timeout with it.
+    pub fn run(&self) -> impl Future<Item = (), Error = timer::Error> {
+        let tick = self.inner.lock().unwrap().tick;
+        let wheel = self.clone();
+        let stopped = self.clone();
+        Interval::new(Instant::now() + tick, tick)
+            .for_each(move |now| {
+                wheel.inner.lock().unwrap().advance(now);
+                Ok(())
+            })
+            .then(move |result| {
+                stopped.inner.lock().unwrap().stop();
+                result
+            })
+    }
+}
+
+impl Inner {
+    /// The first tick at or after `deadline`.
+    fn tick_of(&self, deadline: Instant) -> u64 {
+        let since = deadline.saturating_duration_since(self.start);
+        let tick = self.tick.as_nanos().max(1);
+        since.as_nanos().div_ceil(tick) as u64
+    }
+
+    /// Arm the timeout `key` for `deadline`.
+    fn set(&mut self, key: usize, deadline: Instant) {
+        let deadline = self.tick_of(deadline);
+        let entry = &mut self.entries[key];
+        entry.generation += 1;
+        entry.deadline = deadline;
+        entry.fired = false;
+        self.place(key);
+    }
+
+    /// Put the timeout `key` in the slot of its deadline, or fire it if it
+    /// is due.
+    fn place(&mut self, key: usize) {
+        let entry = &mut self.entries[key];
+        if entry.deadline <= self.now {
+            entry.fired = true;
+            if let Some(task) = entry.task.take() {
+                task.notify();
+            }
+            return;
+        }
+        let span = SLOTS.pow(LEVELS as u32);
+        let deadline = entry.deadline.min(self.now + span - 1);
+        let delta = deadline - self.now;
+        let mut level = 0;
+        while delta >= SLOTS.pow(level as u32 + 1) {
+            level += 1;
+        }
+        let slot = (deadline >> (SLOT_BITS * level as u32)) % SLOTS;
+        self.slots[level][slot as usize].push((key, entry.generation));
+    }
+
+    /// Look at every tick up to `now`.
+    fn advance(&mut self, now: Instant) {
+        let target = self.tick_of(now);
+        while self.now < target {
+            self.now += 1;
+            let now = self.now;
+            // The higher levels first, their timeouts may be due now.
+            for level in (1..LEVELS).rev() {
+                let bits = SLOT_BITS * level as u32;
+                if now % (1 << bits) == 0 {
+                    let slot = (now >> bits) % SLOTS;
+                    self.cascade(level, slot as usize);
+                }
+            }
+            self.cascade(0, (now % SLOTS) as usize);
+        }
+    }
+
+    /// Take the timeouts out of `slot` of `level`, placing them anew.
+    fn cascade(&mut self, level: usize, slot: usize) {
+        let keys = std::mem::replace(&mut self.slots[level][slot], Vec::new());
+        for (key, generation) in keys {
+            let entry = &self.entries[key];
+            if entry.generation == generation && !entry.fired {
+                self.place(key);
+            }
+        }
+    }
+
+    /// Fail every timeout.
+    fn stop(&mut self) {
+        self.stopped = true;
+        for entry in &mut self.entries {
+            if let Some(task) = entry.task.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// A timeout of a `Wheel`, ready once its deadline passed. Dropping it
+/// cancels it.
+pub struct Timeout {
+    wheel: Wheel,
+    key: usize,
+}
+
+impl Timeout {
+    /// Fire at `deadline` instead, whether or not it fired already.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.wheel.inner.lock().unwrap().set(self.key, deadline);
+    }
+}
+
+impl Future for Timeout {
+    type Item = ();
+    type Error = Stopped;
+
+    fn poll(&mut self) -> Poll<(), Stopped> {
+        let mut inner = self.wheel.inner.lock().unwrap();
+        if inner.stopped {
+            return Err(Stopped);
+        }
+        let entry = &mut inner.entries[self.key];
+        if entry.fired {
+            return Ok(Async::Ready(()));
+        }
+        entry.task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        let mut inner = self.wheel.inner.lock().unwrap();
+        let entry = &mut inner.entries[self.key];
+        entry.generation += 1;
+        entry.task = None;
+        inner.free.push(self.key);
+    }
+}
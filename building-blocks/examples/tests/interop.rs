@@ -0,0 +1,302 @@
+//! The double server against the Go chat server it bridges to.
+//!
+//! The Go server is not part of this tree, so these tests are ignored
+//! unless asked for, with the command starting it in `GO_CHAT_SERVER`:
+//!
+//! ```text
+//! GO_CHAT_SERVER="/path/to/chat-server -listen {addr}" cargo test --test interop -- --ignored
+//! ```
+//!
+//! `{addr}` is replaced with the address it is to listen on, which is
+//! added as the last argument otherwise. The Go server is taken to speak
+//! like the double server: lines end with `\r\n`, the first line of a
+//! client is its name, and every other line is sent to the other clients
+//! as `name: line`.
+//!
+//! Every test starts a Go server, and a double server dialing it as a peer
+//! of its Go side, see `dial`. Peers of the C side then talk to clients of
+//! the Go server through the two of them:
+//!
+//! ```text
+//! alice -> double server (c) -> "eu-bridge" -> Go server -> bob
+//! bob -> Go server -> "upstream" -> double server (go) -> alice
+//! ```
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest wait for a server to listen, or a line to arrive.
+const WAIT: Duration = Duration::from_secs(10);
+
+/// What the double server logs in with at the Go server.
+const LOGIN: &str = "eu-bridge";
+
+/// What the double server calls the Go server.
+const NAME: &str = "upstream";
+
+/// A process killed once the test is over, however it ends.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// The two servers of a test.
+struct Servers {
+    go: SocketAddr,
+    c: SocketAddr,
+    _processes: Vec<Running>,
+}
+
+impl Servers {
+    fn start() -> Servers {
+        let command = env::var("GO_CHAT_SERVER")
+            .expect("GO_CHAT_SERVER has to be the command starting the Go server");
+        let go = free_addr();
+        let mut words: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+        if words.iter().any(|word| word.contains("{addr}")) {
+            for word in &mut words {
+                *word = word.replace("{addr}", &go.to_string());
+            }
+        } else {
+            words.push(go.to_string());
+        }
+        let go_server = Command::new(&words[0])
+            .args(&words[1..])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("start the Go server");
+        let go_server = Running(go_server);
+        wait_listening(go);
+
+        let (c, go_side) = (free_addr(), free_addr());
+        let config = format!(
+            r#"{{
+                "c_listen": "{}",
+                "go_listen": "{}",
+                "dial": [{{
+                    "side": "go",
+                    "addr": "{}",
+                    "name": "{}",
+                    "login": "{}",
+                    "reconnect_delay_ms": 100
+                }}]
+            }}"#,
+            c, go_side, go, NAME, LOGIN
+        );
+        let path = env::temp_dir().join(format!("interop-{}.json", c.port()));
+        fs::write(&path, config).expect("write the config");
+        let double_server = Command::new(env!("CARGO_BIN_EXE_double_server"))
+            .arg("--config")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("start the double server");
+        let double_server = Running(double_server);
+        wait_listening(c);
+
+        Servers {
+            go,
+            c,
+            _processes: vec![double_server, go_server],
+        }
+    }
+
+    /// A client of the Go server, and a peer of the C side of the double
+    /// server, once the double server is connected to the Go server. The
+    /// clients connected before are connected by then too.
+    fn clients(&self) -> (Client, Client) {
+        let mut bob = Client::connect(self.go, "bob");
+        let mut alice = Client::connect(self.c, "alice");
+        // Dialing takes a moment, what is sent before is lost.
+        let deadline = Instant::now() + WAIT;
+        loop {
+            alice.send("are you there?\r\n");
+            if bob
+                .try_expect(Duration::from_millis(200), |line| {
+                    line.contains("are you there?")
+                })
+                .is_some()
+            {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the double server never reached the Go server"
+            );
+        }
+        // Left out of what the test expects, with any repeats.
+        while bob
+            .try_expect(Duration::from_millis(300), |_| true)
+            .is_some()
+        {}
+        (alice, bob)
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr, name: &str) -> Client {
+        let stream = TcpStream::connect(addr).expect("connect");
+        let reader = BufReader::new(stream.try_clone().expect("clone the stream"));
+        let mut client = Client { stream, reader };
+        client.send(&format!("{}\r\n", name));
+        client
+    }
+
+    /// Write `bytes` as they are, line breaks and all.
+    fn send(&mut self, bytes: &str) {
+        self.stream.write_all(bytes.as_bytes()).expect("send");
+    }
+
+    /// The first line `matches`, without its line break, skipping the
+    /// others. Fails the test if none arrives in time.
+    fn expect<F: Fn(&str) -> bool>(&mut self, matches: F, what: &str) -> String {
+        self.try_expect(WAIT, matches)
+            .unwrap_or_else(|| panic!("no line {} arrived", what))
+    }
+
+    fn try_expect<F: Fn(&str) -> bool>(&mut self, wait: Duration, matches: F) -> Option<String> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let left = deadline.checked_duration_since(Instant::now())?;
+            self.stream
+                .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+                .unwrap();
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => panic!("the connection closed"),
+                Ok(_) => {}
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return None
+                }
+                Err(e) => panic!("read error = {:?}", e),
+            }
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+            if matches(line) {
+                return Some(line.to_string());
+            }
+        }
+    }
+}
+
+/// An address nothing listens on, most likely still free when a server
+/// binds it.
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn wait_listening(addr: SocketAddr) {
+    let deadline = Instant::now() + WAIT;
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "nothing listens on {}", addr);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+#[ignore = "needs the Go server, see the module docs"]
+fn handshake() {
+    let servers = Servers::start();
+    let (mut alice, mut bob) = servers.clients();
+
+    // The Go server took the login for the name of the double server, and
+    // the double server the names of its own peers.
+    alice.send("hello\r\n");
+    bob.expect(
+        |line| line == format!("{}: alice: hello", LOGIN),
+        "from the login",
+    );
+    bob.send("hi\r\n");
+    alice.expect(
+        |line| line == format!("{}: bob: hi", NAME),
+        "named after the dial",
+    );
+}
+
+#[test]
+#[ignore = "needs the Go server, see the module docs"]
+fn rust_to_go() {
+    let servers = Servers::start();
+    // A second client of the Go server hears it too: it is a broadcast.
+    let mut carol = Client::connect(servers.go, "carol");
+    let (mut alice, mut bob) = servers.clients();
+
+    alice.send("ship it\r\n");
+    bob.expect(|line| line.ends_with("alice: ship it"), "at bob");
+    carol.expect(|line| line.ends_with("alice: ship it"), "at carol");
+}
+
+#[test]
+#[ignore = "needs the Go server, see the module docs"]
+fn go_to_rust() {
+    let servers = Servers::start();
+    // A second peer of the C side hears it too, and the sender does not
+    // hear it back from the double server.
+    let mut dave = Client::connect(servers.c, "dave");
+    let (mut alice, mut bob) = servers.clients();
+
+    bob.send("lunch?\r\n");
+    alice.expect(|line| line.ends_with("bob: lunch?"), "at alice");
+    dave.expect(|line| line.ends_with("bob: lunch?"), "at dave");
+    alice.send("sure\r\n");
+    let echoed = bob.expect(
+        |line| line.contains("lunch?") || line.contains("sure"),
+        "after",
+    );
+    assert!(
+        echoed.ends_with("alice: sure"),
+        "bob heard back {:?}",
+        echoed
+    );
+}
+
+#[test]
+#[ignore = "needs the Go server, see the module docs"]
+fn framing() {
+    let servers = Servers::start();
+    let (mut alice, mut bob) = servers.clients();
+
+    // Several lines in one write, and one line over several writes.
+    alice.send("one\r\ntwo\r\nthr");
+    thread::sleep(Duration::from_millis(100));
+    alice.send("ee\r\n");
+    for text in &["one", "two", "three"] {
+        let line = bob.expect(|line| line.contains("alice: "), "in order");
+        assert!(
+            line.ends_with(&format!("alice: {}", text)),
+            "{:?} for {:?}",
+            line,
+            text
+        );
+    }
+
+    // Lines of the Go server come whole, without a stray carriage return.
+    bob.send("four\r\nfive\r\n");
+    for text in &["four", "five"] {
+        let line = alice.expect(|line| line.contains("bob: "), "in order");
+        assert!(
+            line.ends_with(&format!("bob: {}", text)),
+            "{:?} for {:?}",
+            line,
+            text
+        );
+        assert!(!line.contains('\r'), "{:?}", line);
+    }
+}